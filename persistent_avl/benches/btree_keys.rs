@@ -0,0 +1,84 @@
+//! Times `PathCopyBTree::predecessor` on trees whose data arena is laid out
+//! in key order and on trees where it is scattered, which isolates the cost
+//! of following the keys of a page into the data arena. `PathCopyAvl` over
+//! the same data is timed alongside for scale.
+//!
+//! Run with `cargo bench --bench btree_keys`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use persistent_avl::path_copy_avl::path_copy_avl::PathCopyAvl;
+use persistent_avl::path_copy_btree::path_copy_btree::PathCopyBTree;
+use persistent_avl::persistent_avl_tree::PersistentAvlTree;
+
+const ITEMS: u64 = 200_000;
+const QUERIES: u64 = 1_000_000;
+
+/// A xorshift generator, so that runs are repeatable
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+/// The even numbers below `2 * ITEMS`, ascending or shuffled. Data are stored
+/// in the order they are inserted, so this decides whether the keys of a page
+/// sit next to each other in the data arena.
+fn items(scattered: bool) -> Vec<u64> {
+    let mut items: Vec<u64> = (0..ITEMS).map(|item| item * 2).collect();
+    if scattered {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for index in (1..items.len()).rev() {
+            items.swap(index, rng.below(index as u64 + 1) as usize);
+        }
+    }
+
+    items
+}
+
+/// Nanoseconds per `predecessor` query of random items in the newest version
+fn time_queries<Tree: PersistentAvlTree<Data = u64>>(tree: &Tree, timestamp: Tree::Timestamp) -> f64
+where
+    Tree::Timestamp: Copy,
+{
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let queries: Vec<u64> = (0..QUERIES).map(|_| rng.below(ITEMS * 2)).collect();
+
+    let start = Instant::now();
+    for item in queries {
+        black_box(tree.predecessor(black_box(&item), timestamp));
+    }
+
+    start.elapsed().as_nanos() as f64 / QUERIES as f64
+}
+
+fn time_tree<Tree: PersistentAvlTree<Data = u64, Timestamp = usize> + Default>(
+    name: &str,
+    scattered: bool,
+) {
+    let mut tree = Tree::default();
+    let mut timestamp = 0;
+    for item in items(scattered) {
+        timestamp = tree.insert(item);
+    }
+
+    let layout = if scattered { "scattered" } else { "ordered" };
+    let nanos = time_queries(&tree, timestamp);
+    println!("{name:>12}  {layout:>9}  {nanos:>8.1}");
+}
+
+fn main() {
+    println!("        tree       data  ns/query");
+    for scattered in [false, true] {
+        time_tree::<PathCopyAvl<u64>>("avl", scattered);
+        time_tree::<PathCopyBTree<u64, 8>>("btree 8", scattered);
+        time_tree::<PathCopyBTree<u64, 16>>("btree 16", scattered);
+        time_tree::<PathCopyBTree<u64, 64>>("btree 64", scattered);
+    }
+}
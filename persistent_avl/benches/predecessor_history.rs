@@ -0,0 +1,85 @@
+//! Times `FatNodeAvl::predecessor` at recent and old timestamps of trees with
+//! long histories, with and without the Eytzinger index of their versions.
+//!
+//! Run with `cargo bench --bench predecessor_history`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use persistent_avl::fat_node_avl::fat_node_avl::FatNodeAvl;
+use persistent_avl::persistent_avl_tree::PersistentAvlTree;
+
+const ITEMS: u64 = 10_000;
+const UPDATES: u64 = 200_000;
+const QUERIES: u64 = 1_000_000;
+
+/// A xorshift generator, so that runs are repeatable
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+/// A tree of `ITEMS` items after `UPDATES` updates, which in pairs replace a
+/// random item with a new one, so that every version costs the same to descend and
+/// only the histories searched differ
+fn build(children_capacity: usize) -> (FatNodeAvl<u64>, u64) {
+    let mut tree = FatNodeAvl::with_children_capacity(children_capacity);
+    let mut items: Vec<u64> = (0..ITEMS).map(|item| item * 2).collect();
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut last_timestamp = 0;
+    for item in &items {
+        last_timestamp = tree.insert(*item);
+    }
+
+    for _ in 0..UPDATES / 2 {
+        let slot = rng.below(ITEMS) as usize;
+        tree.delete(&items[slot]);
+        items[slot] = rng.below(ITEMS * 2);
+        last_timestamp = tree.insert(items[slot]);
+    }
+
+    (tree, last_timestamp)
+}
+
+/// Nanoseconds per `predecessor` query at timestamps drawn from `timestamps`
+fn time_queries(tree: &FatNodeAvl<u64>, timestamps: (u64, u64)) -> f64 {
+    let (first, last) = timestamps;
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let queries: Vec<(u64, u64)> = (0..QUERIES)
+        .map(|_| (rng.below(ITEMS * 2), first + rng.below(last - first + 1)))
+        .collect();
+
+    let start = Instant::now();
+    for (item, timestamp) in queries {
+        black_box(tree.predecessor(black_box(&item), timestamp));
+    }
+
+    start.elapsed().as_nanos() as f64 / QUERIES as f64
+}
+
+fn main() {
+    println!("capacity  timestamps  index  ns/query");
+    for children_capacity in [8, 1024] {
+        let (mut tree, last_timestamp) = build(children_capacity);
+        let recent = (last_timestamp - 100, last_timestamp);
+        let old = (ITEMS, ITEMS + 100);
+        let any = (0, last_timestamp);
+
+        for indexed in [false, true] {
+            if indexed {
+                tree.index_versions();
+            }
+
+            for (name, timestamps) in [("recent", recent), ("old", old), ("any", any)] {
+                let nanos = time_queries(&tree, timestamps);
+                println!("{children_capacity:>8}  {name:>10}  {indexed:>5}  {nanos:>8.1}");
+            }
+        }
+    }
+}
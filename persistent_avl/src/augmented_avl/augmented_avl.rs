@@ -0,0 +1,247 @@
+use std::ops::RangeBounds;
+
+use crate::augmented_avl::monoid::Monoid;
+use crate::avl::node_store::NodeStore;
+use crate::batch_update;
+use crate::path_copy_avl::path_copy_avl::PathCopyAvl;
+
+/// A partially persistent AVL tree built with path copying, whose nodes also
+/// hold the `M` summary of their subtree.
+///
+/// Summaries are recomputed from the children whenever a node is modified,
+/// rotations and height updates included, and are copied along with the
+/// children, so every version keeps its own. A summary of any range of a
+/// version then takes O(log n) combinations.
+pub type AugmentedAvl<Data, M> = PathCopyAvl<Data, M>;
+
+impl<Data: Ord, M: Monoid<Data>> PathCopyAvl<Data, M> {
+    /// Summary of the data in `range` at `timestamp`, in order, in O(log n)
+    pub fn aggregate(&self, range: impl RangeBounds<Data>, timestamp: usize) -> M::Value {
+        let root = self.root_at(&timestamp);
+
+        self.range_aggregate(root, &range, true, true)
+    }
+
+    /// Summary of the data in `range` in the committed subtree at `node_ptr`
+    ///
+    /// Bounds that every datum of the subtree is known to meet are not
+    /// checked, so below the node where the search for the two ends splits,
+    /// each side follows one path and takes whole subtrees off it.
+    fn range_aggregate(
+        &self,
+        node_ptr: Option<usize>,
+        range: &impl RangeBounds<Data>,
+        check_start: bool,
+        check_end: bool,
+    ) -> M::Value {
+        let Some(node_ptr) = node_ptr else {
+            return M::identity();
+        };
+        let node = &self.node_arena[node_ptr];
+        if !check_start && !check_end {
+            return node.aggregate.clone();
+        }
+
+        let datum = &self.data[node.datum_ptr];
+        if check_start && batch_update::is_before(range, datum) {
+            return self.range_aggregate(node.right, range, check_start, check_end);
+        }
+        if check_end && batch_update::is_after(range, datum) {
+            return self.range_aggregate(node.left, range, check_start, check_end);
+        }
+
+        let left = self.range_aggregate(node.left, range, check_start, false);
+        let right = self.range_aggregate(node.right, range, false, check_end);
+
+        M::combine(&M::combine(&left, &M::lift(datum)), &right)
+    }
+
+    /// Data at `timestamp`, in order, from the subtrees that `skip` does not
+    /// rule out by their summary, stopping at the first datum that `past`
+    /// holds for
+    ///
+    /// `past` must hold for a suffix of the data in order.
+    pub(crate) fn search(
+        &self,
+        timestamp: usize,
+        skip: &impl Fn(&M::Value) -> bool,
+        past: &impl Fn(&Data) -> bool,
+    ) -> Vec<&Data> {
+        let mut found = Vec::new();
+        self.search_subtree(self.root_at(&timestamp), skip, past, &mut found);
+
+        found
+    }
+
+    /// Adds what `search` finds in the committed subtree at `node_ptr` to
+    /// `found`, and returns whether a datum `past` holds for was reached
+    fn search_subtree<'s>(
+        &'s self,
+        node_ptr: Option<usize>,
+        skip: &impl Fn(&M::Value) -> bool,
+        past: &impl Fn(&Data) -> bool,
+        found: &mut Vec<&'s Data>,
+    ) -> bool {
+        let Some(node_ptr) = node_ptr else {
+            return false;
+        };
+        let node = &self.node_arena[node_ptr];
+        if skip(&node.aggregate) {
+            return false;
+        }
+
+        if self.search_subtree(node.left, skip, past, found) {
+            return true;
+        }
+        let datum = &self.data[node.datum_ptr];
+        if past(datum) {
+            return true;
+        }
+        found.push(datum);
+
+        self.search_subtree(node.right, skip, past, found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use super::*;
+    use crate::augmented_avl::monoid::{Count, Max, Min, Sum};
+    use crate::batch_update::BatchUpdate;
+    use crate::persistent_avl_tree::PersistentAvlTree;
+    use crate::test_util::Rng;
+
+    const ITEMS: i64 = 100;
+
+    fn bound(rng: &mut Rng) -> Bound<i64> {
+        let item = rng.below(ITEMS as u64 + 2) as i64 - 1;
+        match rng.below(3) {
+            0 => Bound::Unbounded,
+            1 => Bound::Excluded(item),
+            _ => Bound::Included(item),
+        }
+    }
+
+    /// Runs random updates on a tree summarizing with `M` and checks the
+    /// summary of random ranges of every version against `expected`, which
+    /// summarizes the data in a range directly
+    fn aggregates_match<M: Monoid<i64>>(expected: impl Fn(&[i64]) -> M::Value)
+    where
+        M::Value: PartialEq + std::fmt::Debug,
+    {
+        for seed in 1..10 {
+            let mut rng = Rng(seed);
+            let mut tree = AugmentedAvl::<i64, M>::default();
+            let mut versions: Vec<Vec<i64>> = Vec::new();
+
+            for _ in 0..150 {
+                let mut data = versions.last().cloned().unwrap_or_default();
+                let item = rng.below(ITEMS as u64) as i64;
+                match rng.below(8) {
+                    0 => {
+                        let range = (bound(&mut rng), bound(&mut rng));
+                        data.retain(|datum| !range.contains(datum));
+                        tree.delete_range(range);
+                    }
+                    1 => {
+                        let mut batch: Vec<i64> = (0..rng.below(10))
+                            .map(|_| rng.below(ITEMS as u64) as i64)
+                            .collect();
+                        batch.sort();
+                        data.extend(&batch);
+                        tree.insert_sorted_batch(batch).unwrap();
+                    }
+                    2..=3 if data.contains(&item) => {
+                        data.remove(data.iter().position(|datum| *datum == item).unwrap());
+                        tree.delete(&item);
+                    }
+                    _ => {
+                        data.push(item);
+                        tree.insert(item);
+                    }
+                }
+                data.sort();
+                versions.push(data);
+            }
+
+            for (timestamp, data) in versions.iter().enumerate() {
+                assert_eq!(tree.aggregate(.., timestamp), expected(data));
+
+                for _ in 0..20 {
+                    let range = (bound(&mut rng), bound(&mut rng));
+                    let in_range: Vec<i64> = data
+                        .iter()
+                        .copied()
+                        .filter(|datum| range.contains(datum))
+                        .collect();
+                    assert_eq!(
+                        tree.aggregate(range, timestamp),
+                        expected(&in_range),
+                        "{range:?} at {timestamp}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn counts_match() {
+        aggregates_match::<Count>(|data| data.len());
+    }
+
+    #[test]
+    fn sums_match() {
+        aggregates_match::<Sum<i64>>(|data| data.iter().sum());
+    }
+
+    #[test]
+    fn minimums_and_maximums_match() {
+        aggregates_match::<Min<i64>>(|data| data.iter().min().copied());
+        aggregates_match::<Max<i64>>(|data| data.iter().max().copied());
+    }
+
+    /// A summary that is not commutative, so it only comes out right if every
+    /// subtree is combined in order
+    struct Concat;
+
+    impl Monoid<i64> for Concat {
+        type Value = Vec<i64>;
+
+        fn identity() -> Vec<i64> {
+            Vec::new()
+        }
+
+        fn lift(datum: &i64) -> Vec<i64> {
+            vec![*datum]
+        }
+
+        fn combine(left: &Vec<i64>, right: &Vec<i64>) -> Vec<i64> {
+            [left.as_slice(), right.as_slice()].concat()
+        }
+    }
+
+    #[test]
+    fn summaries_combine_in_order() {
+        aggregates_match::<Concat>(|data| data.to_vec());
+    }
+
+    #[test]
+    fn split_and_joined_versions_keep_summaries() {
+        let mut tree = AugmentedAvl::<i64, Sum<i64>>::default();
+        for item in 0..50 {
+            tree.insert(item);
+        }
+
+        let (less, rest) = tree.split(&20, 49);
+        assert_eq!(tree.aggregate(.., less), (0..20).sum());
+        assert_eq!(tree.aggregate(.., rest), (20..50).sum());
+        assert_eq!(tree.aggregate(10..30, rest), (20..30).sum());
+
+        let joined = tree.join(less, rest);
+        assert_eq!(tree.aggregate(.., joined), (0..50).sum());
+        assert_eq!(tree.aggregate(15..=25, joined), (15..=25).sum());
+        assert_eq!(tree.aggregate(.., 49), (0..50).sum());
+    }
+}
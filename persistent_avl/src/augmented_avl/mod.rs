@@ -0,0 +1,2 @@
+pub mod augmented_avl;
+pub mod monoid;
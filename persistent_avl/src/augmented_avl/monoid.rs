@@ -0,0 +1,116 @@
+use std::marker::PhantomData;
+use std::ops::Add;
+
+/// A summary of data that subtrees can be combined into, like a sum or a
+/// minimum.
+///
+/// `combine` must be associative with `identity` on either side, but need
+/// not be commutative, as subtrees are always combined in order. A summary of
+/// a projection of the data, like the total of one field, is a type
+/// implementing this with `lift` picking the field.
+pub trait Monoid<Data> {
+    type Value: Clone;
+
+    /// Summary of no data
+    fn identity() -> Self::Value;
+
+    /// Summary of a single datum
+    fn lift(datum: &Data) -> Self::Value;
+
+    /// Summary of the data summarized by `left` followed by those summarized
+    /// by `right`
+    fn combine(left: &Self::Value, right: &Self::Value) -> Self::Value;
+}
+
+/// No summary at all, for trees that do not keep one
+impl<Data> Monoid<Data> for () {
+    type Value = ();
+
+    fn identity() {}
+
+    fn lift(_: &Data) {}
+
+    fn combine(_: &(), _: &()) {}
+}
+
+/// Number of data
+pub struct Count;
+
+impl<Data> Monoid<Data> for Count {
+    type Value = usize;
+
+    fn identity() -> usize {
+        0
+    }
+
+    fn lift(_: &Data) -> usize {
+        1
+    }
+
+    fn combine(left: &usize, right: &usize) -> usize {
+        left + right
+    }
+}
+
+/// Total of the data, with `Default` as zero
+pub struct Sum<Data>(PhantomData<Data>);
+
+impl<Data: Clone + Default + Add<Output = Data>> Monoid<Data> for Sum<Data> {
+    type Value = Data;
+
+    fn identity() -> Data {
+        Data::default()
+    }
+
+    fn lift(datum: &Data) -> Data {
+        datum.clone()
+    }
+
+    fn combine(left: &Data, right: &Data) -> Data {
+        left.clone() + right.clone()
+    }
+}
+
+/// Least datum, if any
+pub struct Min<Data>(PhantomData<Data>);
+
+impl<Data: Clone + Ord> Monoid<Data> for Min<Data> {
+    type Value = Option<Data>;
+
+    fn identity() -> Option<Data> {
+        None
+    }
+
+    fn lift(datum: &Data) -> Option<Data> {
+        Some(datum.clone())
+    }
+
+    fn combine(left: &Option<Data>, right: &Option<Data>) -> Option<Data> {
+        match (left, right) {
+            (Some(left), Some(right)) => Some(left.min(right).clone()),
+            _ => left.clone().or_else(|| right.clone()),
+        }
+    }
+}
+
+/// Greatest datum, if any
+pub struct Max<Data>(PhantomData<Data>);
+
+impl<Data: Clone + Ord> Monoid<Data> for Max<Data> {
+    type Value = Option<Data>;
+
+    fn identity() -> Option<Data> {
+        None
+    }
+
+    fn lift(datum: &Data) -> Option<Data> {
+        Some(datum.clone())
+    }
+
+    fn combine(left: &Option<Data>, right: &Option<Data>) -> Option<Data> {
+        match (left, right) {
+            (Some(left), Some(right)) => Some(left.max(right).clone()),
+            _ => left.clone().or_else(|| right.clone()),
+        }
+    }
+}
@@ -108,11 +108,11 @@ pub(crate) fn balance_node<NodePtr: Copy>(
 ) -> NodePtr {
     let balance = get_balance_factor(get_left, get_right, get_height, node);
 
-    if balance <= -2 {
+    if balance >= 2 {
         let left_child = get_left(node).unwrap();
 
         // LR
-        if get_balance_factor(get_left, get_right, get_height, left_child) >= 1 {
+        if get_balance_factor(get_left, get_right, get_height, left_child) <= -1 {
             let new_left_child = rotate_left(get_left, get_right, get_height, modify, left_child);
 
             modify(
@@ -125,11 +125,11 @@ pub(crate) fn balance_node<NodePtr: Copy>(
 
         // LL & LR
         rotate_right(get_left, get_right, get_height, modify, node)
-    } else if balance >= 2 {
+    } else if balance <= -2 {
         let right_child = get_right(node).unwrap();
 
         // RL
-        if get_balance_factor(get_left, get_right, get_height, right_child) <= -1 {
+        if get_balance_factor(get_left, get_right, get_height, right_child) >= 1 {
             let new_right_child =
                 rotate_right(get_left, get_right, get_height, modify, right_child);
 
@@ -148,28 +148,28 @@ pub(crate) fn balance_node<NodePtr: Copy>(
     }
 }
 
-pub(crate) fn balance<NodePtr: Copy>(
+pub(crate) fn balance<NodePtr: Copy + PartialEq>(
     get_left: &impl Fn(NodePtr) -> Option<NodePtr>,
     get_right: &impl Fn(NodePtr) -> Option<NodePtr>,
     get_height: &impl Fn(NodePtr) -> u64,
-    compare: &impl Fn(NodePtr, NodePtr) -> Ordering,
     modify: &mut impl FnMut(NodePtr, Option<NodePtr>, Option<NodePtr>, u64),
-    path: &Vec<NodePtr>,
+    path: &[NodePtr],
 ) -> Option<NodePtr> {
     let mut child = *path.last()?;
 
     path.iter().rev().skip(1).for_each(|&parent| {
+        // Which side the child hangs from is decided by identity rather
+        // than by comparing data, as equal data may sit on either side
+        let is_left_child = get_left(parent) == Some(child);
+
         set_height(get_left, get_right, get_height, modify, child);
 
         child = balance_node(get_left, get_right, get_height, modify, child);
 
-        match compare(child, parent) {
-            Ordering::Less | Ordering::Equal => {
-                modify(parent, Some(child), get_right(parent), get_height(parent));
-            }
-            Ordering::Greater => {
-                modify(parent, get_left(parent), Some(child), get_height(parent));
-            }
+        if is_left_child {
+            modify(parent, Some(child), get_right(parent), get_height(parent));
+        } else {
+            modify(parent, get_left(parent), Some(child), get_height(parent));
         }
 
         child = parent;
@@ -416,15 +416,203 @@ pub(crate) fn successor<NodePtr: Copy, Data>(
 
     while let Some(current) = root {
         match compare(data, current) {
-            Ordering::Less => {
-                root = get_right(current);
-            }
-            Ordering::Greater | Ordering::Equal => {
+            Ordering::Less | Ordering::Equal => {
                 sup = Some(current);
                 root = get_left(current);
             }
+            Ordering::Greater => {
+                root = get_right(current);
+            }
         };
     }
 
     sup
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    /// Datum, children and height of a node
+    type Node = (u64, Option<usize>, Option<usize>, u64);
+
+    /// A tree laid out by hand
+    struct Nodes(RefCell<Vec<Node>>);
+
+    impl Nodes {
+        fn new(nodes: &[Node]) -> Self {
+            Nodes(RefCell::new(nodes.to_vec()))
+        }
+
+        fn left(&self, node: usize) -> Option<usize> {
+            self.0.borrow()[node].1
+        }
+
+        fn right(&self, node: usize) -> Option<usize> {
+            self.0.borrow()[node].2
+        }
+
+        fn height(&self, node: usize) -> u64 {
+            self.0.borrow()[node].3
+        }
+
+        fn datum(&self, node: usize) -> u64 {
+            self.0.borrow()[node].0
+        }
+
+        fn modify(&self, node: usize, left: Option<usize>, right: Option<usize>, height: u64) {
+            let mut nodes = self.0.borrow_mut();
+            nodes[node].1 = left;
+            nodes[node].2 = right;
+            nodes[node].3 = height;
+        }
+
+        fn balance_node(&self, node: usize) -> usize {
+            balance_node(
+                &|node| self.left(node),
+                &|node| self.right(node),
+                &|node| self.height(node),
+                &mut |node, left, right, height| self.modify(node, left, right, height),
+                node,
+            )
+        }
+
+        /// Nodes below `root` in order, checking that heights are right and
+        /// that every node is balanced
+        fn in_order(&self, root: Option<usize>) -> Vec<usize> {
+            fn walk(nodes: &Nodes, node: Option<usize>, order: &mut Vec<usize>) -> u64 {
+                let Some(node) = node else {
+                    return 0;
+                };
+                let left_height = walk(nodes, nodes.left(node), order);
+                order.push(node);
+                let right_height = walk(nodes, nodes.right(node), order);
+
+                assert!(left_height.abs_diff(right_height) <= 1, "unbalanced node");
+                assert_eq!(nodes.height(node), left_height.max(right_height) + 1);
+
+                nodes.height(node)
+            }
+
+            let mut order = Vec::new();
+            walk(self, root, &mut order);
+
+            order
+        }
+    }
+
+    #[test]
+    fn balance_node_rotates_the_heavy_side_up() {
+        // Three nodes holding 1, 2 and 3 in a line, one for each of the four
+        // cases, with the middle datum at index 1
+        let shapes = [
+            // LL
+            [
+                (1, None, None, 1),
+                (2, Some(0), None, 2),
+                (3, Some(1), None, 3),
+            ],
+            // LR
+            [
+                (1, None, Some(1), 2),
+                (2, None, None, 1),
+                (3, Some(0), None, 3),
+            ],
+            // RR
+            [
+                (1, None, Some(1), 3),
+                (2, None, Some(2), 2),
+                (3, None, None, 1),
+            ],
+            // RL
+            [
+                (1, None, Some(2), 3),
+                (2, None, None, 1),
+                (3, Some(1), None, 2),
+            ],
+        ];
+
+        for nodes in shapes {
+            let root = nodes.iter().position(|node| node.3 == 3).unwrap();
+            let nodes = Nodes::new(&nodes);
+
+            let new_root = nodes.balance_node(root);
+            assert_eq!(new_root, 1);
+            assert_eq!(nodes.in_order(Some(new_root)), [0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn balance_node_leaves_balanced_nodes_alone() {
+        let nodes = Nodes::new(&[(1, None, None, 1), (2, Some(0), None, 2)]);
+
+        assert_eq!(nodes.balance_node(1), 1);
+        assert_eq!(nodes.in_order(Some(1)), [0, 1]);
+    }
+
+    #[test]
+    fn successor_is_the_least_datum_at_least_the_item() {
+        // 1, 3, 5, 5 and 7, rooted at the first 5
+        let nodes = Nodes::new(&[
+            (1, None, None, 1),
+            (3, Some(0), None, 2),
+            (5, Some(1), Some(4), 3),
+            (5, None, None, 1),
+            (7, Some(3), None, 2),
+        ]);
+        let data = [1, 3, 5, 5, 7];
+        let compare = |item: &u64, node| item.cmp(&nodes.datum(node));
+
+        for item in 0..9 {
+            let successor = successor(
+                &|node| nodes.left(node),
+                &|node| nodes.right(node),
+                &compare,
+                Some(2),
+                &item,
+            );
+            let predecessor = predecessor(
+                &|node| nodes.left(node),
+                &|node| nodes.right(node),
+                &compare,
+                Some(2),
+                &item,
+            );
+
+            assert_eq!(
+                successor.map(|node| nodes.datum(node)),
+                data.into_iter().find(|&datum| datum >= item),
+                "successor of {item}"
+            );
+            assert_eq!(
+                predecessor.map(|node| nodes.datum(node)),
+                data.into_iter().rev().find(|&datum| datum <= item),
+                "predecessor of {item}"
+            );
+        }
+    }
+
+    #[test]
+    fn balance_keeps_a_child_on_its_side_among_equal_data() {
+        // Three 5s hanging right of each other, the last one just inserted,
+        // with heights not yet updated above it
+        let nodes = Nodes::new(&[
+            (5, None, Some(1), 2),
+            (5, None, Some(2), 1),
+            (5, None, None, 1),
+        ]);
+
+        let root = balance(
+            &|node| nodes.left(node),
+            &|node| nodes.right(node),
+            &|node| nodes.height(node),
+            &mut |node, left, right, height| nodes.modify(node, left, right, height),
+            &[0, 1, 2],
+        );
+
+        assert_eq!(root, Some(1));
+        assert_eq!(nodes.in_order(root), [0, 1, 2]);
+    }
+}
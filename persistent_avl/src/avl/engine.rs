@@ -0,0 +1,419 @@
+// Insertion, deletion and queries of an AVL tree over any `NodeStore`.
+//
+// Linking and rebalancing only need the `NodeLinks` of a store, so trees
+// ordered by something other than their data, like position, can reuse them
+// through `insert_by` and `delete_by`.
+//
+// The engine only decides which nodes change and how. The backend owns the
+// nodes it is handed, and finishes the version afterwards, e.g. by committing
+// path copies or recording the new root.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::ops::RangeBounds;
+
+use crate::avl::avl;
+use crate::avl::node_store::{NodeLinks, NodeStore};
+use crate::batch_update;
+
+/// Outcome of an update to the newest version
+pub(crate) struct Update {
+    /// Root of the new version
+    pub(crate) root: Option<usize>,
+    /// Nodes that were rebalanced, from the root down for a single insertion
+    /// or deletion
+    pub(crate) path: Vec<usize>,
+}
+
+/// Calculates the heights and rebalances the tree up `path`
+///
+/// Returns the element at the root of `path` after modifications are complete
+fn balance<S: NodeLinks>(store: &mut S, path: &[usize]) -> Option<usize> {
+    let store = RefCell::new(store);
+
+    avl::balance(
+        &|node| store.borrow().left(node),
+        &|node| store.borrow().right(node),
+        &|node| store.borrow().height(node),
+        &mut |node, left, right, height| store.borrow_mut().modify(node, left, right, height),
+        path,
+    )
+}
+
+fn modify_left<S: NodeLinks>(store: &mut S, node: usize, left: Option<usize>) {
+    let (right, height) = (store.right(node), store.height(node));
+    store.modify(node, left, right, height);
+}
+
+fn modify_right<S: NodeLinks>(store: &mut S, node: usize, right: Option<usize>) {
+    let (left, height) = (store.left(node), store.height(node));
+    store.modify(node, left, right, height);
+}
+
+/// Links `new_node`, a leaf of height 1 that is not in the tree yet, into
+/// the newest version and rebalances
+pub(crate) fn insert<S: NodeStore>(store: &mut S, new_node: usize) -> Update {
+    insert_by(store, new_node, |store, node| {
+        store.datum(new_node) <= store.datum(node)
+    })
+}
+
+/// Links `new_node`, a leaf of height 1 that is not in the tree yet, into
+/// the newest version and rebalances, going left of every node on the way
+/// down that `goes_left` holds for
+///
+/// `goes_left` is called once for each node on the way down, in order, so it
+/// may keep track of where it is, e.g. by counting positions.
+pub(crate) fn insert_by<S: NodeLinks>(
+    store: &mut S,
+    new_node: usize,
+    mut goes_left: impl FnMut(&S, usize) -> bool,
+) -> Update {
+    let mut path = Vec::new();
+    let mut path_ptr = store.latest_root();
+    let mut is_left = false;
+    while let Some(ptr) = path_ptr {
+        path.push(ptr);
+
+        is_left = goes_left(store, ptr);
+        path_ptr = if is_left {
+            store.left(ptr)
+        } else {
+            store.right(ptr)
+        };
+    }
+
+    if let Some(&parent) = path.last() {
+        if is_left {
+            modify_left(store, parent, Some(new_node));
+        } else {
+            modify_right(store, parent, Some(new_node));
+        }
+    }
+    path.push(new_node);
+
+    let root = balance(store, &path);
+    Update { root, path }
+}
+
+/// Unlinks one node holding `item` from the newest version and rebalances,
+/// or returns `None` if there is none
+pub(crate) fn delete<S: NodeStore>(store: &mut S, item: &S::Data) -> Option<Update> {
+    delete_by(store, |store, node| store.compare(item, node))
+}
+
+/// Unlinks a node from the newest version and rebalances, or returns `None`
+/// if there is none to unlink
+///
+/// `direction` tells for each node on the way down whether it is the one to
+/// unlink, or whether that one is to its left or right, as `Equal`, `Less`
+/// and `Greater`. It is called once for each of those nodes, in order, so it
+/// may keep track of where it is.
+pub(crate) fn delete_by<S: NodeLinks>(
+    store: &mut S,
+    mut direction: impl FnMut(&S, usize) -> Ordering,
+) -> Option<Update> {
+    let mut parent = None;
+    let mut deleted = store.latest_root()?;
+
+    // Path keeping track of all modified nodes in order
+    let mut path = Vec::new();
+
+    // Traverse to node to delete
+    loop {
+        let next = match direction(store, deleted) {
+            Ordering::Equal => break,
+            Ordering::Less => store.left(deleted)?,
+            Ordering::Greater => store.right(deleted)?,
+        };
+
+        path.push(deleted);
+        parent = Some(deleted);
+        deleted = next;
+    }
+
+    let left_of_deleted = store.left(deleted);
+    let right_of_deleted = store.right(deleted);
+
+    let replacement = match left_of_deleted.zip(right_of_deleted) {
+        // With two children, the deleted node is replaced by its successor,
+        // the leftmost node of its right subtree. The successor has no left
+        // child, so its right child is handed to the successor's parent.
+        Some((_, right_subtree)) => {
+            let mut sup = right_subtree;
+            let mut displaced_path = Vec::new();
+
+            while let Some(lesser) = store.left(sup) {
+                displaced_path.push(sup);
+                sup = lesser;
+            }
+
+            if let Some(&sup_parent) = displaced_path.last() {
+                let right_of_sup = store.right(sup);
+                modify_left(store, sup_parent, right_of_sup);
+                modify_right(store, sup, right_of_deleted);
+            }
+            modify_left(store, sup, left_of_deleted);
+
+            // Our path will be up to the deleted node, then the successor,
+            // and then the path down to where the successor was located.
+            path.push(sup);
+            path.append(&mut displaced_path);
+
+            Some(sup)
+        }
+        // With at most one child, the deleted node is replaced by that child.
+        None => left_of_deleted.or(right_of_deleted),
+    };
+
+    if let Some(parent) = parent {
+        if store.left(parent) == Some(deleted) {
+            modify_left(store, parent, replacement);
+        } else {
+            modify_right(store, parent, replacement);
+        }
+    }
+
+    // An empty path means the root itself was removed
+    let root = if path.is_empty() {
+        replacement
+    } else {
+        balance(store, &path)
+    };
+
+    Some(Update { root, path })
+}
+
+fn height<S: NodeLinks>(store: &S, node: Option<usize>) -> u64 {
+    node.map_or(0, |node| store.height(node))
+}
+
+/// Sets the children of `node`, and its height to match theirs
+fn link<S: NodeLinks>(store: &mut S, node: usize, left: Option<usize>, right: Option<usize>) {
+    let height = height(store, left).max(height(store, right)) + 1;
+    store.modify(node, left, right, height);
+}
+
+/// Joins the trees at `left` and `right` with `pivot`, which is in neither,
+/// between them, and returns the root of the joined tree
+///
+/// Everything in `left` must be at most the pivot, and everything in `right`
+/// at least. The pivot hangs from the spine of the taller tree where the
+/// heights meet, so this takes O(|height(left) - height(right)| + 1). The
+/// nodes it rebalances are added to `path`.
+pub(crate) fn join<S: NodeLinks>(
+    store: &mut S,
+    left: Option<usize>,
+    pivot: usize,
+    right: Option<usize>,
+    path: &mut Vec<usize>,
+) -> usize {
+    let left_height = height(store, left);
+    let right_height = height(store, right);
+
+    let mut spine_path = Vec::new();
+    if left_height > right_height + 1 {
+        let mut spine = left;
+        while let Some(node) = spine.filter(|&node| store.height(node) > right_height + 1) {
+            spine_path.push(node);
+            spine = store.right(node);
+        }
+
+        link(store, pivot, spine, right);
+        modify_right(store, *spine_path.last().unwrap(), Some(pivot));
+    } else if right_height > left_height + 1 {
+        let mut spine = right;
+        while let Some(node) = spine.filter(|&node| store.height(node) > left_height + 1) {
+            spine_path.push(node);
+            spine = store.left(node);
+        }
+
+        link(store, pivot, left, spine);
+        modify_left(store, *spine_path.last().unwrap(), Some(pivot));
+    } else {
+        link(store, pivot, left, right);
+    }
+    spine_path.push(pivot);
+
+    let root = balance(store, &spine_path).unwrap();
+    path.append(&mut spine_path);
+
+    root
+}
+
+/// Joins the trees at `left` and `right`, where everything in `left` is at
+/// most everything in `right`, and returns the new root
+pub(crate) fn join_trees<S: NodeLinks>(
+    store: &mut S,
+    left: Option<usize>,
+    right: Option<usize>,
+    path: &mut Vec<usize>,
+) -> Option<usize> {
+    let Some(right) = right else {
+        return left;
+    };
+
+    // The first node of `right` becomes the pivot
+    let (first, rest) = split_first(store, right, path);
+    Some(join(store, left, first, rest, path))
+}
+
+/// Unlinks the first node of the tree at `root`, returning it and the root of
+/// the remaining tree
+fn split_first<S: NodeLinks>(
+    store: &mut S,
+    root: usize,
+    path: &mut Vec<usize>,
+) -> (usize, Option<usize>) {
+    match store.left(root) {
+        Some(left) => {
+            let (first, rest) = split_first(store, left, path);
+            let right = store.right(root);
+
+            (first, Some(join(store, rest, root, right, path)))
+        }
+        None => (root, store.right(root)),
+    }
+}
+
+/// Splits the tree at `root` into the nodes `before` holds for and the rest,
+/// and returns the roots of both in that order
+///
+/// `before` must hold for a prefix of the nodes in order. Every node along
+/// the search path for the end of that prefix is joined back onto one side,
+/// which takes O(log n) altogether.
+pub(crate) fn split_by<S: NodeStore>(
+    store: &mut S,
+    root: Option<usize>,
+    before: &impl Fn(&S, usize) -> bool,
+    path: &mut Vec<usize>,
+) -> (Option<usize>, Option<usize>) {
+    let Some(root) = root else {
+        return (None, None);
+    };
+    let (left, right) = (store.left(root), store.right(root));
+
+    if before(store, root) {
+        let (less, rest) = split_by(store, right, before, path);
+        (Some(join(store, left, root, less, path)), rest)
+    } else {
+        let (less, rest) = split_by(store, left, before, path);
+        (less, Some(join(store, rest, root, right, path)))
+    }
+}
+
+/// Splits the tree at `root` into the nodes holding data less than `item`
+/// and the rest, and returns the roots of both in that order
+pub(crate) fn split<S: NodeStore>(
+    store: &mut S,
+    root: Option<usize>,
+    item: &S::Data,
+    path: &mut Vec<usize>,
+) -> (Option<usize>, Option<usize>) {
+    split_by(store, root, &|store, node| store.datum(node) < item, path)
+}
+
+/// Merges the tree at `other`, whose nodes are in no version yet, into the
+/// tree at `root`, and returns the new root
+///
+/// The tree at `root` is split at every node of `other`, which becomes the
+/// pivot joining the halves merged below it. For m nodes in a balanced
+/// `other` this takes O(m log(n / m + 1)).
+pub(crate) fn union<S: NodeStore>(
+    store: &mut S,
+    root: Option<usize>,
+    other: Option<usize>,
+    path: &mut Vec<usize>,
+) -> Option<usize> {
+    let Some(pivot) = other else {
+        return root;
+    };
+    let (other_left, other_right) = (store.left(pivot), store.right(pivot));
+
+    let (less, rest) = split_by(
+        store,
+        root,
+        &|store, node| store.datum(node) < store.datum(pivot),
+        path,
+    );
+    let left = union(store, less, other_left, path);
+    let right = union(store, rest, other_right, path);
+
+    Some(join(store, left, pivot, right, path))
+}
+
+/// Removes every node in `range` from the newest version in O(log n), by
+/// splitting off the nodes before and after it and joining those
+pub(crate) fn delete_range<S: NodeStore>(
+    store: &mut S,
+    range: &impl RangeBounds<S::Data>,
+) -> Update {
+    let mut path = Vec::new();
+    let root = store.latest_root();
+
+    let (before, rest) = split_by(
+        store,
+        root,
+        &|store, node| batch_update::is_before(range, store.datum(node)),
+        &mut path,
+    );
+    let (_, after) = split_by(
+        store,
+        rest,
+        &|store, node| !batch_update::is_after(range, store.datum(node)),
+        &mut path,
+    );
+
+    let root = join_trees(store, before, after, &mut path);
+    Update { root, path }
+}
+
+/// Merges the tree at `batch`, whose nodes are in no version yet, into the
+/// newest version
+pub(crate) fn insert_batch<S: NodeStore>(store: &mut S, batch: Option<usize>) -> Update {
+    let mut path = Vec::new();
+    let root = store.latest_root();
+
+    let root = union(store, root, batch, &mut path);
+    Update { root, path }
+}
+
+pub(crate) fn contains<S: NodeStore>(store: &S, item: &S::Data, version: &S::Version) -> bool {
+    avl::contains(
+        &|node| store.left_at(node, version),
+        &|node| store.right_at(node, version),
+        &|item, node| store.compare(item, node),
+        store.root_at(version),
+        item,
+    )
+}
+
+pub(crate) fn predecessor<'s, S: NodeStore>(
+    store: &'s S,
+    item: &S::Data,
+    version: &S::Version,
+) -> Option<&'s S::Data> {
+    avl::predecessor(
+        &|node| store.left_at(node, version),
+        &|node| store.right_at(node, version),
+        &|item, node| store.compare(item, node),
+        store.root_at(version),
+        item,
+    )
+    .map(|node| store.datum(node))
+}
+
+pub(crate) fn successor<'s, S: NodeStore>(
+    store: &'s S,
+    item: &S::Data,
+    version: &S::Version,
+) -> Option<&'s S::Data> {
+    avl::successor(
+        &|node| store.left_at(node, version),
+        &|node| store.right_at(node, version),
+        &|item, node| store.compare(item, node),
+        store.root_at(version),
+        item,
+    )
+    .map(|node| store.datum(node))
+}
@@ -1 +1,3 @@
 pub(crate) mod avl;
+pub(crate) mod engine;
+pub(crate) mod node_store;
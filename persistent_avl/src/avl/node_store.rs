@@ -0,0 +1,37 @@
+use std::cmp::Ordering;
+
+/// Children and heights of the nodes of the newest version of an AVL tree,
+/// which is all that linking nodes and rebalancing them takes.
+///
+/// Nodes are arena pointers. Reads see the changes the update in progress
+/// has made so far. How a write is recorded, and how the new version is
+/// finished once the engine is done, is up to the backend.
+pub(crate) trait NodeLinks {
+    fn latest_root(&self) -> Option<usize>;
+    fn left(&self, node: usize) -> Option<usize>;
+    fn right(&self, node: usize) -> Option<usize>;
+    fn height(&self, node: usize) -> u64;
+
+    /// Sets the children and height of `node` in the newest version
+    fn modify(&mut self, node: usize, left: Option<usize>, right: Option<usize>, height: u64);
+}
+
+/// Storage of the nodes of a persistent AVL tree ordered by their data,
+/// through which `avl::engine` updates and queries every backend.
+///
+/// Reads at a version see that version as it was, while the reads and
+/// writes of `NodeLinks` act on the newest version.
+pub(crate) trait NodeStore: NodeLinks {
+    type Data: Ord;
+    type Version;
+
+    fn datum(&self, node: usize) -> &Self::Data;
+
+    fn compare(&self, item: &Self::Data, node: usize) -> Ordering {
+        item.cmp(self.datum(node))
+    }
+
+    fn root_at(&self, version: &Self::Version) -> Option<usize>;
+    fn left_at(&self, node: usize, version: &Self::Version) -> Option<usize>;
+    fn right_at(&self, node: usize, version: &Self::Version) -> Option<usize>;
+}
@@ -0,0 +1,291 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::bulk_load::error::BulkLoadError;
+use crate::persistent_avl_tree::PersistentAvlTree;
+
+/// Updates of many items of the newest version at once, each made as one new
+/// version rather than one version per item
+pub trait BatchUpdate: PersistentAvlTree {
+    /// Deletes every item in `range` from the newest version, and returns the
+    /// timestamp of the new version
+    fn delete_range(&mut self, range: impl RangeBounds<Self::Data>) -> Self::Timestamp;
+
+    /// Inserts the items of `iter`, which must be sorted, into the newest
+    /// version, and returns the timestamp of the new version
+    ///
+    /// Nothing is inserted if `iter` is out of order.
+    fn insert_sorted_batch(
+        &mut self,
+        iter: impl IntoIterator<Item = Self::Data>,
+    ) -> Result<Self::Timestamp, BulkLoadError>;
+}
+
+/// Whether `item` sorts before every item in `range`
+pub(crate) fn is_before<Data: Ord>(range: &impl RangeBounds<Data>, item: &Data) -> bool {
+    match range.start_bound() {
+        Bound::Included(start) => item < start,
+        Bound::Excluded(start) => item <= start,
+        Bound::Unbounded => false,
+    }
+}
+
+/// Whether `item` sorts after every item in `range`
+pub(crate) fn is_after<Data: Ord>(range: &impl RangeBounds<Data>, item: &Data) -> bool {
+    match range.end_bound() {
+        Bound::Included(end) => item > end,
+        Bound::Excluded(end) => item >= end,
+        Bound::Unbounded => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fmt::Debug;
+
+    use super::*;
+    use crate::augmented_avl::augmented_avl::AugmentedAvl;
+    use crate::augmented_avl::monoid::Count;
+    use crate::fat_node_avl::fat_node_avl::FatNodeAvl;
+    use crate::fat_node_rb::fat_node_rb::FatNodeRbTree;
+    use crate::opt_avl::opt_avl::OptAVL;
+    use crate::path_copy_avl::path_copy_avl::PathCopyAvl;
+    use crate::path_copy_btree::path_copy_btree::PathCopyBTree;
+    use crate::path_copy_rb::path_copy_rb::PathCopyRbTree;
+    use crate::path_copy_treap::path_copy_treap::PathCopyTreap;
+    use crate::path_copy_wb::path_copy_wb::PathCopyWbTree;
+    use crate::test_util::Rng;
+
+    /// Items are drawn from `0..ITEMS`, so batches overlap and repeat
+    const ITEMS: u64 = 64;
+
+    fn bound(rng: &mut Rng) -> Bound<u64> {
+        match rng.below(4) {
+            0 => Bound::Unbounded,
+            1 => Bound::Excluded(rng.below(ITEMS + 2)),
+            _ => Bound::Included(rng.below(ITEMS + 2)),
+        }
+    }
+
+    /// Timestamps for `OptAVL`, which borrows the timestamp of each version
+    /// for as long as it lives
+    static TIMESTAMPS: [usize; 128] = {
+        let mut timestamps = [0; 128];
+        let mut index = 0;
+        while index < timestamps.len() {
+            timestamps[index] = index;
+            index += 1;
+        }
+        timestamps
+    };
+
+    /// An `OptAVL` that numbers its versions itself, as the other backends do
+    #[derive(Default)]
+    struct NumberedOptAvl<const P: usize> {
+        tree: OptAVL<'static, u64, usize, P>,
+        versions: usize,
+    }
+
+    impl<const P: usize> NumberedOptAvl<P> {
+        /// Timestamp of the next version, counted once it is made
+        fn next_timestamp(&self) -> &'static usize {
+            &TIMESTAMPS[self.versions]
+        }
+
+        fn made(&mut self) -> usize {
+            self.versions += 1;
+            self.versions - 1
+        }
+    }
+
+    impl<const P: usize> PersistentAvlTree for NumberedOptAvl<P> {
+        type Data = u64;
+        type Timestamp = usize;
+
+        fn insert(&mut self, item: u64) -> usize {
+            self.tree.insert(item, self.next_timestamp());
+            self.made()
+        }
+
+        fn delete(&mut self, item: &u64) -> Option<usize> {
+            let deleted = self.tree.delete(item, self.next_timestamp());
+            deleted.then(|| self.made())
+        }
+
+        fn contains(&self, item: &u64, timestamp: usize) -> bool {
+            self.tree.contains(item, &timestamp)
+        }
+
+        fn predecessor(&self, item: &u64, timestamp: usize) -> Option<&u64> {
+            self.tree.predecessor(item, &timestamp)
+        }
+
+        fn successor(&self, item: &u64, timestamp: usize) -> Option<&u64> {
+            self.tree.successor(item, &timestamp)
+        }
+    }
+
+    impl<const P: usize> BatchUpdate for NumberedOptAvl<P> {
+        fn delete_range(&mut self, range: impl RangeBounds<u64>) -> usize {
+            self.tree.delete_range(range, self.next_timestamp());
+            self.made()
+        }
+
+        fn insert_sorted_batch(
+            &mut self,
+            iter: impl IntoIterator<Item = u64>,
+        ) -> Result<usize, BulkLoadError> {
+            self.tree.insert_sorted_batch(iter, self.next_timestamp())?;
+            Ok(self.made())
+        }
+    }
+
+    /// Number of copies of each item in a version
+    type Counts = BTreeMap<u64, usize>;
+
+    fn assert_version<Tree>(tree: &Tree, timestamp: Tree::Timestamp, reference: &Counts)
+    where
+        Tree: BatchUpdate<Data = u64>,
+        Tree::Timestamp: Copy,
+    {
+        for item in 0..ITEMS + 2 {
+            assert_eq!(
+                tree.contains(&item, timestamp),
+                reference.contains_key(&item),
+                "contains {item}"
+            );
+            assert_eq!(
+                tree.predecessor(&item, timestamp),
+                reference.range(..=item).next_back().map(|(item, _)| item),
+                "predecessor of {item}"
+            );
+            assert_eq!(
+                tree.successor(&item, timestamp),
+                reference.range(item..).next().map(|(item, _)| item),
+                "successor of {item}"
+            );
+        }
+    }
+
+    /// Applies random range deletions, sorted batches and single insertions
+    /// and deletions to `tree` and to a map of item counts, and compares every
+    /// version with the counts it should hold.
+    ///
+    /// Items are inserted again and again, so a range deletion must remove
+    /// every copy, and a single deletion only one. Ranges may be unbounded,
+    /// empty or inverted.
+    fn matches_a_count_map<Tree>(new_tree: impl Fn() -> Tree)
+    where
+        Tree: BatchUpdate<Data = u64>,
+        Tree::Timestamp: Copy + Debug + PartialEq + TryFrom<usize>,
+    {
+        let timestamp = |version: usize| Tree::Timestamp::try_from(version).ok().unwrap();
+
+        for seed in 1..10 {
+            let mut rng = Rng(seed);
+            let mut tree = new_tree();
+            let mut versions: Vec<Counts> = Vec::new();
+
+            for _ in 0..100 {
+                let mut reference = versions.last().cloned().unwrap_or_default();
+                let item = rng.below(ITEMS);
+                let new_timestamp = match rng.below(6) {
+                    0 => {
+                        let range = (bound(&mut rng), bound(&mut rng));
+                        reference.retain(|item, _| !range.contains(item));
+                        tree.delete_range(range)
+                    }
+                    1 | 2 => {
+                        let mut batch: Vec<u64> =
+                            (0..rng.below(20)).map(|_| rng.below(ITEMS)).collect();
+                        batch.sort();
+                        for &item in &batch {
+                            *reference.entry(item).or_default() += 1;
+                        }
+                        tree.insert_sorted_batch(batch).unwrap()
+                    }
+                    3 => {
+                        let Some(timestamp) = tree.delete(&item) else {
+                            assert!(!reference.contains_key(&item));
+                            continue;
+                        };
+                        let count = reference.get_mut(&item).expect("deleted a missing item");
+                        *count -= 1;
+                        if *count == 0 {
+                            reference.remove(&item);
+                        }
+                        timestamp
+                    }
+                    _ => {
+                        *reference.entry(item).or_default() += 1;
+                        tree.insert(item)
+                    }
+                };
+
+                assert_eq!(new_timestamp, timestamp(versions.len()));
+                assert_version(&tree, new_timestamp, &reference);
+                versions.push(reference);
+            }
+
+            // An unsorted batch adds no version
+            assert!(tree.insert_sorted_batch([2, 1]).is_err());
+            assert_eq!(tree.delete_range(ITEMS..), timestamp(versions.len()));
+            versions.push(versions.last().unwrap().clone());
+
+            for (version, reference) in versions.iter().enumerate() {
+                assert_version(&tree, timestamp(version), reference);
+            }
+        }
+    }
+
+    #[test]
+    fn path_copy_avl_matches_a_count_map() {
+        matches_a_count_map(PathCopyAvl::new);
+    }
+
+    #[test]
+    fn fat_node_avl_matches_a_count_map() {
+        matches_a_count_map(FatNodeAvl::new);
+        matches_a_count_map(|| FatNodeAvl::with_children_capacity(1));
+    }
+
+    #[test]
+    fn augmented_avl_matches_a_count_map() {
+        matches_a_count_map(AugmentedAvl::<u64, Count>::default);
+    }
+
+    #[test]
+    fn path_copy_treap_matches_a_count_map() {
+        matches_a_count_map(PathCopyTreap::new);
+    }
+
+    #[test]
+    fn path_copy_wb_tree_matches_a_count_map() {
+        matches_a_count_map(PathCopyWbTree::new);
+    }
+
+    #[test]
+    fn path_copy_rb_tree_matches_a_count_map() {
+        matches_a_count_map(PathCopyRbTree::new);
+    }
+
+    #[test]
+    fn fat_node_rb_tree_matches_a_count_map() {
+        matches_a_count_map(FatNodeRbTree::new);
+    }
+
+    #[test]
+    fn path_copy_btree_matches_a_count_map() {
+        matches_a_count_map(PathCopyBTree::<u64>::new);
+        matches_a_count_map(PathCopyBTree::<u64, 3>::new);
+        matches_a_count_map(PathCopyBTree::<u64, 4>::new);
+    }
+
+    #[test]
+    fn opt_avl_matches_a_count_map() {
+        matches_a_count_map(NumberedOptAvl::<0>::default);
+        matches_a_count_map(NumberedOptAvl::<1>::default);
+        matches_a_count_map(NumberedOptAvl::<2>::default);
+        matches_a_count_map(NumberedOptAvl::<8>::default);
+    }
+}
@@ -0,0 +1,64 @@
+// Building a tree from sorted input directly, written against a closure
+// that creates nodes like the helpers in `avl::avl`.
+//
+// The middle item becomes the root and both halves are built the same way,
+// so sibling subtrees differ in size by at most one and the tree is perfectly
+// balanced, which satisfies AVL and red-black invariants alike.
+
+use crate::bulk_load::error::BulkLoadError;
+
+/// Collects `iter`, refusing it as soon as an item is less than the one
+/// before it. Equal items are kept.
+pub(crate) fn collect_sorted<Data: Ord>(
+    iter: impl IntoIterator<Item = Data>,
+) -> Result<Vec<Data>, BulkLoadError> {
+    let mut data: Vec<Data> = Vec::new();
+    for item in iter {
+        if data.last().is_some_and(|last| *last > item) {
+            return Err(BulkLoadError::Unsorted { index: data.len() });
+        }
+        data.push(item);
+    }
+
+    Ok(data)
+}
+
+/// Builds a perfectly balanced tree over the positions in `start..end`, in
+/// O(end - start), and returns its root
+///
+/// `make(position, left, right, height)` creates the node of a position once
+/// both of its children exist, and returns a pointer to it.
+pub(crate) fn build_balanced<NodePtr: Copy>(
+    start: usize,
+    end: usize,
+    make: &mut impl FnMut(usize, Option<NodePtr>, Option<NodePtr>, u64) -> NodePtr,
+) -> Option<NodePtr> {
+    build(start, end, make).map(|(root, _)| root)
+}
+
+fn build<NodePtr: Copy>(
+    start: usize,
+    end: usize,
+    make: &mut impl FnMut(usize, Option<NodePtr>, Option<NodePtr>, u64) -> NodePtr,
+) -> Option<(NodePtr, u64)> {
+    if start >= end {
+        return None;
+    }
+
+    let middle = start + (end - start) / 2;
+    let left = build(start, middle, make);
+    let right = build(middle + 1, end, make);
+
+    let height = left
+        .map_or(0, |(_, height)| height)
+        .max(right.map_or(0, |(_, height)| height))
+        + 1;
+    let node = make(
+        middle,
+        left.map(|(node, _)| node),
+        right.map(|(node, _)| node),
+        height,
+    );
+
+    Some((node, height))
+}
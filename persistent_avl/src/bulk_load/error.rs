@@ -0,0 +1,21 @@
+use std::error::Error;
+use std::fmt;
+
+/// Reasons a bulk load was refused, leaving the tree as it was
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BulkLoadError {
+    /// The item at this position of the input is less than the one before it
+    Unsorted { index: usize },
+}
+
+impl fmt::Display for BulkLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BulkLoadError::Unsorted { index } => {
+                write!(f, "bulk load input is out of order at index {}", index)
+            }
+        }
+    }
+}
+
+impl Error for BulkLoadError {}
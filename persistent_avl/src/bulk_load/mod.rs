@@ -0,0 +1,2 @@
+pub(crate) mod bulk_load;
+pub mod error;
@@ -0,0 +1,612 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::durable::error::DurableError;
+use crate::durable::wal::{sync_parent_dir, LogOp, SyncPolicy, WriteAheadLog};
+use crate::persistent_avl_tree::PersistentAvlTree;
+use crate::snapshot::binary_snapshot::BinarySnapshot;
+use crate::snapshot::record::Record;
+
+// A durable tree lives in a directory of numbered files:
+//
+//   checkpoint-N.snap  snapshot of the tree after every update in the
+//                      log segments numbered below N
+//   log-N.wal          updates made after checkpoint N was taken
+//
+// Taking checkpoint N + 1 writes it to a temporary file, renames it into
+// place, starts log segment N + 1 and only then removes the files it
+// supersedes. A crash between any two of these steps leaves either the old
+// checkpoint with its complete log, or the new checkpoint, so recovery
+// loads the newest checkpoint, replays the segments numbered from it on,
+// and finishes any cleanup that was interrupted.
+
+const CHECKPOINT_PREFIX: &str = "checkpoint-";
+const CHECKPOINT_SUFFIX: &str = ".snap";
+const LOG_PREFIX: &str = "log-";
+const LOG_SUFFIX: &str = ".wal";
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// When a `DurableTree` takes checkpoints on its own
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CheckpointPolicy {
+    /// Only when `checkpoint` is called
+    Manual,
+    /// Before an update, once the current log segment has grown to at least
+    /// this many bytes
+    LogBytes(u64),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DurableOptions {
+    pub sync: SyncPolicy,
+    pub checkpoint: CheckpointPolicy,
+}
+
+impl Default for DurableOptions {
+    fn default() -> Self {
+        DurableOptions {
+            sync: SyncPolicy::Always,
+            checkpoint: CheckpointPolicy::LogBytes(64 * 1024 * 1024),
+        }
+    }
+}
+
+/// A persistent tree stored as periodic checkpoints plus a write-ahead log.
+///
+/// Updates are logged before they are applied. Checkpoints hold the full
+/// arena state, so recovery only has to replay the log written since the
+/// newest one.
+pub struct DurableTree<Tree: PersistentAvlTree> {
+    dir: PathBuf,
+    options: DurableOptions,
+    tree: Tree,
+    log: WriteAheadLog,
+    sequence: u64,
+}
+
+impl<Tree> DurableTree<Tree>
+where
+    Tree: PersistentAvlTree + BinarySnapshot + Default,
+    Tree::Data: Record,
+{
+    /// Opens the tree stored in `dir`, creating an empty one if needed.
+    ///
+    /// Recovery loads the newest checkpoint, replays the log segments written
+    /// after it, truncates a torn final log record, and removes files left
+    /// over from an interrupted checkpoint.
+    pub fn open(dir: impl AsRef<Path>, options: DurableOptions) -> Result<Self, DurableError> {
+        Self::open_with(dir, options, Tree::default)
+    }
+}
+
+impl<Tree> DurableTree<Tree>
+where
+    Tree: PersistentAvlTree + BinarySnapshot,
+    Tree::Data: Record,
+{
+    /// Same as `open`, but starts from `new_tree()` rather than the default
+    /// tree when there is no checkpoint yet, such as a tree with Merkle
+    /// hashing enabled
+    pub fn open_with(
+        dir: impl AsRef<Path>,
+        options: DurableOptions,
+        new_tree: impl FnOnce() -> Tree,
+    ) -> Result<Self, DurableError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let files = list_files(&dir)?;
+
+        // Checkpoints that never made it to their final name are incomplete
+        for temp in files.temps.iter() {
+            fs::remove_file(temp)?;
+        }
+
+        let sequence = files.checkpoints.last().copied().unwrap_or(0);
+        let mut tree = match files.checkpoints.last() {
+            Some(&sequence) => {
+                let file = File::open(checkpoint_path(&dir, sequence))?;
+                Tree::read_from(BufReader::new(file))?
+            }
+            None => new_tree(),
+        };
+
+        // Replay every segment written since the checkpoint, in order
+        let segments: Vec<u64> = files
+            .logs
+            .iter()
+            .copied()
+            .filter(|&log| log >= sequence)
+            .collect();
+
+        for (expected, &segment) in (sequence..).zip(segments.iter()) {
+            if segment != expected {
+                return Err(DurableError::MissingLogSegment(expected));
+            }
+        }
+
+        let last_segment = segments.last().copied().unwrap_or(sequence);
+        let mut log = None;
+        for segment in sequence..=last_segment {
+            log = Some(WriteAheadLog::open(
+                &log_path(&dir, segment),
+                options.sync,
+                |op, datum| replay(&mut tree, op, datum),
+            )?);
+        }
+
+        let mut durable_tree = DurableTree {
+            dir,
+            options,
+            tree,
+            log: log.unwrap(),
+            sequence: last_segment,
+        };
+        durable_tree.remove_superseded(sequence)?;
+
+        Ok(durable_tree)
+    }
+
+    /// Logs and then applies an insertion
+    pub fn insert(&mut self, item: Tree::Data) -> Result<Tree::Timestamp, DurableError> {
+        self.checkpoint_if_due()?;
+
+        self.log.append(LogOp::Insert, &item)?;
+        Ok(self.tree.insert(item))
+    }
+
+    /// Logs and then applies a deletion
+    ///
+    /// Deletions of missing items are logged too, and are no-ops on replay.
+    pub fn delete(&mut self, item: &Tree::Data) -> Result<Option<Tree::Timestamp>, DurableError> {
+        self.checkpoint_if_due()?;
+
+        self.log.append(LogOp::Delete, item)?;
+        Ok(self.tree.delete(item))
+    }
+
+    /// Writes the full tree to a new checkpoint, starts a new log segment and
+    /// removes the checkpoint and log segments it supersedes
+    pub fn checkpoint(&mut self) -> Result<(), DurableError> {
+        let sequence = self.sequence + 1;
+
+        let temp = self.write_checkpoint(sequence)?;
+        commit_checkpoint(&temp, &checkpoint_path(&self.dir, sequence))?;
+        self.start_log_segment(sequence)?;
+
+        self.remove_superseded(sequence)
+    }
+
+    /// Forces every logged update to stable storage
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.log.sync()
+    }
+
+    /// The tree, for queries at any timestamp
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Writes the full tree to the temporary file of checkpoint `sequence`,
+    /// once the log it covers is on stable storage, and returns its path
+    fn write_checkpoint(&mut self, sequence: u64) -> Result<PathBuf, DurableError> {
+        self.log.sync()?;
+
+        let temp = temp_path(&checkpoint_path(&self.dir, sequence));
+        let file = File::create(&temp)?;
+        let mut writer = BufWriter::new(file);
+        self.tree.write_to(&mut writer)?;
+
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+
+        Ok(temp)
+    }
+
+    /// Starts log segment `sequence` and appends to it from now on
+    fn start_log_segment(&mut self, sequence: u64) -> Result<(), DurableError> {
+        self.log = WriteAheadLog::open(
+            &log_path(&self.dir, sequence),
+            self.options.sync,
+            |_: LogOp, _: Tree::Data| {},
+        )?;
+        self.sequence = sequence;
+
+        Ok(())
+    }
+
+    fn checkpoint_if_due(&mut self) -> Result<(), DurableError> {
+        match self.options.checkpoint {
+            CheckpointPolicy::LogBytes(limit) if self.log.len() >= limit => self.checkpoint(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes checkpoints and log segments older than checkpoint `sequence`
+    fn remove_superseded(&mut self, sequence: u64) -> Result<(), DurableError> {
+        let files = list_files(&self.dir)?;
+
+        let checkpoints = files
+            .checkpoints
+            .iter()
+            .filter(|&&checkpoint| checkpoint < sequence)
+            .map(|&checkpoint| checkpoint_path(&self.dir, checkpoint));
+        let logs = files
+            .logs
+            .iter()
+            .filter(|&&log| log < sequence)
+            .map(|&log| log_path(&self.dir, log));
+
+        let mut removed = false;
+        for path in checkpoints.chain(logs) {
+            fs::remove_file(path)?;
+            removed = true;
+        }
+
+        if removed {
+            sync_parent_dir(&checkpoint_path(&self.dir, sequence))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<Tree: PersistentAvlTree> Drop for DurableTree<Tree> {
+    fn drop(&mut self) {
+        let _ = self.log.sync();
+    }
+}
+
+/// Renames the written `temp` to `checkpoint`, which is the commit point of
+/// the checkpoint
+fn commit_checkpoint(temp: &Path, checkpoint: &Path) -> io::Result<()> {
+    fs::rename(temp, checkpoint)?;
+    sync_parent_dir(checkpoint)
+}
+
+fn replay<Tree: PersistentAvlTree>(tree: &mut Tree, op: LogOp, datum: Tree::Data) {
+    match op {
+        LogOp::Insert => {
+            tree.insert(datum);
+        }
+        LogOp::Delete => {
+            tree.delete(&datum);
+        }
+    }
+}
+
+/// Sequence numbers of the files in a durable tree's directory, ascending
+struct Files {
+    checkpoints: Vec<u64>,
+    logs: Vec<u64>,
+    temps: Vec<PathBuf>,
+}
+
+fn list_files(dir: &Path) -> io::Result<Files> {
+    let mut files = Files {
+        checkpoints: Vec::new(),
+        logs: Vec::new(),
+        temps: Vec::new(),
+    };
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+
+        if name.ends_with(TEMP_SUFFIX) {
+            files.temps.push(entry.path());
+        } else if let Some(sequence) = parse_sequence(name, CHECKPOINT_PREFIX, CHECKPOINT_SUFFIX) {
+            files.checkpoints.push(sequence);
+        } else if let Some(sequence) = parse_sequence(name, LOG_PREFIX, LOG_SUFFIX) {
+            files.logs.push(sequence);
+        }
+    }
+
+    files.checkpoints.sort_unstable();
+    files.logs.sort_unstable();
+
+    Ok(files)
+}
+
+fn parse_sequence(name: &str, prefix: &str, suffix: &str) -> Option<u64> {
+    name.strip_prefix(prefix)?
+        .strip_suffix(suffix)?
+        .parse()
+        .ok()
+}
+
+fn checkpoint_path(dir: &Path, sequence: u64) -> PathBuf {
+    dir.join(format!(
+        "{}{:020}{}",
+        CHECKPOINT_PREFIX, sequence, CHECKPOINT_SUFFIX
+    ))
+}
+
+fn log_path(dir: &Path, sequence: u64) -> PathBuf {
+    dir.join(format!("{}{:020}{}", LOG_PREFIX, sequence, LOG_SUFFIX))
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(TEMP_SUFFIX);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::OpenOptions;
+    use std::ops::Range;
+
+    use super::*;
+    use crate::path_copy_avl::path_copy_avl::PathCopyAvl;
+    use crate::test_util::TempDir;
+
+    type Tree = PathCopyAvl<u64>;
+
+    /// Items are drawn from `0..KEYS`, so that updates hit duplicates and
+    /// missing items
+    const KEYS: u64 = 16;
+
+    /// Counts of every item, after each update that made a version
+    type History = Vec<BTreeMap<u64, usize>>;
+
+    fn options() -> DurableOptions {
+        DurableOptions {
+            sync: SyncPolicy::Always,
+            checkpoint: CheckpointPolicy::Manual,
+        }
+    }
+
+    /// Applies updates `steps` of a fixed mix of insertions and deletions,
+    /// checking that each new version gets the next timestamp
+    fn update(tree: &mut DurableTree<Tree>, history: &mut History, steps: Range<u64>) {
+        for step in steps {
+            let item = step * 7 % KEYS;
+            let mut counts = history.last().cloned().unwrap_or_default();
+
+            let timestamp = if step % 3 == 2 {
+                let timestamp = tree.delete(&item).unwrap();
+                if timestamp.is_some() {
+                    *counts.get_mut(&item).unwrap() -= 1;
+                    counts.retain(|_, count| *count > 0);
+                }
+                timestamp
+            } else {
+                *counts.entry(item).or_default() += 1;
+                Some(tree.insert(item).unwrap())
+            };
+
+            if let Some(timestamp) = timestamp {
+                assert_eq!(timestamp, history.len());
+                history.push(counts);
+            }
+        }
+    }
+
+    fn assert_history(tree: &DurableTree<Tree>, history: &History) {
+        for (timestamp, counts) in history.iter().enumerate() {
+            for item in 0..KEYS {
+                assert_eq!(
+                    tree.tree().contains(&item, timestamp),
+                    counts.contains_key(&item),
+                    "item {} at timestamp {}",
+                    item,
+                    timestamp
+                );
+            }
+        }
+    }
+
+    /// A tree with checkpoint 1 taken and updates logged after it
+    fn checkpointed(dir: &TempDir, history: &mut History) -> DurableTree<Tree> {
+        let mut tree = DurableTree::open(dir.path(), options()).unwrap();
+        update(&mut tree, history, 0..30);
+        tree.checkpoint().unwrap();
+        update(&mut tree, history, 30..60);
+
+        tree
+    }
+
+    /// Reopens the tree in `dir` after a crash, and checks that it recovered
+    /// `history`, was left with the files of checkpoint `sequence` only, and
+    /// keeps working across another reopen
+    fn assert_recovers(dir: &TempDir, history: &mut History, sequence: u64) {
+        let mut tree = DurableTree::<Tree>::open(dir.path(), options()).unwrap();
+        assert_history(&tree, history);
+
+        let files = list_files(dir.path()).unwrap();
+        assert!(files.temps.is_empty());
+        // Sequence 0 is the log written before the first checkpoint
+        let checkpoints: Vec<u64> = (sequence > 0).then_some(sequence).into_iter().collect();
+        assert_eq!(files.checkpoints, checkpoints);
+        assert_eq!(files.logs, [sequence]);
+
+        update(&mut tree, history, 60..80);
+        drop(tree);
+
+        let tree = DurableTree::<Tree>::open(dir.path(), options()).unwrap();
+        assert_history(&tree, history);
+    }
+
+    #[test]
+    fn recovers_from_the_log_alone() {
+        let dir = TempDir::new("durable-log");
+        let mut history = History::new();
+        let mut tree = DurableTree::open(dir.path(), options()).unwrap();
+        update(&mut tree, &mut history, 0..60);
+        drop(tree);
+
+        assert_recovers(&dir, &mut history, 0);
+    }
+
+    #[test]
+    fn recovers_after_a_checkpoint() {
+        let dir = TempDir::new("durable-checkpoint");
+        let mut history = History::new();
+        let mut tree = checkpointed(&dir, &mut history);
+        tree.checkpoint().unwrap();
+        drop(tree);
+
+        assert_recovers(&dir, &mut history, 2);
+    }
+
+    #[test]
+    fn reopens_into_a_hashed_tree() {
+        let dir = TempDir::new("durable-hashed");
+        let open = || {
+            DurableTree::open_with(dir.path(), options(), PathCopyAvl::with_merkle_hashing).unwrap()
+        };
+
+        let mut tree = open();
+        let mut history = History::new();
+        update(&mut tree, &mut history, 0..30);
+        let root_hashes: Vec<_> = (0..history.len())
+            .map(|timestamp| tree.tree().root_hash(timestamp).unwrap())
+            .collect();
+        drop(tree);
+
+        // Once from the log alone, and once from a checkpoint
+        for checkpoint in [false, true] {
+            let mut tree = open();
+            for (timestamp, root_hash) in root_hashes.iter().enumerate() {
+                assert_eq!(tree.tree().root_hash(timestamp).as_ref(), Some(root_hash));
+            }
+
+            if checkpoint {
+                tree.checkpoint().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn crash_after_writing_the_temp_snapshot() {
+        let dir = TempDir::new("durable-temp");
+        let mut history = History::new();
+        let mut tree = checkpointed(&dir, &mut history);
+        tree.write_checkpoint(2).unwrap();
+        drop(tree);
+
+        assert_recovers(&dir, &mut history, 1);
+    }
+
+    #[test]
+    fn crash_after_the_rename() {
+        let dir = TempDir::new("durable-rename");
+        let mut history = History::new();
+        let mut tree = checkpointed(&dir, &mut history);
+        let temp = tree.write_checkpoint(2).unwrap();
+        fs::rename(temp, checkpoint_path(dir.path(), 2)).unwrap();
+        drop(tree);
+
+        assert_recovers(&dir, &mut history, 2);
+    }
+
+    #[test]
+    fn crash_before_the_new_log_is_created() {
+        let dir = TempDir::new("durable-before-log");
+        let mut history = History::new();
+        let mut tree = checkpointed(&dir, &mut history);
+        let temp = tree.write_checkpoint(2).unwrap();
+        commit_checkpoint(&temp, &checkpoint_path(dir.path(), 2)).unwrap();
+        drop(tree);
+
+        assert_recovers(&dir, &mut history, 2);
+    }
+
+    #[test]
+    fn crash_while_the_new_log_is_created() {
+        let dir = TempDir::new("durable-log-header");
+        let mut history = History::new();
+        let mut tree = checkpointed(&dir, &mut history);
+        let temp = tree.write_checkpoint(2).unwrap();
+        commit_checkpoint(&temp, &checkpoint_path(dir.path(), 2)).unwrap();
+        drop(tree);
+
+        // Only part of the header of the new segment made it to disk
+        fs::write(log_path(dir.path(), 2), b"PAVL").unwrap();
+
+        assert_recovers(&dir, &mut history, 2);
+    }
+
+    #[test]
+    fn crash_before_removing_superseded_files() {
+        let dir = TempDir::new("durable-superseded");
+        let mut history = History::new();
+        let mut tree = checkpointed(&dir, &mut history);
+        let temp = tree.write_checkpoint(2).unwrap();
+        commit_checkpoint(&temp, &checkpoint_path(dir.path(), 2)).unwrap();
+        tree.start_log_segment(2).unwrap();
+        drop(tree);
+
+        let files = list_files(dir.path()).unwrap();
+        assert_eq!(files.checkpoints, [1, 2]);
+        assert_eq!(files.logs, [1, 2]);
+
+        assert_recovers(&dir, &mut history, 2);
+    }
+
+    #[test]
+    fn crash_tearing_the_last_log_record() {
+        let dir = TempDir::new("durable-torn");
+        let mut history = History::new();
+        let mut tree = checkpointed(&dir, &mut history);
+
+        // The last update made it to the log only in part, so it is lost
+        update(&mut tree, &mut history, 60..61);
+        history.pop();
+        drop(tree);
+
+        let file = OpenOptions::new()
+            .write(true)
+            .open(log_path(dir.path(), 1))
+            .unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 3).unwrap();
+
+        assert_recovers(&dir, &mut history, 1);
+    }
+
+    #[test]
+    fn checkpoints_once_the_log_passes_its_limit() {
+        // Room for the log header and ten records of a `u64`
+        const LIMIT: u64 = 20 + 10 * 13;
+        let options = DurableOptions {
+            sync: SyncPolicy::Always,
+            checkpoint: CheckpointPolicy::LogBytes(LIMIT),
+        };
+
+        let dir = TempDir::new("durable-log-bytes");
+        let mut history = History::new();
+        let mut tree = DurableTree::open(dir.path(), options).unwrap();
+
+        let mut sequence = 0;
+        for step in 0..100 {
+            let before = tree.log.len();
+            update(&mut tree, &mut history, step..step + 1);
+
+            // The update that finds the log past the limit goes to a fresh
+            // segment, behind a checkpoint of everything before it
+            if before >= LIMIT {
+                sequence += 1;
+            }
+            assert_eq!(tree.sequence, sequence);
+            assert!(tree.log.len() <= LIMIT + 13);
+
+            let files = list_files(dir.path()).unwrap();
+            let checkpoints: Vec<u64> = (sequence > 0).then_some(sequence).into_iter().collect();
+            assert_eq!(files.checkpoints, checkpoints);
+            assert_eq!(files.logs, [sequence]);
+        }
+        assert!(sequence >= 5);
+        drop(tree);
+
+        let tree = DurableTree::<Tree>::open(dir.path(), options).unwrap();
+        assert_eq!(tree.sequence, sequence);
+        assert_history(&tree, &history);
+    }
+}
@@ -0,0 +1,72 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::snapshot::error::SnapshotError;
+
+/// Reasons a durable tree could not be opened, recovered or checkpointed
+#[derive(Debug)]
+pub enum DurableError {
+    /// The underlying file system operation failed
+    Io(io::Error),
+    /// A log file does not start with the log magic bytes
+    BadMagic,
+    /// A log file was written by an incompatible format version
+    UnsupportedVersion(u16),
+    /// A log file holds data records that are not `Data::WIDTH` bytes wide
+    DataWidthMismatch { expected: u32, found: u32 },
+    /// The log record at this byte offset fails its checksum although more
+    /// of the log follows it, so it was not torn by a crash
+    CorruptLog { offset: u64 },
+    /// A checkpoint could not be written or read back
+    Snapshot(SnapshotError),
+    /// The log segment with this sequence number is missing, although both
+    /// older and newer segments are present
+    MissingLogSegment(u64),
+}
+
+impl fmt::Display for DurableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DurableError::Io(err) => write!(f, "i/o error: {}", err),
+            DurableError::BadMagic => write!(f, "not a log file (bad magic)"),
+            DurableError::UnsupportedVersion(version) => {
+                write!(f, "unsupported log format version {}", version)
+            }
+            DurableError::DataWidthMismatch { expected, found } => write!(
+                f,
+                "log data records are {} bytes wide, expected {}",
+                found, expected
+            ),
+            DurableError::CorruptLog { offset } => {
+                write!(f, "log record at offset {} is corrupt", offset)
+            }
+            DurableError::Snapshot(err) => write!(f, "checkpoint error: {}", err),
+            DurableError::MissingLogSegment(sequence) => {
+                write!(f, "log segment {} is missing", sequence)
+            }
+        }
+    }
+}
+
+impl Error for DurableError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DurableError::Io(err) => Some(err),
+            DurableError::Snapshot(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DurableError {
+    fn from(err: io::Error) -> Self {
+        DurableError::Io(err)
+    }
+}
+
+impl From<SnapshotError> for DurableError {
+    fn from(err: SnapshotError) -> Self {
+        DurableError::Snapshot(err)
+    }
+}
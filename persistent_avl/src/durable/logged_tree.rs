@@ -0,0 +1,123 @@
+use std::io;
+use std::path::Path;
+
+use crate::durable::error::DurableError;
+use crate::durable::wal::{LogOp, SyncPolicy, WriteAheadLog};
+use crate::persistent_avl_tree::PersistentAvlTree;
+use crate::snapshot::record::Record;
+
+/// A persistent tree whose updates are recorded in a write-ahead log.
+///
+/// Every `insert` and `delete` is appended to the log before it is applied,
+/// so reopening the log rebuilds the same tree, with the same timestamps.
+pub struct LoggedTree<Tree: PersistentAvlTree> {
+    tree: Tree,
+    log: WriteAheadLog,
+}
+
+impl<Tree> LoggedTree<Tree>
+where
+    Tree: PersistentAvlTree + Default,
+    Tree::Data: Record,
+{
+    /// Opens the log at `path`, creating an empty one if needed, and rebuilds
+    /// the tree by replaying it.
+    ///
+    /// A record torn by a crash in the middle of an append is discarded.
+    pub fn open(path: impl AsRef<Path>, sync: SyncPolicy) -> Result<Self, DurableError> {
+        Self::open_with(path, sync, Tree::default)
+    }
+}
+
+impl<Tree> LoggedTree<Tree>
+where
+    Tree: PersistentAvlTree,
+    Tree::Data: Record,
+{
+    /// Same as `open`, but replays the log into `new_tree()` rather than the
+    /// default tree, such as a tree with Merkle hashing enabled
+    pub fn open_with(
+        path: impl AsRef<Path>,
+        sync: SyncPolicy,
+        new_tree: impl FnOnce() -> Tree,
+    ) -> Result<Self, DurableError> {
+        let mut tree = new_tree();
+
+        let log = WriteAheadLog::open(path.as_ref(), sync, |op, datum| match op {
+            LogOp::Insert => {
+                tree.insert(datum);
+            }
+            LogOp::Delete => {
+                tree.delete(&datum);
+            }
+        })?;
+
+        Ok(LoggedTree { tree, log })
+    }
+
+    /// Logs and then applies an insertion
+    pub fn insert(&mut self, item: Tree::Data) -> io::Result<Tree::Timestamp> {
+        self.log.append(LogOp::Insert, &item)?;
+        Ok(self.tree.insert(item))
+    }
+
+    /// Logs and then applies a deletion
+    ///
+    /// Deletions of missing items are logged too, and are no-ops on replay.
+    pub fn delete(&mut self, item: &Tree::Data) -> io::Result<Option<Tree::Timestamp>> {
+        self.log.append(LogOp::Delete, item)?;
+        Ok(self.tree.delete(item))
+    }
+
+    /// Forces every logged update to stable storage
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.log.sync()
+    }
+
+    /// The tree, for queries at any timestamp
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+}
+
+impl<Tree: PersistentAvlTree> Drop for LoggedTree<Tree> {
+    fn drop(&mut self) {
+        let _ = self.log.sync();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_copy_avl::path_copy_avl::PathCopyAvl;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn reopens_into_a_hashed_tree() {
+        let dir = TempDir::new("logged-hashed");
+        let path = dir.path().join("tree.wal");
+
+        let mut tree = LoggedTree::open_with(
+            &path,
+            SyncPolicy::Always,
+            PathCopyAvl::<u64>::with_merkle_hashing,
+        )
+        .unwrap();
+        let mut root_hashes = Vec::new();
+        for item in 0..20u64 {
+            let timestamp = tree.insert(item * 7 % 11).unwrap();
+            root_hashes.push(tree.tree().root_hash(timestamp).unwrap());
+        }
+        drop(tree);
+
+        let tree = LoggedTree::open_with(
+            &path,
+            SyncPolicy::Always,
+            PathCopyAvl::<u64>::with_merkle_hashing,
+        )
+        .unwrap();
+        for (timestamp, root_hash) in root_hashes.iter().enumerate() {
+            assert_eq!(tree.tree().root_hash(timestamp).as_ref(), Some(root_hash));
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub mod durable_tree;
+pub mod error;
+pub mod logged_tree;
+pub mod wal;
@@ -0,0 +1,343 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::durable::error::DurableError;
+use crate::snapshot::crc::{crc32, Crc32};
+use crate::snapshot::record::Record;
+
+// Log layout, all integers little-endian:
+//
+//   header  magic[8] | version u16 | reserved u16 | data width u32 | header crc u32
+//   record  crc u32 | operation u8 | datum
+//
+// Every record has the same length, and its crc covers the operation and
+// datum. A crash in the middle of an append can only damage the last record,
+// so recovery truncates a last record that is cut short or fails its
+// checksum. A damaged record with more of the log after it was not torn by a
+// crash, and recovery refuses the log rather than drop the records after it.
+
+const MAGIC: [u8; 8] = *b"PAVLWAL\0";
+const FORMAT_VERSION: u16 = 1;
+
+const HEADER_LEN: usize = 20;
+const RECORD_HEADER_LEN: usize = 5;
+
+/// When appended records are forced to stable storage
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// fsync after every record; an update is durable once it returns
+    Always,
+    /// fsync after every `n` records; up to `n - 1` updates may be lost on a crash
+    Every(u32),
+    /// Leave flushing to the operating system and explicit `sync` calls
+    Never,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum LogOp {
+    Insert,
+    Delete,
+}
+
+impl LogOp {
+    fn to_byte(self) -> u8 {
+        match self {
+            LogOp::Insert => 1,
+            LogOp::Delete => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<LogOp> {
+        match byte {
+            1 => Some(LogOp::Insert),
+            2 => Some(LogOp::Delete),
+            _ => None,
+        }
+    }
+}
+
+/// An append-only log of tree updates
+pub(crate) struct WriteAheadLog {
+    file: File,
+    sync: SyncPolicy,
+    record_len: usize,
+    unsynced: u32,
+    len: u64,
+}
+
+impl WriteAheadLog {
+    /// Opens the log at `path`, creating it if it does not exist, and feeds
+    /// every intact record to `replay` in order.
+    ///
+    /// A torn final record is truncated away before the log is reopened
+    /// for appending, while a damaged record before the final one is an
+    /// error.
+    pub(crate) fn open<Data: Record>(
+        path: &Path,
+        sync: SyncPolicy,
+        mut replay: impl FnMut(LogOp, Data),
+    ) -> Result<WriteAheadLog, DurableError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let record_len = RECORD_HEADER_LEN + Data::WIDTH;
+        let file_len = file.metadata()?.len();
+
+        // A log shorter than its header was never fully created
+        if file_len < HEADER_LEN as u64 {
+            file.set_len(0)?;
+            file.write_all(&encode_header(Data::WIDTH))?;
+            file.sync_all()?;
+            sync_parent_dir(path)?;
+
+            return Ok(WriteAheadLog {
+                file,
+                sync,
+                record_len,
+                unsynced: 0,
+                len: HEADER_LEN as u64,
+            });
+        }
+
+        let mut len = HEADER_LEN as u64;
+        {
+            let mut reader = BufReader::new(&mut file);
+
+            let mut header = [0u8; HEADER_LEN];
+            reader.read_exact(&mut header)?;
+            decode_header(&header, Data::WIDTH)?;
+
+            let mut record = vec![0u8; record_len];
+            while len + record_len as u64 <= file_len {
+                reader.read_exact(&mut record)?;
+
+                let stored_crc = u32::from_le_bytes(record[0..4].try_into().unwrap());
+
+                match LogOp::from_byte(record[4]) {
+                    Some(op) if crc32(&record[4..]) == stored_crc => {
+                        replay(op, Data::decode(&record[RECORD_HEADER_LEN..]));
+                        len += record_len as u64;
+                    }
+                    _ if len + (record_len as u64) < file_len => {
+                        return Err(DurableError::CorruptLog { offset: len });
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        if len < file_len {
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(len))?;
+
+        Ok(WriteAheadLog {
+            file,
+            sync,
+            record_len,
+            unsynced: 0,
+            len,
+        })
+    }
+
+    /// Appends a record, syncing it according to the log's `SyncPolicy`
+    pub(crate) fn append<Data: Record>(&mut self, op: LogOp, datum: &Data) -> io::Result<()> {
+        let mut record = vec![0u8; self.record_len];
+        record[4] = op.to_byte();
+        datum.encode(&mut record[RECORD_HEADER_LEN..]);
+
+        let mut crc = Crc32::new();
+        crc.update(&record[4..]);
+        record[0..4].copy_from_slice(&crc.finish().to_le_bytes());
+
+        if let Err(err) = self.file.write_all(&record) {
+            // Drop whatever part of the record made it out, so that later
+            // appends are not stranded behind a torn record
+            self.file.set_len(self.len)?;
+            self.file.seek(SeekFrom::Start(self.len))?;
+            return Err(err);
+        }
+
+        self.len += self.record_len as u64;
+        self.unsynced += 1;
+
+        match self.sync {
+            SyncPolicy::Always => self.sync(),
+            SyncPolicy::Every(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Length of the log file in bytes, including its header
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Forces every appended record to stable storage
+    pub(crate) fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+
+        Ok(())
+    }
+}
+
+fn encode_header(data_width: usize) -> [u8; HEADER_LEN] {
+    let mut buf = [0u8; HEADER_LEN];
+
+    buf[0..8].copy_from_slice(&MAGIC);
+    buf[8..10].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buf[12..16].copy_from_slice(&(data_width as u32).to_le_bytes());
+
+    let crc = crc32(&buf[0..16]);
+    buf[16..20].copy_from_slice(&crc.to_le_bytes());
+
+    buf
+}
+
+fn decode_header(buf: &[u8; HEADER_LEN], data_width: usize) -> Result<(), DurableError> {
+    if buf[0..8] != MAGIC || crc32(&buf[0..16]).to_le_bytes() != buf[16..20] {
+        return Err(DurableError::BadMagic);
+    }
+
+    let version = u16::from_le_bytes([buf[8], buf[9]]);
+    if version != FORMAT_VERSION {
+        return Err(DurableError::UnsupportedVersion(version));
+    }
+
+    let found = u32::from_le_bytes(buf[12..16].try_into().unwrap());
+    if found as usize != data_width {
+        return Err(DurableError::DataWidthMismatch {
+            expected: data_width as u32,
+            found,
+        });
+    }
+
+    Ok(())
+}
+
+/// Makes the creation, removal or renaming of a file in `path`'s directory durable
+#[cfg(unix)]
+pub(crate) fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const RECORD_LEN: u64 = (RECORD_HEADER_LEN + 8) as u64;
+
+    fn write_log(path: &Path, items: impl IntoIterator<Item = u64>) {
+        let mut log = WriteAheadLog::open(path, SyncPolicy::Never, |_: LogOp, _: u64| {}).unwrap();
+        for item in items {
+            log.append(LogOp::Insert, &item).unwrap();
+        }
+        log.sync().unwrap();
+    }
+
+    fn replayed(path: &Path) -> Result<Vec<u64>, DurableError> {
+        let mut items = Vec::new();
+        WriteAheadLog::open(path, SyncPolicy::Never, |op, item: u64| {
+            assert_eq!(op, LogOp::Insert);
+            items.push(item);
+        })?;
+
+        Ok(items)
+    }
+
+    fn flip_byte(path: &Path, offset: u64) {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        let mut byte = [0u8];
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[byte[0] ^ 0x40]).unwrap();
+    }
+
+    fn record_offset(index: u64) -> u64 {
+        HEADER_LEN as u64 + index * RECORD_LEN
+    }
+
+    #[test]
+    fn replays_every_record() {
+        let dir = TempDir::new("wal-replay");
+        let path = dir.path().join("log.wal");
+        write_log(&path, 0..10);
+        write_log(&path, 10..20);
+
+        assert_eq!(replayed(&path).unwrap(), (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn truncates_a_record_cut_short() {
+        let dir = TempDir::new("wal-cut");
+        let path = dir.path().join("log.wal");
+        write_log(&path, 0..10);
+
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(record_offset(10) - 3).unwrap();
+
+        assert_eq!(replayed(&path).unwrap(), (0..9).collect::<Vec<_>>());
+        assert_eq!(fs_len(&path), record_offset(9));
+
+        // Appends after recovery are not stranded behind the torn record
+        write_log(&path, [42]);
+        assert_eq!(replayed(&path).unwrap().last(), Some(&42));
+    }
+
+    #[test]
+    fn truncates_a_damaged_last_record() {
+        let dir = TempDir::new("wal-torn");
+        let path = dir.path().join("log.wal");
+        write_log(&path, 0..10);
+        flip_byte(&path, record_offset(9) + 6);
+
+        assert_eq!(replayed(&path).unwrap(), (0..9).collect::<Vec<_>>());
+        assert_eq!(fs_len(&path), record_offset(9));
+    }
+
+    #[test]
+    fn refuses_a_damaged_record_before_the_last() {
+        let dir = TempDir::new("wal-corrupt");
+        let path = dir.path().join("log.wal");
+        write_log(&path, 0..10);
+        flip_byte(&path, record_offset(4) + 6);
+
+        assert!(matches!(
+            replayed(&path),
+            Err(DurableError::CorruptLog { offset }) if offset == record_offset(4)
+        ));
+        // The intact records after the damaged one are kept
+        assert_eq!(fs_len(&path), record_offset(10));
+    }
+
+    fn fs_len(path: &Path) -> u64 {
+        std::fs::metadata(path).unwrap().len()
+    }
+}
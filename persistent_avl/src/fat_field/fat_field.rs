@@ -0,0 +1,159 @@
+use crate::timestamp::search_time;
+
+/// A value that changes over time and keeps every value it has had.
+///
+/// This is the fat node technique for a single field. Each `set` records the
+/// value in effect from its timestamp on, and `get` finds the value in effect
+/// at any timestamp in O(log h) for a history of length h. Partially
+/// persistent linked structures can be built from these, like the children
+/// of the nodes of `FatNodeAvl`.
+///
+/// Timestamps must not decrease from one `set` to the next.
+#[derive(Debug, Clone)]
+pub struct FatField<T, Timestamp: Ord> {
+    history: Vec<(Timestamp, T)>,
+}
+
+impl<T, Timestamp: Ord> Default for FatField<T, Timestamp> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, Timestamp: Ord> FatField<T, Timestamp> {
+    /// A field without a value at any time
+    pub fn new() -> Self {
+        FatField {
+            history: Vec::new(),
+        }
+    }
+
+    /// Sets the value from `timestamp` on
+    ///
+    /// A value set at the same timestamp as the latest one replaces it, so
+    /// repeated changes within one version don't grow the history.
+    ///
+    /// Panics if `timestamp` precedes the latest timestamp.
+    pub fn set(&mut self, timestamp: Timestamp, value: T) {
+        match self.history.last_mut() {
+            Some((last_timestamp, last_value)) if *last_timestamp == timestamp => {
+                *last_value = value;
+            }
+            Some((last_timestamp, _)) if *last_timestamp > timestamp => {
+                panic!("FatField timestamps must not decrease");
+            }
+            _ => self.history.push((timestamp, value)),
+        }
+    }
+
+    /// The value in effect at `timestamp`, if any was set by then
+    pub fn get(&self, timestamp: &Timestamp) -> Option<&T> {
+        search_time(
+            self.history.len(),
+            |index| &self.history[index].0,
+            timestamp,
+        )
+        .map(|index| &self.history[index].1)
+    }
+
+    /// The value in effect from the latest timestamp on
+    pub fn latest(&self) -> Option<&T> {
+        self.history.last().map(|(_, value)| value)
+    }
+
+    /// Every value along with the timestamp it was set at, oldest first
+    pub fn history(&self) -> impl DoubleEndedIterator<Item = (&Timestamp, &T)> + ExactSizeIterator {
+        self.history
+            .iter()
+            .map(|(timestamp, value)| (timestamp, value))
+    }
+
+    /// The `index`-th value of the history along with the timestamp it was
+    /// set at
+    pub(crate) fn entry(&self, index: usize) -> (&Timestamp, &T) {
+        let (timestamp, value) = &self.history[index];
+        (timestamp, value)
+    }
+
+    /// Number of values in the history
+    pub fn len(&self) -> usize {
+        self.history.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.history.is_empty()
+    }
+
+    /// Removes the latest value, leaving the history before it
+    pub(crate) fn pop(&mut self) -> Option<(Timestamp, T)> {
+        self.history.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_finds_the_value_in_effect() {
+        let mut field = FatField::new();
+        assert!(field.is_empty());
+        assert_eq!(field.get(&0), None);
+        assert_eq!(field.latest(), None);
+
+        field.set(3, 'a');
+        field.set(5, 'b');
+        field.set(9, 'c');
+
+        for timestamp in 0..12 {
+            let value = match timestamp {
+                0..=2 => None,
+                3..=4 => Some('a'),
+                5..=8 => Some('b'),
+                _ => Some('c'),
+            };
+            assert_eq!(field.get(&timestamp).copied(), value);
+        }
+        assert_eq!(field.get(&usize::MAX), Some(&'c'));
+        assert_eq!(field.latest(), Some(&'c'));
+    }
+
+    #[test]
+    fn set_at_the_latest_timestamp_replaces_the_value() {
+        let mut field = FatField::new();
+        field.set(1, 'a');
+        field.set(4, 'b');
+        field.set(4, 'c');
+
+        assert_eq!(field.len(), 2);
+        assert_eq!(field.get(&3), Some(&'a'));
+        assert_eq!(field.get(&4), Some(&'c'));
+        assert_eq!(field.latest(), Some(&'c'));
+    }
+
+    #[test]
+    fn history_is_oldest_first() {
+        let mut field = FatField::new();
+        for (timestamp, value) in [(0, 'a'), (2, 'b'), (2, 'c'), (7, 'd')] {
+            field.set(timestamp, value);
+        }
+
+        let history: Vec<_> = field.history().collect();
+        assert_eq!(history, [(&0, &'a'), (&2, &'c'), (&7, &'d')]);
+        assert_eq!(field.history().len(), field.len());
+        assert_eq!(field.history().next_back(), Some((&7, &'d')));
+        assert_eq!(field.entry(1), (&2, &'c'));
+
+        assert_eq!(field.pop(), Some((7, 'd')));
+        assert_eq!(field.latest(), Some(&'c'));
+        assert_eq!(field.get(&9), Some(&'c'));
+    }
+
+    #[test]
+    #[should_panic(expected = "must not decrease")]
+    fn set_panics_on_an_earlier_timestamp() {
+        let mut field = FatField::new();
+        field.set(5, 'a');
+        field.set(4, 'b');
+    }
+}
@@ -0,0 +1 @@
+pub mod fat_field;
@@ -1,58 +1,69 @@
+use crate::fat_field::fat_field::FatField;
 use crate::timestamp::*;
 
-pub(crate) struct ChildrenAtTime {
-    pub(crate) timestamp: u64,
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Children {
     pub(crate) left: Option<usize>,
     pub(crate) right: Option<usize>,
 }
 
-impl TimestampSupplier for ChildrenAtTime {
-    type Timestamp = u64;
-
-    fn get_timestamp(&self) -> &Self::Timestamp {
-        &self.timestamp
-    }
-}
-
-pub(crate) struct FatNode<Data: Ord> {
-    pub(crate) datum: Data,
+pub(crate) struct FatNode {
+    pub(crate) datum_ptr: usize,
     pub(crate) height: u64,
-    pub(crate) children: Vec<ChildrenAtTime>,
+    pub(crate) children: FatField<Children, u64>,
 }
 
 // All modifications to a FatNode assume that the given
 // timestamp is >= the timestamp of latest child
-impl<Data: Ord> FatNode<Data> {
+impl FatNode {
+    pub(crate) fn left(&self) -> Option<usize> {
+        self.children.latest().and_then(|children| children.left)
+    }
+
+    pub(crate) fn right(&self) -> Option<usize> {
+        self.children.latest().and_then(|children| children.right)
+    }
+
     pub(crate) fn modify_left(&mut self, timestamp: u64, new_left: Option<usize>) {
-        match self
-            .children
-            .last_mut()
-            .filter(|last_children| last_children.timestamp == timestamp)
-        {
-            // When last children exist & match your timestamp, just mutate instead
-            Some(last_children) => last_children.left = new_left,
-            None => self.children.push(ChildrenAtTime {
-                timestamp: timestamp,
-                left: new_left,
-                right: self.children.last().and_then(|children| children.right),
-            }),
-        };
+        // Rewriting an unchanged pointer would only grow the history
+        if self.left() != new_left {
+            let right = self.right();
+            self.children.set(
+                timestamp,
+                Children {
+                    left: new_left,
+                    right,
+                },
+            );
+        }
     }
 
     pub(crate) fn modify_right(&mut self, timestamp: u64, new_right: Option<usize>) {
-        match self
-            .children
-            .last_mut()
-            .filter(|last_children| last_children.timestamp == timestamp)
-        {
-            // When last children exist & match your timestamp, just mutate instead
-            Some(last_children) => last_children.right = new_right,
-            None => self.children.push(ChildrenAtTime {
-                timestamp: timestamp,
-                left: self.children.last().and_then(|children| children.left),
-                right: new_right,
-            }),
-        };
+        if self.right() != new_right {
+            let left = self.left();
+            self.children.set(
+                timestamp,
+                Children {
+                    left,
+                    right: new_right,
+                },
+            );
+        }
+    }
+
+    /// Moves the latest children into a copy of this node, leaving this node
+    /// with the history before them
+    pub(crate) fn split_latest(&mut self) -> FatNode {
+        let mut children = FatField::new();
+        if let Some((timestamp, latest)) = self.children.pop() {
+            children.set(timestamp, latest);
+        }
+
+        FatNode {
+            datum_ptr: self.datum_ptr,
+            height: self.height,
+            children,
+        }
     }
 }
 
@@ -1,201 +1,450 @@
-use std::cell::RefCell;
-use std::cmp::max;
-use std::rc::Rc;
-
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Range, RangeBounds};
+
+use crate::batch_update::BatchUpdate;
+use crate::bulk_load::bulk_load;
+use crate::bulk_load::error::BulkLoadError;
+use crate::fat_node_avl::fat_node::Children;
 use crate::persistent_avl_tree::PersistentAvlTree;
 
+use crate::fat_field::fat_field::FatField;
 use crate::fat_node_avl::fat_node::{FatNode, RootNode};
-use crate::timestamp::get_time;
+use crate::timestamp::{get_time, search_time, EytzingerIndex};
+
+use crate::avl::engine::{self, Update};
+use crate::avl::node_store::{NodeLinks, NodeStore};
+
+/// Number of children entries a node holds before it is split, unless
+/// configured otherwise
+pub const DEFAULT_CHILDREN_CAPACITY: usize = 8;
 
-use crate::avl::avl;
+/// Number of newest versions whose root is found by galloping even once the
+/// versions are indexed
+const RECENT_VERSIONS: usize = 64;
 
+/// A partially persistent AVL tree built from bounded fat nodes.
+///
+/// Every node records the history of its children. Once a node holds more
+/// than `children_capacity` entries, its latest entry moves to a fresh copy
+/// of the node and the parent is pointed at the copy, so each step of a
+/// query searches a history of constant length.
 pub struct FatNodeAvl<Data: Ord> {
-    node_arena: Vec<FatNode<Data>>,
-    root_nodes: Vec<RootNode>,
-    last_time: u64,
+    pub(crate) data: Vec<Data>,
+    pub(crate) node_arena: Vec<FatNode>,
+    pub(crate) root_nodes: Vec<RootNode>,
+    pub(crate) last_time: u64,
+    pub(crate) children_capacity: usize,
+    /// Eytzinger copy of the timestamps of the first root nodes, if built
+    pub(crate) root_index: Option<EytzingerIndex<u64>>,
+}
+
+impl<Data: Ord> Default for FatNodeAvl<Data> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<Data: Ord> FatNodeAvl<Data> {
-    fn modify_root(&mut self, new_node_ptr: Option<usize>, timestamp: u64) {
-        if let None = self
-            .root_nodes
-            .last()
-            .filter(|root_node| root_node.root == new_node_ptr)
-        {
-            self.root_nodes.push(RootNode {
-                timestamp,
-                root: new_node_ptr,
-            });
-        }
+    pub fn new() -> Self {
+        Self::with_children_capacity(DEFAULT_CHILDREN_CAPACITY)
     }
 
-    fn get_height(&self, node_ptr: Option<usize>) -> u64 {
-        match node_ptr {
-            Some(ptr) => self.node_arena[ptr].height,
-            None => 0,
+    /// Creates an empty tree whose nodes are split once they hold more than
+    /// `children_capacity` children entries
+    ///
+    /// Panics if `children_capacity` is zero.
+    pub fn with_children_capacity(children_capacity: usize) -> Self {
+        assert!(children_capacity > 0, "children capacity must be positive");
+
+        FatNodeAvl {
+            data: Vec::new(),
+            node_arena: Vec::new(),
+            root_nodes: Vec::new(),
+            last_time: 0,
+            children_capacity,
+            root_index: None,
         }
     }
 
-    /// Calculates the height of a node assuming its childrens heights are set
-    fn set_height(&mut self, node_ptr: usize) {
-        let children = self.node_arena[node_ptr].children.last();
+    /// Lays the timestamps of the versions so far out in Eytzinger order,
+    /// which speeds up finding the root of versions scattered over a history
+    /// too long to stay in cache
+    ///
+    /// Only the search for the root gets faster, which pays off for millions
+    /// of versions at most. The newest versions, and those added later, are
+    /// found by galloping as before.
+    pub fn index_versions(&mut self) {
+        self.root_index = Some(EytzingerIndex::new(self.root_nodes.len(), |index| {
+            self.root_nodes[index].timestamp
+        }));
+    }
 
-        let child_height = match children {
-            None => 0,
-            Some(children) => {
-                let left_height = children
-                    .left
-                    .map_or(0, |left_child| self.node_arena[left_child].height);
-                let right_height = children
-                    .right
-                    .map_or(0, |right_child| self.node_arena[right_child].height);
+    /// Whether `item` is in the tree at each of `timestamps`, in the same
+    /// order
+    pub fn contains_many(&self, item: &Data, timestamps: &[u64]) -> Vec<bool> {
+        self.predecessor_many(item, timestamps)
+            .into_iter()
+            .map(|predecessor| predecessor == Some(item))
+            .collect()
+    }
 
-                max(left_height, right_height)
-            }
-        };
+    /// Greatest datum at most `item` at each of `timestamps`, in the same
+    /// order
+    ///
+    /// The timestamps are sorted and walk down the tree together, splitting
+    /// only where the versions they see part ways. Every node is compared
+    /// with `item` once, and its children history is merged with the sorted
+    /// timestamps that reach it rather than searched for each of them.
+    pub fn predecessor_many(&self, item: &Data, timestamps: &[u64]) -> Vec<Option<&Data>> {
+        let mut sorted: Vec<(u64, usize)> = timestamps
+            .iter()
+            .enumerate()
+            .map(|(query, timestamp)| (*timestamp, query))
+            .collect();
+        sorted.sort_unstable();
+
+        // Runs of `sorted` that reached the same node, along with the greatest
+        // node at most `item` they passed
+        let mut stack: Vec<(Option<usize>, Option<usize>, Range<usize>)> = Vec::new();
+        Self::push_runs(
+            &mut stack,
+            &sorted,
+            0..sorted.len(),
+            None,
+            self.root_nodes.len(),
+            |index| {
+                (
+                    self.root_nodes[index].timestamp,
+                    self.root_nodes[index].root,
+                )
+            },
+        );
+
+        let mut found = vec![None; timestamps.len()];
+        while let Some((node_ptr, inf, run)) = stack.pop() {
+            let Some(node_ptr) = node_ptr else {
+                for (_, query) in &sorted[run] {
+                    found[*query] = inf.map(|inf| self.get_datum(inf));
+                }
+                continue;
+            };
+
+            let (go_left, inf) = match self.compare(item, node_ptr) {
+                Ordering::Less => (true, inf),
+                Ordering::Greater | Ordering::Equal => (false, Some(node_ptr)),
+            };
+
+            // A node without children at a timestamp is a leaf
+            let children = &self.node_arena[node_ptr].children;
+            Self::push_runs(&mut stack, &sorted, run, inf, children.len(), |index| {
+                let (timestamp, children) = children.entry(index);
+                let child = if go_left {
+                    children.left
+                } else {
+                    children.right
+                };
+
+                (*timestamp, child)
+            });
+        }
 
-        self.node_arena[node_ptr].height = child_height + 1;
+        found
     }
 
-    fn rotate_left(&mut self, timestamp: u64, original_root_ptr: usize) -> usize {
-        let original_root = &self.node_arena[original_root_ptr];
+    /// Creates a tree whose first version holds the items of `iter`, which
+    /// must be sorted
+    pub fn from_sorted_iter(iter: impl IntoIterator<Item = Data>) -> Result<Self, BulkLoadError> {
+        let mut tree = Self::new();
+        tree.bulk_load_at_new_version(iter)?;
 
-        let new_root_ptr = original_root.children.last().unwrap().right.unwrap();
-        let new_root = &self.node_arena[new_root_ptr];
+        Ok(tree)
+    }
 
-        let original_root_left = original_root
-            .children
-            .last()
-            .and_then(|children| children.left);
-        let original_root_right = new_root.children.last().and_then(|children| children.left);
+    /// Adds a version holding exactly the items of `iter`, which must be
+    /// sorted, and returns its timestamp
+    ///
+    /// The version is built perfectly balanced in O(n), with one children
+    /// entry per node rather than the history n insertions would leave. It
+    /// shares no nodes with earlier versions.
+    pub fn bulk_load_at_new_version(
+        &mut self,
+        iter: impl IntoIterator<Item = Data>,
+    ) -> Result<u64, BulkLoadError> {
+        let data = bulk_load::collect_sorted(iter)?;
+        let timestamp = self.last_time;
+
+        let root = self.push_balanced(data, timestamp);
+        self.modify_root(root, timestamp);
 
-        let new_root_left = Some(original_root_ptr);
-        let new_root_right = new_root.children.last().and_then(|children| children.right);
+        self.last_time += 1;
+        Ok(timestamp)
+    }
 
-        // Input node is now lower
-        let original_root = &mut self.node_arena[original_root_ptr];
+    /// Pushes `data`, which must be sorted, and a perfectly balanced tree of
+    /// new nodes holding it as of `timestamp`, and returns the root
+    fn push_balanced(&mut self, data: Vec<Data>, timestamp: u64) -> Option<usize> {
+        let first_datum_ptr = self.data.len();
+        self.data.extend(data);
+
+        let node_arena = &mut self.node_arena;
+        bulk_load::build_balanced(
+            first_datum_ptr,
+            self.data.len(),
+            &mut |datum_ptr, left, right, height| {
+                // Leaves have no children entries, as after an insertion
+                let mut children = FatField::new();
+                if left.is_some() || right.is_some() {
+                    children.set(timestamp, Children { left, right });
+                }
 
-        original_root.modify_left(timestamp, original_root_left);
-        original_root.modify_right(timestamp, original_root_right);
-        self.set_height(original_root_ptr);
+                node_arena.push(FatNode {
+                    datum_ptr,
+                    height,
+                    children,
+                });
+                node_arena.len() - 1
+            },
+        )
+    }
 
-        // Child node is now upper
-        let new_root = &mut self.node_arena[original_root_ptr];
+    /// Makes the newest version out of `update` and returns its timestamp
+    fn finish(&mut self, update: Update) -> u64 {
+        self.modify_root(update.root, self.last_time);
+        self.split_full_nodes(self.last_time, &update.path);
 
-        new_root.modify_left(timestamp, new_root_left);
-        new_root.modify_right(timestamp, new_root_right);
-        self.set_height(new_root_ptr);
+        self.last_time += 1;
+        self.last_time - 1
+    }
 
-        new_root_ptr
+    fn get_datum(&self, node_ptr: usize) -> &Data {
+        &self.data[self.node_arena[node_ptr].datum_ptr]
     }
 
-    fn rotate_right(&mut self, timestamp: u64, original_root_ptr: usize) -> usize {
-        let original_root = &self.node_arena[original_root_ptr];
+    /// Splits `run` of `sorted` into the runs that find the same node in a
+    /// history of `len` timestamped node pointers read through `entry_at`,
+    /// and pushes each of them onto `stack` along with `inf`
+    ///
+    /// A timestamp only searches the history when the entry found for the one
+    /// before it has been superseded by then.
+    fn push_runs(
+        stack: &mut Vec<(Option<usize>, Option<usize>, Range<usize>)>,
+        sorted: &[(u64, usize)],
+        run: Range<usize>,
+        inf: Option<usize>,
+        len: usize,
+        entry_at: impl Fn(usize) -> (u64, Option<usize>),
+    ) {
+        let mut entry: Option<usize> = None;
+        let mut start = run.start;
+        let mut node_ptr = None;
+
+        for position in run.clone() {
+            let timestamp = sorted[position].0;
+            let next = entry.map_or(0, |entry| entry + 1);
+            if position == run.start || (next < len && entry_at(next).0 <= timestamp) {
+                entry = search_time(len, |index| entry_at(index).0, &timestamp);
+            }
 
-        let new_root_ptr = original_root.children.last().unwrap().left.unwrap();
-        let new_root = &self.node_arena[new_root_ptr];
+            let entry_node_ptr = entry.and_then(|entry| entry_at(entry).1);
+            if position > run.start && entry_node_ptr != node_ptr {
+                stack.push((node_ptr, inf, start..position));
+                start = position;
+            }
+            node_ptr = entry_node_ptr;
+        }
 
-        let original_root_left = original_root
-            .children
+        if !run.is_empty() {
+            stack.push((node_ptr, inf, start..run.end));
+        }
+    }
+
+    fn modify_root(&mut self, new_node_ptr: Option<usize>, timestamp: u64) {
+        if let None = self
+            .root_nodes
             .last()
-            .and_then(|children| children.right);
-        let original_root_right = new_root.children.last().and_then(|children| children.right);
+            .filter(|root_node| root_node.root == new_node_ptr)
+        {
+            self.root_nodes.push(RootNode {
+                timestamp,
+                root: new_node_ptr,
+            });
+        }
+    }
 
-        let new_root_left = new_root.children.last().and_then(|children| children.left);
-        let new_root_right = Some(original_root_ptr);
+    /// Splits every node that outgrew the children capacity at `timestamp`
+    ///
+    /// Only nodes changed at `timestamp` can have grown. Every one of them
+    /// that is still in the tree hangs from the root through `path` and
+    /// other changed nodes, so only those are searched for parents.
+    fn split_full_nodes(&mut self, timestamp: u64, path: &[usize]) {
+        let Some(root) = self.root_nodes.last().and_then(|root_node| root_node.root) else {
+            return;
+        };
 
-        // Input node is now lower
-        let original_root = &mut self.node_arena[original_root_ptr];
+        let path: HashSet<usize> = path.iter().copied().collect();
+        let changed = |node: &FatNode| {
+            node.children
+                .history()
+                .next_back()
+                .is_some_and(|(changed_at, _)| *changed_at == timestamp)
+        };
 
-        original_root.modify_left(timestamp, original_root_left);
-        original_root.modify_right(timestamp, original_root_right);
-        self.set_height(original_root_ptr);
+        let mut parents = HashMap::new();
+        let mut full = Vec::new();
 
-        // Child node is now upper
-        let new_root = &mut self.node_arena[original_root_ptr];
+        let mut stack = vec![root];
+        while let Some(node_ptr) = stack.pop() {
+            let node = &self.node_arena[node_ptr];
+            if node.children.len() > self.children_capacity {
+                full.push(node_ptr);
+            }
 
-        new_root.modify_left(timestamp, new_root_left);
-        new_root.modify_right(timestamp, new_root_right);
-        self.set_height(new_root_ptr);
+            for child_ptr in [node.left(), node.right()].into_iter().flatten() {
+                if path.contains(&child_ptr) || changed(&self.node_arena[child_ptr]) {
+                    parents.insert(child_ptr, node_ptr);
+                    stack.push(child_ptr);
+                }
+            }
+        }
 
-        new_root_ptr
+        for node_ptr in full {
+            // A node may already have been split on behalf of one of its children
+            if self.node_arena[node_ptr].children.len() > self.children_capacity {
+                self.split_node(timestamp, node_ptr, &mut parents);
+            }
+        }
     }
 
-    fn get_balance_factor(&self, node_ptr: usize) -> i32 {
-        let node = &self.node_arena[node_ptr];
-        match node.children.last() {
-            Some(cat) => self.get_height(cat.right) as i32 - self.get_height(cat.left) as i32,
-            None => 0,
+    /// Moves the latest children of `node_ptr` into a copy and points its
+    /// parent, or the root, at the copy, splitting the parent in turn if that
+    /// overflows it
+    fn split_node(&mut self, timestamp: u64, node_ptr: usize, parents: &mut HashMap<usize, usize>) {
+        let copy = self.node_arena[node_ptr].split_latest();
+        let copy_ptr = self.node_arena.len();
+
+        for child_ptr in [copy.left(), copy.right()].into_iter().flatten() {
+            if let Some(parent_ptr) = parents.get_mut(&child_ptr) {
+                *parent_ptr = copy_ptr;
+            }
+        }
+        self.node_arena.push(copy);
+
+        match parents.remove(&node_ptr) {
+            Some(parent_ptr) => {
+                parents.insert(copy_ptr, parent_ptr);
+
+                let parent = &mut self.node_arena[parent_ptr];
+                if parent.left() == Some(node_ptr) {
+                    parent.modify_left(timestamp, Some(copy_ptr));
+                } else {
+                    parent.modify_right(timestamp, Some(copy_ptr));
+                }
+
+                if parent.children.len() > self.children_capacity {
+                    self.split_node(timestamp, parent_ptr, parents);
+                }
+            }
+            None => match self.root_nodes.last_mut() {
+                Some(root_node) if root_node.timestamp == timestamp => {
+                    root_node.root = Some(copy_ptr)
+                }
+                _ => self.root_nodes.push(RootNode {
+                    timestamp,
+                    root: Some(copy_ptr),
+                }),
+            },
         }
     }
+}
 
-    fn balance_node(&mut self, timestamp: u64, node_ptr: usize) -> usize {
-        let balance = self.get_balance_factor(node_ptr);
+impl<Data: Ord> NodeLinks for FatNodeAvl<Data> {
+    fn latest_root(&self) -> Option<usize> {
+        self.root_nodes.last().and_then(|root_node| root_node.root)
+    }
 
-        if balance <= -2 {
-            let left_child_ptr = self.node_arena[node_ptr]
-                .children
-                .last()
-                .unwrap()
-                .left
-                .unwrap();
+    fn left(&self, node: usize) -> Option<usize> {
+        self.node_arena[node].left()
+    }
 
-            // LR
-            if self.get_balance_factor(left_child_ptr) >= 1 {
-                let new_left_child = self.rotate_left(timestamp, left_child_ptr);
+    fn right(&self, node: usize) -> Option<usize> {
+        self.node_arena[node].right()
+    }
 
-                self.node_arena[node_ptr].modify_left(timestamp, Some(new_left_child));
+    fn height(&self, node: usize) -> u64 {
+        self.node_arena[node].height
+    }
 
-                self.set_height(node_ptr);
-            }
+    fn modify(&mut self, node: usize, left: Option<usize>, right: Option<usize>, height: u64) {
+        let node = &mut self.node_arena[node];
 
-            // LL & LR
-            self.rotate_right(timestamp, node_ptr)
-        } else if balance >= 2 {
-            let right_child_ptr = self.node_arena[node_ptr]
-                .children
-                .last()
-                .unwrap()
-                .right
-                .unwrap();
+        node.modify_left(self.last_time, left);
+        node.modify_right(self.last_time, right);
+        node.height = height;
+    }
+}
 
-            // RL
-            if self.get_balance_factor(right_child_ptr) <= -1 {
-                let new_right_child_ptr = self.rotate_right(timestamp, right_child_ptr);
+impl<Data: Ord> NodeStore for FatNodeAvl<Data> {
+    type Data = Data;
+    type Version = u64;
+
+    fn datum(&self, node: usize) -> &Data {
+        self.get_datum(node)
+    }
 
-                self.node_arena[node_ptr].modify_right(timestamp, Some(new_right_child_ptr));
+    fn root_at(&self, version: &u64) -> Option<usize> {
+        let Some(root_index) = &self.root_index else {
+            return get_time(&self.root_nodes, version).and_then(|root_node| root_node.root);
+        };
 
-                self.set_height(node_ptr);
+        // Galloping finds the newest versions and those added after the
+        // index faster than the index does
+        let first_galloped = root_index
+            .len()
+            .min(self.root_nodes.len().saturating_sub(RECENT_VERSIONS));
+        let root_node = match self.root_nodes.get(first_galloped) {
+            Some(root_node) if root_node.timestamp <= *version => {
+                get_time(&self.root_nodes, version)
             }
+            _ => root_index
+                .search(version)
+                .map(|index| &self.root_nodes[index]),
+        };
 
-            // RL & RR
-            self.rotate_left(timestamp, node_ptr)
-        } else {
-            node_ptr
-        }
+        root_node.and_then(|root_node| root_node.root)
     }
 
-    /// Calculates the heights and rebalances the tree up `path`
-    ///
-    /// Returns the element at the root of `path` after modifications are complete
-    fn balance(&mut self, timestamp: u64, path: &Vec<usize>) -> Option<usize> {
-        let mut child_ptr = *path.last()?;
-
-        path.iter().rev().skip(1).for_each(|&parent_ptr| {
-            self.set_height(child_ptr);
-            child_ptr = self.balance_node(timestamp, child_ptr);
-
-            if self.node_arena[parent_ptr].datum <= self.node_arena[child_ptr].datum {
-                self.node_arena[parent_ptr].modify_left(timestamp, Some(child_ptr));
-            } else {
-                self.node_arena[parent_ptr].modify_right(timestamp, Some(child_ptr));
-            }
+    // A node without children at this time is a leaf
+    fn left_at(&self, node: usize, version: &u64) -> Option<usize> {
+        self.node_arena[node]
+            .children
+            .get(version)
+            .and_then(|children| children.left)
+    }
 
-            child_ptr = parent_ptr;
-        });
+    fn right_at(&self, node: usize, version: &u64) -> Option<usize> {
+        self.node_arena[node]
+            .children
+            .get(version)
+            .and_then(|children| children.right)
+    }
+}
 
-        self.set_height(child_ptr);
-        Some(self.balance_node(timestamp, child_ptr))
+impl<Data: Ord> BatchUpdate for FatNodeAvl<Data> {
+    fn delete_range(&mut self, range: impl RangeBounds<Self::Data>) -> Self::Timestamp {
+        let update = engine::delete_range(self, &range);
+        self.finish(update)
+    }
+
+    fn insert_sorted_batch(
+        &mut self,
+        iter: impl IntoIterator<Item = Self::Data>,
+    ) -> Result<Self::Timestamp, BulkLoadError> {
+        let data = bulk_load::collect_sorted(iter)?;
+
+        let batch = self.push_balanced(data, self.last_time);
+        let update = engine::insert_batch(self, batch);
+        Ok(self.finish(update))
     }
 }
 
@@ -205,262 +454,250 @@ impl<Data: Ord> PersistentAvlTree for FatNodeAvl<Data> {
 
     fn insert(&mut self, item: Self::Data) -> Self::Timestamp {
         // Allocation
+        self.data.push(item);
         self.node_arena.push(FatNode {
-            datum: item,
+            datum_ptr: self.data.len() - 1,
             height: 1,
-            children: Vec::new(),
+            children: FatField::new(),
         });
         let new_node_ptr = self.node_arena.len() - 1;
-        let item = &self.node_arena[new_node_ptr].datum;
-
-        // Insertion
-        let root = self.root_nodes.last().and_then(|root_node| root_node.root);
-
-        match root {
-            Some(mut parent_ptr) => {
-                let mut path = vec![parent_ptr];
-
-                while let Some(child_ptr) =
-                    self.node_arena[parent_ptr]
-                        .children
-                        .last()
-                        .and_then(|children| {
-                            if *item <= self.node_arena[parent_ptr].datum {
-                                children.left
-                            } else {
-                                children.right
-                            }
-                        })
-                {
-                    path.push(child_ptr);
-                    parent_ptr = child_ptr;
-                }
 
-                if *item <= self.node_arena[parent_ptr].datum {
-                    self.node_arena[parent_ptr].modify_left(self.last_time, Some(new_node_ptr));
-                } else {
-                    self.node_arena[parent_ptr].modify_right(self.last_time, Some(new_node_ptr));
-                }
-
-                let new_root = self.balance(self.last_time, &path);
-                self.modify_root(new_root, self.last_time);
-            }
-            None => self.modify_root(Some(new_node_ptr), self.last_time),
-        }
-
-        self.last_time += 1;
-        self.last_time - 1
+        let update = engine::insert(self, new_node_ptr);
+        self.finish(update)
     }
 
     fn delete(&mut self, item: &Self::Data) -> Option<Self::Timestamp> {
-        let mut parent_ptr = None;
-        let mut child_ptr = self
-            .root_nodes
-            .last()
-            .and_then(|root_node| root_node.root)?;
+        let update = engine::delete(self, item)?;
+        Some(self.finish(update))
+    }
 
-        // Path keeping track of all modified nodes in order
-        let mut path = Vec::new();
+    fn contains(&self, item: &Self::Data, timestamp: Self::Timestamp) -> bool {
+        engine::contains(self, item, &timestamp)
+    }
 
-        // Traverse to node to delete
-        while self.node_arena[child_ptr].datum != *item {
-            path.push(child_ptr);
-            parent_ptr = Some(child_ptr);
+    fn predecessor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        engine::predecessor(self, item, &timestamp)
+    }
+
+    fn successor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        engine::successor(self, item, &timestamp)
+    }
+}
 
-            let children = self.node_arena[child_ptr].children.last()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{newest_avl_data, random_update, Rng};
+
+    #[test]
+    fn bulk_loads_balanced_versions() {
+        let mut rng = Rng(7);
+        let mut tree = FatNodeAvl::new();
+        let mut versions: Vec<Vec<u64>> = Vec::new();
+
+        for len in [0, 1, 2, 3, 7, 8, 100, 1000] {
+            let mut data: Vec<u64> = (0..len).map(|_| rng.below(50)).collect();
+            data.sort();
+
+            let timestamp = tree.bulk_load_at_new_version(data.clone()).unwrap();
+            assert_eq!(timestamp, versions.len() as u64);
+            assert!(newest_avl_data(&tree).into_iter().eq(data.iter()));
+            versions.push(data);
+        }
 
-            child_ptr = if self.node_arena[child_ptr].datum < *item {
-                children.right?
-            } else {
-                children.left?
-            };
+        // Each bulk load leaves the versions before it as they were
+        for (timestamp, data) in versions.iter().enumerate() {
+            for item in 0..50 {
+                assert_eq!(tree.contains(&item, timestamp as u64), data.contains(&item));
+            }
         }
+    }
 
-        let children_of_deleted = self.node_arena[child_ptr].children.last();
-
-        let left_of_deleted = children_of_deleted.and_then(|children| children.left);
-        let right_of_deleted = children_of_deleted.and_then(|children| children.left);
-
-        // match against both children of deleted node existing
-        match left_of_deleted.zip(right_of_deleted) {
-            Some((_, right_subtree_ptr)) => {
-                match self.node_arena[right_subtree_ptr]
-                    .children
-                    .last()
-                    .and_then(|children| children.left)
-                {
-                    // If the left child of our right subtree exists we find the
-                    // successor of our deleted node. Note that this successor
-                    // necessarily doesn't have a right child, as otherwise that
-                    // would be our successor. We replace our deleted node with its
-                    // successor, and give the successors right child to its parent.
-                    Some(mut sup_ptr) => {
-                        let mut sup_parent_ptr = right_subtree_ptr;
-                        let mut displaced_path = vec![sup_parent_ptr];
-
-                        while let Some(lesser) = self.node_arena[sup_ptr]
-                            .children
-                            .last()
-                            .and_then(|children| children.left)
-                        {
-                            sup_parent_ptr = sup_ptr;
-                            displaced_path.push(sup_parent_ptr);
-
-                            sup_ptr = lesser;
-                        }
+    #[test]
+    fn builds_from_a_sorted_iter() {
+        let tree = FatNodeAvl::from_sorted_iter(0..100u64).unwrap();
 
-                        // Our path will be up to the deleted node, then the next
-                        // node will be our successor, and then the next nodes will
-                        // be the the path down to where our successor was located.
-                        path.push(sup_ptr);
-                        path.append(&mut displaced_path);
-
-                        let right_of_sup = self.node_arena[sup_ptr]
-                            .children
-                            .last()
-                            .and_then(|children| children.right);
-                        self.node_arena[sup_parent_ptr].modify_left(self.last_time, right_of_sup);
-
-                        self.node_arena[sup_ptr].modify_left(self.last_time, left_of_deleted);
-                        self.node_arena[sup_ptr].modify_right(self.last_time, right_of_deleted);
-
-                        if let Some(parent_ptr) = parent_ptr {
-                            if self.node_arena[parent_ptr].children.last().unwrap().left
-                                == Some(child_ptr)
-                            {
-                                self.node_arena[parent_ptr]
-                                    .modify_left(self.last_time, Some(sup_ptr));
-                            } else {
-                                self.node_arena[parent_ptr]
-                                    .modify_right(self.last_time, Some(sup_ptr));
-                            }
-                        };
-                    }
+        assert!(newest_avl_data(&tree).into_iter().copied().eq(0..100));
+        assert_eq!(tree.predecessor(&50, 0), Some(&50));
+    }
 
-                    // If the left child of our right subtree does not exist,
-                    // we give the left child of our deleted node to the right
-                    // subtree, and replace our deleted node with its right child.
-                    None => {
-                        self.node_arena[right_subtree_ptr]
-                            .modify_left(self.last_time, left_of_deleted);
-
-                        if let Some(parent_ptr) = parent_ptr {
-                            if self.node_arena[parent_ptr].children.last().unwrap().left
-                                == Some(child_ptr)
-                            {
-                                self.node_arena[parent_ptr]
-                                    .modify_left(self.last_time, Some(right_subtree_ptr));
-                            } else {
-                                self.node_arena[parent_ptr]
-                                    .modify_right(self.last_time, Some(right_subtree_ptr));
-                            }
-                        };
+    #[test]
+    fn refuses_unsorted_input() {
+        assert_eq!(
+            FatNodeAvl::from_sorted_iter([1, 3, 2]).err(),
+            Some(BulkLoadError::Unsorted { index: 2 })
+        );
+
+        let mut tree = FatNodeAvl::from_sorted_iter([1, 2, 2, 3]).unwrap();
+        assert_eq!(
+            tree.bulk_load_at_new_version([5, 4]),
+            Err(BulkLoadError::Unsorted { index: 1 })
+        );
+
+        // The tree is left as it was
+        assert!(!tree.contains(&5, 1));
+        assert_eq!(tree.insert(4), 1);
+        assert!(newest_avl_data(&tree)
+            .into_iter()
+            .eq([1, 2, 2, 3, 4].iter()));
+    }
+
+    #[test]
+    fn split_nodes_stay_within_capacity_and_keep_old_versions() {
+        for children_capacity in [1, 2, DEFAULT_CHILDREN_CAPACITY] {
+            let mut rng = Rng(children_capacity as u64);
+            let mut tree = FatNodeAvl::with_children_capacity(children_capacity);
+            let mut versions: Vec<Vec<u64>> = Vec::new();
+
+            for _ in 0..400 {
+                let mut data = versions.last().cloned().unwrap_or_default();
+                let item = rng.below(40);
+                let timestamp = if rng.below(3) == 0 {
+                    match data.iter().position(|&datum| datum == item) {
+                        Some(position) => {
+                            data.remove(position);
+                            tree.delete(&item).unwrap()
+                        }
+                        None => {
+                            assert_eq!(tree.delete(&item), None);
+                            continue;
+                        }
                     }
-                }
-            }
-            // If the deleted node has a single child then we replace it with that child.
-            // Otherwise if the deleted node has no children we remove it without replacement.
-            None => {
-                let new_child = if let None = left_of_deleted {
-                    right_of_deleted
-                } else if let None = right_of_deleted {
-                    left_of_deleted
                 } else {
-                    None
+                    let position = data.partition_point(|&datum| datum <= item);
+                    data.insert(position, item);
+                    tree.insert(item)
                 };
 
-                if let Some(parent_ptr) = parent_ptr {
-                    if self.node_arena[parent_ptr].children.last().unwrap().left == Some(child_ptr)
-                    {
-                        self.node_arena[parent_ptr].modify_left(self.last_time, new_child);
-                    } else {
-                        self.node_arena[parent_ptr].modify_right(self.last_time, new_child);
-                    }
-                };
+                assert_eq!(timestamp, versions.len() as u64);
+                assert!(tree
+                    .node_arena
+                    .iter()
+                    .all(|node| node.children.len() <= children_capacity));
+                assert!(newest_avl_data(&tree).into_iter().eq(data.iter()));
+                versions.push(data);
+            }
+
+            // Splitting copied nodes rather than growing their history
+            assert!(tree.node_arena.len() > tree.data.len());
+
+            for (timestamp, data) in versions.iter().enumerate() {
+                let timestamp = timestamp as u64;
+                for item in 0..41 {
+                    assert_eq!(tree.contains(&item, timestamp), data.contains(&item));
+                    assert_eq!(
+                        tree.predecessor(&item, timestamp),
+                        data.iter().rev().find(|&&datum| datum <= item)
+                    );
+                    assert_eq!(
+                        tree.successor(&item, timestamp),
+                        data.iter().find(|&&datum| datum >= item)
+                    );
+                }
             }
         }
+    }
 
-        let new_root = self.balance(self.last_time, &path);
-        self.modify_root(new_root, self.last_time);
+    /// A tree after random updates of items below 40, along with the sorted
+    /// data of each of its versions
+    fn history(children_capacity: usize, updates: usize) -> (FatNodeAvl<u64>, Vec<Vec<u64>>) {
+        let mut rng = Rng(updates as u64 + 1);
+        let mut tree = FatNodeAvl::with_children_capacity(children_capacity);
+        let mut versions: Vec<Vec<u64>> = Vec::new();
 
-        self.last_time += 1;
-        Some(self.last_time - 1)
+        for _ in 0..updates {
+            extend_history(&mut tree, &mut versions, &mut rng);
+        }
+
+        (tree, versions)
     }
 
-    fn contains(&self, item: &Self::Data, timestamp: Self::Timestamp) -> bool {
-        match self.predecessor(item, timestamp) {
-            Some(predecessor) => *item == *predecessor,
-            None => false,
+    fn extend_history(tree: &mut FatNodeAvl<u64>, versions: &mut Vec<Vec<u64>>, rng: &mut Rng) {
+        let mut data = versions.last().cloned().unwrap_or_default();
+        if let Some(timestamp) = random_update(tree, &mut data, rng, 40) {
+            assert_eq!(timestamp, versions.len() as u64);
+            versions.push(data);
         }
     }
 
-    fn predecessor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
-        let mut node_ptr = get_time(&self.root_nodes, &timestamp)?.root;
-        let mut inf: Option<&Self::Data> = None;
-
-        while let Some(current_ptr) = node_ptr {
-            let node = &self.node_arena[current_ptr];
-            let children = get_time(&node.children, &timestamp);
-
-            match children {
-                Some(children) => {
-                    if node.datum > *item {
-                        node_ptr = children.left;
-                    } else {
-                        inf = Some(&node.datum);
-                        node_ptr = children.right;
-                    }
+    #[test]
+    fn indexed_versions_find_the_roots_a_scan_does() {
+        // Indexed while empty, within the galloped recent versions, and with
+        // many older versions, then followed by more versions than are
+        // galloped
+        for indexed in [0, 1, 30, RECENT_VERSIONS + 1, 500] {
+            let (mut tree, mut versions) = history(2, indexed);
+            tree.index_versions();
+
+            let mut rng = Rng(1);
+            for _ in 0..RECENT_VERSIONS * 2 {
+                extend_history(&mut tree, &mut versions, &mut rng);
+            }
+
+            for timestamp in 0..versions.len() as u64 + 2 {
+                let root = tree
+                    .root_nodes
+                    .iter()
+                    .rev()
+                    .find(|root_node| root_node.timestamp <= timestamp)
+                    .and_then(|root_node| root_node.root);
+                assert_eq!(tree.root_at(&timestamp), root, "{timestamp}");
+            }
+
+            for (timestamp, data) in versions.iter().enumerate() {
+                for item in 0..41 {
+                    assert_eq!(tree.contains(&item, timestamp as u64), data.contains(&item));
                 }
-                None => break,
             }
         }
-
-        inf
     }
 
-    fn successor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
-        let root = get_time(&self.root_nodes, &timestamp)?.root;
-
-        let arena = Rc::new(RefCell::new(&self.node_arena));
+    #[test]
+    fn many_timestamps_answer_as_one_at_a_time() {
+        for children_capacity in [1, 2, DEFAULT_CHILDREN_CAPACITY] {
+            let (tree, versions) = history(children_capacity, 300);
+            let last = versions.len() as u64;
+            let mut rng = Rng(children_capacity as u64);
+
+            // Unsorted, repeated, and past the last version
+            let mut timestamps: Vec<u64> = (0..200).map(|_| rng.below(last + 5)).collect();
+            timestamps.extend([0, 0, last, last + 100, 3, 3]);
+
+            for item in 0..41 {
+                let contains = tree.contains_many(&item, &timestamps);
+                let predecessors = tree.predecessor_many(&item, &timestamps);
+                assert_eq!(contains.len(), timestamps.len());
+                assert_eq!(predecessors.len(), timestamps.len());
+
+                for (query, &timestamp) in timestamps.iter().enumerate() {
+                    assert_eq!(contains[query], tree.contains(&item, timestamp));
+                    assert_eq!(predecessors[query], tree.predecessor(&item, timestamp));
+                }
+            }
+        }
 
-        avl::successor(
-            &|node: usize| {
-                get_time(&arena.borrow()[node].children, &timestamp)
-                    .and_then(|children| children.left)
-            },
-            &|node: usize| {
-                get_time(&arena.borrow()[node].children, &timestamp)
-                    .and_then(|children| children.right)
-            },
-            &|item: &Data, node: usize| Ord::cmp(item, &arena.borrow()[node].datum),
-            root,
-            item,
-        )
-        .and_then(|successor_ptr| Some(&self.node_arena[successor_ptr].datum))
+        let (empty, _) = history(1, 0);
+        assert_eq!(empty.contains_many(&1, &[0, 5, 0]), [false; 3]);
+        assert_eq!(empty.predecessor_many(&1, &[2, 0]), [None; 2]);
+        assert!(empty.predecessor_many(&1, &[]).is_empty());
     }
 
-    // fn successor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
-
-    //     let mut node_ptr = get_time(&self.root_nodes, &timestamp)?.root;
-    //     let mut sup: Option<&Self::Data> = None;
-
-    //     while let Some(current_ptr) = node_ptr {
-    //         let node = &self.node_arena[current_ptr];
-    //         let children = get_time(&node.children, &timestamp);
-
-    //         match children {
-    //             Some(children) => {
-    //                 if node.datum < *item {
-    //                     node_ptr = children.right;
-    //                 } else {
-    //                     sup = Some(&node.datum);
-    //                     node_ptr = children.left;
-    //                 }
-    //             }
-    //             None => break,
-    //         }
-    //     }
-
-    //     sup
-    // }
+    #[test]
+    fn many_timestamps_see_nothing_before_the_first_version() {
+        // Start the history at 5, so that no version covers 0 to 4
+        let mut tree = FatNodeAvl::with_children_capacity(1);
+        tree.last_time = 5;
+        tree.insert(7u64);
+        tree.insert(3);
+
+        assert_eq!(
+            tree.contains_many(&7, &[6, 0, 5, 4]),
+            [true, false, true, false]
+        );
+        assert_eq!(
+            tree.predecessor_many(&9, &[5, 6, 2]),
+            [Some(&7), Some(&7), None]
+        );
+        assert_eq!(tree.predecessor_many(&5, &[6, 5]), [Some(&3), None]);
+    }
 }
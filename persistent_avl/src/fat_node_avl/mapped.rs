@@ -0,0 +1,341 @@
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::avl::avl;
+use crate::fat_node_avl::snapshot::*;
+use crate::snapshot::error::SnapshotError;
+use crate::snapshot::format::*;
+use crate::snapshot::record::Record;
+use crate::timestamp::search_time;
+
+/// A read-only view of a `FatNodeAvl` snapshot, queried in place through a
+/// memory map.
+///
+/// Opening only locates the sections of the snapshot, so it takes the same
+/// time however long the history is. Records are decoded as queries reach
+/// them, and results are returned by value.
+///
+/// Checksums are not verified on open, as that would mean reading the whole
+/// file; call `verify` to check them. Pointers are still bounds checked and
+/// walks are cut off at the greatest height a valid tree can have, so a
+/// damaged file yields errors or wrong answers rather than out of bounds
+/// reads or endless loops.
+pub struct MappedFatNodeAvl<Data: Ord + Record> {
+    map: Mmap,
+    spans: Vec<SectionSpan>,
+    data: SectionSpan,
+    nodes: SectionSpan,
+    children: SectionSpan,
+    roots: SectionSpan,
+    _data: PhantomData<Data>,
+}
+
+impl<Data: Ord + Record> MappedFatNodeAvl<Data> {
+    /// Maps the snapshot at `path`, as written by `FatNodeAvl::write_to`.
+    ///
+    /// The file must not be modified while it is mapped.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        let file = File::open(path)?;
+
+        // Safety: the snapshot is only ever read through the map, and every
+        // access below is bounds checked against the length of the map.
+        let map = unsafe { Mmap::map(&file)? };
+
+        let spans = section_spans(&map, BACKEND_FAT_NODE_AVL, Data::WIDTH)?;
+
+        find_span(&spans, META, META_WIDTH)?;
+        let data = find_span(&spans, DATA, Data::WIDTH)?;
+        let nodes = find_span(&spans, NODE, NODE_WIDTH)?;
+        let children = find_span(&spans, CHLD, CHLD_WIDTH)?;
+        let roots = find_span(&spans, ROOT, ROOT_WIDTH)?;
+
+        Ok(MappedFatNodeAvl {
+            map,
+            spans,
+            data,
+            nodes,
+            children,
+            roots,
+            _data: PhantomData,
+        })
+    }
+
+    /// Checks the checksum of every section, reading the whole file
+    pub fn verify(&self) -> Result<(), SnapshotError> {
+        self.spans
+            .iter()
+            .try_for_each(|span| span.verify(&self.map))
+    }
+
+    pub fn contains(&self, item: &Data, timestamp: u64) -> Result<bool, SnapshotError> {
+        let steps = Cell::new(0);
+        let damaged = Cell::new(false);
+        let found = avl::contains(
+            &|node| self.step(&steps, self.get_left(node, timestamp)),
+            &|node| self.step(&steps, self.get_right(node, timestamp)),
+            &|item: &Data, node| self.compare(&damaged, item, node),
+            self.get_root(timestamp),
+            item,
+        );
+
+        self.check_walk(&steps, &damaged)?;
+        Ok(found)
+    }
+
+    pub fn predecessor(&self, item: &Data, timestamp: u64) -> Result<Option<Data>, SnapshotError> {
+        let steps = Cell::new(0);
+        let damaged = Cell::new(false);
+        let found = avl::predecessor(
+            &|node| self.step(&steps, self.get_left(node, timestamp)),
+            &|node| self.step(&steps, self.get_right(node, timestamp)),
+            &|item: &Data, node| self.compare(&damaged, item, node),
+            self.get_root(timestamp),
+            item,
+        );
+
+        self.check_walk(&steps, &damaged)?;
+        found.map(|node| self.read_datum(node)).transpose()
+    }
+
+    pub fn successor(&self, item: &Data, timestamp: u64) -> Result<Option<Data>, SnapshotError> {
+        let steps = Cell::new(0);
+        let damaged = Cell::new(false);
+        let found = avl::successor(
+            &|node| self.step(&steps, self.get_left(node, timestamp)),
+            &|node| self.step(&steps, self.get_right(node, timestamp)),
+            &|item: &Data, node| self.compare(&damaged, item, node),
+            self.get_root(timestamp),
+            item,
+        );
+
+        self.check_walk(&steps, &damaged)?;
+        found.map(|node| self.read_datum(node)).transpose()
+    }
+
+    /// Greatest number of steps down from a root in a valid tree, as AVL
+    /// trees of n nodes are less than 1.45 log2(n + 2) high
+    fn max_steps(&self) -> usize {
+        2 * (usize::BITS - self.nodes.count.leading_zeros()) as usize + 2
+    }
+
+    /// Counts a step down to `child`, and ends the walk instead once it has
+    /// gone deeper than a valid tree allows, as it would if the child
+    /// pointers formed a cycle
+    fn step(&self, steps: &Cell<usize>, child: Option<usize>) -> Option<usize> {
+        let child = child?;
+        steps.set(steps.get() + 1);
+
+        (steps.get() <= self.max_steps()).then_some(child)
+    }
+
+    /// Fails a walk that went too deep or met a datum pointer out of range
+    fn check_walk(&self, steps: &Cell<usize>, damaged: &Cell<bool>) -> Result<(), SnapshotError> {
+        if steps.get() > self.max_steps() {
+            return Err(SnapshotError::Corrupt("child pointers form a cycle"));
+        }
+        if damaged.get() {
+            return Err(SnapshotError::Corrupt("datum pointer out of range"));
+        }
+
+        Ok(())
+    }
+
+    fn get_root(&self, timestamp: u64) -> Option<usize> {
+        let roots = self.roots;
+
+        search_time(
+            roots.count,
+            |index| read_u64(&self.map, roots.record(index)),
+            &timestamp,
+        )
+        .and_then(|index| read_ptr(&self.map, roots.record(index) + 8))
+        .filter(|&node| node < self.nodes.count)
+    }
+
+    /// Offset of the children record of `node` in effect at `timestamp`
+    fn get_children(&self, node: usize, timestamp: u64) -> Option<usize> {
+        let node = self.nodes.record(node);
+        let first_child = read_u64(&self.map, node + 16) as usize;
+        let child_count = read_u64(&self.map, node + 24) as usize;
+
+        if first_child.saturating_add(child_count) > self.children.count {
+            return None;
+        }
+
+        search_time(
+            child_count,
+            |index| read_u64(&self.map, self.children.record(first_child + index)),
+            &timestamp,
+        )
+        .map(|index| self.children.record(first_child + index))
+    }
+
+    fn get_left(&self, node: usize, timestamp: u64) -> Option<usize> {
+        self.get_children(node, timestamp)
+            .and_then(|children| read_ptr(&self.map, children + 8))
+            .filter(|&left| left < self.nodes.count)
+    }
+
+    fn get_right(&self, node: usize, timestamp: u64) -> Option<usize> {
+        self.get_children(node, timestamp)
+            .and_then(|children| read_ptr(&self.map, children + 16))
+            .filter(|&right| right < self.nodes.count)
+    }
+
+    fn get_datum(&self, node: usize) -> Option<Data> {
+        let datum_ptr = read_u64(&self.map, self.nodes.record(node)) as usize;
+        if datum_ptr >= self.data.count {
+            return None;
+        }
+
+        let datum = self.data.record(datum_ptr);
+        Some(Data::decode(&self.map[datum..datum + Data::WIDTH]))
+    }
+
+    fn read_datum(&self, node: usize) -> Result<Data, SnapshotError> {
+        self.get_datum(node)
+            .ok_or(SnapshotError::Corrupt("datum pointer out of range"))
+    }
+
+    /// Compares `item` with the datum of `node`, noting a damaged datum
+    /// pointer in `damaged` for the walk to fail on once it ends
+    fn compare(&self, damaged: &Cell<bool>, item: &Data, node: usize) -> Ordering {
+        match self.get_datum(node) {
+            Some(datum) => item.cmp(&datum),
+            None => {
+                damaged.set(true);
+                Ordering::Less
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::BufWriter;
+    use std::path::Path;
+
+    use super::*;
+    use crate::fat_node_avl::fat_node_avl::FatNodeAvl;
+    use crate::persistent_avl_tree::PersistentAvlTree;
+    use crate::snapshot::binary_snapshot::BinarySnapshot;
+    use crate::test_util::TempDir;
+
+    /// A tree with a history of mixed updates, and its last timestamp
+    fn tree() -> (FatNodeAvl<u64>, u64) {
+        let mut tree = FatNodeAvl::with_children_capacity(2);
+        let mut last_timestamp = 0;
+        for step in 0..300u64 {
+            let item = step * 37 % 101;
+            last_timestamp = match step % 4 {
+                3 => tree.delete(&item).unwrap_or(last_timestamp),
+                _ => tree.insert(item),
+            };
+        }
+
+        (tree, last_timestamp)
+    }
+
+    fn write(tree: &FatNodeAvl<u64>, path: &Path) {
+        tree.write_to(BufWriter::new(File::create(path).unwrap()))
+            .unwrap();
+    }
+
+    #[test]
+    fn answers_as_the_tree() {
+        let dir = TempDir::new("mapped-answers");
+        let path = dir.path().join("tree.snap");
+        let (tree, last_timestamp) = tree();
+        write(&tree, &path);
+
+        let mapped = MappedFatNodeAvl::<u64>::open(&path).unwrap();
+        mapped.verify().unwrap();
+        for timestamp in 0..=last_timestamp + 1 {
+            for item in 0..=102 {
+                assert_eq!(
+                    mapped.contains(&item, timestamp).unwrap(),
+                    tree.contains(&item, timestamp)
+                );
+                assert_eq!(
+                    mapped.predecessor(&item, timestamp).unwrap().as_ref(),
+                    tree.predecessor(&item, timestamp)
+                );
+                assert_eq!(
+                    mapped.successor(&item, timestamp).unwrap().as_ref(),
+                    tree.successor(&item, timestamp)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stops_at_a_cycle_of_child_pointers() {
+        let dir = TempDir::new("mapped-cycle");
+        let path = dir.path().join("tree.snap");
+        let (tree, last_timestamp) = tree();
+        write(&tree, &path);
+
+        // Point every child of every node back at the first node, which
+        // passes the bounds check
+        let children = MappedFatNodeAvl::<u64>::open(&path).unwrap().children;
+        let mut bytes = fs::read(&path).unwrap();
+        for index in 0..children.count {
+            let record = children.record(index);
+            bytes[record + 8..record + 24].fill(0);
+        }
+        fs::write(&path, bytes).unwrap();
+
+        let mapped = MappedFatNodeAvl::<u64>::open(&path).unwrap();
+        assert!(matches!(
+            mapped.contains(&1000, last_timestamp),
+            Err(SnapshotError::Corrupt(_))
+        ));
+        assert!(matches!(
+            mapped.predecessor(&1000, last_timestamp),
+            Err(SnapshotError::Corrupt(_))
+        ));
+        assert!(matches!(
+            mapped.successor(&1000, last_timestamp),
+            Err(SnapshotError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn fails_on_a_datum_pointer_out_of_range() {
+        let dir = TempDir::new("mapped-datum");
+        let path = dir.path().join("tree.snap");
+        let (tree, last_timestamp) = tree();
+        write(&tree, &path);
+
+        // Point the datum of every node past the data section
+        let nodes = MappedFatNodeAvl::<u64>::open(&path).unwrap().nodes;
+        let mut bytes = fs::read(&path).unwrap();
+        for index in 0..nodes.count {
+            let record = nodes.record(index);
+            bytes[record..record + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        }
+        fs::write(&path, bytes).unwrap();
+
+        let mapped = MappedFatNodeAvl::<u64>::open(&path).unwrap();
+        for item in [0, 50, 1000] {
+            assert!(matches!(
+                mapped.contains(&item, last_timestamp),
+                Err(SnapshotError::Corrupt(_))
+            ));
+            assert!(matches!(
+                mapped.predecessor(&item, last_timestamp),
+                Err(SnapshotError::Corrupt(_))
+            ));
+            assert!(matches!(
+                mapped.successor(&item, last_timestamp),
+                Err(SnapshotError::Corrupt(_))
+            ));
+        }
+    }
+}
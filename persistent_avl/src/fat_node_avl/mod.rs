@@ -1,2 +1,4 @@
-mod fat_node;
-mod fat_node_avl;
+pub(crate) mod fat_node;
+pub mod fat_node_avl;
+pub mod mapped;
+mod snapshot;
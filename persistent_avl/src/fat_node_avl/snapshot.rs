@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use crate::fat_field::fat_field::FatField;
+use crate::fat_node_avl::fat_node::{Children, FatNode, RootNode};
+use crate::fat_node_avl::fat_node_avl::FatNodeAvl;
+use crate::snapshot::binary_snapshot::BinarySnapshot;
+use crate::snapshot::error::SnapshotError;
+use crate::snapshot::format::*;
+use crate::snapshot::record::Record;
+
+// Sections of a FatNodeAvl snapshot:
+//
+//   META  last_time u64 | children capacity u64
+//   DATA  datum
+//   NODE  datum pointer u64 | height u64 | first child record u64
+//         | child record count u64
+//   CHLD  timestamp u64 | left u64 | right u64
+//   ROOT  timestamp u64 | root u64
+//
+// The children of each node are stored contiguously in CHLD, in node order.
+// A node and the copies split off from it share one DATA record.
+
+pub(crate) const META: [u8; 4] = *b"META";
+pub(crate) const DATA: [u8; 4] = *b"DATA";
+pub(crate) const NODE: [u8; 4] = *b"NODE";
+pub(crate) const CHLD: [u8; 4] = *b"CHLD";
+pub(crate) const ROOT: [u8; 4] = *b"ROOT";
+
+pub(crate) const META_WIDTH: usize = 16;
+pub(crate) const NODE_WIDTH: usize = 32;
+pub(crate) const CHLD_WIDTH: usize = 24;
+pub(crate) const ROOT_WIDTH: usize = 16;
+
+impl<Data: Ord + Record> BinarySnapshot for FatNodeAvl<Data> {
+    fn write_to(&self, writer: impl Write) -> Result<(), SnapshotError> {
+        let mut writer = SnapshotWriter::new(writer, BACKEND_FAT_NODE_AVL, Data::WIDTH, 5)?;
+
+        writer.section(
+            META,
+            META_WIDTH,
+            1,
+            std::iter::once((self.last_time, self.children_capacity)),
+            |(last_time, children_capacity), buf| {
+                write_u64(buf, 0, last_time);
+                write_u64(buf, 8, children_capacity as u64);
+            },
+        )?;
+
+        writer.section(
+            DATA,
+            Data::WIDTH,
+            self.data.len(),
+            self.data.iter(),
+            |datum, buf| datum.encode(buf),
+        )?;
+
+        let mut first_child = 0;
+        writer.section(
+            NODE,
+            NODE_WIDTH,
+            self.node_arena.len(),
+            self.node_arena.iter(),
+            |node, buf| {
+                write_u64(buf, 0, node.datum_ptr as u64);
+                write_u64(buf, 8, node.height);
+                write_u64(buf, 16, first_child);
+                write_u64(buf, 24, node.children.len() as u64);
+
+                first_child += node.children.len() as u64;
+            },
+        )?;
+
+        let child_count = self.node_arena.iter().map(|node| node.children.len()).sum();
+        writer.section(
+            CHLD,
+            CHLD_WIDTH,
+            child_count,
+            self.node_arena
+                .iter()
+                .flat_map(|node| node.children.history()),
+            |(&timestamp, children), buf| {
+                write_u64(buf, 0, timestamp);
+                write_ptr(buf, 8, children.left);
+                write_ptr(buf, 16, children.right);
+            },
+        )?;
+
+        writer.section(
+            ROOT,
+            ROOT_WIDTH,
+            self.root_nodes.len(),
+            self.root_nodes.iter(),
+            |root_node, buf| {
+                write_u64(buf, 0, root_node.timestamp);
+                write_ptr(buf, 8, root_node.root);
+            },
+        )?;
+
+        writer.finish()
+    }
+
+    fn read_from(reader: impl Read) -> Result<Self, SnapshotError> {
+        let contents = SnapshotContents::read(reader, BACKEND_FAT_NODE_AVL, Data::WIDTH)?;
+
+        let meta = contents.section(META, META_WIDTH)?;
+        let data = contents.section(DATA, Data::WIDTH)?;
+        let nodes = contents.section(NODE, NODE_WIDTH)?;
+        let children = contents.section(CHLD, CHLD_WIDTH)?;
+        let roots = contents.section(ROOT, ROOT_WIDTH)?;
+
+        let (last_time, children_capacity) = meta
+            .records()
+            .next()
+            .map(|buf| (read_u64(buf, 0), read_u64(buf, 8) as usize))
+            .ok_or(SnapshotError::Corrupt("missing metadata record"))?;
+
+        if children_capacity == 0 {
+            return Err(SnapshotError::Corrupt("children capacity is zero"));
+        }
+
+        let data: Vec<Data> = data.records().map(Data::decode).collect();
+
+        let node_count = nodes.len();
+        let check_ptr = |ptr: Option<usize>| match ptr {
+            Some(ptr) if ptr >= node_count => {
+                Err(SnapshotError::Corrupt("node pointer out of range"))
+            }
+            _ => Ok(ptr),
+        };
+
+        let mut children = children.records();
+        let mut next_child = 0;
+        let mut node_arena = Vec::with_capacity(node_count);
+
+        for buf in nodes.records() {
+            let datum_ptr = read_u64(buf, 0) as usize;
+            if datum_ptr >= data.len() {
+                return Err(SnapshotError::Corrupt("datum pointer out of range"));
+            }
+
+            let height = read_u64(buf, 8);
+            let first_child = read_u64(buf, 16);
+            let child_count = read_u64(buf, 24);
+
+            if first_child != next_child {
+                return Err(SnapshotError::Corrupt(
+                    "children are not stored contiguously",
+                ));
+            }
+            next_child += child_count;
+
+            let mut node_children = FatField::new();
+            for _ in 0..child_count {
+                let buf = children
+                    .next()
+                    .ok_or(SnapshotError::Corrupt("child record out of range"))?;
+
+                let timestamp = read_u64(buf, 0);
+                if node_children
+                    .history()
+                    .next_back()
+                    .is_some_and(|(&last, _)| last >= timestamp)
+                {
+                    return Err(SnapshotError::Corrupt("child timestamps out of order"));
+                }
+
+                node_children.set(
+                    timestamp,
+                    Children {
+                        left: check_ptr(read_ptr(buf, 8))?,
+                        right: check_ptr(read_ptr(buf, 16))?,
+                    },
+                );
+            }
+
+            node_arena.push(FatNode {
+                datum_ptr,
+                height,
+                children: node_children,
+            });
+        }
+
+        if children.next().is_some() {
+            return Err(SnapshotError::Corrupt(
+                "child records not owned by any node",
+            ));
+        }
+
+        let mut root_nodes: Vec<RootNode> = Vec::with_capacity(roots.len());
+        for buf in roots.records() {
+            let timestamp = read_u64(buf, 0);
+            if root_nodes
+                .last()
+                .is_some_and(|last| last.timestamp >= timestamp)
+            {
+                return Err(SnapshotError::Corrupt("root timestamps out of order"));
+            }
+
+            root_nodes.push(RootNode {
+                timestamp,
+                root: check_ptr(read_ptr(buf, 8))?,
+            });
+        }
+
+        check_shape(&node_arena, &root_nodes)?;
+
+        Ok(FatNodeAvl {
+            data,
+            node_arena,
+            root_nodes,
+            last_time,
+            children_capacity,
+            root_index: None,
+        })
+    }
+}
+
+/// Checks that in every version the nodes reached from the root form a tree,
+/// as a damaged file could otherwise make walks down the loaded tree loop
+/// forever
+///
+/// Unlike path copying, fat nodes can point at nodes created after them, so
+/// the versions are replayed in timestamp order instead. Only the nodes whose
+/// subtree changed since the version before are walked again: any other node
+/// of the version before keeps a subtree that was already checked.
+fn check_shape(node_arena: &[FatNode], root_nodes: &[RootNode]) -> Result<(), SnapshotError> {
+    let mut changes: Vec<(u64, usize, Children)> = node_arena
+        .iter()
+        .enumerate()
+        .flat_map(|(node_ptr, node)| {
+            node.children
+                .history()
+                .map(move |(&timestamp, &children)| (timestamp, node_ptr, children))
+        })
+        .collect();
+    changes.sort_by_key(|&(timestamp, _, _)| timestamp);
+
+    let mut timestamps: Vec<u64> = changes
+        .iter()
+        .map(|&(timestamp, _, _)| timestamp)
+        .chain(root_nodes.iter().map(|root_node| root_node.timestamp))
+        .collect();
+    timestamps.sort_unstable();
+    timestamps.dedup();
+
+    // Children and parents in the version last replayed, whether each node is
+    // in it, and the last round each node was reached or changed in
+    let mut children = vec![Children::default(); node_arena.len()];
+    let mut parent: Vec<Option<usize>> = vec![None; node_arena.len()];
+    let mut in_tree = vec![false; node_arena.len()];
+    let mut reached = vec![0; node_arena.len()];
+    let mut dirty = vec![0; node_arena.len()];
+
+    let mut root = None;
+    let mut roots = root_nodes.iter().peekable();
+    let mut changes = changes.into_iter().peekable();
+
+    for (round, timestamp) in (1..).zip(timestamps) {
+        let old_root = root;
+        while let Some(root_node) = roots.next_if(|root_node| root_node.timestamp <= timestamp) {
+            root = root_node.root;
+        }
+
+        // Apply the changes, marking the changed nodes of the version before
+        // and their ancestors as dirty
+        let mut old_children = HashMap::new();
+        let mut dirty_nodes = Vec::new();
+        while let Some((_, node_ptr, new_children)) =
+            changes.next_if(|&(change_time, _, _)| change_time == timestamp)
+        {
+            old_children.insert(node_ptr, children[node_ptr]);
+            children[node_ptr] = new_children;
+
+            let mut ancestor = Some(node_ptr);
+            while let Some(node_ptr) =
+                ancestor.filter(|&node_ptr| in_tree[node_ptr] && dirty[node_ptr] != round)
+            {
+                dirty[node_ptr] = round;
+                dirty_nodes.push(node_ptr);
+                ancestor = parent[node_ptr];
+            }
+        }
+
+        // Walk the version from its root, down to the clean nodes. A clean
+        // node may move to a new parent, but only if its clean old parent,
+        // which still points at it, drops out of the version
+        let mut stack: Vec<usize> = root.into_iter().collect();
+        let mut visited = Vec::new();
+        let mut old_parents = Vec::new();
+        while let Some(node_ptr) = stack.pop() {
+            if reached[node_ptr] == round {
+                return Err(SnapshotError::Corrupt("child pointers do not form a tree"));
+            }
+            reached[node_ptr] = round;
+            visited.push(node_ptr);
+
+            if in_tree[node_ptr] && dirty[node_ptr] != round {
+                continue;
+            }
+
+            let Children { left, right } = children[node_ptr];
+            for child_ptr in [left, right].into_iter().flatten() {
+                if in_tree[child_ptr] && dirty[child_ptr] != round {
+                    old_parents.extend(parent[child_ptr].filter(|&old| old != node_ptr));
+                }
+                parent[child_ptr] = Some(node_ptr);
+                stack.push(child_ptr);
+            }
+        }
+        if let Some(root) = root {
+            parent[root] = None;
+        }
+
+        // Take the nodes no longer reached out of the version, walking down
+        // from where they may have been cut off
+        let mut stack: Vec<usize> = old_root.into_iter().collect();
+        for &node_ptr in &dirty_nodes {
+            let Children { left, right } = old_children
+                .get(&node_ptr)
+                .copied()
+                .unwrap_or(children[node_ptr]);
+            stack.extend([Some(node_ptr), left, right].into_iter().flatten());
+        }
+        while let Some(node_ptr) = stack.pop() {
+            if reached[node_ptr] == round || !in_tree[node_ptr] {
+                continue;
+            }
+            in_tree[node_ptr] = false;
+
+            let Children { left, right } = old_children
+                .get(&node_ptr)
+                .copied()
+                .unwrap_or(children[node_ptr]);
+            stack.extend([left, right].into_iter().flatten());
+        }
+
+        if old_parents
+            .into_iter()
+            .any(|old| in_tree[old] && dirty[old] != round)
+        {
+            return Err(SnapshotError::Corrupt("child pointers do not form a tree"));
+        }
+
+        for node_ptr in visited {
+            in_tree[node_ptr] = true;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avl::node_store::NodeStore;
+    use crate::fat_node_avl::fat_node_avl::DEFAULT_CHILDREN_CAPACITY;
+    use crate::persistent_avl_tree::PersistentAvlTree;
+    use crate::test_util::Rng;
+
+    /// A tree with a history of mixed updates, a bulk load that replaces every
+    /// node midway, and nodes split along the way
+    fn tree(children_capacity: usize) -> FatNodeAvl<u64> {
+        let mut tree = FatNodeAvl::with_children_capacity(children_capacity);
+        for step in 0..300u64 {
+            let item = step * 37 % 101;
+            if step == 150 {
+                tree.bulk_load_at_new_version(0..20).unwrap();
+            } else if step % 4 == 3 {
+                tree.delete(&item);
+            } else {
+                tree.insert(item);
+            }
+        }
+
+        tree
+    }
+
+    fn round_trip(tree: &FatNodeAvl<u64>) -> Result<FatNodeAvl<u64>, SnapshotError> {
+        let mut bytes = Vec::new();
+        tree.write_to(&mut bytes).unwrap();
+
+        FatNodeAvl::read_from(bytes.as_slice())
+    }
+
+    /// Whether the nodes reached from the root of every version form a tree
+    fn forms_trees(tree: &FatNodeAvl<u64>) -> bool {
+        (0..=tree.last_time + 1).all(|timestamp| {
+            let mut reached = vec![false; tree.node_arena.len()];
+            let mut stack: Vec<usize> = tree.root_at(&timestamp).into_iter().collect();
+            while let Some(node_ptr) = stack.pop() {
+                if std::mem::replace(&mut reached[node_ptr], true) {
+                    return false;
+                }
+                stack.extend(tree.left_at(node_ptr, &timestamp));
+                stack.extend(tree.right_at(node_ptr, &timestamp));
+            }
+
+            true
+        })
+    }
+
+    #[test]
+    fn round_trips_every_version() {
+        for children_capacity in [1, 2, DEFAULT_CHILDREN_CAPACITY] {
+            let tree = tree(children_capacity);
+            let read = round_trip(&tree).unwrap();
+
+            assert_eq!(read.children_capacity, children_capacity);
+            assert_eq!(read.last_time, tree.last_time);
+            for timestamp in 0..=tree.last_time + 1 {
+                for item in 0..=102 {
+                    assert_eq!(
+                        read.contains(&item, timestamp),
+                        tree.contains(&item, timestamp)
+                    );
+                    assert_eq!(
+                        read.predecessor(&item, timestamp),
+                        tree.predecessor(&item, timestamp)
+                    );
+                    assert_eq!(
+                        read.successor(&item, timestamp),
+                        tree.successor(&item, timestamp)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_a_cycle_of_child_pointers() {
+        let mut tree = tree(2);
+        let root = tree.root_at(&tree.last_time).unwrap();
+
+        // Point the root of the newest version back at itself
+        let (&timestamp, &children) = tree.node_arena[root]
+            .children
+            .history()
+            .next_back()
+            .unwrap();
+        tree.node_arena[root].children.set(
+            timestamp,
+            Children {
+                left: Some(root),
+                ..children
+            },
+        );
+
+        assert!(matches!(
+            round_trip(&tree),
+            Err(SnapshotError::Corrupt("child pointers do not form a tree"))
+        ));
+    }
+
+    #[test]
+    fn loads_exactly_the_snapshots_whose_versions_are_trees() {
+        let mut rng = Rng(11);
+
+        // Repoint one child of one node at a random node, anywhere in its
+        // history, which may or may not break a version
+        let mut refused = 0;
+        for _ in 0..300 {
+            let mut tree = tree(2);
+            let node_ptr = rng.below(tree.node_arena.len() as u64) as usize;
+            let history: Vec<(u64, Children)> = tree.node_arena[node_ptr]
+                .children
+                .history()
+                .map(|(&timestamp, &children)| (timestamp, children))
+                .collect();
+            if history.is_empty() {
+                continue;
+            }
+            let entry = rng.below(history.len() as u64) as usize;
+
+            let target = Some(rng.below(tree.node_arena.len() as u64) as usize);
+            let mut children = FatField::new();
+            for (index, &(timestamp, mut entry_children)) in history.iter().enumerate() {
+                if index == entry {
+                    match rng.below(2) {
+                        0 => entry_children.left = target,
+                        _ => entry_children.right = target,
+                    }
+                }
+                children.set(timestamp, entry_children);
+            }
+            tree.node_arena[node_ptr].children = children;
+
+            let read = round_trip(&tree);
+            assert_eq!(read.is_ok(), forms_trees(&tree));
+            if let Err(err) = read {
+                assert!(matches!(err, SnapshotError::Corrupt(_)));
+                refused += 1;
+            }
+        }
+
+        assert!(refused > 0 && refused < 300);
+    }
+}
@@ -0,0 +1,357 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::ops::RangeBounds;
+
+use crate::avl::avl;
+use crate::batch_update::{self, BatchUpdate};
+use crate::bulk_load::bulk_load;
+use crate::bulk_load::error::BulkLoadError;
+use crate::fat_field::fat_field::FatField;
+use crate::fat_node_avl::fat_node::RootNode;
+use crate::fat_node_rb::rb_fat_node::RbFatNode;
+use crate::persistent_avl_tree::PersistentAvlTree;
+use crate::rb::rb;
+use crate::timestamp::get_time;
+
+/// A partially persistent red-black tree built from fat nodes.
+///
+/// Red-black updates make O(1) amortized pointer changes, and colors are
+/// kept for the newest version only, so every update adds O(1) amortized
+/// children entries.
+pub struct FatNodeRbTree<Data: Ord> {
+    data: Vec<Data>,
+    node_arena: Vec<RbFatNode>,
+    root_nodes: Vec<RootNode>,
+    last_time: u64,
+}
+
+impl<Data: Ord> Default for FatNodeRbTree<Data> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Data: Ord> FatNodeRbTree<Data> {
+    pub fn new() -> Self {
+        FatNodeRbTree {
+            data: Vec::new(),
+            node_arena: Vec::new(),
+            root_nodes: Vec::new(),
+            last_time: 0,
+        }
+    }
+
+    fn get_datum(&self, node_ptr: usize) -> &Data {
+        &self.data[self.node_arena[node_ptr].datum_ptr]
+    }
+
+    fn modify_root(&mut self, new_node_ptr: Option<usize>, timestamp: u64) {
+        let latest_root = self.root_nodes.last().and_then(|root_node| root_node.root);
+
+        if self.root_nodes.is_empty() || latest_root != new_node_ptr {
+            self.root_nodes.push(RootNode {
+                timestamp,
+                root: new_node_ptr,
+            });
+        }
+    }
+
+    fn latest_root(&self) -> Option<usize> {
+        self.root_nodes.last().and_then(|root_node| root_node.root)
+    }
+
+    fn get_left(&self, node_ptr: usize, timestamp: u64) -> Option<usize> {
+        self.node_arena[node_ptr]
+            .children
+            .get(&timestamp)
+            .and_then(|children| children.left)
+    }
+
+    fn get_right(&self, node_ptr: usize, timestamp: u64) -> Option<usize> {
+        self.node_arena[node_ptr]
+            .children
+            .get(&timestamp)
+            .and_then(|children| children.right)
+    }
+
+    fn compare(&self, item: &Data, node_ptr: usize) -> Ordering {
+        item.cmp(self.get_datum(node_ptr))
+    }
+}
+
+impl<Data: Ord> PersistentAvlTree for FatNodeRbTree<Data> {
+    type Data = Data;
+    type Timestamp = u64;
+
+    fn insert(&mut self, item: Self::Data) -> Self::Timestamp {
+        let timestamp = self.last_time;
+
+        // Allocation
+        self.data.push(item);
+        self.node_arena.push(RbFatNode {
+            datum_ptr: self.data.len() - 1,
+            red: true,
+            children: FatField::new(),
+        });
+        let new_node_ptr = self.node_arena.len() - 1;
+
+        // Insertion
+        let mut path = Vec::new();
+        let mut path_ptr = self.latest_root();
+        while let Some(ptr) = path_ptr {
+            path.push(ptr);
+
+            path_ptr = if *self.get_datum(new_node_ptr) <= *self.get_datum(ptr) {
+                self.node_arena[ptr].left()
+            } else {
+                self.node_arena[ptr].right()
+            };
+        }
+
+        if let Some(&parent_ptr) = path.last() {
+            let parent = &self.node_arena[parent_ptr];
+            let (left, right) = if *self.get_datum(new_node_ptr) <= *self.get_datum(parent_ptr) {
+                (Some(new_node_ptr), parent.right())
+            } else {
+                (parent.left(), Some(new_node_ptr))
+            };
+
+            self.node_arena[parent_ptr].modify(timestamp, left, right);
+        }
+        path.push(new_node_ptr);
+
+        let arena = RefCell::new(&mut self.node_arena);
+        let new_root = rb::insert_fixup(
+            &|node_ptr: usize| arena.borrow()[node_ptr].left(),
+            &|node_ptr| arena.borrow()[node_ptr].right(),
+            &|node_ptr| arena.borrow()[node_ptr].red,
+            &mut |node_ptr, left, right, red| {
+                let node = &mut arena.borrow_mut()[node_ptr];
+                node.modify(timestamp, left, right);
+                node.red = red;
+            },
+            &path,
+        );
+        self.modify_root(new_root, timestamp);
+
+        self.last_time += 1;
+        timestamp
+    }
+
+    fn delete(&mut self, item: &Self::Data) -> Option<Self::Timestamp> {
+        let timestamp = self.last_time;
+
+        // Path from the root down to the node to delete
+        let mut path = Vec::new();
+        let mut path_ptr = self.latest_root();
+        loop {
+            let ptr = path_ptr?;
+            path.push(ptr);
+
+            path_ptr = match self.compare(item, ptr) {
+                Ordering::Equal => break,
+                Ordering::Less => self.node_arena[ptr].left(),
+                Ordering::Greater => self.node_arena[ptr].right(),
+            };
+        }
+
+        let arena = RefCell::new(&mut self.node_arena);
+        let new_root = rb::delete(
+            &|node_ptr: usize| arena.borrow()[node_ptr].left(),
+            &|node_ptr| arena.borrow()[node_ptr].right(),
+            &|node_ptr| arena.borrow()[node_ptr].red,
+            &mut |node_ptr, left, right, red| {
+                let node = &mut arena.borrow_mut()[node_ptr];
+                node.modify(timestamp, left, right);
+                node.red = red;
+            },
+            &path,
+        );
+        self.modify_root(new_root, timestamp);
+
+        self.last_time += 1;
+        Some(timestamp)
+    }
+
+    fn contains(&self, item: &Self::Data, timestamp: Self::Timestamp) -> bool {
+        let Some(root_node) = get_time(&self.root_nodes, &timestamp) else {
+            return false;
+        };
+
+        avl::contains(
+            &|node_ptr| self.get_left(node_ptr, timestamp),
+            &|node_ptr| self.get_right(node_ptr, timestamp),
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            root_node.root,
+            item,
+        )
+    }
+
+    fn predecessor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        let root = get_time(&self.root_nodes, &timestamp)?.root;
+
+        avl::predecessor(
+            &|node_ptr| self.get_left(node_ptr, timestamp),
+            &|node_ptr| self.get_right(node_ptr, timestamp),
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            root,
+            item,
+        )
+        .map(|node_ptr| self.get_datum(node_ptr))
+    }
+
+    fn successor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        let root = get_time(&self.root_nodes, &timestamp)?.root;
+
+        avl::successor(
+            &|node_ptr| self.get_left(node_ptr, timestamp),
+            &|node_ptr| self.get_right(node_ptr, timestamp),
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            root,
+            item,
+        )
+        .map(|node_ptr| self.get_datum(node_ptr))
+    }
+}
+
+impl<Data: Ord> BatchUpdate for FatNodeRbTree<Data> {
+    fn delete_range(&mut self, range: impl RangeBounds<Self::Data>) -> Self::Timestamp {
+        let timestamp = self.last_time;
+        let root = self.latest_root();
+
+        let data = &self.data;
+        let arena = RefCell::new(&mut self.node_arena);
+        let datum = |node_ptr: usize| &data[arena.borrow()[node_ptr].datum_ptr];
+        let new_root = rb::delete_range(
+            &|node_ptr: usize| arena.borrow()[node_ptr].left(),
+            &|node_ptr| arena.borrow()[node_ptr].right(),
+            &|node_ptr| arena.borrow()[node_ptr].red,
+            &mut |node_ptr, left, right, red| {
+                let node = &mut arena.borrow_mut()[node_ptr];
+                node.modify(timestamp, left, right);
+                node.red = red;
+            },
+            root,
+            &|node_ptr| batch_update::is_before(&range, datum(node_ptr)),
+            &|node_ptr| batch_update::is_after(&range, datum(node_ptr)),
+        );
+        self.modify_root(new_root, timestamp);
+
+        self.last_time += 1;
+        timestamp
+    }
+
+    fn insert_sorted_batch(
+        &mut self,
+        iter: impl IntoIterator<Item = Self::Data>,
+    ) -> Result<Self::Timestamp, BulkLoadError> {
+        let batch = bulk_load::collect_sorted(iter)?;
+        let timestamp = self.last_time;
+
+        let first_datum_ptr = self.data.len();
+        let first_node_ptr = self.node_arena.len();
+        self.data.extend(batch);
+        for datum_ptr in first_datum_ptr..self.data.len() {
+            self.node_arena.push(RbFatNode {
+                datum_ptr,
+                red: false,
+                children: FatField::new(),
+            });
+        }
+
+        let batch_root = bulk_load::build_balanced(
+            first_datum_ptr,
+            self.data.len(),
+            &mut |datum_ptr, left, right, _| {
+                let node_ptr = first_node_ptr + (datum_ptr - first_datum_ptr);
+                self.node_arena[node_ptr].modify(timestamp, left, right);
+
+                node_ptr
+            },
+        );
+        let root = self.latest_root();
+
+        let data = &self.data;
+        let arena = RefCell::new(&mut self.node_arena);
+        let datum = |node_ptr: usize| &data[arena.borrow()[node_ptr].datum_ptr];
+        let new_root = rb::union(
+            &|node_ptr: usize| arena.borrow()[node_ptr].left(),
+            &|node_ptr| arena.borrow()[node_ptr].right(),
+            &|node_ptr| arena.borrow()[node_ptr].red,
+            &mut |node_ptr, left, right, red| {
+                let node = &mut arena.borrow_mut()[node_ptr];
+                node.modify(timestamp, left, right);
+                node.red = red;
+            },
+            root,
+            batch_root,
+            &|node_ptr, pivot| datum(node_ptr) < datum(pivot),
+        );
+        self.modify_root(new_root, timestamp);
+
+        self.last_time += 1;
+        Ok(timestamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{random_update, red_black_nodes, Rng};
+
+    /// Data of the version at `timestamp` in order
+    fn data_at(tree: &FatNodeRbTree<u64>, timestamp: u64) -> Vec<u64> {
+        fn walk(
+            tree: &FatNodeRbTree<u64>,
+            node: Option<usize>,
+            timestamp: u64,
+            data: &mut Vec<u64>,
+        ) {
+            let Some(node) = node else {
+                return;
+            };
+            walk(tree, tree.get_left(node, timestamp), timestamp, data);
+            data.push(*tree.get_datum(node));
+            walk(tree, tree.get_right(node, timestamp), timestamp, data);
+        }
+
+        let root = get_time(&tree.root_nodes, &timestamp).and_then(|root_node| root_node.root);
+        let mut data = Vec::new();
+        walk(tree, root, timestamp, &mut data);
+
+        data
+    }
+
+    #[test]
+    fn every_version_is_a_red_black_tree() {
+        let mut rng = Rng(3);
+        let mut tree = FatNodeRbTree::new();
+        let mut versions: Vec<Vec<u64>> = Vec::new();
+
+        for _ in 0..600 {
+            let mut data = versions.last().cloned().unwrap_or_default();
+            let Some(timestamp) = random_update(&mut tree, &mut data, &mut rng, 60) else {
+                continue;
+            };
+            assert_eq!(timestamp, versions.len() as u64);
+
+            // Colors are only kept for the newest version, so each version is
+            // checked while it is the newest
+            let nodes = red_black_nodes(
+                tree.latest_root(),
+                |node_ptr| tree.node_arena[node_ptr].left(),
+                |node_ptr| tree.node_arena[node_ptr].right(),
+                |node_ptr| tree.node_arena[node_ptr].red,
+            );
+            assert!(nodes
+                .into_iter()
+                .map(|node_ptr| tree.get_datum(node_ptr))
+                .eq(data.iter()));
+            versions.push(data);
+        }
+
+        for (timestamp, data) in versions.iter().enumerate() {
+            assert_eq!(&data_at(&tree, timestamp as u64), data);
+        }
+    }
+}
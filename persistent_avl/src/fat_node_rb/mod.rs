@@ -0,0 +1,2 @@
+pub mod fat_node_rb;
+mod rb_fat_node;
@@ -0,0 +1,28 @@
+use crate::fat_field::fat_field::FatField;
+use crate::fat_node_avl::fat_node::Children;
+
+pub(crate) struct RbFatNode {
+    pub(crate) datum_ptr: usize,
+    /// Color in the newest version, the only one that is ever rebalanced
+    pub(crate) red: bool,
+    pub(crate) children: FatField<Children, u64>,
+}
+
+// All modifications to a RbFatNode assume that the given
+// timestamp is >= the timestamp of latest child
+impl RbFatNode {
+    pub(crate) fn left(&self) -> Option<usize> {
+        self.children.latest().and_then(|children| children.left)
+    }
+
+    pub(crate) fn right(&self) -> Option<usize> {
+        self.children.latest().and_then(|children| children.right)
+    }
+
+    pub(crate) fn modify(&mut self, timestamp: u64, left: Option<usize>, right: Option<usize>) {
+        // Rewriting unchanged pointers would only grow the history
+        if self.left() != left || self.right() != right {
+            self.children.set(timestamp, Children { left, right });
+        }
+    }
+}
@@ -0,0 +1,34 @@
+/// A half-open interval `[start, end)`, never empty.
+///
+/// Intervals are ordered by start, then by end.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Interval<T: Ord> {
+    start: T,
+    end: T,
+}
+
+impl<T: Ord> Interval<T> {
+    /// Panics unless `start < end`
+    pub fn new(start: T, end: T) -> Self {
+        assert!(start < end, "interval must not be empty");
+
+        Interval { start, end }
+    }
+
+    pub fn start(&self) -> &T {
+        &self.start
+    }
+
+    pub fn end(&self) -> &T {
+        &self.end
+    }
+
+    pub fn contains(&self, point: &T) -> bool {
+        self.start <= *point && *point < self.end
+    }
+
+    /// Whether some point is in both
+    pub fn overlaps(&self, other: &Interval<T>) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
@@ -0,0 +1,188 @@
+use std::marker::PhantomData;
+
+use crate::augmented_avl::augmented_avl::AugmentedAvl;
+use crate::augmented_avl::monoid::Monoid;
+use crate::interval_tree::interval::Interval;
+use crate::persistent_avl_tree::PersistentAvlTree;
+
+/// Greatest end of the intervals, if any
+struct MaxEnd<T>(PhantomData<T>);
+
+impl<T: Ord + Clone> Monoid<Interval<T>> for MaxEnd<T> {
+    type Value = Option<T>;
+
+    fn identity() -> Option<T> {
+        None
+    }
+
+    fn lift(interval: &Interval<T>) -> Option<T> {
+        Some(interval.end().clone())
+    }
+
+    fn combine(left: &Option<T>, right: &Option<T>) -> Option<T> {
+        match (left, right) {
+            (Some(left), Some(right)) => Some(left.max(right).clone()),
+            _ => left.clone().or_else(|| right.clone()),
+        }
+    }
+}
+
+/// A partially persistent interval tree, an `AugmentedAvl` of intervals
+/// ordered by start whose nodes know the greatest end in their subtree.
+///
+/// A query skips every subtree ending at or before the point or range it
+/// asks about, and every node starting after it, so it reports k intervals
+/// in O(min(n, (k + 1) log n)).
+pub struct IntervalTree<T: Ord + Clone> {
+    tree: AugmentedAvl<Interval<T>, MaxEnd<T>>,
+}
+
+impl<T: Ord + Clone> Default for IntervalTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Ord + Clone> IntervalTree<T> {
+    pub fn new() -> Self {
+        IntervalTree {
+            tree: AugmentedAvl::default(),
+        }
+    }
+
+    /// Inserts `interval` into the newest version, and returns the timestamp
+    /// of the new version
+    pub fn insert_interval(&mut self, interval: Interval<T>) -> usize {
+        self.tree.insert(interval)
+    }
+
+    /// Deletes one copy of `interval` from the newest version, and returns
+    /// the timestamp of the new version, or `None` if there was none
+    pub fn delete_interval(&mut self, interval: &Interval<T>) -> Option<usize> {
+        self.tree.delete(interval)
+    }
+
+    /// Intervals containing `point` at `timestamp`, ordered by start
+    pub fn stabbing(&self, point: &T, timestamp: usize) -> Vec<&Interval<T>> {
+        let mut found = self.tree.search(
+            timestamp,
+            &|max_end| max_end.as_ref().is_none_or(|max_end| max_end <= point),
+            &|interval| interval.start() > point,
+        );
+        found.retain(|interval| interval.contains(point));
+
+        found
+    }
+
+    /// Intervals overlapping `range` at `timestamp`, ordered by start
+    pub fn overlapping(&self, range: &Interval<T>, timestamp: usize) -> Vec<&Interval<T>> {
+        let mut found = self.tree.search(
+            timestamp,
+            &|max_end| {
+                max_end
+                    .as_ref()
+                    .is_none_or(|max_end| max_end <= range.start())
+            },
+            &|interval| interval.start() >= range.end(),
+        );
+        found.retain(|interval| interval.overlaps(range));
+
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Rng;
+
+    /// Coordinates are drawn from `0..POINTS`, so intervals share endpoints
+    const POINTS: u64 = 40;
+
+    fn interval(rng: &mut Rng) -> Interval<i64> {
+        let start = rng.below(POINTS - 1);
+        let end = start + 1 + rng.below(POINTS - 1 - start);
+
+        Interval::new(start as i64, end as i64)
+    }
+
+    /// Random insertions and deletions, with repeated intervals, along with
+    /// the intervals each version holds, sorted
+    fn random_versions(seed: u64) -> (IntervalTree<i64>, Vec<Vec<Interval<i64>>>) {
+        let mut rng = Rng(seed);
+        let mut tree = IntervalTree::new();
+        let mut versions: Vec<Vec<Interval<i64>>> = Vec::new();
+
+        for _ in 0..150 {
+            let mut intervals = versions.last().cloned().unwrap_or_default();
+            let timestamp = if !intervals.is_empty() && rng.below(4) == 0 {
+                let deleted = intervals.remove(rng.below(intervals.len() as u64) as usize);
+                tree.delete_interval(&deleted).unwrap()
+            } else {
+                let inserted = if !intervals.is_empty() && rng.below(5) == 0 {
+                    intervals[rng.below(intervals.len() as u64) as usize]
+                } else {
+                    interval(&mut rng)
+                };
+                intervals.push(inserted);
+                tree.insert_interval(inserted)
+            };
+
+            intervals.sort();
+            assert_eq!(timestamp, versions.len());
+            versions.push(intervals);
+        }
+
+        (tree, versions)
+    }
+
+    #[test]
+    fn stabbing_matches_brute_force() {
+        for seed in 1..10 {
+            let (tree, versions) = random_versions(seed);
+
+            for (timestamp, intervals) in versions.iter().enumerate() {
+                // Every endpoint is stabbed, along with points outside of all
+                for point in -1..=POINTS as i64 {
+                    let expected: Vec<&Interval<i64>> = intervals
+                        .iter()
+                        .filter(|interval| interval.contains(&point))
+                        .collect();
+                    assert_eq!(tree.stabbing(&point, timestamp), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_matches_brute_force() {
+        for seed in 1..10 {
+            let (tree, versions) = random_versions(seed);
+            let mut rng = Rng(seed);
+
+            for (timestamp, intervals) in versions.iter().enumerate() {
+                for _ in 0..40 {
+                    let range = interval(&mut rng);
+                    let expected: Vec<&Interval<i64>> = intervals
+                        .iter()
+                        .filter(|interval| interval.overlaps(&range))
+                        .collect();
+                    assert_eq!(tree.overlapping(&range, timestamp), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn intervals_are_half_open() {
+        let mut tree = IntervalTree::new();
+        tree.insert_interval(Interval::new(2, 5));
+        let timestamp = tree.insert_interval(Interval::new(5, 7));
+
+        assert_eq!(tree.stabbing(&5, timestamp), [&Interval::new(5, 7)]);
+        assert!(tree.overlapping(&Interval::new(0, 2), timestamp).is_empty());
+        assert!(tree.overlapping(&Interval::new(7, 9), timestamp).is_empty());
+        assert_eq!(tree.overlapping(&Interval::new(4, 6), timestamp).len(), 2);
+        assert!(tree.stabbing(&5, 0).is_empty());
+    }
+}
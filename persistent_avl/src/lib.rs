@@ -1,9 +1,30 @@
+pub mod augmented_avl;
+pub mod batch_update;
+pub mod bulk_load;
+pub mod interval_tree;
+pub mod persistent_array;
 pub mod persistent_avl_tree;
+pub mod persistent_sequence;
+pub mod point_location;
+pub mod range_counter;
 
 mod avl;
+mod rb;
+mod wb;
 
+pub mod fat_field;
 pub mod fat_node_avl;
+pub mod fat_node_rb;
 mod timestamp;
 
+pub mod durable;
 pub mod opt_avl;
 pub mod path_copy_avl;
+pub mod path_copy_btree;
+pub mod path_copy_rb;
+pub mod path_copy_treap;
+pub mod path_copy_wb;
+mod sha256;
+pub mod snapshot;
+#[cfg(test)]
+mod test_util;
@@ -1,139 +1,159 @@
-pub(crate) struct OptAVLNode<'a, Timestamp: Ord> {
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Field {
+    Left,
+    Right,
+}
+
+/// A change to one child pointer of a node, in effect from `timestamp` on
+pub(crate) struct Modification<'a, Timestamp: Ord> {
+    pub(crate) field: Field,
+    pub(crate) value: Option<usize>,
+    pub(crate) timestamp: &'a Timestamp,
+}
+
+/// A node of a node copying tree with `P` modification slots.
+///
+/// The child pointers are versioned: `left` and `right` hold their values as
+/// of `timestamp`, when the node was created, and `mods` holds up to `P`
+/// later changes, filled in order. The height and the parent pointer are
+/// only ever needed to update the newest version, so they are overwritten
+/// in place.
+pub(crate) struct OptAVLNode<'a, Timestamp: Ord, const P: usize> {
     pub(crate) datum_ptr: usize,
     pub(crate) height: u64,
     pub(crate) timestamp: &'a Timestamp, // Remark: For data, it is reasonable for the tree to own the data. Not so much for timestamps.
-    pub(crate) l1: Option<usize>,
-    pub(crate) r1: Option<usize>,
-    pub(crate) l2: Option<usize>,
-    pub(crate) r2: Option<usize>,
+    pub(crate) left: Option<usize>,
+    pub(crate) right: Option<usize>,
+    /// Parent in the newest version, the inverse of its child pointer
+    pub(crate) parent: Option<usize>,
+    pub(crate) mods: [Option<Modification<'a, Timestamp>>; P],
 }
 
-impl<Timestamp: Ord> OptAVLNode<'_, Timestamp> {
-    pub(crate) fn get_left(
-        &self,
-        timestamp: &Timestamp,
-    ) -> Option<usize> {
-        if timestamp < self.timestamp {
-            self.l1
-        } else {
-            self.l2
+impl<'a, Timestamp: Ord, const P: usize> OptAVLNode<'a, Timestamp, P> {
+    pub(crate) fn new(
+        datum_ptr: usize,
+        height: u64,
+        timestamp: &'a Timestamp,
+        left: Option<usize>,
+        right: Option<usize>,
+        parent: Option<usize>,
+    ) -> Self {
+        OptAVLNode {
+            datum_ptr,
+            height,
+            timestamp,
+            left,
+            right,
+            parent,
+            mods: std::array::from_fn(|_| None),
         }
     }
 
-    pub(crate) fn get_right(
-        &self,
-        timestamp: &Timestamp,
-    ) -> Option<usize> {
-        if timestamp < self.timestamp {
-            self.r1
-        } else {
-            self.r2
-        }
+    /// Value of `field` at `timestamp`, which must not precede the node
+    fn get(&self, field: Field, timestamp: &Timestamp) -> Option<usize> {
+        let original = match field {
+            Field::Left => self.left,
+            Field::Right => self.right,
+        };
+
+        self.used_mods()
+            .rev()
+            .find(|modification| modification.field == field && modification.timestamp <= timestamp)
+            .map_or(original, |modification| modification.value)
     }
 
-    /// These functions are helpers for rotations.
+    /// Value of `field` in the newest version
+    fn latest(&self, field: Field) -> Option<usize> {
+        let original = match field {
+            Field::Left => self.left,
+            Field::Right => self.right,
+        };
 
-    /// Ignores the presence of any pre-existing pointers.
-    /// Sets l1 if timestamp < self.timestamp, l2 if timestamp >= self.timestamp
-    pub(crate) fn modify_left_with_replacement(
-        &mut self,
-        new_ptr: Option<usize>,
-        timestamp: &Timestamp,
-    ) {
-        if timestamp < self.timestamp {
-            self.l1 = new_ptr;
-        } else {
-            self.l2 = new_ptr;
-        }
+        self.used_mods()
+            .rev()
+            .find(|modification| modification.field == field)
+            .map_or(original, |modification| modification.value)
     }
 
-    /// Ignores the presence of any pre-existing pointers.
-    /// Sets r1 if timestamp < self.timestamp, r2 if timestamp >= self.timestamp
-    pub(crate) fn modify_right_with_replacement(
-        &mut self,
-        new_ptr: Option<usize>,
-        timestamp: &Timestamp,
-    ) {
-        if timestamp < self.timestamp {
-            self.r1 = new_ptr;
-        } else {
-            self.r2 = new_ptr;
-        }
+    pub(crate) fn get_left(&self, timestamp: &Timestamp) -> Option<usize> {
+        self.get(Field::Left, timestamp)
     }
 
-    fn duplicate_with_new_ptrs<'a>(
-        &self,
-        timestamp: &'a Timestamp,
-        l2: Option<usize>,
-        r2: Option<usize>,
-    ) -> OptAVLNode<'a, Timestamp> {
-        // Note that the lifetime of self need not match
-        // the lifetime of the duplicated node.
-        // Of course, this eventually ends up not mattering
-        // because all nodes will have the same lifetime as the
-        // AVL tree/arena itself.
-        OptAVLNode {
-            datum_ptr: self.datum_ptr,
-            height: self.height,
-            timestamp,
-            l1: self.l2,
-            r1: self.r2,
-            l2,
-            r2,
-        }
+    pub(crate) fn get_right(&self, timestamp: &Timestamp) -> Option<usize> {
+        self.get(Field::Right, timestamp)
+    }
+
+    pub(crate) fn latest_left(&self) -> Option<usize> {
+        self.latest(Field::Left)
+    }
+
+    pub(crate) fn latest_right(&self) -> Option<usize> {
+        self.latest(Field::Right)
+    }
+
+    fn used_mods(&self) -> impl DoubleEndedIterator<Item = &Modification<'a, Timestamp>> {
+        self.mods.iter().flatten()
+    }
+
+    /// Index of the modification of `field` made at exactly `timestamp`
+    fn find_mod(&self, field: Field, timestamp: &Timestamp) -> Option<usize> {
+        self.used_mods().position(|modification| {
+            modification.field == field && modification.timestamp == timestamp
+        })
     }
 
-    /// Remark: if you ever want to update both pointers
-    /// at once, you need to add a third helper.
+    /// Sets the child pointers from `timestamp` on, which must not precede any
+    /// earlier modification.
     ///
-    /// If you need to modify both the left and right pointer,
-    /// you don't want to handle generating two duplicates,
-    /// so you need one that simultaneously updates both.
-    /// If you only want to modify one of them, you don't want
-    /// to incorrectly duplicate when you don't need to.
-
-    /// These functions should only be used to perform the actual
-    /// insertion; helpers for rotation appear above.
-
-    /// Sets l2 if timestamp >= self.timestamp and l2 == None.
-    /// Panics if timestamp < self.timestamp; this is undefined
-    /// behavior.
-    /// Otherwise, returns a new node with l1 := l2, r1 := r2, l2 := new_ptr.
-    pub(crate) fn modify_left_or_duplicate<'a>(
+    /// Returns false, leaving the node untouched, if that takes more free
+    /// slots than the node has left, in which case the node has to be copied.
+    pub(crate) fn modify(
         &mut self,
-        new_ptr: Option<usize>,
+        left: Option<usize>,
+        right: Option<usize>,
         timestamp: &'a Timestamp,
-    ) -> Option<OptAVLNode<'a, Timestamp>> {
-        debug_assert!(
-            timestamp >= self.timestamp,
-            "Attempted to modify l1 in previous time on insertion/deletion"
-        );
-        if let None = self.l2 {
-            self.l2 = new_ptr;
-            None
-        } else {
-            Some(self.duplicate_with_new_ptrs(timestamp, new_ptr, None))
+    ) -> bool {
+        // A node created by this update has no older version to preserve
+        if self.timestamp == timestamp {
+            self.left = left;
+            self.right = right;
+            return true;
         }
-    }
 
-    /// Sets r2 if timestamp >= self.timestamp and r2 == None.
-    /// Panics if timestamp < self.timestamp; this is undefined
-    /// behavior.
-    /// Otherwise, returns a new node with l1 := l2, r1 := r2, r2 := new_ptr.
-    pub(crate) fn modify_right_or_duplicate<'a>(
-        &mut self,
-        new_ptr: Option<usize>,
-        timestamp: &'a Timestamp,
-    ) -> Option<OptAVLNode<'a, Timestamp>> {
-        debug_assert!(
-            timestamp >= self.timestamp,
-            "Attempted to modify r1 in previous time on insertion/deletion"
-        );
-        if let None = self.r2 {
-            self.r2 = new_ptr;
-            None
-        } else {
-            Some(self.duplicate_with_new_ptrs(timestamp, None, new_ptr))
+        let changes: Vec<(Field, Option<usize>)> = [(Field::Left, left), (Field::Right, right)]
+            .into_iter()
+            .filter(|&(field, value)| self.latest(field) != value)
+            .collect();
+
+        // Changes repeated within one update overwrite the modification they
+        // made before, without taking another slot
+        let new_mods = changes
+            .iter()
+            .filter(|&&(field, _)| self.find_mod(field, timestamp).is_none())
+            .count();
+        let mut used = self.used_mods().count();
+        if used + new_mods > P {
+            return false;
+        }
+
+        for (field, value) in changes {
+            match self.find_mod(field, timestamp) {
+                Some(index) => {
+                    if let Some(modification) = &mut self.mods[index] {
+                        modification.value = value;
+                    }
+                }
+                None => {
+                    self.mods[used] = Some(Modification {
+                        field,
+                        value,
+                        timestamp,
+                    });
+                    used += 1;
+                }
+            }
         }
+
+        true
     }
 }
@@ -1,143 +1,459 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeBounds;
+
+use crate::avl::engine;
+use crate::avl::node_store::{NodeLinks, NodeStore};
+use crate::bulk_load::bulk_load;
+use crate::bulk_load::error::BulkLoadError;
 
 use super::opt::OptAVLNode;
 
-pub struct OptAVL<'a, Data: Ord, Timestamp: Ord> {
-    node_arena: Vec<OptAVLNode<'a, Timestamp>>,
+/// Number of modifications a node holds before it is copied, when `P` is
+/// not given
+pub const DEFAULT_MODIFICATION_SLOTS: usize = 2;
+
+/// A partially persistent AVL tree using Driscoll et al.'s node copying.
+///
+/// A change to a child pointer is recorded as a timestamped modification in
+/// the node. Once a node has no free modification slot left, it is copied
+/// with its newest pointers instead, and its parent, found through the
+/// node's back pointer, is changed to point at the copy, which may in turn
+/// copy the parent. Space is O(1) amortized per update.
+///
+/// Every node has `P` modification slots. More slots mean fewer copies but
+/// larger nodes and longer scans per query step.
+///
+/// Timestamps are supplied by the caller and must not decrease from one
+/// update to the next.
+pub struct OptAVL<'a, Data: Ord, Timestamp: Ord, const P: usize = DEFAULT_MODIFICATION_SLOTS> {
+    node_arena: Vec<OptAVLNode<'a, Timestamp, P>>,
     data_arena: Vec<Data>,
-    roots: BTreeMap<&'a Timestamp, Option<usize>>
+    roots: BTreeMap<&'a Timestamp, Option<usize>>,
+    /// Changes made by the update in progress, empty between updates
+    update_cache: HashMap<usize, Staged>,
 }
 
-enum ParentToChildDirection {
-    Left,
-    Right,
-    RootToChild,
+/// Newest child pointers and height of a node, staged during an update
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Staged {
+    left: Option<usize>,
+    right: Option<usize>,
+    height: u64,
 }
 
-impl<'a, Data: Ord, Timestamp: Ord> OptAVL<'a, Data, Timestamp> {
-    pub(crate) fn get_left(&self, node_ptr: Option<usize>, timestamp: &Timestamp) -> Option<usize> {
-        node_ptr.and_then(|node_ptr| self.node_arena[node_ptr].get_left(timestamp))
+impl<'a, Data: Ord, Timestamp: Ord, const P: usize> Default for OptAVL<'a, Data, Timestamp, P> {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    pub(crate) fn get_right(&self, node_ptr: Option<usize>, timestamp: &Timestamp) -> Option<usize> {
-        node_ptr.and_then(|node_ptr| self.node_arena[node_ptr].get_right(timestamp))
+impl<'a, Data: Ord, Timestamp: Ord, const P: usize> OptAVL<'a, Data, Timestamp, P> {
+    pub fn new() -> Self {
+        OptAVL {
+            node_arena: Vec::new(),
+            data_arena: Vec::new(),
+            roots: BTreeMap::new(),
+            update_cache: HashMap::new(),
+        }
     }
 
-    /// Precondition: the given node is a L2 or R2 child of the last ancestor, or is the root.
-    fn get_parent_direction(&self, ancestors: &[usize], node: usize, timestamp: &Timestamp) -> ParentToChildDirection {
-        if ancestors.is_empty() {
-            return ParentToChildDirection::RootToChild;
-        }
-        let parent_ptr = ancestors.last().unwrap();
-        if self.get_left(Some(*parent_ptr), timestamp) == Some(node) {
-            ParentToChildDirection::Left
-        } else {
-            ParentToChildDirection::Right
+    fn get_root(&self, timestamp: &Timestamp) -> Option<usize> {
+        self.roots
+            .range::<Timestamp, _>(..=timestamp)
+            .next_back()
+            .and_then(|(_, root)| *root)
+    }
+
+    fn get_node(&self, node_ptr: usize) -> Staged {
+        match self.update_cache.get(&node_ptr) {
+            Some(staged) => *staged,
+            None => {
+                let node = &self.node_arena[node_ptr];
+
+                Staged {
+                    left: node.latest_left(),
+                    right: node.latest_right(),
+                    height: node.height,
+                }
+            }
         }
     }
 
-    /// Updates the last ancestor's l2 := node_ptr.
-    /// If this operation causes the parent to duplicate, also
-    /// calls update_xx_pointer(ancestors[..|n - 1|], parent_duplicate)
-    /// 
-    /// Precondition: timestamp is newest
-    /// Precondition: all ancestors are L2/R2 children
-    pub(crate) fn update_left_pointer(&mut self, ancestors: &[usize], node_ptr: Option<usize>, timestamp: &'a Timestamp) {
-        if ancestors.is_empty() {
-            self.roots.insert(timestamp, node_ptr);
-            return;
+    /// Applies the changes staged in `update_cache` as the version at
+    /// `timestamp`, with `new_root` at its root
+    fn commit(&mut self, new_root: Option<usize>, timestamp: &'a Timestamp) {
+        let update_cache = std::mem::take(&mut self.update_cache);
+
+        // Heights and back pointers only describe the newest version, so
+        // they are simply overwritten
+        for (&node_ptr, staged) in update_cache.iter() {
+            self.node_arena[node_ptr].height = staged.height;
+
+            for child_ptr in [staged.left, staged.right].into_iter().flatten() {
+                self.node_arena[child_ptr].parent = Some(node_ptr);
+            }
+        }
+        if let Some(root) = new_root {
+            self.node_arena[root].parent = None;
+        }
+
+        if self.latest_root() != new_root {
+            self.roots.insert(timestamp, new_root);
         }
 
-        let parent = ancestors.last().unwrap();
-        let result = self.node_arena[*parent].modify_left_or_duplicate(node_ptr, timestamp);
-        
-        if let Some(duplicate) = result {
-            // Allocate
-            self.node_arena.push(duplicate);
-            let duplicate_ptr = self.node_arena.len() - 1;
-
-            // Check the direction of the grandparent to the parent
-            let gpp_dir = self.get_parent_direction(ancestors, *parent, timestamp);
-            match gpp_dir {
-                ParentToChildDirection::Left => self.update_left_pointer(&ancestors[0..ancestors.len() - 1], Some(duplicate_ptr), timestamp),
-                ParentToChildDirection::Right => self.update_right_pointer(&ancestors[0..ancestors.len() - 1], Some(duplicate_ptr), timestamp),
-                ParentToChildDirection::RootToChild => { self.roots.insert(timestamp, node_ptr); },
+        // Nodes cut out of the tree, as by a range deletion, may have been
+        // staged on the way; copying them would rewire their stale parents
+        let mut pending: HashMap<usize, (Option<usize>, Option<usize>)> = update_cache
+            .iter()
+            .filter(|&(&node_ptr, _)| self.is_in_tree(node_ptr, new_root, &update_cache))
+            .map(|(&node_ptr, staged)| (node_ptr, (staged.left, staged.right)))
+            .collect();
+
+        while let Some(&node_ptr) = pending.keys().next() {
+            let (left, right) = pending.remove(&node_ptr).unwrap();
+            self.set_children(&mut pending, node_ptr, left, right, timestamp);
+        }
+    }
+
+    /// Whether `node_ptr` is in the tree at `new_root` once `update_cache` is
+    /// applied, found by following back pointers up to the root as long as
+    /// each parent still has the node as a child
+    fn is_in_tree(
+        &self,
+        mut node_ptr: usize,
+        new_root: Option<usize>,
+        update_cache: &HashMap<usize, Staged>,
+    ) -> bool {
+        while let Some(parent_ptr) = self.node_arena[node_ptr].parent {
+            let (left, right) = match update_cache.get(&parent_ptr) {
+                Some(staged) => (staged.left, staged.right),
+                None => {
+                    let parent = &self.node_arena[parent_ptr];
+                    (parent.latest_left(), parent.latest_right())
+                }
+            };
+            if left != Some(node_ptr) && right != Some(node_ptr) {
+                return false;
             }
+
+            node_ptr = parent_ptr;
         }
+
+        new_root == Some(node_ptr)
     }
 
-    /// Updates the last ancestor's r2 := node_ptr.
-    /// If this operation causes the parent to duplicate, also
-    /// calls update_xx_pointer(ancestors[..|n - 1|], parent_duplicate)
-    /// 
-    /// Precondition: timestamp is newest
-    /// Precondition: all ancestors are L2/R2 children
-    pub(crate) fn update_right_pointer(&mut self, ancestors: &[usize], node_ptr: Option<usize>, timestamp: &'a Timestamp) {
-        if ancestors.is_empty() {
-            self.roots.insert(timestamp, node_ptr);
+    /// Sets the newest child pointers of `node_ptr`, copying it if it has no
+    /// room for the modifications
+    ///
+    /// A copy replaces the node in its parent, either by rewriting the
+    /// parent's entry in `pending` or, if the parent has none, by setting the
+    /// parent's children in turn.
+    fn set_children(
+        &mut self,
+        pending: &mut HashMap<usize, (Option<usize>, Option<usize>)>,
+        node_ptr: usize,
+        left: Option<usize>,
+        right: Option<usize>,
+        timestamp: &'a Timestamp,
+    ) {
+        if self.node_arena[node_ptr].modify(left, right, timestamp) {
             return;
         }
 
-        let parent = ancestors.last().unwrap();
-        let result = self.node_arena[*parent].modify_right_or_duplicate(node_ptr, timestamp);
-        
-        if let Some(duplicate) = result {
-            // Allocate
-            self.node_arena.push(duplicate);
-            let duplicate_ptr = self.node_arena.len() - 1;
-
-            // Check the direction of the grandparent to the parent
-            let gpp_dir = self.get_parent_direction(ancestors, *parent, timestamp);
-            match gpp_dir {
-                ParentToChildDirection::Left => self.update_left_pointer(&ancestors[0..ancestors.len() - 1], Some(duplicate_ptr), timestamp),
-                ParentToChildDirection::Right => self.update_right_pointer(&ancestors[0..ancestors.len() - 1], Some(duplicate_ptr), timestamp),
-                ParentToChildDirection::RootToChild => { self.roots.insert(timestamp, node_ptr); },
+        let node = &self.node_arena[node_ptr];
+        let parent_ptr = node.parent;
+        let copy = OptAVLNode::new(
+            node.datum_ptr,
+            node.height,
+            timestamp,
+            left,
+            right,
+            parent_ptr,
+        );
+
+        self.node_arena.push(copy);
+        let copy_ptr = self.node_arena.len() - 1;
+
+        for child_ptr in [left, right].into_iter().flatten() {
+            self.node_arena[child_ptr].parent = Some(copy_ptr);
+        }
+
+        let Some(parent_ptr) = parent_ptr else {
+            self.roots.insert(timestamp, Some(copy_ptr));
+            return;
+        };
+
+        let replace = |ptr: Option<usize>| {
+            if ptr == Some(node_ptr) {
+                Some(copy_ptr)
+            } else {
+                ptr
+            }
+        };
+
+        match pending.get_mut(&parent_ptr) {
+            Some((parent_left, parent_right)) => {
+                *parent_left = replace(*parent_left);
+                *parent_right = replace(*parent_right);
+            }
+            None => {
+                let parent = &self.node_arena[parent_ptr];
+                let parent_left = replace(parent.latest_left());
+                let parent_right = replace(parent.latest_right());
+
+                self.set_children(pending, parent_ptr, parent_left, parent_right, timestamp);
             }
         }
     }
 
+    /// Inserts `datum` as of `timestamp`
+    ///
     /// Precondition: timestamp is newest
     pub fn insert(&mut self, datum: Data, timestamp: &'a Timestamp) {
-        // Allocate datum
         self.data_arena.push(datum);
         let datum_ptr = self.data_arena.len() - 1;
-        let datum = &self.data_arena[datum_ptr];
 
-        // Allocate node
-        self.node_arena.push(OptAVLNode {
-            datum_ptr,
-            height: 0,
-            timestamp,
-            l1: None,
-            r1: None,
-            l2: None,
-            r2: None,
-        });
+        self.node_arena
+            .push(OptAVLNode::new(datum_ptr, 1, timestamp, None, None, None));
         let node_ptr = self.node_arena.len() - 1;
 
-        // Traverse
-        let mut path_ptr = self.roots.last_key_value().and_then(|(_, ptr)| *ptr);
+        let update = engine::insert(self, node_ptr);
+        self.commit(update.root, timestamp);
+    }
 
-        let mut path = Vec::new();
-        let mut last_dir = ParentToChildDirection::RootToChild;
-        while let Some(ptr) = path_ptr {
-            path.push(ptr);
+    /// Deletes one occurrence of `datum` as of `timestamp`, returning whether
+    /// there was one
+    ///
+    /// Precondition: timestamp is newest
+    pub fn delete(&mut self, datum: &Data, timestamp: &'a Timestamp) -> bool {
+        match engine::delete(self, datum) {
+            Some(update) => {
+                self.commit(update.root, timestamp);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Deletes every datum in `range` as of `timestamp`
+    ///
+    /// Precondition: timestamp is newest
+    pub fn delete_range(&mut self, range: impl RangeBounds<Data>, timestamp: &'a Timestamp) {
+        let update = engine::delete_range(self, &range);
+        self.commit(update.root, timestamp);
+    }
 
-            let node_datum = &self.data_arena[self.node_arena[ptr].datum_ptr];
+    /// Inserts the data of `iter`, which must be sorted, as of `timestamp`
+    ///
+    /// Nothing is inserted if `iter` is out of order.
+    ///
+    /// Precondition: timestamp is newest
+    pub fn insert_sorted_batch(
+        &mut self,
+        iter: impl IntoIterator<Item = Data>,
+        timestamp: &'a Timestamp,
+    ) -> Result<(), BulkLoadError> {
+        let data = bulk_load::collect_sorted(iter)?;
+
+        let first_datum_ptr = self.data_arena.len();
+        self.data_arena.extend(data);
+
+        // The new nodes are created at `timestamp`, so they take their
+        // children as original pointers, like a single inserted node
+        let batch = bulk_load::build_balanced(
+            first_datum_ptr,
+            self.data_arena.len(),
+            &mut |datum_ptr, left, right, height| {
+                self.node_arena.push(OptAVLNode::new(
+                    datum_ptr, height, timestamp, left, right, None,
+                ));
+                let node_ptr = self.node_arena.len() - 1;
 
-            if *datum <= *node_datum {
-                path_ptr = self.get_left(Some(ptr), timestamp);
-                last_dir = ParentToChildDirection::Left;
+                for child_ptr in [left, right].into_iter().flatten() {
+                    self.node_arena[child_ptr].parent = Some(node_ptr);
+                }
+
+                node_ptr
+            },
+        );
+
+        let update = engine::insert_batch(self, batch);
+        self.commit(update.root, timestamp);
+        Ok(())
+    }
+
+    pub fn contains(&self, datum: &Data, timestamp: &Timestamp) -> bool {
+        engine::contains(self, datum, timestamp)
+    }
+
+    pub fn predecessor(&self, datum: &Data, timestamp: &Timestamp) -> Option<&Data> {
+        engine::predecessor(self, datum, timestamp)
+    }
+
+    pub fn successor(&self, datum: &Data, timestamp: &Timestamp) -> Option<&Data> {
+        engine::successor(self, datum, timestamp)
+    }
+}
+
+impl<'a, Data: Ord, Timestamp: Ord, const P: usize> NodeLinks for OptAVL<'a, Data, Timestamp, P> {
+    fn latest_root(&self) -> Option<usize> {
+        self.roots.last_key_value().and_then(|(_, root)| *root)
+    }
+
+    fn left(&self, node: usize) -> Option<usize> {
+        self.get_node(node).left
+    }
+
+    fn right(&self, node: usize) -> Option<usize> {
+        self.get_node(node).right
+    }
+
+    fn height(&self, node: usize) -> u64 {
+        self.get_node(node).height
+    }
+
+    fn modify(&mut self, node: usize, left: Option<usize>, right: Option<usize>, height: u64) {
+        self.update_cache.insert(
+            node,
+            Staged {
+                left,
+                right,
+                height,
+            },
+        );
+    }
+}
+
+impl<'a, Data: Ord, Timestamp: Ord, const P: usize> NodeStore for OptAVL<'a, Data, Timestamp, P> {
+    type Data = Data;
+    type Version = Timestamp;
+
+    fn datum(&self, node: usize) -> &Data {
+        &self.data_arena[self.node_arena[node].datum_ptr]
+    }
+
+    fn root_at(&self, version: &Timestamp) -> Option<usize> {
+        self.get_root(version)
+    }
+
+    fn left_at(&self, node: usize, version: &Timestamp) -> Option<usize> {
+        self.node_arena[node].get_left(version)
+    }
+
+    fn right_at(&self, node: usize, version: &Timestamp) -> Option<usize> {
+        self.node_arena[node].get_right(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{newest_avl_data, Rng};
+
+    /// Applies random insertions and deletions with duplicates at every other
+    /// timestamp, and compares every version, and the timestamps between
+    /// them, with a sorted Vec of the data it should hold
+    fn matches_a_sorted_vec<const P: usize>() {
+        for seed in 1..4 {
+            let mut rng = Rng(seed);
+            let timestamps: Vec<u64> = (1..=500).map(|step| 2 * step).collect();
+            let mut tree = OptAVL::<u64, u64, P>::new();
+            let mut versions: Vec<Vec<u64>> = Vec::new();
+
+            for timestamp in &timestamps {
+                let mut data = versions.last().cloned().unwrap_or_default();
+                let item = rng.below(40);
+                if rng.below(3) == 0 {
+                    let position = data.iter().position(|&datum| datum == item);
+                    assert_eq!(tree.delete(&item, timestamp), position.is_some());
+                    if let Some(position) = position {
+                        data.remove(position);
+                    }
+                } else {
+                    data.insert(data.partition_point(|&datum| datum <= item), item);
+                    tree.insert(item, timestamp);
+                }
+
+                assert!(newest_avl_data(&tree).into_iter().eq(data.iter()));
+                versions.push(data);
+            }
+
+            for item in 0..41 {
+                assert!(!tree.contains(&item, &0));
+            }
+            for (timestamp, data) in timestamps.iter().zip(&versions) {
+                for timestamp in [*timestamp, timestamp + 1] {
+                    for item in 0..41 {
+                        assert_eq!(tree.contains(&item, &timestamp), data.contains(&item));
+                        assert_eq!(
+                            tree.predecessor(&item, &timestamp),
+                            data.iter().rev().find(|&&datum| datum <= item)
+                        );
+                        assert_eq!(
+                            tree.successor(&item, &timestamp),
+                            data.iter().find(|&&datum| datum >= item)
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_version_matches_a_sorted_vec() {
+        matches_a_sorted_vec::<DEFAULT_MODIFICATION_SLOTS>();
+    }
+
+    #[test]
+    fn every_version_matches_a_sorted_vec_with_any_slot_count() {
+        // Without slots every change copies the node, like path copying
+        matches_a_sorted_vec::<0>();
+        matches_a_sorted_vec::<1>();
+        matches_a_sorted_vec::<8>();
+    }
+
+    /// Number of nodes copied per update, over a run of random updates and
+    /// one of ascending insertions followed by deletions
+    fn copies_per_update<const P: usize>(updates: u64) -> f64 {
+        let timestamps: Vec<u64> = (0..2 * updates).collect();
+        let (random, sorted) = timestamps.split_at(updates as usize);
+        let mut rng = Rng(5);
+        let mut tree = OptAVL::<u64, u64, P>::new();
+
+        let mut inserted = 0;
+        for timestamp in random {
+            if rng.below(3) == 0 {
+                tree.delete(&rng.below(updates), timestamp);
             } else {
-                path_ptr = self.get_right(Some(ptr), timestamp);
-                last_dir = ParentToChildDirection::Right;
+                tree.insert(rng.below(updates), timestamp);
+                inserted += 1;
+            }
+        }
+        for timestamp in sorted {
+            let step = timestamp - updates;
+            if step < updates / 2 {
+                tree.insert(updates + step, timestamp);
+                inserted += 1;
+            } else {
+                tree.delete(&(2 * updates - step), timestamp);
             }
         }
 
-        match last_dir {
-            ParentToChildDirection::Left => self.update_left_pointer(&path, Some(node_ptr), timestamp),
-            ParentToChildDirection::Right => self.update_right_pointer(&path, Some(node_ptr), timestamp),
-            ParentToChildDirection::RootToChild => { self.roots.insert(timestamp, Some(node_ptr)); },
+        (tree.node_arena.len() - inserted) as f64 / (2 * updates) as f64
+    }
+
+    #[test]
+    fn copies_a_constant_number_of_nodes_per_update() {
+        // With a free slot per node, every copy is paid for by the updates
+        // that filled the node, however large the tree grows
+        for updates in [500, 4000] {
+            assert!(copies_per_update::<DEFAULT_MODIFICATION_SLOTS>(updates) < 2.0);
+            assert!(copies_per_update::<1>(updates) < 2.0);
+            assert!(copies_per_update::<8>(updates) < 0.5);
         }
+
+        // Without slots every update copies its whole path, which grows
+        // with the tree
+        assert!(copies_per_update::<0>(4000) > copies_per_update::<0>(500) + 1.0);
     }
 }
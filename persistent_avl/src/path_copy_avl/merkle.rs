@@ -0,0 +1,307 @@
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+
+use crate::augmented_avl::monoid::Monoid;
+use crate::path_copy_avl::path_copy::CopyNode;
+use crate::path_copy_avl::path_copy_avl::PathCopyAvl;
+use crate::sha256::Sha256;
+use crate::snapshot::record::Record;
+
+// Every node of a hashed PathCopyAvl carries the SHA-256 of its subtree:
+//
+//   empty subtree  H(0x00)
+//   datum          H(0x01 | encoded datum)
+//   node           H(0x02 | left hash | datum digest | right hash)
+//
+// Only nodes copied by an update get new hashes, and copies are never
+// modified afterwards, so the root hash of every version stays fixed once
+// that version is committed.
+
+pub type Digest = [u8; 32];
+
+/// Hash of an empty subtree
+pub fn empty_hash() -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update(&[0x00]);
+    hasher.finish()
+}
+
+/// Digest of a single datum, from its `Record` encoding
+pub fn datum_digest<Data: Record>(datum: &Data) -> Digest {
+    let mut buf = vec![0u8; Data::WIDTH];
+    datum.encode(&mut buf);
+
+    let mut hasher = Sha256::new();
+    hasher.update(&[0x01]);
+    hasher.update(&buf);
+    hasher.finish()
+}
+
+/// Hash of a node from the hashes of its subtrees and the digest of its datum
+pub fn node_hash(left: &Digest, datum: &Digest, right: &Digest) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update(&[0x02]);
+    hasher.update(left);
+    hasher.update(datum);
+    hasher.update(right);
+    hasher.finish()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// One ancestor on the way from a proven node up to the root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MembershipStep {
+    /// Which child of this ancestor the path came up from
+    pub side: Side,
+    pub datum: Digest,
+    /// Hash of the other child
+    pub sibling: Digest,
+}
+
+/// Proof that a version's tree holds a node with a given datum.
+///
+/// Checking it takes only the datum and the root hash of the version. The
+/// proof is typed by the data of the tree, so that it is checked against the
+/// same encoding the tree hashed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MembershipProof<Data> {
+    /// Hashes of the subtrees of the node holding the datum
+    pub left: Digest,
+    pub right: Digest,
+    /// Ancestors of that node, from its parent up to the root
+    pub path: Vec<MembershipStep>,
+    pub(crate) data: PhantomData<fn(&Data)>,
+}
+
+impl<Data: Record> MembershipProof<Data> {
+    /// Whether this proves that the tree with root hash `root` holds `item`
+    pub fn verify(&self, item: &Data, root: &Digest) -> bool {
+        let hash = self.path.iter().fold(
+            node_hash(&self.left, &datum_digest(item), &self.right),
+            |hash, step| match step.side {
+                Side::Left => node_hash(&hash, &step.datum, &step.sibling),
+                Side::Right => node_hash(&step.sibling, &step.datum, &hash),
+            },
+        );
+
+        hash == *root
+    }
+}
+
+/// One node on the search path of an absent datum
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonMembershipStep<Data> {
+    pub datum: Data,
+    /// Hash of the child the search did not descend into
+    pub sibling: Digest,
+}
+
+/// Proof that a version's tree does not hold a given datum.
+///
+/// It holds the full search path for the datum, from the root down to the
+/// empty subtree where the datum would have to be. The data along the path
+/// are revealed so that the verifier can check each turn the search took.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonMembershipProof<Data> {
+    pub path: Vec<NonMembershipStep<Data>>,
+}
+
+impl<Data: Ord + Record> NonMembershipProof<Data> {
+    /// Whether this proves that the tree with root hash `root` does not hold
+    /// `item`, given that the tree is ordered
+    pub fn verify(&self, item: &Data, root: &Digest) -> bool {
+        let mut hash = empty_hash();
+
+        for step in self.path.iter().rev() {
+            hash = match item.cmp(&step.datum) {
+                Ordering::Less => node_hash(&hash, &datum_digest(&step.datum), &step.sibling),
+                Ordering::Greater => node_hash(&step.sibling, &datum_digest(&step.datum), &hash),
+                Ordering::Equal => return false,
+            };
+        }
+
+        hash == *root
+    }
+}
+
+impl<Data: Ord, M: Monoid<Data>> PathCopyAvl<Data, M> {
+    /// Hash of `node` from the hashes of its children, if hashing is enabled
+    pub(crate) fn hash_node(&self, node: &CopyNode<M::Value>) -> Option<Digest> {
+        let datum_digest = self.datum_digest?;
+
+        Some(node_hash(
+            &self.subtree_hash(node.left),
+            &datum_digest(&self.data[node.datum_ptr]),
+            &self.subtree_hash(node.right),
+        ))
+    }
+
+    fn subtree_hash(&self, node_ptr: Option<usize>) -> Digest {
+        match node_ptr {
+            Some(ptr) => self.hashes[ptr],
+            None => empty_hash(),
+        }
+    }
+
+    /// Whether nodes carry Merkle hashes
+    pub fn is_hashed(&self) -> bool {
+        self.datum_digest.is_some()
+    }
+
+    /// Root hash of the version at `timestamp`, if hashing is enabled
+    pub fn root_hash(&self, timestamp: usize) -> Option<Digest> {
+        self.datum_digest?;
+
+        let root = *self.root_nodes.get(timestamp)?;
+        Some(self.subtree_hash(root))
+    }
+
+    /// Proof that `item` is in the version at `timestamp`, or `None` if it
+    /// is not or hashing is disabled
+    pub fn prove_membership(&self, item: &Data, timestamp: usize) -> Option<MembershipProof<Data>> {
+        let datum_digest = self.datum_digest?;
+        let mut node_ptr = *self.root_nodes.get(timestamp)?;
+
+        let mut path = Vec::new();
+        while let Some(ptr) = node_ptr {
+            let node = &self.node_arena[ptr];
+            let datum = &self.data[node.datum_ptr];
+
+            let (side, next, sibling) = match item.cmp(datum) {
+                Ordering::Less => (Side::Left, node.left, node.right),
+                Ordering::Greater => (Side::Right, node.right, node.left),
+                Ordering::Equal => {
+                    // The path was recorded root first
+                    path.reverse();
+
+                    return Some(MembershipProof {
+                        left: self.subtree_hash(node.left),
+                        right: self.subtree_hash(node.right),
+                        path,
+                        data: PhantomData,
+                    });
+                }
+            };
+
+            path.push(MembershipStep {
+                side,
+                datum: datum_digest(datum),
+                sibling: self.subtree_hash(sibling),
+            });
+            node_ptr = next;
+        }
+
+        None
+    }
+}
+
+impl<Data: Ord + Record> PathCopyAvl<Data> {
+    /// Creates an empty tree that keeps a Merkle hash in every node
+    pub fn with_merkle_hashing() -> Self {
+        PathCopyAvl {
+            datum_digest: Some(datum_digest::<Data>),
+            ..PathCopyAvl::new()
+        }
+    }
+}
+
+impl<Data: Ord + Clone, M: Monoid<Data>> PathCopyAvl<Data, M> {
+    /// Proof that `item` is not in the version at `timestamp`, or `None` if
+    /// it is or hashing is disabled
+    pub fn prove_non_membership(
+        &self,
+        item: &Data,
+        timestamp: usize,
+    ) -> Option<NonMembershipProof<Data>> {
+        self.datum_digest?;
+        let mut node_ptr = *self.root_nodes.get(timestamp)?;
+
+        let mut path = Vec::new();
+        while let Some(ptr) = node_ptr {
+            let node = &self.node_arena[ptr];
+            let datum = &self.data[node.datum_ptr];
+
+            let (next, sibling) = match item.cmp(datum) {
+                Ordering::Less => (node.left, node.right),
+                Ordering::Greater => (node.right, node.left),
+                Ordering::Equal => return None,
+            };
+
+            path.push(NonMembershipStep {
+                datum: datum.clone(),
+                sibling: self.subtree_hash(sibling),
+            });
+            node_ptr = next;
+        }
+
+        Some(NonMembershipProof { path })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistent_avl_tree::PersistentAvlTree;
+
+    /// A hashed tree with a history of mixed updates, and its last timestamp
+    fn tree() -> (PathCopyAvl<i64>, usize) {
+        let mut tree = PathCopyAvl::with_merkle_hashing();
+        let mut last_timestamp = 0;
+        for step in 0..120i64 {
+            let item = step * 13 % 41;
+            last_timestamp = match step % 3 {
+                2 => tree.delete(&item).unwrap_or(last_timestamp),
+                _ => tree.insert(item),
+            };
+        }
+
+        (tree, last_timestamp)
+    }
+
+    #[test]
+    fn proofs_verify_against_their_version() {
+        let (tree, last_timestamp) = tree();
+        assert_eq!(tree.hashes.len(), tree.node_arena.len());
+
+        for timestamp in 0..=last_timestamp {
+            let root = tree.root_hash(timestamp).unwrap();
+            let other_root = tree
+                .root_hash((timestamp + 1) % (last_timestamp + 1))
+                .unwrap();
+
+            for item in -1..42 {
+                match tree.prove_membership(&item, timestamp) {
+                    Some(proof) => {
+                        assert!(tree.contains(&item, timestamp));
+                        assert!(proof.verify(&item, &root));
+                        assert!(!proof.verify(&(item + 1), &root));
+                        assert!(other_root == root || !proof.verify(&item, &other_root));
+                        assert!(tree.prove_non_membership(&item, timestamp).is_none());
+                    }
+                    None => {
+                        assert!(!tree.contains(&item, timestamp));
+                        let proof = tree.prove_non_membership(&item, timestamp).unwrap();
+                        assert!(proof.verify(&item, &root));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unhashed_trees_give_no_proofs() {
+        let mut tree = PathCopyAvl::new();
+        tree.insert(1i64);
+
+        assert!(!tree.is_hashed());
+        assert!(tree.hashes.is_empty());
+        assert_eq!(tree.root_hash(0), None);
+        assert!(tree.prove_membership(&1, 0).is_none());
+        assert!(tree.prove_non_membership(&2, 0).is_none());
+    }
+}
@@ -1,2 +1,4 @@
+pub mod merkle;
 mod path_copy;
 pub mod path_copy_avl;
+mod snapshot;
@@ -1,208 +1,500 @@
 use std::collections::HashMap;
-use std::usize;
-
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+
+use crate::augmented_avl::monoid::Monoid;
+use crate::avl::engine;
+use crate::avl::node_store::{NodeLinks, NodeStore};
+use crate::batch_update::BatchUpdate;
+use crate::bulk_load::bulk_load;
+use crate::bulk_load::error::BulkLoadError;
+use crate::path_copy_avl::merkle::Digest;
 use crate::path_copy_avl::path_copy::CopyNode;
 use crate::persistent_avl_tree::PersistentAvlTree;
 
-pub struct PathCopyAvl<Data: Ord> {
-    data: Vec<Data>,
-    node_arena: Vec<CopyNode>,
-    root_nodes: Vec<Option<usize>>,
+/// A partially persistent AVL tree built with path copying.
+///
+/// Every node can also hold the `M` summary of its subtree, which is
+/// recomputed whenever the node is copied, so that each version keeps its
+/// own. The default `()` keeps none.
+pub struct PathCopyAvl<Data: Ord, M: Monoid<Data> = ()> {
+    pub(crate) data: Vec<Data>,
+    pub(crate) node_arena: Vec<CopyNode<M::Value>>,
+    pub(crate) root_nodes: Vec<Option<usize>>,
+    /// Digest of a datum, set when Merkle hashing is enabled
+    pub(crate) datum_digest: Option<fn(&Data) -> Digest>,
+    /// Merkle hash of the subtree of each node in `node_arena`, at the same
+    /// index, and empty unless hashing is enabled, so that trees that don't
+    /// hash don't pay for it in every node
+    pub(crate) hashes: Vec<Digest>,
+    /// Copies of the nodes changed by the update in progress, keyed by the
+    /// node they copy, and empty between updates
+    pub(crate) update_cache: HashMap<usize, CopyNode<M::Value>>,
+    pub(crate) monoid: PhantomData<M>,
+}
+
+impl<Data: Ord, M: Monoid<Data>> Default for PathCopyAvl<Data, M> {
+    /// An empty tree, which is how a tree keeping summaries is created
+    fn default() -> Self {
+        PathCopyAvl {
+            data: Vec::new(),
+            node_arena: Vec::new(),
+            root_nodes: Vec::new(),
+            datum_digest: None,
+            hashes: Vec::new(),
+            update_cache: HashMap::new(),
+            monoid: PhantomData,
+        }
+    }
 }
 
 impl<Data: Ord> PathCopyAvl<Data> {
-    fn get_data(&self, node: &CopyNode) -> &Data {
-        &self.data[node.datum_ptr]
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn get_node(&self, update_cache: &HashMap<usize, CopyNode>, node_ptr: usize) -> CopyNode {
-        match update_cache.get(&node_ptr) {
-            Some(node) => node.clone(),
-            None => self.node_arena[node_ptr].clone(),
-        }
+    /// Creates a tree whose first version holds the items of `iter`, which
+    /// must be sorted
+    pub fn from_sorted_iter(iter: impl IntoIterator<Item = Data>) -> Result<Self, BulkLoadError> {
+        let mut tree = Self::new();
+        tree.bulk_load_at_new_version(iter)?;
+
+        Ok(tree)
     }
+}
 
-    fn modify(
-        &self,
-        update_cache: &mut HashMap<usize, CopyNode>,
-        node_ptr: usize,
-        height: u64,
-        new_left_ptr: Option<usize>,
-        new_right_ptr: Option<usize>,
-    ) {
-        update_cache.insert(
-            node_ptr,
-            self.get_node(&update_cache, node_ptr)
-                .update(height, new_left_ptr, new_right_ptr),
+impl<Data: Ord, M: Monoid<Data>> PathCopyAvl<Data, M> {
+    /// Adds a version holding exactly the items of `iter`, which must be
+    /// sorted, and returns its timestamp
+    ///
+    /// The version is built perfectly balanced in O(n) and shares no nodes
+    /// with earlier versions.
+    pub fn bulk_load_at_new_version(
+        &mut self,
+        iter: impl IntoIterator<Item = Data>,
+    ) -> Result<usize, BulkLoadError> {
+        let data = bulk_load::collect_sorted(iter)?;
+
+        let first_datum_ptr = self.data.len();
+        self.data.extend(data);
+
+        let root = bulk_load::build_balanced(
+            first_datum_ptr,
+            self.data.len(),
+            &mut |datum_ptr, left, right, height| {
+                let node = self.new_node(datum_ptr, left, right, height);
+                self.push_node(node)
+            },
         );
+        self.root_nodes.push(root);
+
+        Ok(self.root_nodes.len() - 1)
     }
 
-    fn modify_height(
+    fn get_node(&self, node_ptr: usize) -> &CopyNode<M::Value> {
+        match self.update_cache.get(&node_ptr) {
+            Some(node) => node,
+            None => &self.node_arena[node_ptr],
+        }
+    }
+
+    /// Summary of the subtree at `node_ptr` in the newest version
+    fn subtree_aggregate(&self, node_ptr: Option<usize>) -> M::Value {
+        node_ptr.map_or_else(M::identity, |node_ptr| {
+            self.get_node(node_ptr).aggregate.clone()
+        })
+    }
+
+    /// A node holding the datum at `datum_ptr` above the given children of
+    /// the newest version, summarizing them along with its datum
+    ///
+    /// Its hash, if any, is computed once it is pushed.
+    fn new_node(
         &self,
-        update_cache: &mut HashMap<usize, CopyNode>,
-        node_ptr: usize,
+        datum_ptr: usize,
+        left: Option<usize>,
+        right: Option<usize>,
         height: u64,
-    ) {
-        self.modify(
-            update_cache,
-            node_ptr,
-            height,
-            self.get_node(&update_cache, node_ptr).left,
-            self.get_node(&update_cache, node_ptr).right,
+    ) -> CopyNode<M::Value> {
+        let aggregate = M::combine(
+            &M::combine(
+                &self.subtree_aggregate(left),
+                &M::lift(&self.data[datum_ptr]),
+            ),
+            &self.subtree_aggregate(right),
         );
+
+        CopyNode {
+            datum_ptr,
+            height,
+            left,
+            right,
+            aggregate,
+        }
     }
 
-    fn modify_node_left(
-        &self,
-        update_cache: &mut HashMap<usize, CopyNode>,
-        node_ptr: usize,
-        new_left_ptr: Option<usize>,
-    ) {
-        self.modify(
-            update_cache,
-            node_ptr,
-            self.node_arena[node_ptr].height,
-            new_left_ptr,
-            self.get_node(&update_cache, node_ptr).right,
-        );
+    /// Copies every node in `update_cache` reachable from `node_ptr` into the
+    /// arena, children before parents, and returns where `node_ptr` ended up.
+    ///
+    /// Nodes outside of `update_cache` are shared with earlier versions as is.
+    fn commit(
+        &mut self,
+        update_cache: &mut HashMap<usize, CopyNode<M::Value>>,
+        node_ptr: Option<usize>,
+    ) -> Option<usize> {
+        let node_ptr = node_ptr?;
+
+        match update_cache.remove(&node_ptr) {
+            Some(node) => {
+                let left = self.commit(update_cache, node.left);
+                let right = self.commit(update_cache, node.right);
+
+                Some(self.push_node(CopyNode {
+                    left,
+                    right,
+                    ..node
+                }))
+            }
+            None => Some(node_ptr),
+        }
     }
 
-    fn modify_node_right(
-        &self,
-        update_cache: &mut HashMap<usize, CopyNode>,
-        node_ptr: usize,
-        new_right_ptr: Option<usize>,
-    ) {
-        self.modify(
-            update_cache,
-            node_ptr,
-            self.node_arena[node_ptr].height,
-            self.get_node(&update_cache, node_ptr).left,
-            new_right_ptr,
+    /// Pushes `node` into the arena, along with its hash if hashing is
+    /// enabled, and returns its pointer
+    fn push_node(&mut self, node: CopyNode<M::Value>) -> usize {
+        if let Some(hash) = self.hash_node(&node) {
+            self.hashes.push(hash);
+        }
+        self.node_arena.push(node);
+
+        self.node_arena.len() - 1
+    }
+
+    /// Commits the update in progress as a new version rooted at `new_root`
+    fn finish(&mut self, new_root: Option<usize>) -> usize {
+        let mut update_cache = std::mem::take(&mut self.update_cache);
+        let new_root = self.commit(&mut update_cache, new_root);
+        self.root_nodes.push(new_root);
+
+        self.root_nodes.len() - 1
+    }
+
+    /// Splits the version at `timestamp` into a version holding the data
+    /// less than `item` and one holding the rest, and returns their
+    /// timestamps in that order
+    ///
+    /// Both share all but O(log n) nodes with the version split, which is
+    /// left as it is. The second becomes the newest version.
+    ///
+    /// Panics if there is no version at `timestamp`.
+    pub fn split(&mut self, item: &Data, timestamp: usize) -> (usize, usize) {
+        assert!(timestamp < self.root_nodes.len(), "no version at timestamp");
+
+        let root = self.root_at(&timestamp);
+        let (less, rest) = engine::split(self, root, item, &mut Vec::new());
+
+        let mut update_cache = std::mem::take(&mut self.update_cache);
+        let less = self.commit(&mut update_cache, less);
+        let rest = self.commit(&mut update_cache, rest);
+        self.root_nodes.push(less);
+        self.root_nodes.push(rest);
+
+        (self.root_nodes.len() - 2, self.root_nodes.len() - 1)
+    }
+
+    /// Joins the versions at `first` and `second` into a new version in
+    /// O(log n), and returns its timestamp
+    ///
+    /// Panics if either timestamp has no version, or if some datum of
+    /// `first` is not less than every datum of `second`.
+    pub fn join(&mut self, first: usize, second: usize) -> usize {
+        assert!(
+            first < self.root_nodes.len() && second < self.root_nodes.len(),
+            "no version at timestamp"
         );
+
+        let left = self.root_at(&first);
+        let right = self.root_at(&second);
+
+        let last = self.extreme(left, |node| node.right);
+        let first_of_right = self.extreme(right, |node| node.left);
+        if let Some((last, first_of_right)) = last.zip(first_of_right) {
+            assert!(
+                self.datum(last) < self.datum(first_of_right),
+                "joined versions overlap"
+            );
+        }
+
+        let new_root = engine::join_trees(self, left, right, &mut Vec::new());
+        self.finish(new_root)
     }
 
-    fn balance_and_clone(
+    /// Last node reached from `root` by following `next`
+    fn extreme(
         &self,
-        update_cache: &mut HashMap<usize, CopyNode>,
-        path: Vec<usize>,
+        root: Option<usize>,
+        next: impl Fn(&CopyNode<M::Value>) -> Option<usize>,
     ) -> Option<usize> {
-        if path.len() <= 1 {
-            path.last().copied()
-        } else {
-            todo!()
+        let mut node_ptr = root?;
+        while let Some(next_ptr) = next(&self.node_arena[node_ptr]) {
+            node_ptr = next_ptr;
         }
+
+        Some(node_ptr)
     }
 }
 
-impl<Data: Ord> PersistentAvlTree for PathCopyAvl<Data> {
-    type Data = Data;
+impl<Data: Ord, M: Monoid<Data>> NodeLinks for PathCopyAvl<Data, M> {
+    fn latest_root(&self) -> Option<usize> {
+        self.root_nodes.last().and_then(|root| *root)
+    }
 
-    type Timestamp = usize;
+    fn left(&self, node: usize) -> Option<usize> {
+        self.get_node(node).left
+    }
 
-    fn insert(&mut self, item: Self::Data) -> Self::Timestamp {
-        // Allocation
-        let mut update_cache = HashMap::new();
+    fn right(&self, node: usize) -> Option<usize> {
+        self.get_node(node).right
+    }
 
-        self.data.push(item);
-        let datum_ptr = self.data.len() - 1;
+    fn height(&self, node: usize) -> u64 {
+        self.get_node(node).height
+    }
 
-        let item = &self.data[datum_ptr];
+    /// Also recomputes the summary of `node` from its new children, which
+    /// the engine always modifies before their parents
+    fn modify(&mut self, node: usize, left: Option<usize>, right: Option<usize>, height: u64) {
+        let copy = self.new_node(self.get_node(node).datum_ptr, left, right, height);
+        self.update_cache.insert(node, copy);
+    }
+}
 
-        let new_node_ptr = self.node_arena.len();
-        update_cache.insert(
-            new_node_ptr,
-            CopyNode {
-                datum_ptr: datum_ptr,
-                height: 0,
-                left: None,
-                right: None,
-            },
-        );
+impl<Data: Ord, M: Monoid<Data>> NodeStore for PathCopyAvl<Data, M> {
+    type Data = Data;
+    type Version = usize;
 
-        let mut path_ptr = self.root_nodes.last().and_then(|latest_time| *latest_time);
+    fn datum(&self, node: usize) -> &Data {
+        &self.data[self.get_node(node).datum_ptr]
+    }
 
-        let mut path = Vec::new();
-        while let Some(ptr) = path_ptr {
-            path.push(ptr);
+    fn root_at(&self, version: &usize) -> Option<usize> {
+        self.root_nodes.get(*version).and_then(|root| *root)
+    }
 
-            let node = &self.node_arena[ptr];
-            let node_datum = self.get_data(node);
+    fn left_at(&self, node: usize, _: &usize) -> Option<usize> {
+        self.node_arena[node].left
+    }
 
-            if *item <= *node_datum {
-                path_ptr = node.left;
-            } else {
-                path_ptr = node.right;
-            }
-        }
+    fn right_at(&self, node: usize, _: &usize) -> Option<usize> {
+        self.node_arena[node].right
+    }
+}
 
-        path.push(new_node_ptr);
+impl<Data: Ord, M: Monoid<Data>> PersistentAvlTree for PathCopyAvl<Data, M> {
+    type Data = Data;
 
-        self.root_nodes
-            .push(self.balance_and_clone(&mut update_cache, path));
+    type Timestamp = usize;
 
-        self.node_arena
-            .push(update_cache.remove(&new_node_ptr).unwrap());
-        self.node_arena
-            .append(&mut update_cache.into_values().collect());
+    fn insert(&mut self, item: Self::Data) -> Self::Timestamp {
+        self.data.push(item);
 
-        self.root_nodes.len() - 1
+        // Nodes in `update_cache` keep the pointer of the node they copy until
+        // they are committed, so the new node borrows the first free pointer
+        let new_node_ptr = self.node_arena.len();
+        let new_node = self.new_node(self.data.len() - 1, None, None, 1);
+        self.update_cache.insert(new_node_ptr, new_node);
+
+        let update = engine::insert(self, new_node_ptr);
+        self.finish(update.root)
     }
 
     fn delete(&mut self, item: &Self::Data) -> Option<Self::Timestamp> {
-        todo!()
+        let update = engine::delete(self, item)?;
+        Some(self.finish(update.root))
     }
 
     fn contains(&self, item: &Self::Data, timestamp: Self::Timestamp) -> bool {
-        match self.predecessor(item, timestamp) {
-            Some(predecessor) => *item == *predecessor,
-            None => false,
-        }
+        engine::contains(self, item, &timestamp)
     }
 
     fn predecessor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
-        if timestamp >= self.root_nodes.len() {
-            return None;
-        }
+        engine::predecessor(self, item, &timestamp)
+    }
 
-        let mut node_ptr = self.root_nodes[timestamp];
-        let mut inf: Option<&Self::Data> = None;
+    fn successor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        engine::successor(self, item, &timestamp)
+    }
+}
+
+impl<Data: Ord, M: Monoid<Data>> BatchUpdate for PathCopyAvl<Data, M> {
+    fn delete_range(&mut self, range: impl RangeBounds<Self::Data>) -> Self::Timestamp {
+        let update = engine::delete_range(self, &range);
+        self.finish(update.root)
+    }
 
-        while let Some(ptr) = node_ptr {
-            let node = &self.node_arena[ptr];
-            let datum = self.get_data(node);
+    fn insert_sorted_batch(
+        &mut self,
+        iter: impl IntoIterator<Item = Self::Data>,
+    ) -> Result<Self::Timestamp, BulkLoadError> {
+        let data = bulk_load::collect_sorted(iter)?;
+
+        let first_datum_ptr = self.data.len();
+        self.data.extend(data);
+
+        // As with a single insertion, the new nodes borrow the first free
+        // pointers until they are committed
+        let first_node_ptr = self.node_arena.len();
+        let batch = bulk_load::build_balanced(
+            first_datum_ptr,
+            self.data.len(),
+            &mut |datum_ptr, left, right, height| {
+                let node_ptr = first_node_ptr + (datum_ptr - first_datum_ptr);
+                let node = self.new_node(datum_ptr, left, right, height);
+                self.update_cache.insert(node_ptr, node);
+
+                node_ptr
+            },
+        );
 
-            if *datum > *item {
-                node_ptr = node.left;
-            } else {
-                inf = Some(datum);
-                node_ptr = node.right;
+        let update = engine::insert_batch(self, batch);
+        Ok(self.finish(update.root))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{avl_data, newest_avl_data, Rng};
+
+    #[test]
+    fn bulk_loads_balanced_versions() {
+        let mut rng = Rng(7);
+        let mut tree = PathCopyAvl::new();
+        let mut versions: Vec<Vec<u64>> = Vec::new();
+
+        for len in [0, 1, 2, 3, 7, 8, 100, 1000] {
+            let mut data: Vec<u64> = (0..len).map(|_| rng.below(50)).collect();
+            data.sort();
+
+            let timestamp = tree.bulk_load_at_new_version(data.clone()).unwrap();
+            assert_eq!(timestamp, versions.len());
+            assert!(newest_avl_data(&tree).into_iter().eq(data.iter()));
+            versions.push(data);
+        }
+
+        // Each bulk load leaves the versions before it as they were
+        for (timestamp, data) in versions.iter().enumerate() {
+            for item in 0..50 {
+                assert_eq!(tree.contains(&item, timestamp), data.contains(&item));
             }
         }
+    }
+
+    #[test]
+    fn builds_from_a_sorted_iter() {
+        let tree = PathCopyAvl::from_sorted_iter(0..100u64).unwrap();
 
-        inf
+        assert!(newest_avl_data(&tree).into_iter().copied().eq(0..100));
+        assert_eq!(tree.predecessor(&50, 0), Some(&50));
     }
 
-    fn successor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
-        if timestamp >= self.root_nodes.len() {
-            return None;
-        }
+    #[test]
+    fn refuses_unsorted_input() {
+        assert_eq!(
+            PathCopyAvl::from_sorted_iter([1, 3, 2]).err(),
+            Some(BulkLoadError::Unsorted { index: 2 })
+        );
+
+        let mut tree = PathCopyAvl::from_sorted_iter([1, 2, 2, 3]).unwrap();
+        assert_eq!(
+            tree.bulk_load_at_new_version([5, 4]),
+            Err(BulkLoadError::Unsorted { index: 1 })
+        );
 
-        let mut node_ptr = self.root_nodes[timestamp];
-        let mut sup: Option<&Self::Data> = None;
+        // The tree is left as it was
+        assert!(!tree.contains(&5, 1));
+        assert_eq!(tree.insert(4), 1);
+        assert!(newest_avl_data(&tree)
+            .into_iter()
+            .eq([1, 2, 2, 3, 4].iter()));
+    }
 
-        while let Some(ptr) = node_ptr {
-            let node = &self.node_arena[ptr];
-            let datum = self.get_data(node);
+    #[test]
+    fn splits_before_the_first_datum_not_less_than_the_item() {
+        let mut rng = Rng(13);
 
-            if *datum < *item {
-                node_ptr = node.right;
-            } else {
-                sup = Some(datum);
-                node_ptr = node.right;
+        for len in [1, 2, 5, 40, 300] {
+            let mut tree = PathCopyAvl::new();
+            let mut data: Vec<u64> = (0..len).map(|_| rng.below(60)).collect();
+            for &datum in &data {
+                tree.insert(datum);
+            }
+            data.sort();
+            let source = tree.root_nodes.len() - 1;
+
+            // Items below, among and above the data, most of them held
+            // several times
+            for item in [0, 1, 7, 30, 59, 60, 100] {
+                let versions = tree.root_nodes.len();
+                let (less, rest) = tree.split(&item, source);
+                assert_eq!((less, rest), (versions, versions + 1));
+
+                let point = data.partition_point(|&datum| datum < item);
+                assert!(avl_data(&tree, tree.root_at(&less))
+                    .into_iter()
+                    .eq(&data[..point]));
+                assert!(newest_avl_data(&tree).into_iter().eq(&data[point..]));
+                assert!(avl_data(&tree, tree.root_at(&source)).into_iter().eq(&data));
+
+                let joined = tree.join(less, rest);
+                assert!(newest_avl_data(&tree).into_iter().eq(&data));
+                assert_eq!(tree.contains(&item, joined), data.contains(&item));
             }
         }
+    }
+
+    #[test]
+    fn joins_versions_of_any_heights() {
+        let mut tree = PathCopyAvl::new();
+        for item in 0..200u64 {
+            tree.insert(item);
+        }
+
+        for point in [0, 1, 3, 60, 199, 200] {
+            let (less, rest) = tree.split(&point, 199);
+            let (_, high) = tree.split(&(point + 1), rest);
+            let low = tree.split(&point, less).0;
+
+            // Versions that differ a lot in height, and one empty side
+            let joined = tree.join(low, high);
+            let expected: Vec<u64> = (0..200).filter(|&item| item != point).collect();
+            assert_eq!(joined, tree.root_nodes.len() - 1);
+            assert!(newest_avl_data(&tree).into_iter().eq(&expected));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "joined versions overlap")]
+    fn join_panics_on_overlapping_versions() {
+        let mut tree = PathCopyAvl::new();
+        for item in [1u64, 5, 9] {
+            tree.insert(item);
+        }
+
+        let (less, rest) = tree.split(&5, 2);
+        tree.join(rest, less);
+    }
+
+    #[test]
+    #[should_panic(expected = "joined versions overlap")]
+    fn join_panics_on_a_datum_in_both_versions() {
+        let mut tree = PathCopyAvl::new();
+        for item in [1u64, 5, 5, 9] {
+            tree.insert(item);
+        }
 
-        sup
+        let (less, _) = tree.split(&6, 3);
+        let (_, fives) = tree.split(&5, less);
+        tree.join(less, fives);
     }
 }
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+
+use crate::path_copy_avl::merkle;
+use crate::path_copy_avl::path_copy::CopyNode;
+use crate::path_copy_avl::path_copy_avl::PathCopyAvl;
+use crate::snapshot::binary_snapshot::BinarySnapshot;
+use crate::snapshot::error::SnapshotError;
+use crate::snapshot::format::*;
+use crate::snapshot::record::Record;
+
+// Sections of a PathCopyAvl snapshot:
+//
+//   DATA  datum
+//   NODE  datum pointer u64 | height u64 | left u64 | right u64
+//   ROOT  root u64, one record per version
+//   HASH  Merkle hash of each node, only written when hashing is enabled
+
+const DATA: [u8; 4] = *b"DATA";
+const NODE: [u8; 4] = *b"NODE";
+const ROOT: [u8; 4] = *b"ROOT";
+const HASH: [u8; 4] = *b"HASH";
+
+const NODE_WIDTH: usize = 32;
+const ROOT_WIDTH: usize = 8;
+const HASH_WIDTH: usize = 32;
+
+impl<Data: Ord + Record> BinarySnapshot for PathCopyAvl<Data> {
+    fn write_to(&self, writer: impl Write) -> Result<(), SnapshotError> {
+        let section_count = if self.is_hashed() { 4 } else { 3 };
+        let mut writer =
+            SnapshotWriter::new(writer, BACKEND_PATH_COPY_AVL, Data::WIDTH, section_count)?;
+
+        writer.section(
+            DATA,
+            Data::WIDTH,
+            self.data.len(),
+            self.data.iter(),
+            |datum, buf| datum.encode(buf),
+        )?;
+
+        writer.section(
+            NODE,
+            NODE_WIDTH,
+            self.node_arena.len(),
+            self.node_arena.iter(),
+            |node, buf| {
+                write_u64(buf, 0, node.datum_ptr as u64);
+                write_u64(buf, 8, node.height);
+                write_ptr(buf, 16, node.left);
+                write_ptr(buf, 24, node.right);
+            },
+        )?;
+
+        writer.section(
+            ROOT,
+            ROOT_WIDTH,
+            self.root_nodes.len(),
+            self.root_nodes.iter(),
+            |root, buf| write_ptr(buf, 0, *root),
+        )?;
+
+        if self.is_hashed() {
+            writer.section(
+                HASH,
+                HASH_WIDTH,
+                self.hashes.len(),
+                self.hashes.iter(),
+                |hash, buf| buf.copy_from_slice(hash),
+            )?;
+        }
+
+        writer.finish()
+    }
+
+    fn read_from(reader: impl Read) -> Result<Self, SnapshotError> {
+        let contents = SnapshotContents::read(reader, BACKEND_PATH_COPY_AVL, Data::WIDTH)?;
+
+        let data = contents.section(DATA, Data::WIDTH)?;
+        let nodes = contents.section(NODE, NODE_WIDTH)?;
+        let roots = contents.section(ROOT, ROOT_WIDTH)?;
+        let hashes = contents.optional_section(HASH, HASH_WIDTH)?;
+
+        let node_count = nodes.len();
+        let check_ptr = |ptr: Option<usize>| match ptr {
+            Some(ptr) if ptr >= node_count => {
+                Err(SnapshotError::Corrupt("node pointer out of range"))
+            }
+            _ => Ok(ptr),
+        };
+
+        // Nodes are committed children before parents, so a child pointing
+        // at its own node or a later one can only come from a damaged file,
+        // and would let a walk down the tree loop forever
+        let check_child = |ptr: Option<usize>, node_ptr: usize| match ptr {
+            Some(ptr) if ptr >= node_ptr => Err(SnapshotError::Corrupt(
+                "child pointer does not precede its node",
+            )),
+            _ => Ok(ptr),
+        };
+
+        let data: Vec<Data> = data.records().map(Data::decode).collect();
+
+        let node_arena = nodes
+            .records()
+            .enumerate()
+            .map(|(node_ptr, buf)| {
+                let datum_ptr = read_u64(buf, 0) as usize;
+                if datum_ptr >= data.len() {
+                    return Err(SnapshotError::Corrupt("datum pointer out of range"));
+                }
+
+                Ok(CopyNode {
+                    datum_ptr,
+                    height: read_u64(buf, 8),
+                    left: check_child(read_ptr(buf, 16), node_ptr)?,
+                    right: check_child(read_ptr(buf, 24), node_ptr)?,
+                    aggregate: (),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let root_nodes = roots
+            .records()
+            .map(|buf| check_ptr(read_ptr(buf, 0)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut tree = PathCopyAvl {
+            data,
+            node_arena,
+            root_nodes,
+            datum_digest: None,
+            hashes: Vec::new(),
+            update_cache: HashMap::new(),
+            monoid: PhantomData,
+        };
+
+        if let Some(hashes) = hashes {
+            if hashes.len() != tree.node_arena.len() {
+                return Err(SnapshotError::Corrupt(
+                    "hash count does not match node count",
+                ));
+            }
+
+            tree.hashes = hashes
+                .records()
+                .map(|hash| hash.try_into().unwrap())
+                .collect();
+            tree.datum_digest = Some(merkle::datum_digest::<Data>);
+        }
+
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistent_avl_tree::PersistentAvlTree;
+
+    /// A tree with a history of mixed updates, and its last timestamp
+    fn tree(mut tree: PathCopyAvl<u64>) -> (PathCopyAvl<u64>, usize) {
+        let mut last_timestamp = 0;
+        for step in 0..300u64 {
+            let item = step * 37 % 101;
+            last_timestamp = match step % 4 {
+                3 => tree.delete(&item).unwrap_or(last_timestamp),
+                _ => tree.insert(item),
+            };
+        }
+
+        (tree, last_timestamp)
+    }
+
+    fn round_trip(tree: &PathCopyAvl<u64>) -> Result<PathCopyAvl<u64>, SnapshotError> {
+        let mut bytes = Vec::new();
+        tree.write_to(&mut bytes).unwrap();
+
+        PathCopyAvl::read_from(bytes.as_slice())
+    }
+
+    #[test]
+    fn round_trips_every_version() {
+        for tree in [PathCopyAvl::new(), PathCopyAvl::with_merkle_hashing()] {
+            let (tree, last_timestamp) = self::tree(tree);
+            let read = round_trip(&tree).unwrap();
+
+            assert_eq!(read.is_hashed(), tree.is_hashed());
+            for timestamp in 0..=last_timestamp {
+                assert_eq!(read.root_hash(timestamp), tree.root_hash(timestamp));
+                for item in 0..=102 {
+                    assert_eq!(
+                        read.contains(&item, timestamp),
+                        tree.contains(&item, timestamp)
+                    );
+                    assert_eq!(
+                        read.predecessor(&item, timestamp),
+                        tree.predecessor(&item, timestamp)
+                    );
+                    assert_eq!(
+                        read.successor(&item, timestamp),
+                        tree.successor(&item, timestamp)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_children_that_do_not_precede_their_node() {
+        let (mut tree, last_timestamp) = tree(PathCopyAvl::new());
+        let root = tree.root_nodes[last_timestamp].unwrap();
+
+        // A node pointing at itself
+        tree.node_arena[root].left = Some(root);
+        assert!(matches!(
+            round_trip(&tree),
+            Err(SnapshotError::Corrupt(
+                "child pointer does not precede its node"
+            ))
+        ));
+
+        // A cycle through an older node
+        let child = tree.node_arena[root].right.unwrap();
+        tree.node_arena[root].left = None;
+        tree.node_arena[child].right = Some(root);
+        assert!(matches!(
+            round_trip(&tree),
+            Err(SnapshotError::Corrupt(
+                "child pointer does not precede its node"
+            ))
+        ));
+    }
+}
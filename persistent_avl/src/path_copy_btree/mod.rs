@@ -0,0 +1,2 @@
+mod page;
+pub mod path_copy_btree;
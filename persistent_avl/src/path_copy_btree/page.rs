@@ -0,0 +1,55 @@
+/// A B-tree node with room for `F` children and `F - 1` keys.
+///
+/// Keys are pointers into the data arena and children are pointers into the
+/// page arena, both stored inline so that a page is one contiguous block.
+///
+/// Holding the data themselves would save a jump into the data arena for
+/// every key a search compares, but every update copies each page on its
+/// path, so pages would then hold `F - 1` copies of `Data` each, the tree
+/// would need `Data: Clone`, and a datum would be stored once per version
+/// of its page instead of once overall. `benches/btree_keys.rs` times
+/// searches over a data arena laid out in key order against one shuffled
+/// out of it, and finds no consistent gap at 200 000 items.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Page<const F: usize> {
+    len: usize,
+    leaf: bool,
+    keys: [usize; F], // The last slot is spare, arrays can't be sized F - 1
+    children: [usize; F],
+}
+
+impl<const F: usize> Page<F> {
+    /// Builds a page out of `keys` and, unless it is a leaf, `children`
+    pub(crate) fn new(keys: &[usize], children: &[usize]) -> Self {
+        debug_assert!(keys.len() < F);
+        debug_assert!(children.is_empty() || children.len() == keys.len() + 1);
+
+        let mut page = Page {
+            len: keys.len(),
+            leaf: children.is_empty(),
+            keys: [0; F],
+            children: [0; F],
+        };
+        page.keys[..keys.len()].copy_from_slice(keys);
+        page.children[..children.len()].copy_from_slice(children);
+
+        page
+    }
+
+    pub(crate) fn keys(&self) -> &[usize] {
+        &self.keys[..self.len]
+    }
+
+    /// Empty for leaves
+    pub(crate) fn children(&self) -> &[usize] {
+        if self.leaf {
+            &[]
+        } else {
+            &self.children[..self.len + 1]
+        }
+    }
+
+    pub(crate) fn is_leaf(&self) -> bool {
+        self.leaf
+    }
+}
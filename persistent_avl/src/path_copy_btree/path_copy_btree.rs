@@ -0,0 +1,479 @@
+use std::ops::RangeBounds;
+
+use crate::batch_update::{self, BatchUpdate};
+use crate::bulk_load::bulk_load;
+use crate::bulk_load::error::BulkLoadError;
+use crate::path_copy_btree::page::Page;
+use crate::persistent_avl_tree::PersistentAvlTree;
+
+pub const DEFAULT_FANOUT: usize = 16;
+
+/// A partially persistent B-tree built with path copying over an arena of pages.
+///
+/// Every page holds up to `F` children, so a search touches `log_F(n)` pages
+/// instead of `log_2(n)` nodes, and an update copies as many pages.
+pub struct PathCopyBTree<Data: Ord, const F: usize = DEFAULT_FANOUT> {
+    data: Vec<Data>,
+    page_arena: Vec<Page<F>>,
+    root_nodes: Vec<Option<usize>>,
+}
+
+impl<Data: Ord, const F: usize> Default for PathCopyBTree<Data, F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Data: Ord, const F: usize> PathCopyBTree<Data, F> {
+    /// Fewest keys a page other than the root holds
+    const MIN_KEYS: usize = F.div_ceil(2) - 1;
+
+    pub fn new() -> Self {
+        assert!(F >= 3, "B-tree pages need room for at least 3 children");
+
+        PathCopyBTree {
+            data: Vec::new(),
+            page_arena: Vec::new(),
+            root_nodes: Vec::new(),
+        }
+    }
+
+    fn latest_root(&self) -> Option<usize> {
+        self.root_nodes.last().and_then(|root| *root)
+    }
+
+    fn get_root(&self, timestamp: usize) -> Option<usize> {
+        self.root_nodes.get(timestamp).and_then(|root| *root)
+    }
+
+    /// Number of keys in `page` that are at most `item`
+    fn keys_at_most(&self, page: &Page<F>, item: &Data) -> usize {
+        page.keys().partition_point(|&key| self.data[key] <= *item)
+    }
+
+    /// Number of keys in `page` that are less than `item`
+    fn keys_less(&self, page: &Page<F>, item: &Data) -> usize {
+        page.keys().partition_point(|&key| self.data[key] < *item)
+    }
+
+    fn push_page(&mut self, keys: &[usize], children: &[usize]) -> usize {
+        self.page_arena.push(Page::new(keys, children));
+        self.page_arena.len() - 1
+    }
+
+    /// Pushes a page, splitting it in two around its median key if it has
+    /// one key too many.
+    ///
+    /// Returns the page, or the left half along with the median and the
+    /// right half.
+    fn push_split(
+        &mut self,
+        keys: &[usize],
+        children: &[usize],
+    ) -> (usize, Option<(usize, usize)>) {
+        if keys.len() < F {
+            return (self.push_page(keys, children), None);
+        }
+
+        let middle = keys.len() / 2;
+        let (left_children, right_children) = if children.is_empty() {
+            (children, children)
+        } else {
+            children.split_at(middle + 1)
+        };
+
+        let left = self.push_page(&keys[..middle], left_children);
+        let right = self.push_page(&keys[middle + 1..], right_children);
+
+        (left, Some((keys[middle], right)))
+    }
+
+    /// Pushes the child at `index` of a parent page, topping it up from a
+    /// sibling or merging it into one if it has too few keys
+    fn push_child(
+        &mut self,
+        keys: &mut Vec<usize>,
+        children: &mut Vec<usize>,
+        index: usize,
+        mut child_keys: Vec<usize>,
+        mut child_children: Vec<usize>,
+    ) {
+        if child_keys.len() >= Self::MIN_KEYS {
+            children[index] = self.push_page(&child_keys, &child_children);
+            return;
+        }
+
+        let left = index
+            .checked_sub(1)
+            .map(|left| self.page_arena[children[left]]);
+        let right = children.get(index + 1).map(|&right| self.page_arena[right]);
+
+        if let Some(left) = left.filter(|left| left.keys().len() > Self::MIN_KEYS) {
+            // Rotate the last key of the left sibling through the parent
+            let mut left_keys = left.keys().to_vec();
+            let mut left_children = left.children().to_vec();
+
+            child_keys.insert(0, keys[index - 1]);
+            keys[index - 1] = left_keys.pop().unwrap();
+            if let Some(last) = left_children.pop() {
+                child_children.insert(0, last);
+            }
+
+            children[index - 1] = self.push_page(&left_keys, &left_children);
+            children[index] = self.push_page(&child_keys, &child_children);
+        } else if let Some(right) = right.filter(|right| right.keys().len() > Self::MIN_KEYS) {
+            // Rotate the first key of the right sibling through the parent
+            let mut right_keys = right.keys().to_vec();
+            let mut right_children = right.children().to_vec();
+
+            child_keys.push(keys[index]);
+            keys[index] = right_keys.remove(0);
+            if !right_children.is_empty() {
+                child_children.push(right_children.remove(0));
+            }
+
+            children[index] = self.push_page(&child_keys, &child_children);
+            children[index + 1] = self.push_page(&right_keys, &right_children);
+        } else if let Some(left) = left {
+            // Both are at the minimum, so they fit in one page with the key
+            // that separated them
+            let mut merged_keys = left.keys().to_vec();
+            merged_keys.push(keys.remove(index - 1));
+            merged_keys.append(&mut child_keys);
+
+            let mut merged_children = left.children().to_vec();
+            merged_children.append(&mut child_children);
+
+            children.remove(index);
+            children[index - 1] = self.push_page(&merged_keys, &merged_children);
+        } else {
+            // Pages other than the root have a sibling
+            let right = right.unwrap();
+
+            child_keys.push(keys.remove(index));
+            child_keys.extend_from_slice(right.keys());
+            child_children.extend_from_slice(right.children());
+
+            children.remove(index + 1);
+            children[index] = self.push_page(&child_keys, &child_children);
+        }
+    }
+
+    /// Inserts the key `datum_ptr` into the tree at `root`, copying the path
+    /// down to it, and returns the new root
+    fn insert_key(&mut self, root: Option<usize>, datum_ptr: usize) -> usize {
+        let item = &self.data[datum_ptr];
+
+        // Path from the root down to a leaf, with the child taken at each page,
+        // and the position of the new key in the leaf
+        let mut path = Vec::new();
+        let mut page_ptr = root;
+        while let Some(ptr) = page_ptr {
+            let page = &self.page_arena[ptr];
+            let index = self.keys_at_most(page, item);
+
+            path.push((ptr, index));
+            page_ptr = page.children().get(index).copied();
+        }
+
+        let Some((leaf_ptr, index)) = path.pop() else {
+            return self.push_page(&[datum_ptr], &[]);
+        };
+
+        let mut keys = self.page_arena[leaf_ptr].keys().to_vec();
+        keys.insert(index, datum_ptr);
+        let (mut page_ptr, mut split) = self.push_split(&keys, &[]);
+
+        // Copy the path bottom up, handing each split to the parent
+        while let Some((parent_ptr, index)) = path.pop() {
+            let parent = self.page_arena[parent_ptr];
+            let mut keys = parent.keys().to_vec();
+            let mut children = parent.children().to_vec();
+
+            children[index] = page_ptr;
+            if let Some((median, right)) = split {
+                keys.insert(index, median);
+                children.insert(index + 1, right);
+            }
+
+            (page_ptr, split) = self.push_split(&keys, &children);
+        }
+
+        if let Some((median, right)) = split {
+            page_ptr = self.push_page(&[median], &[page_ptr, right]);
+        }
+
+        page_ptr
+    }
+
+    /// Deletes the first key that `accept` holds for, out of those that
+    /// `before` does not, from the tree at `root`, copying the path down to
+    /// it. Returns the new root, or `None` if there is no such key.
+    ///
+    /// `before` must hold for a prefix of the keys in order.
+    fn delete_key(
+        &mut self,
+        root: Option<usize>,
+        before: &impl Fn(&Data) -> bool,
+        accept: &impl Fn(&Data) -> bool,
+    ) -> Option<Option<usize>> {
+        // Path from the root down to the page holding the key, with the child
+        // taken at each page
+        let mut path = Vec::new();
+        let mut page_ptr = root;
+        let found = loop {
+            let ptr = page_ptr?;
+            let page = &self.page_arena[ptr];
+            let index = page.keys().partition_point(|&key| before(&self.data[key]));
+
+            path.push((ptr, index));
+            if page
+                .keys()
+                .get(index)
+                .is_some_and(|&key| accept(&self.data[key]))
+            {
+                break index;
+            }
+            page_ptr = page.children().get(index).copied();
+        };
+        let found_depth = path.len() - 1;
+
+        // A key in an inner page is replaced by its predecessor, the last key
+        // in the rightmost leaf left of it
+        let found_page = &self.page_arena[path[found_depth].0];
+        if !found_page.is_leaf() {
+            let mut ptr = found_page.children()[found];
+            loop {
+                let page = &self.page_arena[ptr];
+                if page.is_leaf() {
+                    path.push((ptr, page.keys().len() - 1));
+                    break;
+                }
+
+                path.push((ptr, page.keys().len()));
+                ptr = page.children()[page.keys().len()];
+            }
+        }
+
+        let (leaf_ptr, index) = path.pop().unwrap();
+        let mut keys = self.page_arena[leaf_ptr].keys().to_vec();
+        let predecessor = keys.remove(index);
+        let mut children = Vec::new();
+
+        // Copy the path bottom up, fixing pages left with too few keys
+        while let Some((parent_ptr, index)) = path.pop() {
+            let parent = self.page_arena[parent_ptr];
+            let mut parent_keys = parent.keys().to_vec();
+            let mut parent_children = parent.children().to_vec();
+
+            if path.len() == found_depth {
+                parent_keys[found] = predecessor;
+            }
+            self.push_child(
+                &mut parent_keys,
+                &mut parent_children,
+                index,
+                keys,
+                children,
+            );
+
+            keys = parent_keys;
+            children = parent_children;
+        }
+
+        // A root left without keys hands its place to its only child
+        Some(if keys.is_empty() {
+            children.first().copied()
+        } else {
+            Some(self.push_page(&keys, &children))
+        })
+    }
+}
+
+impl<Data: Ord, const F: usize> PersistentAvlTree for PathCopyBTree<Data, F> {
+    type Data = Data;
+
+    type Timestamp = usize;
+
+    fn insert(&mut self, item: Self::Data) -> Self::Timestamp {
+        self.data.push(item);
+        let root = self.insert_key(self.latest_root(), self.data.len() - 1);
+        self.root_nodes.push(Some(root));
+
+        self.root_nodes.len() - 1
+    }
+
+    fn delete(&mut self, item: &Self::Data) -> Option<Self::Timestamp> {
+        let new_root = self.delete_key(self.latest_root(), &|datum| datum < item, &|datum| {
+            datum == item
+        })?;
+        self.root_nodes.push(new_root);
+
+        Some(self.root_nodes.len() - 1)
+    }
+
+    fn contains(&self, item: &Self::Data, timestamp: Self::Timestamp) -> bool {
+        match self.predecessor(item, timestamp) {
+            Some(predecessor) => *item == *predecessor,
+            None => false,
+        }
+    }
+
+    fn predecessor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        let mut page_ptr = self.get_root(timestamp);
+        let mut inf = None;
+
+        while let Some(ptr) = page_ptr {
+            let page = &self.page_arena[ptr];
+            let index = self.keys_at_most(page, item);
+
+            if index > 0 {
+                inf = Some(&self.data[page.keys()[index - 1]]);
+            }
+            page_ptr = page.children().get(index).copied();
+        }
+
+        inf
+    }
+
+    fn successor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        let mut page_ptr = self.get_root(timestamp);
+        let mut sup = None;
+
+        while let Some(ptr) = page_ptr {
+            let page = &self.page_arena[ptr];
+            let index = self.keys_less(page, item);
+
+            if let Some(&key) = page.keys().get(index) {
+                sup = Some(&self.data[key]);
+            }
+            page_ptr = page.children().get(index).copied();
+        }
+
+        sup
+    }
+}
+
+/// Pages hold many keys each, so both updates make their changes one key at a
+/// time, though within a single version. That takes O(k log n) for k keys.
+impl<Data: Ord, const F: usize> BatchUpdate for PathCopyBTree<Data, F> {
+    fn delete_range(&mut self, range: impl RangeBounds<Self::Data>) -> Self::Timestamp {
+        let mut root = self.latest_root();
+        while let Some(new_root) = self.delete_key(
+            root,
+            &|datum| batch_update::is_before(&range, datum),
+            &|datum| range.contains(datum),
+        ) {
+            root = new_root;
+        }
+        self.root_nodes.push(root);
+
+        self.root_nodes.len() - 1
+    }
+
+    fn insert_sorted_batch(
+        &mut self,
+        iter: impl IntoIterator<Item = Self::Data>,
+    ) -> Result<Self::Timestamp, BulkLoadError> {
+        let batch = bulk_load::collect_sorted(iter)?;
+
+        let mut root = self.latest_root();
+        let first_datum_ptr = self.data.len();
+        self.data.extend(batch);
+        for datum_ptr in first_datum_ptr..self.data.len() {
+            root = Some(self.insert_key(root, datum_ptr));
+        }
+        self.root_nodes.push(root);
+
+        Ok(self.root_nodes.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{random_update, Rng};
+
+    /// Checks that the version at `timestamp` is a B-tree, whose leaves are
+    /// all as deep, whose pages other than the root are at least half full,
+    /// and whose keys are in order between the keys around their page, and
+    /// returns its data in order
+    fn btree_data<const F: usize>(tree: &PathCopyBTree<u64, F>, timestamp: usize) -> Vec<u64> {
+        /// Depth of the leaves below `page_ptr`
+        fn walk<const F: usize>(
+            tree: &PathCopyBTree<u64, F>,
+            page_ptr: usize,
+            bounds: (Option<u64>, Option<u64>),
+            data: &mut Vec<u64>,
+        ) -> usize {
+            let page = &tree.page_arena[page_ptr];
+            let keys: Vec<u64> = page.keys().iter().map(|&key| tree.data[key]).collect();
+
+            assert!(!keys.is_empty() && keys.len() < F, "page overfull or empty");
+            assert!(keys.is_sorted(), "keys out of order");
+            assert!(bounds.0.is_none_or(|low| low <= keys[0]));
+            assert!(bounds.1.is_none_or(|high| keys[keys.len() - 1] <= high));
+
+            if page.is_leaf() {
+                data.extend(&keys);
+                return 1;
+            }
+
+            assert_eq!(page.children().len(), keys.len() + 1);
+            let mut depths = Vec::new();
+            for (index, &child_ptr) in page.children().iter().enumerate() {
+                let child = &tree.page_arena[child_ptr];
+                assert!(
+                    child.keys().len() >= PathCopyBTree::<u64, F>::MIN_KEYS,
+                    "page underfull"
+                );
+
+                let low = index.checked_sub(1).map(|index| keys[index]).or(bounds.0);
+                let high = keys.get(index).copied().or(bounds.1);
+                depths.push(walk(tree, child_ptr, (low, high), data));
+                if let Some(&key) = keys.get(index) {
+                    data.push(key);
+                }
+            }
+            assert!(
+                depths.windows(2).all(|pair| pair[0] == pair[1]),
+                "leaves at different depths"
+            );
+
+            depths[0] + 1
+        }
+
+        let mut data = Vec::new();
+        if let Some(root) = tree.get_root(timestamp) {
+            walk(tree, root, (None, None), &mut data);
+        }
+
+        data
+    }
+
+    fn every_version_is_a_btree<const F: usize>() {
+        let mut rng = Rng(F as u64);
+        let mut tree = PathCopyBTree::<u64, F>::new();
+        let mut versions: Vec<Vec<u64>> = Vec::new();
+
+        for _ in 0..600 {
+            let mut data = versions.last().cloned().unwrap_or_default();
+            if let Some(timestamp) = random_update(&mut tree, &mut data, &mut rng, 60) {
+                assert_eq!(timestamp, versions.len());
+                versions.push(data);
+            }
+        }
+
+        for (timestamp, data) in versions.iter().enumerate() {
+            assert_eq!(&btree_data(&tree, timestamp), data);
+        }
+    }
+
+    #[test]
+    fn every_version_is_a_btree_of_any_fanout() {
+        every_version_is_a_btree::<3>();
+        every_version_is_a_btree::<4>();
+        every_version_is_a_btree::<5>();
+        every_version_is_a_btree::<DEFAULT_FANOUT>();
+    }
+}
@@ -0,0 +1,2 @@
+pub mod path_copy_rb;
+mod rb_copy_node;
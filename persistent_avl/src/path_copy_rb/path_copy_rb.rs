@@ -0,0 +1,383 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::RangeBounds;
+
+use crate::avl::avl;
+use crate::batch_update::{self, BatchUpdate};
+use crate::bulk_load::bulk_load;
+use crate::bulk_load::error::BulkLoadError;
+use crate::path_copy_rb::rb_copy_node::RbCopyNode;
+use crate::persistent_avl_tree::PersistentAvlTree;
+use crate::rb::rb;
+
+/// A fully path copied red-black tree, the counterpart of `PathCopyAvl`
+pub struct PathCopyRbTree<Data: Ord> {
+    data: Vec<Data>,
+    node_arena: Vec<RbCopyNode>,
+    root_nodes: Vec<Option<usize>>,
+}
+
+impl<Data: Ord> Default for PathCopyRbTree<Data> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Data: Ord> PathCopyRbTree<Data> {
+    pub fn new() -> Self {
+        PathCopyRbTree {
+            data: Vec::new(),
+            node_arena: Vec::new(),
+            root_nodes: Vec::new(),
+        }
+    }
+
+    fn get_datum(&self, node_ptr: usize) -> &Data {
+        &self.data[self.node_arena[node_ptr].datum_ptr]
+    }
+
+    fn get_node(&self, update_cache: &HashMap<usize, RbCopyNode>, node_ptr: usize) -> RbCopyNode {
+        match update_cache.get(&node_ptr) {
+            Some(node) => *node,
+            None => self.node_arena[node_ptr],
+        }
+    }
+
+    fn latest_root(&self) -> Option<usize> {
+        self.root_nodes.last().and_then(|root| *root)
+    }
+
+    fn get_root(&self, timestamp: usize) -> Option<usize> {
+        self.root_nodes.get(timestamp).and_then(|root| *root)
+    }
+
+    fn compare(&self, item: &Data, node_ptr: usize) -> Ordering {
+        item.cmp(self.get_datum(node_ptr))
+    }
+
+    /// Caches every node of `path` that the update has not copied yet, so
+    /// that nodes modified below it are reachable from the new root
+    fn copy_path(&self, update_cache: &mut HashMap<usize, RbCopyNode>, path: &[usize]) {
+        for &node_ptr in path.iter() {
+            let node = self.get_node(update_cache, node_ptr);
+            update_cache.entry(node_ptr).or_insert(node);
+        }
+    }
+
+    /// Copies every node in `update_cache` reachable from `node_ptr` into the
+    /// arena, children before parents, and returns where `node_ptr` ended up.
+    ///
+    /// Nodes outside of `update_cache` are shared with earlier versions as is.
+    fn commit(
+        &mut self,
+        update_cache: &mut HashMap<usize, RbCopyNode>,
+        node_ptr: Option<usize>,
+    ) -> Option<usize> {
+        let node_ptr = node_ptr?;
+
+        match update_cache.remove(&node_ptr) {
+            Some(node) => {
+                let left = self.commit(update_cache, node.left);
+                let right = self.commit(update_cache, node.right);
+
+                self.node_arena.push(RbCopyNode {
+                    left,
+                    right,
+                    ..node
+                });
+                Some(self.node_arena.len() - 1)
+            }
+            None => Some(node_ptr),
+        }
+    }
+}
+
+impl<Data: Ord> PersistentAvlTree for PathCopyRbTree<Data> {
+    type Data = Data;
+
+    type Timestamp = usize;
+
+    fn insert(&mut self, item: Self::Data) -> Self::Timestamp {
+        let mut update_cache = HashMap::new();
+
+        self.data.push(item);
+        let datum_ptr = self.data.len() - 1;
+        let item = &self.data[datum_ptr];
+
+        // Nodes in `update_cache` keep the pointer of the node they copy until
+        // they are committed, so the new node borrows the first free pointer
+        let new_node_ptr = self.node_arena.len();
+        let new_node = RbCopyNode {
+            datum_ptr,
+            red: true,
+            left: None,
+            right: None,
+        };
+        update_cache.insert(new_node_ptr, new_node);
+
+        let mut path = Vec::new();
+        let mut path_ptr = self.latest_root();
+        while let Some(ptr) = path_ptr {
+            path.push(ptr);
+
+            let node = &self.node_arena[ptr];
+            path_ptr = if *item <= *self.get_datum(ptr) {
+                node.left
+            } else {
+                node.right
+            };
+        }
+
+        if let Some(&parent_ptr) = path.last() {
+            let mut parent = self.node_arena[parent_ptr];
+            if *item <= *self.get_datum(parent_ptr) {
+                parent.left = Some(new_node_ptr);
+            } else {
+                parent.right = Some(new_node_ptr);
+            }
+            update_cache.insert(parent_ptr, parent);
+        }
+        path.push(new_node_ptr);
+
+        let cache = RefCell::new(&mut update_cache);
+        let new_root = rb::insert_fixup(
+            &|node_ptr: usize| self.get_node(&cache.borrow(), node_ptr).left,
+            &|node_ptr| self.get_node(&cache.borrow(), node_ptr).right,
+            &|node_ptr| self.get_node(&cache.borrow(), node_ptr).red,
+            &mut |node_ptr, left, right, red| {
+                let node = self.get_node(&cache.borrow(), node_ptr);
+                cache.borrow_mut().insert(
+                    node_ptr,
+                    RbCopyNode {
+                        left,
+                        right,
+                        red,
+                        ..node
+                    },
+                );
+            },
+            &path,
+        );
+        self.copy_path(&mut update_cache, &path);
+        let new_root = self.commit(&mut update_cache, new_root);
+        self.root_nodes.push(new_root);
+
+        self.root_nodes.len() - 1
+    }
+
+    fn delete(&mut self, item: &Self::Data) -> Option<Self::Timestamp> {
+        let mut update_cache = HashMap::new();
+
+        // Path from the root down to the node to delete
+        let mut path = Vec::new();
+        let mut path_ptr = self.latest_root();
+        loop {
+            let ptr = path_ptr?;
+            path.push(ptr);
+
+            let node = &self.node_arena[ptr];
+            path_ptr = match self.compare(item, ptr) {
+                Ordering::Equal => break,
+                Ordering::Less => node.left,
+                Ordering::Greater => node.right,
+            };
+        }
+
+        let cache = RefCell::new(&mut update_cache);
+        let new_root = rb::delete(
+            &|node_ptr: usize| self.get_node(&cache.borrow(), node_ptr).left,
+            &|node_ptr| self.get_node(&cache.borrow(), node_ptr).right,
+            &|node_ptr| self.get_node(&cache.borrow(), node_ptr).red,
+            &mut |node_ptr, left, right, red| {
+                let node = self.get_node(&cache.borrow(), node_ptr);
+                cache.borrow_mut().insert(
+                    node_ptr,
+                    RbCopyNode {
+                        left,
+                        right,
+                        red,
+                        ..node
+                    },
+                );
+            },
+            &path,
+        );
+        // A node with two children is replaced by its successor, so the path
+        // down to it changes as well
+        let deleted = &self.node_arena[*path.last().unwrap()];
+        if let (Some(_), Some(right)) = (deleted.left, deleted.right) {
+            let mut successor_ptr = Some(right);
+            while let Some(ptr) = successor_ptr {
+                path.push(ptr);
+                successor_ptr = self.node_arena[ptr].left;
+            }
+        }
+        self.copy_path(&mut update_cache, &path);
+        let new_root = self.commit(&mut update_cache, new_root);
+        self.root_nodes.push(new_root);
+
+        Some(self.root_nodes.len() - 1)
+    }
+
+    fn contains(&self, item: &Self::Data, timestamp: Self::Timestamp) -> bool {
+        avl::contains(
+            &|node_ptr: usize| self.node_arena[node_ptr].left,
+            &|node_ptr| self.node_arena[node_ptr].right,
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            self.get_root(timestamp),
+            item,
+        )
+    }
+
+    fn predecessor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        avl::predecessor(
+            &|node_ptr: usize| self.node_arena[node_ptr].left,
+            &|node_ptr| self.node_arena[node_ptr].right,
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            self.get_root(timestamp),
+            item,
+        )
+        .map(|node_ptr| self.get_datum(node_ptr))
+    }
+
+    fn successor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        avl::successor(
+            &|node_ptr: usize| self.node_arena[node_ptr].left,
+            &|node_ptr| self.node_arena[node_ptr].right,
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            self.get_root(timestamp),
+            item,
+        )
+        .map(|node_ptr| self.get_datum(node_ptr))
+    }
+}
+
+impl<Data: Ord> BatchUpdate for PathCopyRbTree<Data> {
+    fn delete_range(&mut self, range: impl RangeBounds<Self::Data>) -> Self::Timestamp {
+        let mut update_cache = HashMap::new();
+
+        let cache = RefCell::new(&mut update_cache);
+        let datum = |node_ptr| &self.data[self.get_node(&cache.borrow(), node_ptr).datum_ptr];
+        let new_root = rb::delete_range(
+            &|node_ptr: usize| self.get_node(&cache.borrow(), node_ptr).left,
+            &|node_ptr| self.get_node(&cache.borrow(), node_ptr).right,
+            &|node_ptr| self.get_node(&cache.borrow(), node_ptr).red,
+            &mut |node_ptr, left, right, red| {
+                let node = self.get_node(&cache.borrow(), node_ptr);
+                cache.borrow_mut().insert(
+                    node_ptr,
+                    RbCopyNode {
+                        left,
+                        right,
+                        red,
+                        ..node
+                    },
+                );
+            },
+            self.latest_root(),
+            &|node_ptr| batch_update::is_before(&range, datum(node_ptr)),
+            &|node_ptr| batch_update::is_after(&range, datum(node_ptr)),
+        );
+        let new_root = self.commit(&mut update_cache, new_root);
+        self.root_nodes.push(new_root);
+
+        self.root_nodes.len() - 1
+    }
+
+    fn insert_sorted_batch(
+        &mut self,
+        iter: impl IntoIterator<Item = Self::Data>,
+    ) -> Result<Self::Timestamp, BulkLoadError> {
+        let batch = bulk_load::collect_sorted(iter)?;
+        let mut update_cache = HashMap::new();
+
+        // The new nodes borrow the free pointers after the arena, like a
+        // single insertion, in the order of their data
+        let first_datum_ptr = self.data.len();
+        let first_node_ptr = self.node_arena.len();
+        self.data.extend(batch);
+
+        let batch_root = bulk_load::build_balanced(
+            first_datum_ptr,
+            self.data.len(),
+            &mut |datum_ptr, left, right, _| {
+                let node_ptr = first_node_ptr + (datum_ptr - first_datum_ptr);
+                update_cache.insert(
+                    node_ptr,
+                    RbCopyNode {
+                        datum_ptr,
+                        red: false,
+                        left,
+                        right,
+                    },
+                );
+
+                node_ptr
+            },
+        );
+
+        let cache = RefCell::new(&mut update_cache);
+        let datum = |node_ptr| &self.data[self.get_node(&cache.borrow(), node_ptr).datum_ptr];
+        let new_root = rb::union(
+            &|node_ptr: usize| self.get_node(&cache.borrow(), node_ptr).left,
+            &|node_ptr| self.get_node(&cache.borrow(), node_ptr).right,
+            &|node_ptr| self.get_node(&cache.borrow(), node_ptr).red,
+            &mut |node_ptr, left, right, red| {
+                let node = self.get_node(&cache.borrow(), node_ptr);
+                cache.borrow_mut().insert(
+                    node_ptr,
+                    RbCopyNode {
+                        left,
+                        right,
+                        red,
+                        ..node
+                    },
+                );
+            },
+            self.latest_root(),
+            batch_root,
+            &|node_ptr, pivot| datum(node_ptr) < datum(pivot),
+        );
+        let new_root = self.commit(&mut update_cache, new_root);
+        self.root_nodes.push(new_root);
+
+        Ok(self.root_nodes.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{random_update, red_black_nodes, Rng};
+
+    #[test]
+    fn every_version_is_a_red_black_tree() {
+        let mut rng = Rng(3);
+        let mut tree = PathCopyRbTree::new();
+        let mut versions: Vec<Vec<u64>> = Vec::new();
+
+        for _ in 0..600 {
+            let mut data = versions.last().cloned().unwrap_or_default();
+            if let Some(timestamp) = random_update(&mut tree, &mut data, &mut rng, 60) {
+                assert_eq!(timestamp, versions.len());
+                versions.push(data);
+            }
+        }
+
+        // Colors are copied along with the nodes, so every version keeps its
+        // own
+        for (timestamp, data) in versions.iter().enumerate() {
+            let nodes = red_black_nodes(
+                tree.get_root(timestamp),
+                |node_ptr| tree.node_arena[node_ptr].left,
+                |node_ptr| tree.node_arena[node_ptr].right,
+                |node_ptr| tree.node_arena[node_ptr].red,
+            );
+            assert!(nodes
+                .into_iter()
+                .map(|node_ptr| tree.get_datum(node_ptr))
+                .eq(data.iter()));
+        }
+    }
+}
@@ -0,0 +1,7 @@
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct RbCopyNode {
+    pub(crate) datum_ptr: usize,
+    pub(crate) red: bool,
+    pub(crate) left: Option<usize>,
+    pub(crate) right: Option<usize>,
+}
@@ -0,0 +1,3 @@
+pub mod path_copy_treap;
+mod priority;
+mod treap_node;
@@ -0,0 +1,392 @@
+use std::cmp::Ordering;
+use std::hash::Hash;
+use std::ops::RangeBounds;
+
+use crate::avl::avl;
+use crate::batch_update::{self, BatchUpdate};
+use crate::bulk_load::bulk_load;
+use crate::bulk_load::error::BulkLoadError;
+use crate::path_copy_treap::priority::priority;
+use crate::path_copy_treap::treap_node::TreapNode;
+use crate::persistent_avl_tree::PersistentAvlTree;
+
+/// A partially persistent treap built with path copying.
+///
+/// Priorities are a seeded hash of each element rather than random draws, so
+/// the same seed and the same updates always build the same tree.
+pub struct PathCopyTreap<Data: Ord + Hash> {
+    data: Vec<Data>,
+    node_arena: Vec<TreapNode>,
+    root_nodes: Vec<Option<usize>>,
+    seed: u64,
+}
+
+impl<Data: Ord + Hash> Default for PathCopyTreap<Data> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Data: Ord + Hash> PathCopyTreap<Data> {
+    pub const DEFAULT_SEED: u64 = 0;
+
+    pub fn new() -> Self {
+        Self::with_seed(Self::DEFAULT_SEED)
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        PathCopyTreap {
+            data: Vec::new(),
+            node_arena: Vec::new(),
+            root_nodes: Vec::new(),
+            seed,
+        }
+    }
+
+    fn get_datum(&self, node_ptr: usize) -> &Data {
+        &self.data[self.node_arena[node_ptr].datum_ptr]
+    }
+
+    fn latest_root(&self) -> Option<usize> {
+        self.root_nodes.last().and_then(|root| *root)
+    }
+
+    fn get_root(&self, timestamp: usize) -> Option<usize> {
+        self.root_nodes.get(timestamp).and_then(|root| *root)
+    }
+
+    fn compare(&self, item: &Data, node_ptr: usize) -> Ordering {
+        item.cmp(self.get_datum(node_ptr))
+    }
+
+    /// Copies `node_ptr` with new children, leaving the original untouched
+    fn copy(&mut self, node_ptr: usize, left: Option<usize>, right: Option<usize>) -> usize {
+        self.node_arena.push(TreapNode {
+            left,
+            right,
+            ..self.node_arena[node_ptr]
+        });
+
+        self.node_arena.len() - 1
+    }
+
+    /// Splits the subtree at `node_ptr` into the elements less than the datum
+    /// at `datum_ptr` and the rest, copying the nodes along the split path
+    fn split(
+        &mut self,
+        node_ptr: Option<usize>,
+        datum_ptr: usize,
+    ) -> (Option<usize>, Option<usize>) {
+        self.split_by(node_ptr, &|treap: &Self, node_ptr| {
+            *treap.get_datum(node_ptr) < treap.data[datum_ptr]
+        })
+    }
+
+    /// Splits the subtree at `node_ptr` into the nodes that `before` holds
+    /// for, which must come first in order, and the rest
+    fn split_by(
+        &mut self,
+        node_ptr: Option<usize>,
+        before: &impl Fn(&Self, usize) -> bool,
+    ) -> (Option<usize>, Option<usize>) {
+        let Some(node_ptr) = node_ptr else {
+            return (None, None);
+        };
+        let node = self.node_arena[node_ptr];
+
+        if before(self, node_ptr) {
+            let (less, rest) = self.split_by(node.right, before);
+            (Some(self.copy(node_ptr, node.left, less)), rest)
+        } else {
+            let (less, rest) = self.split_by(node.left, before);
+            (less, Some(self.copy(node_ptr, rest, node.right)))
+        }
+    }
+
+    /// Joins two subtrees where every element of `left` is at most every
+    /// element of `right`, copying the nodes along the seam
+    fn join(&mut self, left: Option<usize>, right: Option<usize>) -> Option<usize> {
+        let (left_ptr, right_ptr) = match (left, right) {
+            (Some(left_ptr), Some(right_ptr)) => (left_ptr, right_ptr),
+            _ => return left.or(right),
+        };
+        let (left_node, right_node) = (self.node_arena[left_ptr], self.node_arena[right_ptr]);
+
+        if left_node.rank() > right_node.rank() {
+            let joined = self.join(left_node.right, right);
+            Some(self.copy(left_ptr, left_node.left, joined))
+        } else {
+            let joined = self.join(left, right_node.left);
+            Some(self.copy(right_ptr, joined, right_node.right))
+        }
+    }
+
+    /// Builds a treap of the consecutive nodes `start..end`, whose data are
+    /// sorted, linking them in place in linear time
+    fn build(&mut self, start: usize, end: usize) -> Option<usize> {
+        // Right spine of the treap built so far
+        let mut spine: Vec<usize> = Vec::new();
+
+        for node_ptr in start..end {
+            let rank = self.node_arena[node_ptr].rank();
+
+            let mut left = None;
+            while let Some(&top) = spine.last() {
+                if self.node_arena[top].rank() > rank {
+                    break;
+                }
+                left = spine.pop();
+            }
+            self.node_arena[node_ptr].left = left;
+
+            if let Some(&top) = spine.last() {
+                self.node_arena[top].right = Some(node_ptr);
+            }
+            spine.push(node_ptr);
+        }
+
+        spine.first().copied()
+    }
+
+    /// Merges the subtrees at `node_ptr` and `other`, which may overlap in
+    /// order, copying every node it relinks
+    ///
+    /// Takes O(m log(n/m + 1)) expected time, where m is the size of the
+    /// smaller subtree.
+    fn union(&mut self, node_ptr: Option<usize>, other: Option<usize>) -> Option<usize> {
+        let (node_ptr, other) = match (node_ptr, other) {
+            (Some(node_ptr), Some(other)) => (node_ptr, other),
+            _ => return node_ptr.or(other),
+        };
+        let (top_ptr, bottom_ptr) =
+            if self.node_arena[node_ptr].rank() > self.node_arena[other].rank() {
+                (node_ptr, other)
+            } else {
+                (other, node_ptr)
+            };
+        let top = self.node_arena[top_ptr];
+
+        let (less, rest) = self.split(Some(bottom_ptr), top.datum_ptr);
+        let left = self.union(top.left, less);
+        let right = self.union(top.right, rest);
+
+        Some(self.copy(top_ptr, left, right))
+    }
+
+    /// Inserts the node `new_node_ptr` into the subtree at `node_ptr`, and
+    /// returns the new root of the subtree
+    fn insert_node(&mut self, node_ptr: Option<usize>, new_node_ptr: usize) -> usize {
+        let Some(node_ptr) = node_ptr else {
+            return new_node_ptr;
+        };
+        let node = self.node_arena[node_ptr];
+        let new_node = self.node_arena[new_node_ptr];
+
+        // The new node is not shared with any version yet, so it is linked in
+        // place instead of copied
+        if new_node.rank() > node.rank() {
+            let (left, right) = self.split(Some(node_ptr), new_node.datum_ptr);
+            let new_node = &mut self.node_arena[new_node_ptr];
+            new_node.left = left;
+            new_node.right = right;
+
+            return new_node_ptr;
+        }
+
+        if self.data[new_node.datum_ptr] <= *self.get_datum(node_ptr) {
+            let left = self.insert_node(node.left, new_node_ptr);
+            self.copy(node_ptr, Some(left), node.right)
+        } else {
+            let right = self.insert_node(node.right, new_node_ptr);
+            self.copy(node_ptr, node.left, Some(right))
+        }
+    }
+}
+
+impl<Data: Ord + Hash> PersistentAvlTree for PathCopyTreap<Data> {
+    type Data = Data;
+
+    type Timestamp = usize;
+
+    fn insert(&mut self, item: Self::Data) -> Self::Timestamp {
+        let priority = priority(&item, self.seed);
+
+        self.data.push(item);
+        self.node_arena.push(TreapNode {
+            datum_ptr: self.data.len() - 1,
+            priority,
+            left: None,
+            right: None,
+        });
+        let new_node_ptr = self.node_arena.len() - 1;
+
+        let new_root = self.insert_node(self.latest_root(), new_node_ptr);
+        self.root_nodes.push(Some(new_root));
+
+        self.root_nodes.len() - 1
+    }
+
+    fn delete(&mut self, item: &Self::Data) -> Option<Self::Timestamp> {
+        // Path from the root down to the node to delete
+        let mut path = Vec::new();
+        let mut path_ptr = self.latest_root();
+        loop {
+            let ptr = path_ptr?;
+            path.push(ptr);
+
+            let node = &self.node_arena[ptr];
+            path_ptr = match self.compare(item, ptr) {
+                Ordering::Equal => break,
+                Ordering::Less => node.left,
+                Ordering::Greater => node.right,
+            };
+        }
+
+        // The children of the deleted node take its place, and the path above
+        // it is copied bottom up
+        let mut old_ptr = path.pop().unwrap();
+        let deleted = self.node_arena[old_ptr];
+        let mut new_ptr = self.join(deleted.left, deleted.right);
+        while let Some(parent_ptr) = path.pop() {
+            let parent = self.node_arena[parent_ptr];
+            new_ptr = Some(if parent.left == Some(old_ptr) {
+                self.copy(parent_ptr, new_ptr, parent.right)
+            } else {
+                self.copy(parent_ptr, parent.left, new_ptr)
+            });
+            old_ptr = parent_ptr;
+        }
+        self.root_nodes.push(new_ptr);
+
+        Some(self.root_nodes.len() - 1)
+    }
+
+    fn contains(&self, item: &Self::Data, timestamp: Self::Timestamp) -> bool {
+        avl::contains(
+            &|node_ptr: usize| self.node_arena[node_ptr].left,
+            &|node_ptr| self.node_arena[node_ptr].right,
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            self.get_root(timestamp),
+            item,
+        )
+    }
+
+    fn predecessor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        avl::predecessor(
+            &|node_ptr: usize| self.node_arena[node_ptr].left,
+            &|node_ptr| self.node_arena[node_ptr].right,
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            self.get_root(timestamp),
+            item,
+        )
+        .map(|node_ptr| self.get_datum(node_ptr))
+    }
+
+    fn successor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        avl::successor(
+            &|node_ptr: usize| self.node_arena[node_ptr].left,
+            &|node_ptr| self.node_arena[node_ptr].right,
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            self.get_root(timestamp),
+            item,
+        )
+        .map(|node_ptr| self.get_datum(node_ptr))
+    }
+}
+
+impl<Data: Ord + Hash> BatchUpdate for PathCopyTreap<Data> {
+    fn delete_range(&mut self, range: impl RangeBounds<Self::Data>) -> Self::Timestamp {
+        let (less, rest) = self.split_by(self.latest_root(), &|treap: &Self, node_ptr| {
+            batch_update::is_before(&range, treap.get_datum(node_ptr))
+        });
+        let (_, greater) = self.split_by(rest, &|treap: &Self, node_ptr| {
+            !batch_update::is_after(&range, treap.get_datum(node_ptr))
+        });
+
+        let new_root = self.join(less, greater);
+        self.root_nodes.push(new_root);
+
+        self.root_nodes.len() - 1
+    }
+
+    fn insert_sorted_batch(
+        &mut self,
+        iter: impl IntoIterator<Item = Self::Data>,
+    ) -> Result<Self::Timestamp, BulkLoadError> {
+        let batch = bulk_load::collect_sorted(iter)?;
+
+        let start = self.node_arena.len();
+        for item in batch {
+            let priority = priority(&item, self.seed);
+
+            self.data.push(item);
+            self.node_arena.push(TreapNode {
+                datum_ptr: self.data.len() - 1,
+                priority,
+                left: None,
+                right: None,
+            });
+        }
+        let batch_root = self.build(start, self.node_arena.len());
+
+        let new_root = self.union(self.latest_root(), batch_root);
+        self.root_nodes.push(new_root);
+
+        Ok(self.root_nodes.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{random_update, Rng};
+
+    /// Checks that the version at `timestamp` is in heap order by rank, with
+    /// every priority the hash of its datum, and returns its data in order
+    fn treap_data(tree: &PathCopyTreap<u64>, timestamp: usize) -> Vec<u64> {
+        fn walk(tree: &PathCopyTreap<u64>, node_ptr: Option<usize>, data: &mut Vec<u64>) {
+            let Some(node_ptr) = node_ptr else {
+                return;
+            };
+            let node = tree.node_arena[node_ptr];
+            assert_eq!(node.priority, priority(tree.get_datum(node_ptr), tree.seed));
+
+            for child_ptr in [node.left, node.right].into_iter().flatten() {
+                assert!(
+                    tree.node_arena[child_ptr].rank() < node.rank(),
+                    "child outranks its parent"
+                );
+            }
+
+            walk(tree, node.left, data);
+            data.push(*tree.get_datum(node_ptr));
+            walk(tree, node.right, data);
+        }
+
+        let mut data = Vec::new();
+        walk(tree, tree.get_root(timestamp), &mut data);
+
+        data
+    }
+
+    #[test]
+    fn every_version_is_a_treap() {
+        for seed in [PathCopyTreap::<u64>::DEFAULT_SEED, 1, 0xdead_beef] {
+            let mut rng = Rng(seed + 1);
+            let mut tree = PathCopyTreap::with_seed(seed);
+            let mut versions: Vec<Vec<u64>> = Vec::new();
+
+            for _ in 0..600 {
+                let mut data = versions.last().cloned().unwrap_or_default();
+                if let Some(timestamp) = random_update(&mut tree, &mut data, &mut rng, 60) {
+                    assert_eq!(timestamp, versions.len());
+                    versions.push(data);
+                }
+            }
+
+            for (timestamp, data) in versions.iter().enumerate() {
+                assert_eq!(&treap_data(&tree, timestamp), data);
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::hash::{Hash, Hasher};
+
+/// FNV-1a over the bytes of an element, finished with the splitmix64 mixer.
+///
+/// Unlike `DefaultHasher`, the output is fixed across Rust versions and
+/// platforms, so a seed always reproduces the same tree.
+struct PriorityHasher {
+    state: u64,
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn mix(mut value: u64) -> u64 {
+    value = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+impl Hasher for PriorityHasher {
+    fn finish(&self) -> u64 {
+        mix(self.state)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.state ^= byte as u64;
+            self.state = self.state.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    // Integers are hashed little endian on every platform
+    fn write_u16(&mut self, value: u16) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, value: u128) {
+        self.write(&value.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+}
+
+/// Priority of `item` in a treap seeded with `seed`
+pub(crate) fn priority<Data: Hash>(item: &Data, seed: u64) -> u64 {
+    let mut hasher = PriorityHasher {
+        state: FNV_OFFSET ^ mix(seed),
+    };
+    item.hash(&mut hasher);
+
+    hasher.finish()
+}
@@ -0,0 +1,17 @@
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct TreapNode {
+    pub(crate) datum_ptr: usize,
+    pub(crate) priority: u64,
+    pub(crate) left: Option<usize>,
+    pub(crate) right: Option<usize>,
+}
+
+impl TreapNode {
+    /// Heap order of the node, higher ranks sit closer to the root.
+    ///
+    /// Equal elements hash to equal priorities, so ties are broken by
+    /// insertion order.
+    pub(crate) fn rank(&self) -> (u64, usize) {
+        (self.priority, self.datum_ptr)
+    }
+}
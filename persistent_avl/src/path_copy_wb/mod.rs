@@ -0,0 +1,2 @@
+pub mod path_copy_wb;
+mod size_node;
@@ -0,0 +1,653 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::RangeBounds;
+
+use crate::avl::avl;
+use crate::batch_update::{self, BatchUpdate};
+use crate::bulk_load::bulk_load;
+use crate::bulk_load::error::BulkLoadError;
+use crate::path_copy_wb::size_node::SizeNode;
+use crate::persistent_avl_tree::PersistentAvlTree;
+use crate::wb::wb;
+
+/// A partially persistent weight-balanced tree built with path copying.
+///
+/// Every node knows the size of its subtree, which is both what balancing
+/// works from and what answers `rank` and `select` in O(log n).
+pub struct PathCopyWbTree<Data: Ord> {
+    data: Vec<Data>,
+    node_arena: Vec<SizeNode>,
+    root_nodes: Vec<Option<usize>>,
+}
+
+impl<Data: Ord> Default for PathCopyWbTree<Data> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Data: Ord> PathCopyWbTree<Data> {
+    pub fn new() -> Self {
+        PathCopyWbTree {
+            data: Vec::new(),
+            node_arena: Vec::new(),
+            root_nodes: Vec::new(),
+        }
+    }
+
+    fn get_datum(&self, node_ptr: usize) -> &Data {
+        &self.data[self.node_arena[node_ptr].datum_ptr]
+    }
+
+    fn get_node(&self, update_cache: &HashMap<usize, SizeNode>, node_ptr: usize) -> SizeNode {
+        match update_cache.get(&node_ptr) {
+            Some(node) => *node,
+            None => self.node_arena[node_ptr],
+        }
+    }
+
+    fn latest_root(&self) -> Option<usize> {
+        self.root_nodes.last().and_then(|root| *root)
+    }
+
+    fn get_root(&self, timestamp: usize) -> Option<usize> {
+        self.root_nodes.get(timestamp).and_then(|root| *root)
+    }
+
+    fn compare(&self, item: &Data, node_ptr: usize) -> Ordering {
+        item.cmp(self.get_datum(node_ptr))
+    }
+
+    /// Sets the children of `node_ptr`, recomputing its size from theirs
+    fn modify(
+        &self,
+        update_cache: &mut HashMap<usize, SizeNode>,
+        node_ptr: usize,
+        left: Option<usize>,
+        right: Option<usize>,
+    ) {
+        let size =
+            |child: Option<usize>| child.map_or(0, |ptr| self.get_node(update_cache, ptr).size);
+
+        let node = SizeNode {
+            size: size(left) + size(right) + 1,
+            left,
+            right,
+            ..self.get_node(update_cache, node_ptr)
+        };
+        update_cache.insert(node_ptr, node);
+    }
+
+    fn modify_node_left(
+        &self,
+        update_cache: &mut HashMap<usize, SizeNode>,
+        node_ptr: usize,
+        new_left_ptr: Option<usize>,
+    ) {
+        let right = self.get_node(update_cache, node_ptr).right;
+        self.modify(update_cache, node_ptr, new_left_ptr, right);
+    }
+
+    fn modify_node_right(
+        &self,
+        update_cache: &mut HashMap<usize, SizeNode>,
+        node_ptr: usize,
+        new_right_ptr: Option<usize>,
+    ) {
+        let left = self.get_node(update_cache, node_ptr).left;
+        self.modify(update_cache, node_ptr, left, new_right_ptr);
+    }
+
+    /// Recalculates the sizes and rebalances the tree up `path`, recording
+    /// every modified node in `update_cache`
+    ///
+    /// Returns the element at the root of `path` after modifications are complete
+    fn balance_and_clone(
+        &self,
+        update_cache: &mut HashMap<usize, SizeNode>,
+        path: Vec<usize>,
+    ) -> Option<usize> {
+        let update_cache = RefCell::new(update_cache);
+
+        wb::balance(
+            &|node_ptr| self.get_node(&update_cache.borrow(), node_ptr).left,
+            &|node_ptr| self.get_node(&update_cache.borrow(), node_ptr).right,
+            &|node_ptr| self.get_node(&update_cache.borrow(), node_ptr).size,
+            &mut |node_ptr, left, right, _| {
+                self.modify(&mut update_cache.borrow_mut(), node_ptr, left, right)
+            },
+            &path,
+        )
+    }
+
+    fn is_balanced(
+        &self,
+        update_cache: &HashMap<usize, SizeNode>,
+        left: Option<usize>,
+        right: Option<usize>,
+    ) -> bool {
+        wb::balanced(
+            &|node_ptr| self.get_node(update_cache, node_ptr).size,
+            left,
+            right,
+        )
+    }
+
+    /// Joins the trees at `left` and `right` with `pivot`, which is in
+    /// neither, between them, and returns the root of the joined tree
+    ///
+    /// Everything in `left` must be at most the pivot, and everything in
+    /// `right` at least. The pivot hangs from the spine of the heavier tree
+    /// where the weights are balanced, and the spine is rebalanced above it.
+    fn join(
+        &self,
+        update_cache: &mut HashMap<usize, SizeNode>,
+        left: Option<usize>,
+        pivot: usize,
+        right: Option<usize>,
+    ) -> usize {
+        let size = |update_cache: &HashMap<usize, SizeNode>, node_ptr: Option<usize>| {
+            node_ptr.map_or(0, |ptr| self.get_node(update_cache, ptr).size)
+        };
+        if self.is_balanced(update_cache, left, right) {
+            self.modify(update_cache, pivot, left, right);
+            return pivot;
+        }
+        let left_is_heavier = size(update_cache, left) > size(update_cache, right);
+
+        // Descend the inner spine of the heavier tree down to a subtree that
+        // the lighter tree balances
+        let mut path = Vec::new();
+        let mut spine_ptr = if left_is_heavier { left } else { right };
+        while let Some(ptr) = spine_ptr {
+            let (inner, lighter) = if left_is_heavier {
+                (self.get_node(update_cache, ptr).right, right)
+            } else {
+                (self.get_node(update_cache, ptr).left, left)
+            };
+            path.push(ptr);
+
+            if self.is_balanced(update_cache, inner, lighter) {
+                if left_is_heavier {
+                    self.modify(update_cache, pivot, inner, right);
+                    self.modify_node_right(update_cache, ptr, Some(pivot));
+                } else {
+                    self.modify(update_cache, pivot, left, inner);
+                    self.modify_node_left(update_cache, ptr, Some(pivot));
+                }
+                path.push(pivot);
+                break;
+            }
+            spine_ptr = inner;
+        }
+
+        self.balance_and_clone(update_cache, path).unwrap()
+    }
+
+    /// Joins the trees at `left` and `right`, where everything in `left` is
+    /// at most everything in `right`, and returns the new root
+    fn join_trees(
+        &self,
+        update_cache: &mut HashMap<usize, SizeNode>,
+        left: Option<usize>,
+        right: Option<usize>,
+    ) -> Option<usize> {
+        let Some(right_ptr) = right else {
+            return left;
+        };
+        let (first, rest) = self.split_first(update_cache, right_ptr);
+
+        Some(self.join(update_cache, left, first, rest))
+    }
+
+    /// Unlinks the first node of the tree at `node_ptr`, returning it and the
+    /// root of the remaining tree
+    fn split_first(
+        &self,
+        update_cache: &mut HashMap<usize, SizeNode>,
+        node_ptr: usize,
+    ) -> (usize, Option<usize>) {
+        let node = self.get_node(update_cache, node_ptr);
+        let Some(left) = node.left else {
+            return (node_ptr, node.right);
+        };
+
+        let (first, rest) = self.split_first(update_cache, left);
+        (
+            first,
+            Some(self.join(update_cache, rest, node_ptr, node.right)),
+        )
+    }
+
+    /// Splits the tree at `node_ptr` into the nodes whose data `before`
+    /// holds for, which must come first in order, and the rest
+    fn split_by(
+        &self,
+        update_cache: &mut HashMap<usize, SizeNode>,
+        node_ptr: Option<usize>,
+        before: &impl Fn(&Data) -> bool,
+    ) -> (Option<usize>, Option<usize>) {
+        let Some(node_ptr) = node_ptr else {
+            return (None, None);
+        };
+        let node = self.get_node(update_cache, node_ptr);
+
+        if before(&self.data[node.datum_ptr]) {
+            let (less, rest) = self.split_by(update_cache, node.right, before);
+            (
+                Some(self.join(update_cache, node.left, node_ptr, less)),
+                rest,
+            )
+        } else {
+            let (less, rest) = self.split_by(update_cache, node.left, before);
+            (
+                less,
+                Some(self.join(update_cache, rest, node_ptr, node.right)),
+            )
+        }
+    }
+
+    /// Merges the tree at `other`, whose nodes are in no version yet, into
+    /// the tree at `node_ptr`, and returns the new root
+    ///
+    /// The tree at `node_ptr` is split at every node of `other`, which
+    /// becomes the pivot joining the halves merged below it.
+    fn union(
+        &self,
+        update_cache: &mut HashMap<usize, SizeNode>,
+        node_ptr: Option<usize>,
+        other: Option<usize>,
+    ) -> Option<usize> {
+        let Some(other) = other else {
+            return node_ptr;
+        };
+        let pivot = self.get_node(update_cache, other);
+        let item = &self.data[pivot.datum_ptr];
+
+        let (less, rest) = self.split_by(update_cache, node_ptr, &|datum| datum < item);
+        let left = self.union(update_cache, less, pivot.left);
+        let right = self.union(update_cache, rest, pivot.right);
+
+        Some(self.join(update_cache, left, other, right))
+    }
+
+    /// Copies every node in `update_cache` reachable from `node_ptr` into the
+    /// arena, children before parents, and returns where `node_ptr` ended up.
+    ///
+    /// Nodes outside of `update_cache` are shared with earlier versions as is.
+    fn commit(
+        &mut self,
+        update_cache: &mut HashMap<usize, SizeNode>,
+        node_ptr: Option<usize>,
+    ) -> Option<usize> {
+        let node_ptr = node_ptr?;
+
+        match update_cache.remove(&node_ptr) {
+            Some(node) => {
+                let left = self.commit(update_cache, node.left);
+                let right = self.commit(update_cache, node.right);
+
+                self.node_arena.push(SizeNode {
+                    left,
+                    right,
+                    ..node
+                });
+                Some(self.node_arena.len() - 1)
+            }
+            None => Some(node_ptr),
+        }
+    }
+
+    /// Number of elements less than `item` at `timestamp`
+    pub fn rank(&self, item: &Data, timestamp: usize) -> usize {
+        wb::rank(
+            &|node_ptr: usize| self.node_arena[node_ptr].left,
+            &|node_ptr| self.node_arena[node_ptr].right,
+            &|node_ptr| self.node_arena[node_ptr].size,
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            self.get_root(timestamp),
+            item,
+        ) as usize
+    }
+
+    /// Number of elements at most `item` at `timestamp`
+    pub fn rank_at_most(&self, item: &Data, timestamp: usize) -> usize {
+        wb::rank(
+            &|node_ptr: usize| self.node_arena[node_ptr].left,
+            &|node_ptr| self.node_arena[node_ptr].right,
+            &|node_ptr| self.node_arena[node_ptr].size,
+            // Equal elements count as less, so the search passes them
+            &|item: &Data, node_ptr| self.compare(item, node_ptr).then(Ordering::Greater),
+            self.get_root(timestamp),
+            item,
+        ) as usize
+    }
+
+    /// The element at `index` in sorted order at `timestamp`, counting from 0
+    pub fn select(&self, index: usize, timestamp: usize) -> Option<&Data> {
+        wb::select(
+            &|node_ptr: usize| self.node_arena[node_ptr].left,
+            &|node_ptr| self.node_arena[node_ptr].right,
+            &|node_ptr| self.node_arena[node_ptr].size,
+            self.get_root(timestamp),
+            index as u64,
+        )
+        .map(|node_ptr| self.get_datum(node_ptr))
+    }
+
+    /// Number of elements at `timestamp`
+    pub fn len(&self, timestamp: usize) -> usize {
+        self.get_root(timestamp)
+            .map_or(0, |root| self.node_arena[root].size as usize)
+    }
+
+    pub fn is_empty(&self, timestamp: usize) -> bool {
+        self.get_root(timestamp).is_none()
+    }
+}
+
+impl<Data: Ord> PersistentAvlTree for PathCopyWbTree<Data> {
+    type Data = Data;
+
+    type Timestamp = usize;
+
+    fn insert(&mut self, item: Self::Data) -> Self::Timestamp {
+        let mut update_cache = HashMap::new();
+
+        self.data.push(item);
+        let datum_ptr = self.data.len() - 1;
+        let item = &self.data[datum_ptr];
+
+        // Nodes in `update_cache` keep the pointer of the node they copy until
+        // they are committed, so the new node borrows the first free pointer
+        let new_node_ptr = self.node_arena.len();
+        update_cache.insert(
+            new_node_ptr,
+            SizeNode {
+                datum_ptr,
+                size: 1,
+                left: None,
+                right: None,
+            },
+        );
+
+        let mut path = Vec::new();
+        let mut path_ptr = self.latest_root();
+        while let Some(ptr) = path_ptr {
+            path.push(ptr);
+
+            let node = &self.node_arena[ptr];
+            path_ptr = if *item <= *self.get_datum(ptr) {
+                node.left
+            } else {
+                node.right
+            };
+        }
+
+        if let Some(&parent_ptr) = path.last() {
+            if *item <= *self.get_datum(parent_ptr) {
+                self.modify_node_left(&mut update_cache, parent_ptr, Some(new_node_ptr));
+            } else {
+                self.modify_node_right(&mut update_cache, parent_ptr, Some(new_node_ptr));
+            }
+        }
+        path.push(new_node_ptr);
+
+        let new_root = self.balance_and_clone(&mut update_cache, path);
+        let new_root = self.commit(&mut update_cache, new_root);
+        self.root_nodes.push(new_root);
+
+        self.root_nodes.len() - 1
+    }
+
+    fn delete(&mut self, item: &Self::Data) -> Option<Self::Timestamp> {
+        let mut update_cache = HashMap::new();
+
+        // Path from the root down to the parent of the node to delete
+        let mut path = Vec::new();
+        let mut parent_ptr = None;
+        let mut child_ptr = self.latest_root()?;
+        loop {
+            let node = &self.node_arena[child_ptr];
+            let next = match self.compare(item, child_ptr) {
+                Ordering::Equal => break,
+                Ordering::Less => node.left,
+                Ordering::Greater => node.right,
+            };
+
+            path.push(child_ptr);
+            parent_ptr = Some(child_ptr);
+            child_ptr = next?;
+        }
+
+        let left_of_deleted = self.node_arena[child_ptr].left;
+        let right_of_deleted = self.node_arena[child_ptr].right;
+
+        let replacement = match left_of_deleted.zip(right_of_deleted) {
+            // With two children, the deleted node is replaced by its successor,
+            // the leftmost node of its right subtree
+            Some((_, right_subtree_ptr)) => {
+                let mut sup_ptr = right_subtree_ptr;
+                let mut displaced_path = Vec::new();
+
+                while let Some(lesser) = self.node_arena[sup_ptr].left {
+                    displaced_path.push(sup_ptr);
+                    sup_ptr = lesser;
+                }
+
+                if let Some(&sup_parent_ptr) = displaced_path.last() {
+                    let right_of_sup = self.node_arena[sup_ptr].right;
+                    self.modify_node_left(&mut update_cache, sup_parent_ptr, right_of_sup);
+                    self.modify_node_right(&mut update_cache, sup_ptr, right_of_deleted);
+                }
+                self.modify_node_left(&mut update_cache, sup_ptr, left_of_deleted);
+
+                path.push(sup_ptr);
+                path.append(&mut displaced_path);
+
+                Some(sup_ptr)
+            }
+            // With at most one child, the deleted node is replaced by that child
+            None => left_of_deleted.or(right_of_deleted),
+        };
+
+        if let Some(parent_ptr) = parent_ptr {
+            if self.node_arena[parent_ptr].left == Some(child_ptr) {
+                self.modify_node_left(&mut update_cache, parent_ptr, replacement);
+            } else {
+                self.modify_node_right(&mut update_cache, parent_ptr, replacement);
+            }
+        }
+
+        // An empty path means the root itself was removed
+        let new_root = if path.is_empty() {
+            replacement
+        } else {
+            self.balance_and_clone(&mut update_cache, path)
+        };
+        let new_root = self.commit(&mut update_cache, new_root);
+        self.root_nodes.push(new_root);
+
+        Some(self.root_nodes.len() - 1)
+    }
+
+    fn contains(&self, item: &Self::Data, timestamp: Self::Timestamp) -> bool {
+        avl::contains(
+            &|node_ptr: usize| self.node_arena[node_ptr].left,
+            &|node_ptr| self.node_arena[node_ptr].right,
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            self.get_root(timestamp),
+            item,
+        )
+    }
+
+    fn predecessor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        avl::predecessor(
+            &|node_ptr: usize| self.node_arena[node_ptr].left,
+            &|node_ptr| self.node_arena[node_ptr].right,
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            self.get_root(timestamp),
+            item,
+        )
+        .map(|node_ptr| self.get_datum(node_ptr))
+    }
+
+    fn successor(&self, item: &Self::Data, timestamp: Self::Timestamp) -> Option<&Self::Data> {
+        avl::successor(
+            &|node_ptr: usize| self.node_arena[node_ptr].left,
+            &|node_ptr| self.node_arena[node_ptr].right,
+            &|item: &Data, node_ptr| self.compare(item, node_ptr),
+            self.get_root(timestamp),
+            item,
+        )
+        .map(|node_ptr| self.get_datum(node_ptr))
+    }
+}
+
+impl<Data: Ord> BatchUpdate for PathCopyWbTree<Data> {
+    fn delete_range(&mut self, range: impl RangeBounds<Self::Data>) -> Self::Timestamp {
+        let mut update_cache = HashMap::new();
+
+        let (less, rest) = self.split_by(&mut update_cache, self.latest_root(), &|datum| {
+            batch_update::is_before(&range, datum)
+        });
+        let (_, greater) = self.split_by(&mut update_cache, rest, &|datum| {
+            !batch_update::is_after(&range, datum)
+        });
+
+        let new_root = self.join_trees(&mut update_cache, less, greater);
+        let new_root = self.commit(&mut update_cache, new_root);
+        self.root_nodes.push(new_root);
+
+        self.root_nodes.len() - 1
+    }
+
+    fn insert_sorted_batch(
+        &mut self,
+        iter: impl IntoIterator<Item = Self::Data>,
+    ) -> Result<Self::Timestamp, BulkLoadError> {
+        let batch = bulk_load::collect_sorted(iter)?;
+        let mut update_cache = HashMap::new();
+
+        // The new nodes borrow the free pointers after the arena, like a
+        // single insertion, in the order of their data
+        let first_datum_ptr = self.data.len();
+        let first_node_ptr = self.node_arena.len();
+        self.data.extend(batch);
+
+        let batch_root = bulk_load::build_balanced(
+            first_datum_ptr,
+            self.data.len(),
+            &mut |datum_ptr, left, right, _| {
+                let node_ptr = first_node_ptr + (datum_ptr - first_datum_ptr);
+                update_cache.insert(
+                    node_ptr,
+                    SizeNode {
+                        datum_ptr,
+                        size: 0,
+                        left: None,
+                        right: None,
+                    },
+                );
+                self.modify(&mut update_cache, node_ptr, left, right);
+
+                node_ptr
+            },
+        );
+
+        let new_root = self.union(&mut update_cache, self.latest_root(), batch_root);
+        let new_root = self.commit(&mut update_cache, new_root);
+        self.root_nodes.push(new_root);
+
+        Ok(self.root_nodes.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{random_update, Rng};
+
+    #[test]
+    fn every_version_ranks_and_selects_as_a_sorted_vec() {
+        let mut rng = Rng(11);
+        let mut tree = PathCopyWbTree::new();
+        let mut versions: Vec<Vec<u64>> = Vec::new();
+
+        // Few distinct items, so that most versions hold duplicates
+        for _ in 0..500 {
+            let mut data = versions.last().cloned().unwrap_or_default();
+            let Some(timestamp) = random_update(&mut tree, &mut data, &mut rng, 30) else {
+                continue;
+            };
+            assert_eq!(timestamp, versions.len());
+            versions.push(data);
+        }
+
+        for (timestamp, data) in versions.iter().enumerate() {
+            assert_eq!(tree.len(timestamp), data.len());
+            assert_eq!(tree.is_empty(timestamp), data.is_empty());
+
+            for item in 0..=30 {
+                let less = data.partition_point(|&datum| datum < item);
+                let at_most = data.partition_point(|&datum| datum <= item);
+                assert_eq!(tree.rank(&item, timestamp), less);
+                assert_eq!(tree.rank_at_most(&item, timestamp), at_most);
+            }
+
+            for index in 0..data.len() + 3 {
+                assert_eq!(tree.select(index, timestamp), data.get(index));
+            }
+        }
+    }
+
+    /// Data of the version at `timestamp` in order, checking that every node
+    /// knows the size of its subtree and is BB[2/7] balanced, counting each
+    /// subtree one heavier than its size
+    fn weight_balanced_data(tree: &PathCopyWbTree<u64>, timestamp: usize) -> Vec<u64> {
+        fn walk(tree: &PathCopyWbTree<u64>, node: Option<usize>, data: &mut Vec<u64>) -> u64 {
+            let Some(node_ptr) = node else {
+                return 0;
+            };
+            let node = tree.node_arena[node_ptr];
+
+            let left = walk(tree, node.left, data);
+            data.push(*tree.get_datum(node_ptr));
+            let right = walk(tree, node.right, data);
+
+            assert_eq!(node.size, left + right + 1);
+            let (left, right) = (left + 1, right + 1);
+            assert!(7 * left.min(right) >= 2 * (left + right), "unbalanced node");
+
+            node.size
+        }
+
+        let mut data = Vec::new();
+        walk(tree, tree.get_root(timestamp), &mut data);
+
+        data
+    }
+
+    #[test]
+    fn every_version_is_weight_balanced() {
+        let mut rng = Rng(23);
+        let mut tree = PathCopyWbTree::new();
+        let mut versions: Vec<Vec<u64>> = Vec::new();
+
+        for _ in 0..800 {
+            let mut data = versions.last().cloned().unwrap_or_default();
+            let Some(timestamp) = random_update(&mut tree, &mut data, &mut rng, 200) else {
+                continue;
+            };
+            assert_eq!(timestamp, versions.len());
+            versions.push(data);
+        }
+
+        // Path copying leaves old versions as they were, so checking them all
+        // at the end also checks that no update touched an older one
+        for (timestamp, data) in versions.iter().enumerate() {
+            assert_eq!(&weight_balanced_data(&tree, timestamp), data);
+        }
+    }
+}
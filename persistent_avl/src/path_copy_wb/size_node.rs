@@ -0,0 +1,8 @@
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct SizeNode {
+    pub(crate) datum_ptr: usize,
+    /// Number of nodes in the subtree
+    pub(crate) size: u64,
+    pub(crate) left: Option<usize>,
+    pub(crate) right: Option<usize>,
+}
@@ -0,0 +1,10 @@
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum IndexNode {
+    /// Children covering the lower and upper half of the node's indices
+    Branch {
+        left: Option<usize>,
+        right: Option<usize>,
+    },
+    /// Pointer to the value at one index
+    Leaf(usize),
+}
@@ -0,0 +1,3 @@
+mod index_node;
+pub mod path_copy_array;
+pub mod persistent_array;
@@ -0,0 +1,219 @@
+use crate::persistent_array::index_node::IndexNode;
+
+/// Root of one version, a complete binary tree of the given depth whose
+/// leaves are indices 0 to `len`
+#[derive(Debug, Copy, Clone)]
+struct Version {
+    root: Option<usize>,
+    len: usize,
+    depth: u32,
+}
+
+/// A partially persistent growable array built with path copying.
+///
+/// Each version is a binary tree over the bits of the indices, so any value
+/// of any version is reached in O(log n) without searching histories, and
+/// every update copies O(log n) nodes.
+///
+/// As with `PersistentArray`, every update is a new version with its own
+/// timestamp, and timestamps past the newest version see the newest version.
+pub struct PathCopyArray<T> {
+    data: Vec<T>,
+    node_arena: Vec<IndexNode>,
+    versions: Vec<Version>,
+}
+
+impl<T> Default for PathCopyArray<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> PathCopyArray<T> {
+    pub fn new() -> Self {
+        PathCopyArray {
+            data: Vec::new(),
+            node_arena: Vec::new(),
+            versions: Vec::new(),
+        }
+    }
+
+    fn latest_version(&self) -> Version {
+        self.versions.last().copied().unwrap_or(Version {
+            root: None,
+            len: 0,
+            depth: 0,
+        })
+    }
+
+    /// The version in effect at `timestamp`
+    fn version_at(&self, timestamp: u64) -> Option<&Version> {
+        let index = usize::try_from(timestamp).unwrap_or(usize::MAX);
+        self.versions.get(index).or(self.versions.last())
+    }
+
+    /// Records `version` as the newest, returning its timestamp
+    fn push_version(&mut self, version: Version) -> u64 {
+        self.versions.push(version);
+        self.versions.len() as u64 - 1
+    }
+
+    fn push_node(&mut self, node: IndexNode) -> usize {
+        self.node_arena.push(node);
+        self.node_arena.len() - 1
+    }
+
+    /// Copies the path from `node_ptr`, at `depth` levels above the leaves,
+    /// down to `index`, ending in `leaf`. Missing nodes are created.
+    fn set_leaf(
+        &mut self,
+        node_ptr: Option<usize>,
+        depth: u32,
+        index: usize,
+        leaf: usize,
+    ) -> usize {
+        if depth == 0 {
+            return self.push_node(IndexNode::Leaf(leaf));
+        }
+
+        let (left, right) = match node_ptr.map(|ptr| self.node_arena[ptr]) {
+            Some(IndexNode::Branch { left, right }) => (left, right),
+            _ => (None, None),
+        };
+
+        let node = if (index >> (depth - 1)) & 1 == 0 {
+            let left = self.set_leaf(left, depth - 1, index, leaf);
+            IndexNode::Branch {
+                left: Some(left),
+                right,
+            }
+        } else {
+            let right = self.set_leaf(right, depth - 1, index, leaf);
+            IndexNode::Branch {
+                left,
+                right: Some(right),
+            }
+        };
+
+        self.push_node(node)
+    }
+
+    /// Appends `value`, returning the timestamp of the new version
+    pub fn push(&mut self, value: T) -> u64 {
+        let mut version = self.latest_version();
+
+        // A full tree grows a level, with the old tree as its lower half
+        if version.len == 1 << version.depth && version.root.is_some() {
+            version.root = Some(self.push_node(IndexNode::Branch {
+                left: version.root,
+                right: None,
+            }));
+            version.depth += 1;
+        }
+
+        self.data.push(value);
+        let root = self.set_leaf(
+            version.root,
+            version.depth,
+            version.len,
+            self.data.len() - 1,
+        );
+
+        self.push_version(Version {
+            root: Some(root),
+            len: version.len + 1,
+            depth: version.depth,
+        })
+    }
+
+    /// Overwrites the value at `index`, returning the timestamp of the new
+    /// version
+    ///
+    /// Panics if `index` is out of bounds in the newest version.
+    pub fn set(&mut self, index: usize, value: T) -> u64 {
+        let version = self.latest_version();
+        assert!(
+            index < version.len,
+            "index out of bounds: the len is {} but the index is {index}",
+            version.len
+        );
+
+        self.data.push(value);
+        let root = self.set_leaf(version.root, version.depth, index, self.data.len() - 1);
+
+        self.push_version(Version {
+            root: Some(root),
+            ..version
+        })
+    }
+
+    /// The value at `index` at `timestamp`
+    pub fn get(&self, index: usize, timestamp: u64) -> Option<&T> {
+        let version = self.version_at(timestamp)?;
+        if index >= version.len {
+            return None;
+        }
+
+        let mut node_ptr = version.root?;
+        for level in (0..version.depth).rev() {
+            let IndexNode::Branch { left, right } = self.node_arena[node_ptr] else {
+                return None;
+            };
+
+            node_ptr = if (index >> level) & 1 == 0 {
+                left
+            } else {
+                right
+            }?;
+        }
+
+        match self.node_arena[node_ptr] {
+            IndexNode::Leaf(datum_ptr) => Some(&self.data[datum_ptr]),
+            IndexNode::Branch { .. } => None,
+        }
+    }
+
+    /// Number of values at `timestamp`
+    pub fn len(&self, timestamp: u64) -> usize {
+        self.version_at(timestamp).map_or(0, |version| version.len)
+    }
+
+    pub fn is_empty(&self, timestamp: u64) -> bool {
+        self.len(timestamp) == 0
+    }
+
+    /// The values at `timestamp`, in index order
+    pub fn snapshot_iter(&self, timestamp: u64) -> impl Iterator<Item = &T> {
+        let mut stack: Vec<usize> = self
+            .version_at(timestamp)
+            .and_then(|version| version.root)
+            .into_iter()
+            .collect();
+
+        std::iter::from_fn(move || loop {
+            match self.node_arena[stack.pop()?] {
+                IndexNode::Leaf(datum_ptr) => return Some(&self.data[datum_ptr]),
+                IndexNode::Branch { left, right } => {
+                    stack.extend(right);
+                    stack.extend(left);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every version, and the timestamps past the newest, are checked
+    // against a `Vec` along with `PersistentArray`
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn set_past_the_end_panics() {
+        let mut array = PathCopyArray::new();
+        array.push(1);
+        array.set(1, 2);
+    }
+}
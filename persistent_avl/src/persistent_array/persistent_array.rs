@@ -0,0 +1,191 @@
+use crate::fat_field::fat_field::FatField;
+
+/// A partially persistent growable array whose slots are fat fields.
+///
+/// Every update is a new version with its own timestamp, and costs O(1)
+/// space. Reading a slot at a timestamp searches that slot's history.
+/// Timestamps past the newest version see the newest version.
+pub struct PersistentArray<T> {
+    slots: Vec<FatField<T, u64>>,
+    /// Number of slots at each timestamp
+    len: FatField<usize, u64>,
+    last_time: u64,
+}
+
+impl<T> Default for PersistentArray<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> PersistentArray<T> {
+    pub fn new() -> Self {
+        PersistentArray {
+            slots: Vec::new(),
+            len: FatField::new(),
+            last_time: 0,
+        }
+    }
+
+    /// Appends `value`, returning the timestamp of the new version
+    pub fn push(&mut self, value: T) -> u64 {
+        let timestamp = self.last_time;
+
+        let mut slot = FatField::new();
+        slot.set(timestamp, value);
+        self.slots.push(slot);
+        self.len.set(timestamp, self.slots.len());
+
+        self.last_time += 1;
+        timestamp
+    }
+
+    /// Overwrites the value at `index`, returning the timestamp of the new
+    /// version
+    ///
+    /// Panics if `index` is out of bounds in the newest version.
+    pub fn set(&mut self, index: usize, value: T) -> u64 {
+        let timestamp = self.last_time;
+
+        let len = self.slots.len();
+        let Some(slot) = self.slots.get_mut(index) else {
+            panic!("index out of bounds: the len is {len} but the index is {index}");
+        };
+        slot.set(timestamp, value);
+
+        self.last_time += 1;
+        timestamp
+    }
+
+    /// The value at `index` at `timestamp`
+    pub fn get(&self, index: usize, timestamp: u64) -> Option<&T> {
+        if index >= self.len(timestamp) {
+            return None;
+        }
+
+        self.slots[index].get(&timestamp)
+    }
+
+    /// Number of values at `timestamp`
+    pub fn len(&self, timestamp: u64) -> usize {
+        self.len.get(&timestamp).copied().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self, timestamp: u64) -> bool {
+        self.len(timestamp) == 0
+    }
+
+    /// The values at `timestamp`, in index order
+    pub fn snapshot_iter(&self, timestamp: u64) -> impl Iterator<Item = &T> {
+        // A slot holds a value from the version that pushed it on
+        self.slots[..self.len(timestamp)]
+            .iter()
+            .filter_map(move |slot| slot.get(&timestamp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistent_array::path_copy_array::PathCopyArray;
+    use crate::test_util::Rng;
+
+    /// The operations both persistent arrays share
+    trait Array {
+        fn push(&mut self, value: u64) -> u64;
+        fn set(&mut self, index: usize, value: u64) -> u64;
+        fn get(&self, index: usize, timestamp: u64) -> Option<&u64>;
+        fn len(&self, timestamp: u64) -> usize;
+        fn values(&self, timestamp: u64) -> Vec<u64>;
+    }
+
+    impl Array for PersistentArray<u64> {
+        fn push(&mut self, value: u64) -> u64 {
+            PersistentArray::push(self, value)
+        }
+        fn set(&mut self, index: usize, value: u64) -> u64 {
+            PersistentArray::set(self, index, value)
+        }
+        fn get(&self, index: usize, timestamp: u64) -> Option<&u64> {
+            PersistentArray::get(self, index, timestamp)
+        }
+        fn len(&self, timestamp: u64) -> usize {
+            PersistentArray::len(self, timestamp)
+        }
+        fn values(&self, timestamp: u64) -> Vec<u64> {
+            self.snapshot_iter(timestamp).copied().collect()
+        }
+    }
+
+    impl Array for PathCopyArray<u64> {
+        fn push(&mut self, value: u64) -> u64 {
+            PathCopyArray::push(self, value)
+        }
+        fn set(&mut self, index: usize, value: u64) -> u64 {
+            PathCopyArray::set(self, index, value)
+        }
+        fn get(&self, index: usize, timestamp: u64) -> Option<&u64> {
+            PathCopyArray::get(self, index, timestamp)
+        }
+        fn len(&self, timestamp: u64) -> usize {
+            PathCopyArray::len(self, timestamp)
+        }
+        fn values(&self, timestamp: u64) -> Vec<u64> {
+            self.snapshot_iter(timestamp).copied().collect()
+        }
+    }
+
+    /// Runs random pushes and sets on `array` and on a `Vec` per version, and
+    /// checks every version of the array against its `Vec` at the end, and
+    /// timestamps past the newest version against the newest `Vec`
+    fn matches_a_vec_per_version(mut array: impl Array) {
+        let mut rng = Rng(5);
+        let mut versions: Vec<Vec<u64>> = Vec::new();
+        assert_eq!(array.len(0), 0);
+        assert_eq!(array.get(0, 0), None);
+
+        for step in 0..2000 {
+            let mut values = versions.last().cloned().unwrap_or_default();
+            let timestamp = if values.is_empty() || rng.below(4) == 0 {
+                values.push(step);
+                array.push(step)
+            } else {
+                let index = rng.below(values.len() as u64) as usize;
+                values[index] = step;
+                array.set(index, step)
+            };
+
+            assert_eq!(timestamp, versions.len() as u64);
+            versions.push(values);
+        }
+
+        let last = versions.len() as u64 - 1;
+        let timestamps = (0..=last).chain([last + 1, last + 100, u64::MAX]);
+        for timestamp in timestamps {
+            let values = &versions[timestamp.min(last) as usize];
+            assert_eq!(array.len(timestamp), values.len());
+            for index in 0..values.len() + 2 {
+                assert_eq!(array.get(index, timestamp), values.get(index));
+            }
+            assert_eq!(&array.values(timestamp), values);
+        }
+    }
+
+    #[test]
+    fn persistent_array_matches_a_vec_per_version() {
+        matches_a_vec_per_version(PersistentArray::new());
+    }
+
+    #[test]
+    fn path_copy_array_matches_a_vec_per_version() {
+        matches_a_vec_per_version(PathCopyArray::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn set_past_the_end_panics() {
+        let mut array = PersistentArray::new();
+        array.push(1);
+        array.set(1, 2);
+    }
+}
@@ -0,0 +1,2 @@
+pub mod persistent_sequence;
+mod seq_node;
@@ -0,0 +1,522 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::{Bound, RangeBounds};
+
+use crate::avl::engine;
+use crate::avl::node_store::NodeLinks;
+use crate::persistent_sequence::seq_node::SeqNode;
+
+/// A partially persistent sequence, an AVL tree keyed by position.
+///
+/// Nodes are ordered by index rather than by value, and each knows the size
+/// of its subtree, so an index is found by counting down from the root.
+/// Insertions and removals are path copied from the newest version like in
+/// `PathCopyAvl`, while any two versions, old or new, can be concatenated
+/// into a new one in O(log n).
+pub struct PersistentSequence<T> {
+    data: Vec<T>,
+    node_arena: Vec<SeqNode>,
+    root_nodes: Vec<Option<usize>>,
+}
+
+/// Nodes opened by the update in progress.
+///
+/// Since versions can be concatenated with themselves, one node may sit in
+/// several positions of a sequence. An update therefore opens every node it
+/// reaches as a draft with a fresh pointer past the arena, so that it only
+/// ever changes the position it reached a node through.
+#[derive(Default)]
+struct Draft {
+    nodes: HashMap<usize, SeqNode>,
+    /// Node each draft was opened from, if it is not new
+    origins: HashMap<usize, usize>,
+}
+
+/// The newest version of the tree an update works on, seen through the
+/// drafts it has opened, for `avl::engine` to link and rebalance
+struct DraftStore<'a> {
+    node_arena: &'a [SeqNode],
+    draft: RefCell<Draft>,
+    root: Option<usize>,
+}
+
+impl<'a> DraftStore<'a> {
+    /// Opens the tree at `root` of `node_arena` for an update
+    fn new(node_arena: &'a [SeqNode], root: Option<usize>) -> Self {
+        let mut store = DraftStore {
+            node_arena,
+            draft: RefCell::new(Draft::default()),
+            root: None,
+        };
+        store.root = store.open(root);
+
+        store
+    }
+
+    fn get_node(&self, node_ptr: usize) -> SeqNode {
+        match self.draft.borrow().nodes.get(&node_ptr) {
+            Some(node) => *node,
+            None => self.node_arena[node_ptr],
+        }
+    }
+
+    fn size(&self, node_ptr: Option<usize>) -> usize {
+        node_ptr.map_or(0, |ptr| self.get_node(ptr).size)
+    }
+
+    fn subtree_height(&self, node_ptr: Option<usize>) -> u64 {
+        node_ptr.map_or(0, |ptr| self.get_node(ptr).height)
+    }
+
+    /// Size of the left subtree of `node_ptr`, without opening it
+    fn left_size(&self, node_ptr: usize) -> usize {
+        self.size(self.get_node(node_ptr).left)
+    }
+
+    /// Opens `node_ptr` as a draft, unless it already is one
+    fn open(&self, node_ptr: Option<usize>) -> Option<usize> {
+        let node_ptr = node_ptr?;
+        let mut draft = self.draft.borrow_mut();
+        if draft.nodes.contains_key(&node_ptr) {
+            return Some(node_ptr);
+        }
+
+        let draft_ptr = self.node_arena.len() + draft.nodes.len();
+        draft.nodes.insert(draft_ptr, self.node_arena[node_ptr]);
+        draft.origins.insert(draft_ptr, node_ptr);
+
+        Some(draft_ptr)
+    }
+
+    /// Opens a new leaf holding `datum_ptr` as a draft
+    fn new_node(&self, datum_ptr: usize) -> usize {
+        let mut draft = self.draft.borrow_mut();
+        let draft_ptr = self.node_arena.len() + draft.nodes.len();
+        draft.nodes.insert(
+            draft_ptr,
+            SeqNode {
+                datum_ptr,
+                height: 1,
+                size: 1,
+                left: None,
+                right: None,
+            },
+        );
+
+        draft_ptr
+    }
+}
+
+impl NodeLinks for DraftStore<'_> {
+    fn latest_root(&self) -> Option<usize> {
+        self.root
+    }
+
+    /// Left child of the draft `node`, opened as a draft itself
+    fn left(&self, node: usize) -> Option<usize> {
+        let left = self.draft.borrow().nodes[&node].left;
+        let left = self.open(left);
+        self.draft.borrow_mut().nodes.get_mut(&node).unwrap().left = left;
+
+        left
+    }
+
+    /// Right child of the draft `node`, opened as a draft itself
+    fn right(&self, node: usize) -> Option<usize> {
+        let right = self.draft.borrow().nodes[&node].right;
+        let right = self.open(right);
+        self.draft.borrow_mut().nodes.get_mut(&node).unwrap().right = right;
+
+        right
+    }
+
+    fn height(&self, node: usize) -> u64 {
+        self.get_node(node).height
+    }
+
+    /// Sets the children of the draft `node`, recomputing its height and size
+    /// from theirs, so the height handed in is not needed
+    fn modify(&mut self, node: usize, left: Option<usize>, right: Option<usize>, _height: u64) {
+        let height = self.subtree_height(left).max(self.subtree_height(right)) + 1;
+        let size = self.size(left) + self.size(right) + 1;
+
+        let draft = self.draft.get_mut();
+        let node = draft.nodes.get_mut(&node).unwrap();
+        node.left = left;
+        node.right = right;
+        node.height = height;
+        node.size = size;
+    }
+}
+
+impl<T> Default for PersistentSequence<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> PersistentSequence<T> {
+    pub fn new() -> Self {
+        PersistentSequence {
+            data: Vec::new(),
+            node_arena: Vec::new(),
+            root_nodes: Vec::new(),
+        }
+    }
+
+    fn get_root(&self, timestamp: usize) -> Option<usize> {
+        self.root_nodes.get(timestamp).and_then(|root| *root)
+    }
+
+    fn latest_root(&self) -> Option<usize> {
+        self.root_nodes.last().and_then(|root| *root)
+    }
+
+    /// Number of values in the tree at `root`
+    fn size(&self, root: Option<usize>) -> usize {
+        root.map_or(0, |root| self.node_arena[root].size)
+    }
+
+    /// Copies every draft reachable from `node_ptr` into the arena, children
+    /// before parents, and returns where `node_ptr` ended up.
+    ///
+    /// Drafts left as they were opened resolve back to the node they were
+    /// opened from, so untouched nodes stay shared with earlier versions.
+    fn commit(&mut self, draft: &mut Draft, node_ptr: Option<usize>) -> Option<usize> {
+        let node_ptr = node_ptr?;
+
+        let Some(node) = draft.nodes.remove(&node_ptr) else {
+            return Some(node_ptr);
+        };
+        let left = self.commit(draft, node.left);
+        let right = self.commit(draft, node.right);
+
+        if let Some(&origin) = draft.origins.get(&node_ptr) {
+            let original = &self.node_arena[origin];
+            if original.datum_ptr == node.datum_ptr
+                && original.left == left
+                && original.right == right
+            {
+                return Some(origin);
+            }
+        }
+
+        self.node_arena.push(SeqNode {
+            left,
+            right,
+            ..node
+        });
+        Some(self.node_arena.len() - 1)
+    }
+
+    /// Inserts `value` before the value at `index`, or at the end if `index`
+    /// is the length, returning the timestamp of the new version
+    ///
+    /// Panics if `index` is greater than the length of the newest version.
+    pub fn insert_at(&mut self, index: usize, value: T) -> usize {
+        let len = self.size(self.latest_root());
+        assert!(
+            index <= len,
+            "insertion index (is {index}) should be <= len (is {len})"
+        );
+
+        self.data.push(value);
+        let mut store = DraftStore::new(&self.node_arena, self.latest_root());
+        let new_node_ptr = store.new_node(self.data.len() - 1);
+
+        // Counts down from the root, going left when the new value belongs
+        // before the node
+        let mut index = index;
+        let update = engine::insert_by(&mut store, new_node_ptr, |store, node_ptr| {
+            let left_size = store.left_size(node_ptr);
+            if index <= left_size {
+                return true;
+            }
+
+            index -= left_size + 1;
+            false
+        });
+
+        let new_root = self.commit(&mut store.draft.into_inner(), update.root);
+        self.root_nodes.push(new_root);
+
+        self.root_nodes.len() - 1
+    }
+
+    /// Appends `value`, returning the timestamp of the new version
+    pub fn push(&mut self, value: T) -> usize {
+        let len = self.size(self.latest_root());
+        self.insert_at(len, value)
+    }
+
+    /// Removes the value at `index`, returning the timestamp of the new
+    /// version
+    ///
+    /// Panics if `index` is out of bounds in the newest version.
+    pub fn remove_at(&mut self, index: usize) -> usize {
+        let len = self.size(self.latest_root());
+        assert!(
+            index < len,
+            "removal index (is {index}) should be < len (is {len})"
+        );
+
+        let mut store = DraftStore::new(&self.node_arena, self.latest_root());
+
+        let mut index = index;
+        let update = engine::delete_by(&mut store, |store, node_ptr| {
+            let left_size = store.left_size(node_ptr);
+            let direction = index.cmp(&left_size);
+            if direction == Ordering::Greater {
+                index -= left_size + 1;
+            }
+
+            direction
+        })
+        .unwrap();
+
+        let new_root = self.commit(&mut store.draft.into_inner(), update.root);
+        self.root_nodes.push(new_root);
+
+        self.root_nodes.len() - 1
+    }
+
+    /// Appends the version at `second` to the version at `first`, returning
+    /// the timestamp of the new version
+    ///
+    /// Both versions are left as they are, and may be the same version, in
+    /// which case the new version shares each node in two positions.
+    ///
+    /// Panics if either timestamp has no version.
+    pub fn concat(&mut self, first: usize, second: usize) -> usize {
+        assert!(
+            first < self.root_nodes.len() && second < self.root_nodes.len(),
+            "no version at timestamp"
+        );
+
+        let new_root = match (self.get_root(first), self.get_root(second)) {
+            (Some(left), Some(right)) => {
+                let mut store = DraftStore::new(&self.node_arena, None);
+                let left = store.open(Some(left));
+                let right = store.open(Some(right));
+
+                let new_root = engine::join_trees(&mut store, left, right, &mut Vec::new());
+                self.commit(&mut store.draft.into_inner(), new_root)
+            }
+            (left, right) => left.or(right),
+        };
+        self.root_nodes.push(new_root);
+
+        self.root_nodes.len() - 1
+    }
+
+    /// The value at `index` at `timestamp`
+    pub fn get(&self, mut index: usize, timestamp: usize) -> Option<&T> {
+        let mut node_ptr = self.get_root(timestamp);
+
+        while let Some(ptr) = node_ptr {
+            let node = &self.node_arena[ptr];
+            let left_size = node.left.map_or(0, |left| self.node_arena[left].size);
+
+            if index < left_size {
+                node_ptr = node.left;
+            } else if index == left_size {
+                return Some(&self.data[node.datum_ptr]);
+            } else {
+                index -= left_size + 1;
+                node_ptr = node.right;
+            }
+        }
+
+        None
+    }
+
+    /// Number of values at `timestamp`
+    pub fn len(&self, timestamp: usize) -> usize {
+        self.size(self.get_root(timestamp))
+    }
+
+    pub fn is_empty(&self, timestamp: usize) -> bool {
+        self.get_root(timestamp).is_none()
+    }
+
+    /// The values in `range` at `timestamp`, in order
+    ///
+    /// Panics if `range` is out of bounds, like slicing a `Vec`.
+    pub fn slice(
+        &self,
+        range: impl RangeBounds<usize>,
+        timestamp: usize,
+    ) -> impl Iterator<Item = &T> {
+        let len = self.len(timestamp);
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => len,
+        };
+        assert!(
+            start <= end && end <= len,
+            "range {start}..{end} out of bounds for length {len}"
+        );
+
+        // Ancestors still to be visited, down to the value at `start`
+        let mut stack = Vec::new();
+        let mut node_ptr = self.get_root(timestamp);
+        let mut index = start;
+        while let Some(ptr) = node_ptr {
+            let node = &self.node_arena[ptr];
+            let left_size = node.left.map_or(0, |left| self.node_arena[left].size);
+
+            if index <= left_size {
+                stack.push(ptr);
+                node_ptr = if index == left_size { None } else { node.left };
+            } else {
+                index -= left_size + 1;
+                node_ptr = node.right;
+            }
+        }
+
+        std::iter::from_fn(move || {
+            let ptr = stack.pop()?;
+            let node = &self.node_arena[ptr];
+
+            let mut next = node.right;
+            while let Some(next_ptr) = next {
+                stack.push(next_ptr);
+                next = self.node_arena[next_ptr].left;
+            }
+
+            Some(&self.data[node.datum_ptr])
+        })
+        .take(end - start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Rng;
+
+    /// Checks that the subtree at `node_ptr` is balanced and that its heights
+    /// and sizes are right, returning its height and size
+    fn check_node<T>(sequence: &PersistentSequence<T>, node_ptr: Option<usize>) -> (u64, usize) {
+        let Some(ptr) = node_ptr else {
+            return (0, 0);
+        };
+        let node = sequence.node_arena[ptr];
+        let (left_height, left_size) = check_node(sequence, node.left);
+        let (right_height, right_size) = check_node(sequence, node.right);
+
+        assert!(left_height.abs_diff(right_height) <= 1, "unbalanced node");
+        assert_eq!(node.height, left_height.max(right_height) + 1);
+        assert_eq!(node.size, left_size + right_size + 1);
+
+        (node.height, node.size)
+    }
+
+    fn assert_versions(sequence: &PersistentSequence<u64>, versions: &[Vec<u64>]) {
+        for (timestamp, values) in versions.iter().enumerate() {
+            check_node(sequence, sequence.get_root(timestamp));
+            assert_eq!(sequence.len(timestamp), values.len());
+            for index in 0..values.len() + 2 {
+                assert_eq!(sequence.get(index, timestamp), values.get(index));
+            }
+            assert!(sequence.slice(.., timestamp).eq(values.iter()));
+        }
+    }
+
+    #[test]
+    fn matches_a_vec_at_every_version() {
+        for seed in 1..20 {
+            let mut rng = Rng(seed);
+            let mut sequence = PersistentSequence::new();
+            let mut versions: Vec<Vec<u64>> = Vec::new();
+
+            for step in 0..200 {
+                let mut values = versions.last().cloned().unwrap_or_default();
+                let timestamp = match rng.below(6) {
+                    0 if !values.is_empty() => {
+                        let index = rng.below(values.len() as u64) as usize;
+                        values.remove(index);
+                        sequence.remove_at(index)
+                    }
+                    // Concatenating may pick the same version twice
+                    1 if !versions.is_empty() => {
+                        let first = rng.below(versions.len() as u64) as usize;
+                        let second = rng.below(versions.len() as u64) as usize;
+                        if versions[first].len() + versions[second].len() > 500 {
+                            continue;
+                        }
+
+                        values = [versions[first].clone(), versions[second].clone()].concat();
+                        sequence.concat(first, second)
+                    }
+                    _ => {
+                        let index = rng.below(values.len() as u64 + 1) as usize;
+                        values.insert(index, step);
+                        sequence.insert_at(index, step)
+                    }
+                };
+
+                assert_eq!(timestamp, versions.len());
+                versions.push(values);
+            }
+
+            assert_versions(&sequence, &versions);
+        }
+    }
+
+    #[test]
+    fn concatenates_a_version_with_itself() {
+        let mut sequence = PersistentSequence::new();
+        let mut versions = Vec::new();
+        for value in 0..5 {
+            sequence.push(value);
+            versions.push((0..=value).collect::<Vec<_>>());
+        }
+
+        let doubled = sequence.concat(4, 4);
+        versions.push([versions[4].clone(), versions[4].clone()].concat());
+        assert_eq!(doubled, 5);
+
+        // Removing one copy of a shared node leaves the other in place
+        sequence.remove_at(2);
+        let mut values = versions[5].clone();
+        values.remove(2);
+        versions.push(values);
+
+        sequence.insert_at(7, 9);
+        let mut values = versions[6].clone();
+        values.insert(7, 9);
+        versions.push(values);
+
+        assert_versions(&sequence, &versions);
+    }
+
+    #[test]
+    fn slices_within_bounds() {
+        let mut sequence = PersistentSequence::new();
+        for value in 0..10 {
+            sequence.push(value);
+        }
+
+        assert!(sequence.slice(3..7, 9).eq([3, 4, 5, 6].iter()));
+        assert!(sequence.slice(..=2, 9).eq([0, 1, 2].iter()));
+        assert!(sequence.slice(8.., 9).eq([8, 9].iter()));
+        assert_eq!(sequence.slice(5..5, 9).count(), 0);
+        assert!(sequence.slice(.., 3).eq([0, 1, 2, 3].iter()));
+    }
+
+    #[test]
+    #[should_panic(expected = "removal index")]
+    fn remove_past_the_end_panics() {
+        let mut sequence = PersistentSequence::new();
+        sequence.push(1);
+        sequence.remove_at(1);
+    }
+}
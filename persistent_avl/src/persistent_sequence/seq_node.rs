@@ -0,0 +1,9 @@
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct SeqNode {
+    pub(crate) datum_ptr: usize,
+    pub(crate) height: u64,
+    /// Number of nodes in the subtree, which positions every node
+    pub(crate) size: usize,
+    pub(crate) left: Option<usize>,
+    pub(crate) right: Option<usize>,
+}
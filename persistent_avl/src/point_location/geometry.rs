@@ -0,0 +1,61 @@
+use std::cmp::Ordering;
+
+/// A coordinate type the sweep can compute orientations with.
+///
+/// `i64` is exact as long as every coordinate is less than 2^62 in absolute
+/// value, as products are taken in `i128`. `f64` rounds, so points very close
+/// to a segment may be placed on the wrong side of it. NaN is not supported.
+pub trait Coordinate: Copy + PartialOrd {
+    /// Sign of the cross product of `b - a` and `d - c`, `Greater` when `d -
+    /// c` turns counterclockwise from `b - a`
+    fn cross(a: Point<Self>, b: Point<Self>, c: Point<Self>, d: Point<Self>) -> Ordering;
+}
+
+impl Coordinate for i64 {
+    fn cross(a: Point<i64>, b: Point<i64>, c: Point<i64>, d: Point<i64>) -> Ordering {
+        let delta = |from: i64, to: i64| i128::from(to) - i128::from(from);
+
+        let product = delta(a.x, b.x) * delta(c.y, d.y) - delta(a.y, b.y) * delta(c.x, d.x);
+        product.cmp(&0)
+    }
+}
+
+impl Coordinate for f64 {
+    fn cross(a: Point<f64>, b: Point<f64>, c: Point<f64>, d: Point<f64>) -> Ordering {
+        let product = (b.x - a.x) * (d.y - c.y) - (b.y - a.y) * (d.x - c.x);
+        product.partial_cmp(&0.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Point<C> {
+    pub x: C,
+    pub y: C,
+}
+
+impl<C> Point<C> {
+    pub fn new(x: C, y: C) -> Self {
+        Point { x, y }
+    }
+}
+
+/// A segment of a planar subdivision, and the face right above it
+///
+/// Vertical segments have no face above them. They separate faces all the
+/// same, but a point is located by the segment below it, so they are left
+/// out of the sweep.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Edge<C> {
+    pub from: Point<C>,
+    pub to: Point<C>,
+    pub face_above: usize,
+}
+
+/// Segments that meet at most at their endpoints, dividing the plane into
+/// faces, which are numbered by the caller
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subdivision<C> {
+    pub edges: Vec<Edge<C>>,
+    /// The face with no edge below it
+    pub outer_face: usize,
+}
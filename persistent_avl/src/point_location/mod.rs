@@ -0,0 +1,3 @@
+pub mod geometry;
+pub mod point_location;
+mod sweep_segment;
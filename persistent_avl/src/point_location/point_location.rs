@@ -0,0 +1,315 @@
+use std::cmp::Ordering;
+
+use crate::fat_node_avl::fat_node_avl::FatNodeAvl;
+use crate::persistent_avl_tree::PersistentAvlTree;
+use crate::point_location::geometry::{Coordinate, Point, Subdivision};
+use crate::point_location::sweep_segment::SweepSegment;
+
+/// Planar point location by Sarnak and Tarjan's persistent sweep.
+///
+/// A vertical line swept from left to right over the subdivision crosses
+/// its edges in an order that only changes where an edge starts or ends.
+/// Every such x becomes a version of a `FatNodeAvl` of the edges crossing the
+/// slab from it to the next, ordered from bottom to top. The face holding a
+/// point is then the face above the edge right below it, found with a single
+/// `predecessor` query in the version of its slab.
+///
+/// Building takes O(n log n) time and O(n) space, and locating a point
+/// O(log n).
+pub struct PointLocation<C: Coordinate> {
+    tree: FatNodeAvl<SweepSegment<C>>,
+    /// Every x where an edge starts or ends, increasing, with the version
+    /// holding the edges from there to the next
+    slabs: Vec<(C, u64)>,
+    outer_face: usize,
+}
+
+impl<C: Coordinate> PointLocation<C> {
+    /// Sweeps `subdivision`
+    ///
+    /// Edges that cross make the answers meaningless, and may make this
+    /// panic.
+    pub fn new(subdivision: &Subdivision<C>) -> Self {
+        let segments: Vec<SweepSegment<C>> = subdivision
+            .edges
+            .iter()
+            .filter_map(|edge| SweepSegment::new(edge.from, edge.to, edge.face_above))
+            .collect();
+
+        // Where each segment is inserted and deleted. Deletions come first at
+        // the same x, so that a slab only holds the segments crossing it.
+        let mut events: Vec<(C, bool, usize)> = segments
+            .iter()
+            .enumerate()
+            .flat_map(|(index, segment)| {
+                [
+                    (segment.left.x, true, index),
+                    (segment.right.x, false, index),
+                ]
+            })
+            .collect();
+        events.sort_by(|(x, insert, _), (other_x, other_insert, _)| {
+            x.partial_cmp(other_x)
+                .expect("coordinates must not be NaN")
+                .then(insert.cmp(other_insert))
+        });
+
+        let mut tree = FatNodeAvl::new();
+        let mut slabs: Vec<(C, u64)> = Vec::new();
+        for (x, insert, index) in events {
+            let timestamp = if insert {
+                tree.insert(segments[index])
+            } else {
+                tree.delete(&segments[index])
+                    .expect("edges of the subdivision cross")
+            };
+
+            match slabs.last_mut() {
+                Some((last_x, last_timestamp)) if *last_x == x => *last_timestamp = timestamp,
+                _ => slabs.push((x, timestamp)),
+            }
+        }
+
+        PointLocation {
+            tree,
+            slabs,
+            outer_face: subdivision.outer_face,
+        }
+    }
+
+    /// The face holding `point`
+    ///
+    /// A point on an edge counts as above it, and a point on the vertical
+    /// line through an endpoint as right of it.
+    pub fn locate(&self, point: Point<C>) -> usize {
+        let slab = self
+            .slabs
+            .partition_point(|(x, _)| x.partial_cmp(&point.x) != Some(Ordering::Greater));
+        let Some(&(_, timestamp)) = slab.checked_sub(1).map(|slab| &self.slabs[slab]) else {
+            return self.outer_face;
+        };
+
+        self.tree
+            .predecessor(&SweepSegment::point(point), timestamp)
+            .map_or(self.outer_face, |segment| segment.face_above)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point_location::geometry::Edge;
+    use crate::test_util::Rng;
+
+    /// Coordinates of vertices are in `0..=GRID`
+    const GRID: i64 = 12;
+    const OUTER_FACE: usize = usize::MAX;
+
+    fn orientation(a: Point<i64>, b: Point<i64>, c: Point<i64>) -> Ordering {
+        i64::cross(a, b, a, c)
+    }
+
+    /// Whether `point`, which is on the line through `a` and `b`, is on the
+    /// segment between them
+    fn within(a: Point<i64>, b: Point<i64>, point: Point<i64>) -> bool {
+        a.x.min(b.x) <= point.x
+            && point.x <= a.x.max(b.x)
+            && a.y.min(b.y) <= point.y
+            && point.y <= a.y.max(b.y)
+    }
+
+    /// Whether two segments meet anywhere but at an endpoint of both
+    fn conflict(edge: &Edge<i64>, other: &Edge<i64>) -> bool {
+        let (a, b, c, d) = (edge.from, edge.to, other.from, other.to);
+        if (a == c && b == d) || (a == d && b == c) {
+            return true;
+        }
+
+        let touches = |from: Point<i64>, to: Point<i64>, point: Point<i64>| {
+            orientation(from, to, point) == Ordering::Equal
+                && within(from, to, point)
+                && point != from
+                && point != to
+        };
+        if touches(a, b, c) || touches(a, b, d) || touches(c, d, a) || touches(c, d, b) {
+            return true;
+        }
+
+        let crosses = |from: Point<i64>, to: Point<i64>, p: Point<i64>, q: Point<i64>| {
+            orientation(from, to, p).reverse() == orientation(from, to, q)
+                && orientation(from, to, p) != Ordering::Equal
+        };
+        crosses(a, b, c, d) && crosses(c, d, a, b)
+    }
+
+    /// A random subdivision on a small grid, whose edges share many
+    /// vertices and are often vertical, with the face above each edge named
+    /// after the edge
+    fn random_subdivision(rng: &mut Rng) -> Subdivision<i64> {
+        let mut coordinate = || rng.below(GRID as u64 + 1) as i64;
+        let vertices: Vec<Point<i64>> = (0..16)
+            .map(|_| {
+                let x = coordinate();
+                Point::new(x, coordinate())
+            })
+            .collect();
+
+        let mut edges: Vec<Edge<i64>> = Vec::new();
+        for _ in 0..80 {
+            let from = vertices[rng.below(vertices.len() as u64) as usize];
+            let to = if rng.below(4) == 0 {
+                Point::new(from.x, rng.below(GRID as u64 + 1) as i64)
+            } else {
+                vertices[rng.below(vertices.len() as u64) as usize]
+            };
+
+            let edge = Edge {
+                from,
+                to,
+                face_above: edges.len(),
+            };
+            if from != to && edges.iter().all(|other| !conflict(&edge, other)) {
+                edges.push(edge);
+            }
+        }
+
+        Subdivision {
+            edges,
+            outer_face: OUTER_FACE,
+        }
+    }
+
+    /// The face above the highest edge crossing the vertical line through
+    /// `point` at or below it, found by checking every edge
+    ///
+    /// Edges span the x from their left end up to their right end, and of
+    /// edges meeting at `point.x`, the one rising faster is the higher.
+    fn brute_force(subdivision: &Subdivision<i64>, point: Point<i64>) -> usize {
+        // Height of an edge at `point.x` as a fraction, and its slope
+        let at = |edge: &Edge<i64>| {
+            let (left, right) = if edge.from.x < edge.to.x {
+                (edge.from, edge.to)
+            } else {
+                (edge.to, edge.from)
+            };
+            let (dx, dy) = (i128::from(right.x - left.x), i128::from(right.y - left.y));
+            let height = i128::from(left.y) * dx + dy * i128::from(point.x - left.x);
+
+            (left, right, height, dx, dy)
+        };
+
+        let mut below: Option<&Edge<i64>> = None;
+        for edge in &subdivision.edges {
+            let (left, right, height, dx, _) = at(edge);
+            if dx == 0 || point.x < left.x || point.x >= right.x {
+                continue;
+            }
+            if height > i128::from(point.y) * dx {
+                continue;
+            }
+
+            let higher = below.is_none_or(|below| {
+                let (_, _, below_height, below_dx, below_dy) = at(below);
+                let (_, _, _, _, dy) = at(edge);
+                (height * below_dx)
+                    .cmp(&(below_height * dx))
+                    .then((dy * below_dx).cmp(&(below_dy * dx)))
+                    == Ordering::Greater
+            });
+            if higher {
+                below = Some(edge);
+            }
+        }
+
+        below.map_or(subdivision.outer_face, |edge| edge.face_above)
+    }
+
+    #[test]
+    fn locates_as_brute_force() {
+        for seed in 1..40 {
+            let mut rng = Rng(seed);
+            let subdivision = random_subdivision(&mut rng);
+            let location = PointLocation::new(&subdivision);
+
+            // Every point of the grid, so every vertex and every point of a
+            // vertical edge is located, along with points around the grid
+            for x in -1..=GRID + 1 {
+                for y in -1..=GRID + 1 {
+                    let point = Point::new(x, y);
+                    assert_eq!(
+                        location.locate(point),
+                        brute_force(&subdivision, point),
+                        "{point:?} in {subdivision:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn locates_with_floats_as_with_integers() {
+        let to_float = |point: Point<i64>| Point::new(point.x as f64, point.y as f64);
+
+        for seed in 1..10 {
+            let mut rng = Rng(seed);
+            let subdivision = random_subdivision(&mut rng);
+            let location = PointLocation::new(&subdivision);
+            let float_location = PointLocation::new(&Subdivision {
+                edges: subdivision
+                    .edges
+                    .iter()
+                    .map(|edge| Edge {
+                        from: to_float(edge.from),
+                        to: to_float(edge.to),
+                        face_above: edge.face_above,
+                    })
+                    .collect(),
+                outer_face: subdivision.outer_face,
+            });
+
+            for x in -1..=GRID + 1 {
+                for y in -1..=GRID + 1 {
+                    let point = Point::new(x, y);
+                    assert_eq!(
+                        float_location.locate(to_float(point)),
+                        location.locate(point)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn points_on_edges_are_above_them() {
+        // A square split by a vertical edge, with faces 1 and 2 inside
+        let point = Point::new;
+        let edge = |from, to, face_above| Edge {
+            from,
+            to,
+            face_above,
+        };
+        let subdivision = Subdivision {
+            edges: vec![
+                edge(point(0, 0), point(2, 0), 1),
+                edge(point(2, 0), point(4, 0), 2),
+                edge(point(0, 2), point(2, 2), 0),
+                edge(point(2, 2), point(4, 2), 0),
+                edge(point(0, 0), point(0, 2), 0),
+                edge(point(2, 0), point(2, 2), 0),
+                edge(point(4, 0), point(4, 2), 0),
+            ],
+            outer_face: 0,
+        };
+        let location = PointLocation::new(&subdivision);
+
+        assert_eq!(location.locate(point(1, 1)), 1);
+        assert_eq!(location.locate(point(3, 1)), 2);
+        assert_eq!(location.locate(point(1, 0)), 1);
+        assert_eq!(location.locate(point(0, 1)), 1);
+        assert_eq!(location.locate(point(2, 1)), 2);
+        assert_eq!(location.locate(point(2, 0)), 2);
+        assert_eq!(location.locate(point(1, 2)), 0);
+        assert_eq!(location.locate(point(4, 1)), 0);
+        assert_eq!(location.locate(point(-1, 1)), 0);
+    }
+}
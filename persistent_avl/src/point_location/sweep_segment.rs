@@ -0,0 +1,96 @@
+use std::cmp::Ordering;
+
+use crate::point_location::geometry::{Coordinate, Point};
+
+/// A non-vertical segment, from left to right, or a single point, ordered
+/// from bottom to top where they meet a common vertical line.
+///
+/// Segments that do not cross keep the same order wherever their x ranges
+/// overlap, so they are compared at the greater of their left ends. The
+/// order is only meaningful between segments and points that share some x.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct SweepSegment<C> {
+    pub(crate) left: Point<C>,
+    pub(crate) right: Point<C>,
+    pub(crate) face_above: usize,
+}
+
+impl<C: Coordinate> SweepSegment<C> {
+    /// A segment between `from` and `to`, or `None` if it is vertical
+    pub(crate) fn new(from: Point<C>, to: Point<C>, face_above: usize) -> Option<Self> {
+        let (left, right) = match from.x.partial_cmp(&to.x)? {
+            Ordering::Less => (from, to),
+            Ordering::Greater => (to, from),
+            Ordering::Equal => return None,
+        };
+
+        Some(SweepSegment {
+            left,
+            right,
+            face_above,
+        })
+    }
+
+    /// A key for `point` alone
+    pub(crate) fn point(point: Point<C>) -> Self {
+        SweepSegment {
+            left: point,
+            right: point,
+            face_above: 0,
+        }
+    }
+
+    fn is_point(&self) -> bool {
+        self.left == self.right
+    }
+
+    /// Whether `point` is above, on or below the line through this segment
+    fn side_of(&self, point: Point<C>) -> Ordering {
+        C::cross(self.left, self.right, self.left, point)
+    }
+
+    /// Order against `other`, whose x range holds the left end of this one
+    ///
+    /// Segments touching there are told apart by which one rises faster.
+    fn compare_at_left_end(&self, other: &Self) -> Ordering {
+        match other.side_of(self.left) {
+            Ordering::Equal => C::cross(other.left, other.right, self.left, self.right),
+            side => side,
+        }
+    }
+}
+
+impl<C: Coordinate> Ord for SweepSegment<C> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let by_coordinates = |a: &Point<C>, b: &Point<C>| {
+            (a.y.partial_cmp(&b.y).unwrap()).then(a.x.partial_cmp(&b.x).unwrap())
+        };
+
+        match (self.is_point(), other.is_point()) {
+            (true, true) => by_coordinates(&self.left, &other.left),
+            (true, false) => other.side_of(self.left),
+            (false, true) => self.side_of(other.left).reverse(),
+            (false, false) => {
+                if self.left.x >= other.left.x {
+                    self.compare_at_left_end(other)
+                } else {
+                    other.compare_at_left_end(self).reverse()
+                }
+            }
+        }
+    }
+}
+
+impl<C: Coordinate> PartialOrd for SweepSegment<C> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<C: Coordinate> PartialEq for SweepSegment<C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<C: Coordinate> Eq for SweepSegment<C> {}
@@ -0,0 +1,141 @@
+use std::ops::{Bound, RangeBounds};
+
+use crate::batch_update::{self, BatchUpdate};
+use crate::path_copy_wb::path_copy_wb::PathCopyWbTree;
+
+/// A static index counting the points of a set in axis-aligned rectangles.
+///
+/// The points are swept by x, and the y of those at each x are added to a
+/// `PathCopyWbTree` as one version, which then holds the y of every point up
+/// to that x. The points in a rectangle are those in the version of its right
+/// edge but not in the version just left of it, and each version counts its
+/// points in a y range with two ranks.
+///
+/// Building takes O(n log n) time and space, and counting O(log n).
+pub struct RangeCounter2D<X: Ord, Y: Ord> {
+    tree: PathCopyWbTree<Y>,
+    /// Every x of a point, increasing, with the version holding the points
+    /// up to it
+    versions: Vec<(X, usize)>,
+}
+
+impl<X: Ord, Y: Ord> RangeCounter2D<X, Y> {
+    pub fn new(points: impl IntoIterator<Item = (X, Y)>) -> Self {
+        let mut points: Vec<(X, Y)> = points.into_iter().collect();
+        points.sort_by(|(x, y), (other_x, other_y)| x.cmp(other_x).then(y.cmp(other_y)));
+
+        let mut tree = PathCopyWbTree::new();
+        let mut versions = Vec::new();
+        let mut points = points.into_iter().peekable();
+        while let Some((x, y)) = points.next() {
+            let mut column = vec![y];
+            while let Some((_, y)) = points.next_if(|(next_x, _)| *next_x == x) {
+                column.push(y);
+            }
+
+            let timestamp = tree.insert_sorted_batch(column).expect("column is sorted");
+            versions.push((x, timestamp));
+        }
+
+        RangeCounter2D { tree, versions }
+    }
+
+    /// Number of points with x in `x_range` and y in `y_range`
+    pub fn count(&self, x_range: impl RangeBounds<X>, y_range: impl RangeBounds<Y>) -> usize {
+        let left = self.count_up_to(|x| batch_update::is_before(&x_range, x), &y_range);
+        let right = self.count_up_to(|x| !batch_update::is_after(&x_range, x), &y_range);
+
+        right.saturating_sub(left)
+    }
+
+    /// Number of points with y in `y_range` among those whose x `included`
+    /// holds for, which must be a prefix of them in order
+    fn count_up_to(&self, included: impl Fn(&X) -> bool, y_range: &impl RangeBounds<Y>) -> usize {
+        let index = self.versions.partition_point(|(x, _)| included(x));
+        let Some(&(_, timestamp)) = index.checked_sub(1).map(|index| &self.versions[index]) else {
+            return 0;
+        };
+
+        let below = match y_range.start_bound() {
+            Bound::Included(start) => self.tree.rank(start, timestamp),
+            Bound::Excluded(start) => self.tree.rank_at_most(start, timestamp),
+            Bound::Unbounded => 0,
+        };
+        let up_to_end = match y_range.end_bound() {
+            Bound::Included(end) => self.tree.rank_at_most(end, timestamp),
+            Bound::Excluded(end) => self.tree.rank(end, timestamp),
+            Bound::Unbounded => self.tree.len(timestamp),
+        };
+
+        up_to_end.saturating_sub(below)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Rng;
+
+    /// Coordinates are drawn from `0..SIDE`, so many points share an x
+    const SIDE: u64 = 12;
+
+    fn bound(rng: &mut Rng) -> Bound<u64> {
+        match rng.below(3) {
+            0 => Bound::Unbounded,
+            1 => Bound::Excluded(rng.below(SIDE + 2)),
+            _ => Bound::Included(rng.below(SIDE + 2)),
+        }
+    }
+
+    #[test]
+    fn counts_as_a_scan_of_the_points() {
+        for seed in 1..20 {
+            let mut rng = Rng(seed);
+            let points: Vec<(u64, u64)> = (0..rng.below(80))
+                .map(|_| (rng.below(SIDE), rng.below(SIDE)))
+                .collect();
+            let counter = RangeCounter2D::new(points.iter().copied());
+
+            // Random bounds are inverted or empty about half of the time
+            for _ in 0..300 {
+                let x_range = (bound(&mut rng), bound(&mut rng));
+                let y_range = (bound(&mut rng), bound(&mut rng));
+                let expected = points
+                    .iter()
+                    .filter(|(x, y)| x_range.contains(x) && y_range.contains(y))
+                    .count();
+
+                assert_eq!(
+                    counter.count(x_range, y_range),
+                    expected,
+                    "{x_range:?} {y_range:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn counts_a_column_of_points() {
+        let counter = RangeCounter2D::new([(3, 1), (3, 4), (3, 4), (3, 9), (7, 4)]);
+
+        assert_eq!(counter.count(3..=3, ..), 4);
+        assert_eq!(counter.count(3..4, 4..=4), 2);
+        assert_eq!(counter.count(.., 4..5), 3);
+        assert_eq!(counter.count(4..7, ..), 0);
+        assert_eq!(
+            counter.count((Bound::Included(7), Bound::Excluded(3)), ..),
+            0
+        );
+        assert_eq!(
+            counter.count(.., (Bound::Excluded(9), Bound::Included(1))),
+            0
+        );
+    }
+
+    #[test]
+    fn counts_nothing_without_points() {
+        let counter = RangeCounter2D::<u64, u64>::new([]);
+
+        assert_eq!(counter.count(.., ..), 0);
+    }
+}
@@ -0,0 +1,578 @@
+// Red-black balancing, written against closures like `avl::avl`:
+//
+//   get_left, get_right  children of a node in the newest version
+//   get_red              color of a node in the newest version
+//   modify               sets the children and color of a node
+//
+// There are no parent pointers. Each update passes the path from the root
+// down to where it changed the tree, and the fixups walk back up it. Every
+// update makes at most three rotations, so structural changes stay O(1)
+// amortized, while recoloring only touches colors.
+
+use std::marker::PhantomData;
+
+/// The closures of one tree, so that the helpers below can share them
+struct Tree<'f, NodePtr, GetLeft, GetRight, GetRed, Modify> {
+    get_left: &'f GetLeft,
+    get_right: &'f GetRight,
+    get_red: &'f GetRed,
+    modify: &'f mut Modify,
+    _node: PhantomData<NodePtr>,
+}
+
+impl<NodePtr, GetLeft, GetRight, GetRed, Modify>
+    Tree<'_, NodePtr, GetLeft, GetRight, GetRed, Modify>
+where
+    NodePtr: Copy + PartialEq,
+    GetLeft: Fn(NodePtr) -> Option<NodePtr>,
+    GetRight: Fn(NodePtr) -> Option<NodePtr>,
+    GetRed: Fn(NodePtr) -> bool,
+    Modify: FnMut(NodePtr, Option<NodePtr>, Option<NodePtr>, bool),
+{
+    fn left(&self, node: NodePtr) -> Option<NodePtr> {
+        (self.get_left)(node)
+    }
+
+    fn right(&self, node: NodePtr) -> Option<NodePtr> {
+        (self.get_right)(node)
+    }
+
+    /// Missing children count as black
+    fn is_red(&self, node: Option<NodePtr>) -> bool {
+        node.is_some_and(|node| (self.get_red)(node))
+    }
+
+    fn set_left(&mut self, node: NodePtr, left: Option<NodePtr>) {
+        let (right, red) = (self.right(node), self.is_red(Some(node)));
+        (self.modify)(node, left, right, red);
+    }
+
+    fn set_right(&mut self, node: NodePtr, right: Option<NodePtr>) {
+        let (left, red) = (self.left(node), self.is_red(Some(node)));
+        (self.modify)(node, left, right, red);
+    }
+
+    fn set_red(&mut self, node: NodePtr, red: bool) {
+        if self.is_red(Some(node)) != red {
+            let (left, right) = (self.left(node), self.right(node));
+            (self.modify)(node, left, right, red);
+        }
+    }
+
+    /// Points `parent` at `new` where it pointed at `old`, or makes `new`
+    /// the root if `old` was the root
+    fn replace_child(
+        &mut self,
+        parent: Option<NodePtr>,
+        old: NodePtr,
+        new: Option<NodePtr>,
+        root: &mut Option<NodePtr>,
+    ) {
+        match parent {
+            Some(parent) if self.left(parent) == Some(old) => self.set_left(parent, new),
+            Some(parent) => self.set_right(parent, new),
+            None => *root = new,
+        }
+    }
+
+    fn rotate_left(&mut self, node: NodePtr) -> NodePtr {
+        let new_root = self.right(node).unwrap();
+
+        let inner = self.left(new_root);
+        self.set_right(node, inner);
+        self.set_left(new_root, Some(node));
+
+        new_root
+    }
+
+    fn rotate_right(&mut self, node: NodePtr) -> NodePtr {
+        let new_root = self.left(node).unwrap();
+
+        let inner = self.right(new_root);
+        self.set_left(node, inner);
+        self.set_right(new_root, Some(node));
+
+        new_root
+    }
+
+    /// Rotates `node` towards `left`, relinking it under `parent`
+    fn rotate(
+        &mut self,
+        parent: Option<NodePtr>,
+        node: NodePtr,
+        left: bool,
+        root: &mut Option<NodePtr>,
+    ) -> NodePtr {
+        let new_root = if left {
+            self.rotate_left(node)
+        } else {
+            self.rotate_right(node)
+        };
+        self.replace_child(parent, node, Some(new_root), root);
+
+        new_root
+    }
+
+    fn child(&self, node: NodePtr, left: bool) -> Option<NodePtr> {
+        if left {
+            self.left(node)
+        } else {
+            self.right(node)
+        }
+    }
+
+    fn insert_fixup(&mut self, path: &[NodePtr]) -> Option<NodePtr> {
+        let root = self.resolve_red(path);
+
+        if let Some(root) = root {
+            self.set_red(root, false);
+        }
+
+        root
+    }
+
+    /// Rotates and recolors up `path` until no red node has a red parent,
+    /// except maybe below a red root, and returns the root
+    fn resolve_red(&mut self, path: &[NodePtr]) -> Option<NodePtr> {
+        let mut root = path.first().copied();
+        let mut index = path.len() - 1;
+
+        // `path[index]` is red, and may have a red parent
+        while index >= 2 && self.is_red(Some(path[index - 1])) {
+            let node = path[index];
+            let parent = path[index - 1];
+            let grandparent = path[index - 2];
+            let great_grandparent = index.checked_sub(3).map(|index| path[index]);
+
+            let parent_is_left = self.left(grandparent) == Some(parent);
+            let uncle = self.child(grandparent, !parent_is_left);
+
+            if self.is_red(uncle) {
+                self.set_red(parent, false);
+                self.set_red(uncle.unwrap(), false);
+                self.set_red(grandparent, true);
+
+                index -= 2;
+                continue;
+            }
+
+            // Bring the red pair to the outside, then rotate the grandparent
+            let mut parent = parent;
+            if self.child(parent, !parent_is_left) == Some(node) {
+                parent = self.rotate(Some(grandparent), parent, parent_is_left, &mut root);
+            }
+
+            self.set_red(parent, false);
+            self.set_red(grandparent, true);
+            self.rotate(great_grandparent, grandparent, !parent_is_left, &mut root);
+
+            break;
+        }
+
+        root
+    }
+
+    fn delete(&mut self, path: &[NodePtr]) -> Option<NodePtr> {
+        let mut root = path.first().copied();
+
+        let deleted = *path.last()?;
+        let parent = path.len().checked_sub(2).map(|index| path[index]);
+
+        // Ancestors of the position the removal leaves a black height
+        // deficit at, and which side of the last of them it is on
+        let mut ancestors = path[..path.len() - 1].to_vec();
+        let (replacement, removed_red, deficit_is_left) =
+            match (self.left(deleted), self.right(deleted)) {
+                (Some(left), Some(right)) => {
+                    // The successor, the leftmost node of the right subtree,
+                    // takes the place and color of the deleted node
+                    let mut successor = right;
+                    let mut displaced_path = Vec::new();
+                    while let Some(lesser) = self.left(successor) {
+                        displaced_path.push(successor);
+                        successor = lesser;
+                    }
+
+                    let removed_red = self.is_red(Some(successor));
+                    let deleted_red = self.is_red(Some(deleted));
+
+                    ancestors.push(successor);
+                    let deficit_is_left = match displaced_path.last() {
+                        Some(&successor_parent) => {
+                            let right_of_successor = self.right(successor);
+                            self.set_left(successor_parent, right_of_successor);
+                            self.set_right(successor, Some(right));
+                            ancestors.extend(displaced_path.iter());
+                            true
+                        }
+                        None => false,
+                    };
+
+                    self.set_left(successor, Some(left));
+                    self.set_red(successor, deleted_red);
+
+                    (Some(successor), removed_red, deficit_is_left)
+                }
+                (left, right) => {
+                    let deficit_is_left =
+                        parent.is_some_and(|parent| self.left(parent) == Some(deleted));
+
+                    (left.or(right), self.is_red(Some(deleted)), deficit_is_left)
+                }
+            };
+
+        self.replace_child(parent, deleted, replacement, &mut root);
+
+        if !removed_red {
+            // The child that took the removed node's place
+            let child = match ancestors.last() {
+                Some(&last) => self.child(last, deficit_is_left),
+                None => root,
+            };
+            self.delete_fixup(ancestors, child, deficit_is_left, &mut root);
+        }
+
+        root
+    }
+
+    /// Restores black heights after a black node was removed above `node`,
+    /// the `left` child of the last of `ancestors`
+    fn delete_fixup(
+        &mut self,
+        mut ancestors: Vec<NodePtr>,
+        mut node: Option<NodePtr>,
+        mut left: bool,
+        root: &mut Option<NodePtr>,
+    ) {
+        while !self.is_red(node) {
+            let Some(&parent) = ancestors.last() else {
+                break;
+            };
+            let grandparent = ancestors.len().checked_sub(2).map(|index| ancestors[index]);
+
+            // The deficit means the sibling's subtree holds a black node
+            let mut sibling = self.child(parent, !left).unwrap();
+
+            if self.is_red(Some(sibling)) {
+                self.set_red(sibling, false);
+                self.set_red(parent, true);
+                let new_top = self.rotate(grandparent, parent, left, root);
+
+                let last = ancestors.len() - 1;
+                ancestors.insert(last, new_top);
+                sibling = self.child(parent, !left).unwrap();
+            }
+
+            let near = self.child(sibling, left);
+            let far = self.child(sibling, !left);
+
+            if !self.is_red(near) && !self.is_red(far) {
+                // Push the deficit up to the parent
+                self.set_red(sibling, true);
+
+                node = ancestors.pop();
+                left = ancestors
+                    .last()
+                    .is_some_and(|&grandparent| self.left(grandparent) == node);
+                continue;
+            }
+
+            if !self.is_red(far) {
+                self.set_red(near.unwrap(), false);
+                self.set_red(sibling, true);
+                sibling = self.rotate(Some(parent), sibling, !left, root);
+            }
+
+            let parent_red = self.is_red(Some(parent));
+            self.set_red(sibling, parent_red);
+            self.set_red(parent, false);
+            if let Some(far) = self.child(sibling, !left) {
+                self.set_red(far, false);
+            }
+
+            let grandparent = ancestors.len().checked_sub(2).map(|index| ancestors[index]);
+            self.rotate(grandparent, parent, left, root);
+
+            node = *root;
+            break;
+        }
+
+        if let Some(node) = node {
+            self.set_red(node, false);
+        }
+    }
+
+    /// Number of black nodes from `node` down to a missing child
+    fn black_height(&self, mut node: Option<NodePtr>) -> u64 {
+        let mut height = 0;
+        while let Some(current) = node {
+            if !self.is_red(Some(current)) {
+                height += 1;
+            }
+            node = self.left(current);
+        }
+
+        height
+    }
+
+    /// Makes a red root black, and returns the black height after
+    fn blacken(&mut self, root: Option<NodePtr>, height: u64) -> u64 {
+        match root {
+            Some(root) if self.is_red(Some(root)) => {
+                self.set_red(root, false);
+                height + 1
+            }
+            _ => height,
+        }
+    }
+
+    /// Joins the trees at `left` and `right`, of the given black heights,
+    /// with `pivot`, which is in neither, between them. Returns the black
+    /// root of the joined tree and its black height.
+    ///
+    /// The pivot hangs red from the spine of the taller tree, at the black
+    /// node as tall as the shorter tree, and is resolved like an insertion.
+    /// This takes O(|left_height - right_height| + 1).
+    fn join(
+        &mut self,
+        left: Option<NodePtr>,
+        left_height: u64,
+        pivot: NodePtr,
+        right: Option<NodePtr>,
+        right_height: u64,
+    ) -> (NodePtr, u64) {
+        let left_height = self.blacken(left, left_height);
+        let right_height = self.blacken(right, right_height);
+
+        if left_height == right_height {
+            (self.modify)(pivot, left, right, false);
+            return (pivot, left_height + 1);
+        }
+
+        let left_is_taller = left_height > right_height;
+        let (taller, taller_height, shorter_height) = if left_is_taller {
+            (left, left_height, right_height)
+        } else {
+            (right, right_height, left_height)
+        };
+
+        let mut path = Vec::new();
+        let mut node = taller;
+        let mut height = taller_height;
+        while let Some(current) = node {
+            let red = self.is_red(Some(current));
+            if !red && height == shorter_height {
+                break;
+            }
+
+            path.push(current);
+            if !red {
+                height -= 1;
+            }
+            node = self.child(current, !left_is_taller);
+        }
+
+        // Every node of the spine is rewritten, so that backends copying
+        // what `modify` touches copy the way down to the pivot
+        let (&parent, spine) = path.split_last().unwrap();
+        for &spine_node in spine {
+            let (left, right) = (self.left(spine_node), self.right(spine_node));
+            let red = self.is_red(Some(spine_node));
+            (self.modify)(spine_node, left, right, red);
+        }
+        if left_is_taller {
+            (self.modify)(pivot, node, right, true);
+            self.set_right(parent, Some(pivot));
+        } else {
+            (self.modify)(pivot, left, node, true);
+            self.set_left(parent, Some(pivot));
+        }
+        path.push(pivot);
+
+        let root = self.resolve_red(&path).unwrap();
+        let height = self.blacken(Some(root), taller_height);
+
+        (root, height)
+    }
+
+    /// Joins the trees at `left` and `right`, where everything in `left` is
+    /// at most everything in `right`
+    fn join_trees(
+        &mut self,
+        left: Option<NodePtr>,
+        left_height: u64,
+        right: Option<NodePtr>,
+        right_height: u64,
+    ) -> (Option<NodePtr>, u64) {
+        let Some(right) = right else {
+            return (left, left_height);
+        };
+        let (first, (rest, rest_height)) = self.split_first(right, right_height);
+        let (root, height) = self.join(left, left_height, first, rest, rest_height);
+
+        (Some(root), height)
+    }
+
+    /// Unlinks the first node of the tree at `node`, returning it and the
+    /// remaining tree
+    fn split_first(&mut self, node: NodePtr, height: u64) -> (NodePtr, (Option<NodePtr>, u64)) {
+        let child_height = height - u64::from(!self.is_red(Some(node)));
+        let right = self.right(node);
+
+        let Some(left) = self.left(node) else {
+            return (node, (right, child_height));
+        };
+        let (first, (rest, rest_height)) = self.split_first(left, child_height);
+        let (root, height) = self.join(rest, rest_height, node, right, child_height);
+
+        (first, (Some(root), height))
+    }
+
+    /// Splits the tree at `node` into the nodes `before` holds for, which
+    /// must come first in order, and the rest
+    fn split_by(
+        &mut self,
+        node: Option<NodePtr>,
+        height: u64,
+        before: &impl Fn(NodePtr) -> bool,
+    ) -> ((Option<NodePtr>, u64), (Option<NodePtr>, u64)) {
+        let Some(current) = node else {
+            return ((None, 0), (None, 0));
+        };
+        let child_height = height - u64::from(!self.is_red(Some(current)));
+        let (left, right) = (self.left(current), self.right(current));
+
+        if before(current) {
+            let ((less, less_height), rest) = self.split_by(right, child_height, before);
+            let (root, height) = self.join(left, child_height, current, less, less_height);
+            ((Some(root), height), rest)
+        } else {
+            let (less, (rest, rest_height)) = self.split_by(left, child_height, before);
+            let (root, height) = self.join(rest, rest_height, current, right, child_height);
+            (less, (Some(root), height))
+        }
+    }
+
+    /// Merges the nodes of the tree at `other`, whose colors are ignored,
+    /// into the tree at `node`
+    fn union(
+        &mut self,
+        node: Option<NodePtr>,
+        height: u64,
+        other: Option<NodePtr>,
+        less: &impl Fn(NodePtr, NodePtr) -> bool,
+    ) -> (Option<NodePtr>, u64) {
+        let Some(pivot) = other else {
+            return (node, height);
+        };
+        let (other_left, other_right) = (self.left(pivot), self.right(pivot));
+
+        let ((less_root, less_height), (rest, rest_height)) =
+            self.split_by(node, height, &|node| less(node, pivot));
+        let (left, left_height) = self.union(less_root, less_height, other_left, less);
+        let (right, right_height) = self.union(rest, rest_height, other_right, less);
+        let (root, height) = self.join(left, left_height, pivot, right, right_height);
+
+        (Some(root), height)
+    }
+}
+
+/// Recolors and rotates after an insertion
+///
+/// `path` runs from the root to the inserted node, which must be red and
+/// already linked to its parent. Returns the new root.
+pub(crate) fn insert_fixup<NodePtr: Copy + PartialEq>(
+    get_left: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_right: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_red: &impl Fn(NodePtr) -> bool,
+    modify: &mut impl FnMut(NodePtr, Option<NodePtr>, Option<NodePtr>, bool),
+    path: &[NodePtr],
+) -> Option<NodePtr> {
+    let mut tree = Tree {
+        get_left,
+        get_right,
+        get_red,
+        modify,
+        _node: PhantomData,
+    };
+
+    tree.insert_fixup(path)
+}
+
+/// Unlinks the last node of `path`, which runs from the root down to it,
+/// and rebalances. Returns the new root.
+pub(crate) fn delete<NodePtr: Copy + PartialEq>(
+    get_left: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_right: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_red: &impl Fn(NodePtr) -> bool,
+    modify: &mut impl FnMut(NodePtr, Option<NodePtr>, Option<NodePtr>, bool),
+    path: &[NodePtr],
+) -> Option<NodePtr> {
+    let mut tree = Tree {
+        get_left,
+        get_right,
+        get_red,
+        modify,
+        _node: PhantomData,
+    };
+
+    tree.delete(path)
+}
+
+/// Removes every node in a range from the tree at `root`, and returns the
+/// new root
+///
+/// `before` and `after` tell the nodes before and after the range apart. The
+/// nodes before and after it are split off and joined, in O(log n).
+pub(crate) fn delete_range<NodePtr: Copy + PartialEq>(
+    get_left: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_right: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_red: &impl Fn(NodePtr) -> bool,
+    modify: &mut impl FnMut(NodePtr, Option<NodePtr>, Option<NodePtr>, bool),
+    root: Option<NodePtr>,
+    before: &impl Fn(NodePtr) -> bool,
+    after: &impl Fn(NodePtr) -> bool,
+) -> Option<NodePtr> {
+    let mut tree = Tree {
+        get_left,
+        get_right,
+        get_red,
+        modify,
+        _node: PhantomData,
+    };
+
+    let height = tree.black_height(root);
+    let ((less, less_height), (rest, rest_height)) = tree.split_by(root, height, before);
+    let (_, (greater, greater_height)) = tree.split_by(rest, rest_height, &|node| !after(node));
+
+    tree.join_trees(less, less_height, greater, greater_height)
+        .0
+}
+
+/// Merges the tree at `other`, whose nodes are in no version yet and whose
+/// colors are ignored, into the tree at `root`, and returns the new root
+///
+/// `less(node, pivot)` tells whether the datum at `node` is less than the
+/// one at `pivot`. The tree at `root` is split at every node of `other`,
+/// which becomes the pivot joining the halves merged below it, so a
+/// balanced `other` of m nodes takes O(m log(n / m + 1)).
+pub(crate) fn union<NodePtr: Copy + PartialEq>(
+    get_left: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_right: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_red: &impl Fn(NodePtr) -> bool,
+    modify: &mut impl FnMut(NodePtr, Option<NodePtr>, Option<NodePtr>, bool),
+    root: Option<NodePtr>,
+    other: Option<NodePtr>,
+    less: &impl Fn(NodePtr, NodePtr) -> bool,
+) -> Option<NodePtr> {
+    let mut tree = Tree {
+        get_left,
+        get_right,
+        get_red,
+        modify,
+        _node: PhantomData,
+    };
+
+    let height = tree.black_height(root);
+    tree.union(root, height, other, less).0
+}
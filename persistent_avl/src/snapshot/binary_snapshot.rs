@@ -0,0 +1,18 @@
+use std::io::{Read, Write};
+
+use crate::snapshot::error::SnapshotError;
+
+/// A tree that can be saved to and restored from the crate's binary
+/// snapshot format.
+///
+/// A snapshot holds every version of the tree. It starts with a header
+/// identifying the format version, the backend and the width of the data
+/// records, followed by the tree's arenas stored as sections of fixed-width
+/// little-endian records, each protected by a CRC-32.
+pub trait BinarySnapshot: Sized {
+    /// Writes the whole tree, including all past versions, to `writer`
+    fn write_to(&self, writer: impl Write) -> Result<(), SnapshotError>;
+
+    /// Restores a tree previously written with `write_to`
+    fn read_from(reader: impl Read) -> Result<Self, SnapshotError>;
+}
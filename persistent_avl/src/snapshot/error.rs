@@ -0,0 +1,95 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Reasons a snapshot could not be written or read back
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The underlying reader or writer failed
+    Io(io::Error),
+    /// The input ended in the middle of the header or a section
+    Truncated,
+    /// The input does not start with the snapshot magic bytes
+    BadMagic,
+    /// The snapshot was written by an incompatible format version
+    UnsupportedVersion(u16),
+    /// The snapshot holds a different kind of tree than the one requested
+    WrongBackend { expected: u16, found: u16 },
+    /// The snapshot's data records are not `Data::WIDTH` bytes wide
+    DataWidthMismatch { expected: u32, found: u32 },
+    /// A required section is missing from the snapshot
+    MissingSection([u8; 4]),
+    /// A section's records are not the width this format version expects
+    RecordWidthMismatch {
+        section: [u8; 4],
+        expected: u32,
+        found: u32,
+    },
+    /// The stored checksum of a section does not match its contents
+    ChecksumMismatch { section: [u8; 4] },
+    /// The contents passed their checksums but do not describe a valid tree
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "snapshot i/o error: {}", err),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file (bad magic)"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot format version {}", version)
+            }
+            SnapshotError::WrongBackend { expected, found } => write!(
+                f,
+                "snapshot holds backend {} but backend {} was requested",
+                found, expected
+            ),
+            SnapshotError::DataWidthMismatch { expected, found } => write!(
+                f,
+                "snapshot data records are {} bytes wide, expected {}",
+                found, expected
+            ),
+            SnapshotError::MissingSection(section) => {
+                write!(f, "snapshot is missing section {}", tag_name(section))
+            }
+            SnapshotError::RecordWidthMismatch {
+                section,
+                expected,
+                found,
+            } => write!(
+                f,
+                "section {} has {}-byte records, expected {}",
+                tag_name(section),
+                found,
+                expected
+            ),
+            SnapshotError::ChecksumMismatch { section } => {
+                write!(f, "checksum mismatch in section {}", tag_name(section))
+            }
+            SnapshotError::Corrupt(reason) => write!(f, "corrupt snapshot: {}", reason),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::UnexpectedEof => SnapshotError::Truncated,
+            _ => SnapshotError::Io(err),
+        }
+    }
+}
+
+fn tag_name(tag: &[u8; 4]) -> String {
+    String::from_utf8_lossy(tag).into_owned()
+}
@@ -0,0 +1,515 @@
+use std::io::{Read, Write};
+
+use crate::snapshot::crc::{crc32, Crc32};
+use crate::snapshot::error::SnapshotError;
+
+// File layout, all integers little-endian:
+//
+//   header   magic[8] | version u16 | backend u16 | data width u32
+//            | section count u32 | header crc u32
+//   section  tag[4] | record width u32 | record count u64
+//            | count * width bytes of records | section crc u32
+//
+// The section crc covers both the section header and its records, so a
+// damaged record count is caught as well as damaged records.
+
+pub(crate) const MAGIC: [u8; 8] = *b"PAVLSNAP";
+pub(crate) const FORMAT_VERSION: u16 = 2;
+
+pub(crate) const HEADER_LEN: usize = 24;
+pub(crate) const SECTION_HEADER_LEN: usize = 16;
+pub(crate) const SECTION_TRAILER_LEN: usize = 4;
+
+pub(crate) const BACKEND_FAT_NODE_AVL: u16 = 1;
+pub(crate) const BACKEND_PATH_COPY_AVL: u16 = 2;
+
+/// Encoding of `None` in pointer fields
+const NULL_PTR: u64 = u64::MAX;
+
+/// Records are encoded into a buffer of about this size before being
+/// handed to the underlying writer
+const CHUNK_LEN: usize = 64 * 1024;
+
+pub(crate) fn write_u64(buf: &mut [u8], at: usize, value: u64) {
+    buf[at..at + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn read_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+pub(crate) fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+pub(crate) fn write_ptr(buf: &mut [u8], at: usize, ptr: Option<usize>) {
+    write_u64(buf, at, ptr.map_or(NULL_PTR, |ptr| ptr as u64));
+}
+
+pub(crate) fn read_ptr(buf: &[u8], at: usize) -> Option<usize> {
+    match read_u64(buf, at) {
+        NULL_PTR => None,
+        ptr => Some(ptr as usize),
+    }
+}
+
+pub(crate) struct Header {
+    pub(crate) backend: u16,
+    pub(crate) data_width: u32,
+    pub(crate) section_count: u32,
+}
+
+impl Header {
+    pub(crate) fn encode(&self) -> [u8; HEADER_LEN] {
+        let mut buf = [0u8; HEADER_LEN];
+
+        buf[0..8].copy_from_slice(&MAGIC);
+        buf[8..10].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        buf[10..12].copy_from_slice(&self.backend.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.data_width.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.section_count.to_le_bytes());
+
+        let crc = crc32(&buf[0..20]);
+        buf[20..24].copy_from_slice(&crc.to_le_bytes());
+
+        buf
+    }
+
+    /// Decodes and validates a header against the expected backend and data width
+    pub(crate) fn decode(
+        buf: &[u8],
+        backend: u16,
+        data_width: usize,
+    ) -> Result<Header, SnapshotError> {
+        if buf.len() < HEADER_LEN {
+            return Err(SnapshotError::Truncated);
+        }
+
+        if buf[0..8] != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+
+        if crc32(&buf[0..20]) != read_u32(buf, 20) {
+            return Err(SnapshotError::ChecksumMismatch { section: *b"HEAD" });
+        }
+
+        let version = u16::from_le_bytes([buf[8], buf[9]]);
+        if version != FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let header = Header {
+            backend: u16::from_le_bytes([buf[10], buf[11]]),
+            data_width: read_u32(buf, 12),
+            section_count: read_u32(buf, 16),
+        };
+
+        if header.backend != backend {
+            return Err(SnapshotError::WrongBackend {
+                expected: backend,
+                found: header.backend,
+            });
+        }
+
+        if header.data_width as usize != data_width {
+            return Err(SnapshotError::DataWidthMismatch {
+                expected: data_width as u32,
+                found: header.data_width,
+            });
+        }
+
+        Ok(header)
+    }
+}
+
+fn encode_section_header(tag: [u8; 4], width: usize, count: usize) -> [u8; SECTION_HEADER_LEN] {
+    let mut buf = [0u8; SECTION_HEADER_LEN];
+
+    buf[0..4].copy_from_slice(&tag);
+    buf[4..8].copy_from_slice(&(width as u32).to_le_bytes());
+    write_u64(&mut buf, 8, count as u64);
+
+    buf
+}
+
+pub(crate) struct SnapshotWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    /// Writes the file header; exactly `section_count` sections must follow
+    pub(crate) fn new(
+        mut writer: W,
+        backend: u16,
+        data_width: usize,
+        section_count: u32,
+    ) -> Result<Self, SnapshotError> {
+        let header = Header {
+            backend,
+            data_width: data_width as u32,
+            section_count,
+        };
+        writer.write_all(&header.encode())?;
+
+        Ok(SnapshotWriter { writer })
+    }
+
+    /// Writes a section of `count` records of `width` bytes each
+    ///
+    /// `records` must yield exactly `count` items, each of which is encoded by
+    /// `encode` into a zeroed buffer of `width` bytes.
+    pub(crate) fn section<T>(
+        &mut self,
+        tag: [u8; 4],
+        width: usize,
+        count: usize,
+        records: impl Iterator<Item = T>,
+        mut encode: impl FnMut(T, &mut [u8]),
+    ) -> Result<(), SnapshotError> {
+        let section_header = encode_section_header(tag, width, count);
+
+        let mut crc = Crc32::new();
+        crc.update(&section_header);
+        self.writer.write_all(&section_header)?;
+
+        let records_per_chunk = (CHUNK_LEN / width.max(1)).max(1);
+        let mut chunk = Vec::with_capacity(records_per_chunk * width);
+        let mut written = 0;
+
+        for record in records {
+            let start = chunk.len();
+            chunk.resize(start + width, 0);
+            encode(record, &mut chunk[start..]);
+            written += 1;
+
+            if chunk.len() >= records_per_chunk * width {
+                crc.update(&chunk);
+                self.writer.write_all(&chunk)?;
+                chunk.clear();
+            }
+        }
+
+        debug_assert_eq!(written, count, "section record count mismatch");
+
+        crc.update(&chunk);
+        self.writer.write_all(&chunk)?;
+        self.writer.write_all(&crc.finish().to_le_bytes())?;
+
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<(), SnapshotError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+pub(crate) struct Section {
+    pub(crate) tag: [u8; 4],
+    pub(crate) width: usize,
+    pub(crate) count: usize,
+    pub(crate) payload: Vec<u8>,
+}
+
+impl Section {
+    pub(crate) fn len(&self) -> usize {
+        self.count
+    }
+
+    pub(crate) fn records(&self) -> impl Iterator<Item = &[u8]> {
+        (0..self.count).map(|i| &self.payload[i * self.width..(i + 1) * self.width])
+    }
+}
+
+/// Location of a section inside a snapshot held in memory
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct SectionSpan {
+    pub(crate) tag: [u8; 4],
+    pub(crate) width: usize,
+    pub(crate) count: usize,
+    /// Offset of the first record
+    pub(crate) offset: usize,
+}
+
+impl SectionSpan {
+    /// Offset of record `index`
+    pub(crate) fn record(&self, index: usize) -> usize {
+        self.offset + index * self.width
+    }
+
+    /// Checks the section's checksum against the snapshot bytes in `buf`
+    pub(crate) fn verify(&self, buf: &[u8]) -> Result<(), SnapshotError> {
+        let start = self.offset - SECTION_HEADER_LEN;
+        let end = self.offset + self.count * self.width;
+
+        let mut crc = Crc32::new();
+        crc.update(&buf[start..end]);
+        if crc.finish() != read_u32(buf, end) {
+            return Err(SnapshotError::ChecksumMismatch { section: self.tag });
+        }
+
+        Ok(())
+    }
+}
+
+/// Locates the sections of a snapshot held in memory without reading their
+/// records, so the cost depends only on the number of sections
+pub(crate) fn section_spans(
+    buf: &[u8],
+    backend: u16,
+    data_width: usize,
+) -> Result<Vec<SectionSpan>, SnapshotError> {
+    let header = Header::decode(buf, backend, data_width)?;
+
+    let mut spans = Vec::new();
+    let mut offset = HEADER_LEN;
+    for _ in 0..header.section_count {
+        if buf.len() < offset + SECTION_HEADER_LEN {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let tag: [u8; 4] = buf[offset..offset + 4].try_into().unwrap();
+        let width = read_u32(buf, offset + 4) as usize;
+        let count = read_u64(buf, offset + 8);
+
+        let len = (width as u64)
+            .checked_mul(count)
+            .and_then(|len| len.checked_add((SECTION_HEADER_LEN + SECTION_TRAILER_LEN) as u64))
+            .ok_or(SnapshotError::Corrupt("section length overflows"))?;
+        if ((buf.len() - offset) as u64) < len {
+            return Err(SnapshotError::Truncated);
+        }
+
+        spans.push(SectionSpan {
+            tag,
+            width,
+            count: count as usize,
+            offset: offset + SECTION_HEADER_LEN,
+        });
+        offset += len as usize;
+    }
+
+    Ok(spans)
+}
+
+/// Finds the section tagged `tag` among `spans`, checking that its records
+/// are `width` bytes
+pub(crate) fn find_span(
+    spans: &[SectionSpan],
+    tag: [u8; 4],
+    width: usize,
+) -> Result<SectionSpan, SnapshotError> {
+    let span = spans
+        .iter()
+        .find(|span| span.tag == tag)
+        .ok_or(SnapshotError::MissingSection(tag))?;
+
+    if span.width != width {
+        return Err(SnapshotError::RecordWidthMismatch {
+            section: tag,
+            expected: width as u32,
+            found: span.width as u32,
+        });
+    }
+
+    Ok(*span)
+}
+
+/// All checksum-verified sections of a snapshot
+pub(crate) struct SnapshotContents {
+    sections: Vec<Section>,
+}
+
+impl SnapshotContents {
+    /// Reads the header and every section, verifying all checksums
+    pub(crate) fn read(
+        mut reader: impl Read,
+        backend: u16,
+        data_width: usize,
+    ) -> Result<SnapshotContents, SnapshotError> {
+        let mut header = [0u8; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let header = Header::decode(&header, backend, data_width)?;
+
+        let mut sections = Vec::new();
+        for _ in 0..header.section_count {
+            let mut section_header = [0u8; SECTION_HEADER_LEN];
+            reader.read_exact(&mut section_header)?;
+
+            let tag: [u8; 4] = section_header[0..4].try_into().unwrap();
+            let width = read_u32(&section_header, 4) as u64;
+            let count = read_u64(&section_header, 8);
+
+            let len = width
+                .checked_mul(count)
+                .ok_or(SnapshotError::Corrupt("section length overflows"))?;
+
+            // Read through `take` so that a damaged count cannot trigger a huge
+            // allocation before the checksum gets a chance to reject it
+            let mut payload = Vec::new();
+            reader.by_ref().take(len).read_to_end(&mut payload)?;
+            if payload.len() as u64 != len {
+                return Err(SnapshotError::Truncated);
+            }
+
+            let mut stored_crc = [0u8; SECTION_TRAILER_LEN];
+            reader.read_exact(&mut stored_crc)?;
+
+            let mut crc = Crc32::new();
+            crc.update(&section_header);
+            crc.update(&payload);
+            if crc.finish() != u32::from_le_bytes(stored_crc) {
+                return Err(SnapshotError::ChecksumMismatch { section: tag });
+            }
+
+            sections.push(Section {
+                tag,
+                width: width as usize,
+                count: count as usize,
+                payload,
+            });
+        }
+
+        Ok(SnapshotContents { sections })
+    }
+
+    /// Finds the section tagged `tag`, checking that its records are `width` bytes
+    pub(crate) fn section(&self, tag: [u8; 4], width: usize) -> Result<&Section, SnapshotError> {
+        self.optional_section(tag, width)?
+            .ok_or(SnapshotError::MissingSection(tag))
+    }
+
+    /// Like `section`, for sections that older or differently configured
+    /// trees leave out
+    pub(crate) fn optional_section(
+        &self,
+        tag: [u8; 4],
+        width: usize,
+    ) -> Result<Option<&Section>, SnapshotError> {
+        let Some(section) = self.sections.iter().find(|section| section.tag == tag) else {
+            return Ok(None);
+        };
+
+        if section.width != width {
+            return Err(SnapshotError::RecordWidthMismatch {
+                section: tag,
+                expected: width as u32,
+                found: section.width as u32,
+            });
+        }
+
+        Ok(Some(section))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat_node_avl::fat_node_avl::FatNodeAvl;
+    use crate::path_copy_avl::path_copy_avl::PathCopyAvl;
+    use crate::persistent_avl_tree::PersistentAvlTree;
+    use crate::snapshot::binary_snapshot::BinarySnapshot;
+
+    fn snapshot() -> Vec<u8> {
+        let mut tree = PathCopyAvl::new();
+        for item in 0..50u64 {
+            tree.insert(item * 7 % 50);
+        }
+
+        let mut bytes = Vec::new();
+        tree.write_to(&mut bytes).unwrap();
+
+        bytes
+    }
+
+    fn read(bytes: &[u8]) -> Result<PathCopyAvl<u64>, SnapshotError> {
+        PathCopyAvl::read_from(bytes)
+    }
+
+    #[test]
+    fn detects_a_flipped_byte() {
+        let bytes = snapshot();
+
+        // In the header, the first section's header, and its first record
+        for (at, section) in [
+            (12, *b"HEAD"),
+            (HEADER_LEN + 8, *b"DATA"),
+            (HEADER_LEN + SECTION_HEADER_LEN, *b"DATA"),
+        ] {
+            let mut bytes = bytes.clone();
+            bytes[at] ^= 0x10;
+
+            assert!(
+                matches!(read(&bytes), Err(SnapshotError::ChecksumMismatch { section: found }) if found == section),
+                "byte {at}"
+            );
+        }
+
+        // In the trailing checksum of the last section
+        let mut bytes = bytes.clone();
+        *bytes.last_mut().unwrap() ^= 0x10;
+        assert!(matches!(
+            read(&bytes),
+            Err(SnapshotError::ChecksumMismatch { section: _ })
+        ));
+    }
+
+    #[test]
+    fn detects_truncation() {
+        let bytes = snapshot();
+
+        for len in [
+            0,
+            HEADER_LEN - 1,
+            HEADER_LEN + 4,
+            HEADER_LEN + SECTION_HEADER_LEN + 3,
+            bytes.len() - 1,
+        ] {
+            assert!(
+                matches!(read(&bytes[..len]), Err(SnapshotError::Truncated)),
+                "length {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn detects_a_bad_magic() {
+        let mut bytes = snapshot();
+        bytes[0] = b'X';
+
+        assert!(matches!(read(&bytes), Err(SnapshotError::BadMagic)));
+    }
+
+    #[test]
+    fn detects_an_unsupported_version() {
+        let mut bytes = snapshot();
+        bytes[8..10].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let crc = crc32(&bytes[0..20]);
+        bytes[20..24].copy_from_slice(&crc.to_le_bytes());
+
+        assert!(matches!(
+            read(&bytes),
+            Err(SnapshotError::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn detects_the_wrong_backend_and_data_width() {
+        let bytes = snapshot();
+
+        assert!(matches!(
+            FatNodeAvl::<u64>::read_from(bytes.as_slice()),
+            Err(SnapshotError::WrongBackend {
+                expected: BACKEND_FAT_NODE_AVL,
+                found: BACKEND_PATH_COPY_AVL
+            })
+        ));
+        assert!(matches!(
+            PathCopyAvl::<u32>::read_from(bytes.as_slice()),
+            Err(SnapshotError::DataWidthMismatch {
+                expected: 4,
+                found: 8
+            })
+        ));
+    }
+}
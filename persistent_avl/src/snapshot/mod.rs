@@ -0,0 +1,5 @@
+pub mod binary_snapshot;
+pub(crate) mod crc;
+pub mod error;
+pub(crate) mod format;
+pub mod record;
@@ -0,0 +1,59 @@
+/// A value with a fixed-width little-endian binary encoding.
+///
+/// Tree data must implement this to be written to or read from a snapshot.
+/// Every encoded value occupies exactly `WIDTH` bytes, which lets arenas be
+/// stored as flat arrays of fixed-width records.
+pub trait Record: Sized {
+    const WIDTH: usize;
+
+    /// Writes the value into `buf`, which is exactly `WIDTH` bytes long
+    fn encode(&self, buf: &mut [u8]);
+
+    /// Reads a value back from `buf`, which is exactly `WIDTH` bytes long
+    fn decode(buf: &[u8]) -> Self;
+}
+
+macro_rules! impl_record_for_int {
+    ($($int:ty),*) => {
+        $(
+            impl Record for $int {
+                const WIDTH: usize = std::mem::size_of::<$int>();
+
+                fn encode(&self, buf: &mut [u8]) {
+                    buf.copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(buf: &[u8]) -> Self {
+                    <$int>::from_le_bytes(buf.try_into().unwrap())
+                }
+            }
+        )*
+    };
+}
+
+impl_record_for_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl<A: Record, B: Record> Record for (A, B) {
+    const WIDTH: usize = A::WIDTH + B::WIDTH;
+
+    fn encode(&self, buf: &mut [u8]) {
+        self.0.encode(&mut buf[..A::WIDTH]);
+        self.1.encode(&mut buf[A::WIDTH..]);
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        (A::decode(&buf[..A::WIDTH]), B::decode(&buf[A::WIDTH..]))
+    }
+}
+
+impl<const N: usize> Record for [u8; N] {
+    const WIDTH: usize = N;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf.copy_from_slice(self);
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        buf.try_into().unwrap()
+    }
+}
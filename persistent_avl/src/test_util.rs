@@ -0,0 +1,166 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::avl::node_store::NodeStore;
+use crate::batch_update::BatchUpdate;
+
+/// A fresh directory under the system temporary directory, removed with
+/// everything in it when dropped
+pub(crate) struct TempDir(PathBuf);
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = env::temp_dir().join(format!(
+            "persistent_avl-{}-{}-{}",
+            name,
+            process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        TempDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A xorshift generator, so that randomized tests are repeatable, seeded
+/// with anything but zero
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    /// A number in `0..bound`
+    pub(crate) fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+/// Applies a random insertion, deletion, range deletion or sorted batch of
+/// items below `items` both to `tree` and to `data`, the sorted items of its
+/// newest version, and returns the timestamp of the version it made, if any
+pub(crate) fn random_update<Tree: BatchUpdate<Data = u64>>(
+    tree: &mut Tree,
+    data: &mut Vec<u64>,
+    rng: &mut Rng,
+    items: u64,
+) -> Option<Tree::Timestamp> {
+    let item = rng.below(items);
+
+    match rng.below(8) {
+        0 => {
+            let range = item..item + rng.below(10);
+            data.retain(|datum| !range.contains(datum));
+            Some(tree.delete_range(range))
+        }
+        1 => {
+            let mut batch: Vec<u64> = (0..rng.below(8)).map(|_| rng.below(items)).collect();
+            batch.sort();
+            data.extend(&batch);
+            data.sort();
+            Some(tree.insert_sorted_batch(batch).unwrap())
+        }
+        2..=4 => {
+            let position = data.iter().position(|&datum| datum == item);
+            let timestamp = tree.delete(&item);
+            assert_eq!(timestamp.is_some(), position.is_some());
+            data.remove(position?);
+            timestamp
+        }
+        _ => {
+            data.insert(data.partition_point(|&datum| datum <= item), item);
+            Some(tree.insert(item))
+        }
+    }
+}
+
+/// Checks that the newest version of `store` is an AVL tree whose heights
+/// are right, and returns its data in order
+pub(crate) fn newest_avl_data<S: NodeStore>(store: &S) -> Vec<&S::Data> {
+    avl_data(store, store.latest_root())
+}
+
+/// Checks that the tree of `store` below `root` is an AVL tree whose heights
+/// are right, and returns its data in order
+pub(crate) fn avl_data<S: NodeStore>(store: &S, root: Option<usize>) -> Vec<&S::Data> {
+    fn walk<'s, S: NodeStore>(
+        store: &'s S,
+        node: Option<usize>,
+        data: &mut Vec<&'s S::Data>,
+    ) -> u64 {
+        let Some(node) = node else {
+            return 0;
+        };
+        let left_height = walk(store, store.left(node), data);
+        data.push(store.datum(node));
+        let right_height = walk(store, store.right(node), data);
+
+        assert!(left_height.abs_diff(right_height) <= 1, "unbalanced node");
+        assert_eq!(store.height(node), left_height.max(right_height) + 1);
+
+        store.height(node)
+    }
+
+    let mut data = Vec::new();
+    walk(store, root, &mut data);
+
+    data
+}
+
+/// Checks that the tree below `root` is a red-black tree, whose root is
+/// black, where no red node has a red child and every path down passes as
+/// many black nodes, and returns its nodes in order
+pub(crate) fn red_black_nodes(
+    root: Option<usize>,
+    left: impl Fn(usize) -> Option<usize>,
+    right: impl Fn(usize) -> Option<usize>,
+    red: impl Fn(usize) -> bool,
+) -> Vec<usize> {
+    /// Number of black nodes on every path down from `node`
+    fn walk(
+        node: Option<usize>,
+        left: &impl Fn(usize) -> Option<usize>,
+        right: &impl Fn(usize) -> Option<usize>,
+        red: &impl Fn(usize) -> bool,
+        nodes: &mut Vec<usize>,
+    ) -> usize {
+        let Some(node) = node else {
+            return 0;
+        };
+        if red(node) {
+            for child in [left(node), right(node)].into_iter().flatten() {
+                assert!(!red(child), "red node with a red child");
+            }
+        }
+
+        let left_height = walk(left(node), left, right, red, nodes);
+        nodes.push(node);
+        let right_height = walk(right(node), left, right, red, nodes);
+        assert_eq!(left_height, right_height, "unequal black heights");
+
+        left_height + usize::from(!red(node))
+    }
+
+    assert!(!root.is_some_and(&red), "red root");
+
+    let mut nodes = Vec::new();
+    walk(root, &left, &right, &red, &mut nodes);
+
+    nodes
+}
@@ -8,26 +8,172 @@ pub fn get_time<'a, T: TimestampSupplier>(
     container: &'a Vec<T>,
     time: &T::Timestamp,
 ) -> Option<&'a T> {
-    if container.is_empty() {
+    search_time(
+        container.len(),
+        |index| container[index].get_timestamp(),
+        time,
+    )
+    .map(|index| &container[index])
+}
+
+/// Finds the index of the last of `len` entries, sorted by timestamp, whose
+/// timestamp is at most `time`
+///
+/// Entries are only reached through `timestamp_at`, so this also works for
+/// entries that are not laid out as a `Vec`, such as records in a mapped file.
+///
+/// Most lookups are for recent times, so the search gallops back from the
+/// newest entry with doubling steps before searching the last step, and takes
+/// O(log d) probes for an answer d entries from the end.
+pub fn search_time<Timestamp: Ord, T: std::borrow::Borrow<Timestamp>>(
+    len: usize,
+    timestamp_at: impl Fn(usize) -> T,
+    time: &Timestamp,
+) -> Option<usize> {
+    if len == 0 {
         return None;
     }
 
-    let mut low: usize = 0;
-    let mut high: usize = container.len() - 1;
+    // The answer is at most `high`, and at least `low` once the loop ends
+    let mut low: usize = len - 1;
+    let mut high: usize = len - 1;
+    let mut step: usize = 1;
+    while *timestamp_at(low).borrow() > *time {
+        if low == 0 {
+            return None;
+        }
+
+        high = low - 1;
+        low = low.saturating_sub(step);
+        step *= 2;
+    }
+
     while low < high {
         let mid: usize = (low + high + 1) / 2;
-        let mid_time: &T::Timestamp = container[mid].get_timestamp();
 
-        if *mid_time > *time {
+        if *timestamp_at(mid).borrow() > *time {
             high = mid - 1;
         } else {
             low = mid;
         }
     }
 
-    if *container[low].get_timestamp() <= *time {
-        Some(&container[low])
-    } else {
-        None
+    Some(low)
+}
+
+/// A copy of the timestamps of sorted entries in Eytzinger order, that of a
+/// breadth-first walk of the perfectly balanced tree over them.
+///
+/// The first probes of every search fall in the same few cache lines, which
+/// makes searching for scattered times faster than `search_time` once the
+/// entries outgrow the cache, though slower for the newest entries. The copy
+/// does not follow entries added after it was built.
+pub(crate) struct EytzingerIndex<Timestamp> {
+    /// Timestamp and index of the entry of the k-th node at index k - 1,
+    /// together since the answer is the last node a search probes
+    layout: Vec<(Timestamp, usize)>,
+}
+
+impl<Timestamp: Ord + Clone> EytzingerIndex<Timestamp> {
+    pub(crate) fn new<T: std::borrow::Borrow<Timestamp>>(
+        len: usize,
+        timestamp_at: impl Fn(usize) -> T,
+    ) -> Self {
+        let mut positions = vec![0; len];
+        Self::fill(1, &mut 0, &mut positions);
+        let layout = positions
+            .into_iter()
+            .map(|position| (timestamp_at(position).borrow().clone(), position))
+            .collect();
+
+        EytzingerIndex { layout }
+    }
+
+    /// Numbers the subtree of the k-th node in order from `next`
+    fn fill(k: usize, next: &mut usize, positions: &mut [usize]) {
+        if k > positions.len() {
+            return;
+        }
+
+        Self::fill(2 * k, next, positions);
+        positions[k - 1] = *next;
+        *next += 1;
+        Self::fill(2 * k + 1, next, positions);
+    }
+
+    /// Number of entries covered
+    pub(crate) fn len(&self) -> usize {
+        self.layout.len()
+    }
+
+    /// Same as `search_time` over the entries covered
+    pub(crate) fn search(&self, time: &Timestamp) -> Option<usize> {
+        let mut k: usize = 1;
+        while k <= self.layout.len() {
+            k = 2 * k + usize::from(self.layout[k - 1].0 <= *time);
+        }
+
+        // The path ends with the last turn right, at the answer, followed by
+        // turns left only
+        k >>= k.trailing_zeros() + 1;
+        k.checked_sub(1).map(|k| self.layout[k].1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Rng;
+
+    /// Index of the last of `timestamps` at most `time`, found by a scan
+    fn scan(timestamps: &[u64], time: u64) -> Option<usize> {
+        timestamps.iter().rposition(|&timestamp| timestamp <= time)
+    }
+
+    /// Sorted timestamps, with gaps and runs of equal ones, for every length
+    /// up to a few hundred
+    fn histories() -> impl Iterator<Item = Vec<u64>> {
+        let mut rng = Rng(17);
+
+        (0..300).map(move |len| {
+            let mut timestamp = rng.below(3);
+            (0..len)
+                .map(|_| {
+                    timestamp += rng.below(3);
+                    timestamp
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn search_time_matches_a_scan() {
+        for timestamps in histories() {
+            let end = timestamps.last().map_or(2, |last| last + 2);
+            for time in 0..=end {
+                assert_eq!(
+                    search_time(timestamps.len(), |index| timestamps[index], &time),
+                    scan(&timestamps, time),
+                    "{time} in {timestamps:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn eytzinger_search_matches_a_scan() {
+        for timestamps in histories() {
+            let index = EytzingerIndex::new(timestamps.len(), |index| timestamps[index]);
+            assert_eq!(index.len(), timestamps.len());
+
+            let end = timestamps.last().map_or(2, |last| last + 2);
+            for time in 0..=end {
+                assert_eq!(
+                    index.search(&time),
+                    scan(&timestamps, time),
+                    "{time} in {timestamps:?}"
+                );
+            }
+        }
     }
 }
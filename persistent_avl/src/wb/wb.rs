@@ -0,0 +1,157 @@
+// Weight balancing, BB[α] with α = 2/7, written against closures like
+// `avl::avl`. The rotations are the ones from `avl::avl`, with subtree
+// sizes in place of heights:
+//
+//   get_size   number of nodes in the subtree of a node
+//   modify     sets the children of a node and recomputes its size from
+//              them, ignoring the size it is handed
+//
+// A node is balanced when both of its subtrees hold at least α of its
+// weight, the weight of a subtree being its size plus one.
+
+use crate::avl::avl;
+
+/// α = ALPHA_NUM / ALPHA_DEN
+const ALPHA_NUM: u64 = 2;
+const ALPHA_DEN: u64 = 7;
+
+/// A single rotation rebalances when the inner grandchild holds at most
+/// 1 / (2 - α) of the child's weight, otherwise a double rotation does
+const SINGLE_NUM: u64 = ALPHA_DEN;
+const SINGLE_DEN: u64 = 2 * ALPHA_DEN - ALPHA_NUM;
+
+fn weight<NodePtr: Copy>(get_size: &impl Fn(NodePtr) -> u64, node: Option<NodePtr>) -> u64 {
+    node.map_or(0, get_size) + 1
+}
+
+/// Whether a node with subtrees `left` and `right` would be balanced
+pub(crate) fn balanced<NodePtr: Copy>(
+    get_size: &impl Fn(NodePtr) -> u64,
+    left: Option<NodePtr>,
+    right: Option<NodePtr>,
+) -> bool {
+    let (left, right) = (weight(get_size, left), weight(get_size, right));
+
+    ALPHA_DEN * left.min(right) >= ALPHA_NUM * (left + right)
+}
+
+pub(crate) fn balance_node<NodePtr: Copy>(
+    get_left: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_right: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_size: &impl Fn(NodePtr) -> u64,
+    modify: &mut impl FnMut(NodePtr, Option<NodePtr>, Option<NodePtr>, u64),
+    node: NodePtr,
+) -> NodePtr {
+    modify(node, get_left(node), get_right(node), get_size(node));
+
+    let total = weight(get_size, Some(node));
+    let left = get_left(node);
+    let right = get_right(node);
+
+    if ALPHA_DEN * weight(get_size, right) < ALPHA_NUM * total {
+        let left_child = left.unwrap();
+
+        // LR
+        if SINGLE_DEN * weight(get_size, get_right(left_child))
+            > SINGLE_NUM * weight(get_size, left)
+        {
+            let new_left_child =
+                avl::rotate_left(get_left, get_right, get_size, modify, left_child);
+            modify(node, Some(new_left_child), right, get_size(node));
+        }
+
+        // LL & LR
+        avl::rotate_right(get_left, get_right, get_size, modify, node)
+    } else if ALPHA_DEN * weight(get_size, left) < ALPHA_NUM * total {
+        let right_child = right.unwrap();
+
+        // RL
+        if SINGLE_DEN * weight(get_size, get_left(right_child))
+            > SINGLE_NUM * weight(get_size, right)
+        {
+            let new_right_child =
+                avl::rotate_right(get_left, get_right, get_size, modify, right_child);
+            modify(node, left, Some(new_right_child), get_size(node));
+        }
+
+        // RL & RR
+        avl::rotate_left(get_left, get_right, get_size, modify, node)
+    } else {
+        node
+    }
+}
+
+/// Recomputes sizes and rebalances up `path`, which runs from the root down
+/// to the lowest modified node. Returns the new root.
+pub(crate) fn balance<NodePtr: Copy + PartialEq>(
+    get_left: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_right: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_size: &impl Fn(NodePtr) -> u64,
+    modify: &mut impl FnMut(NodePtr, Option<NodePtr>, Option<NodePtr>, u64),
+    path: &[NodePtr],
+) -> Option<NodePtr> {
+    let mut child = *path.last()?;
+
+    for &parent in path.iter().rev().skip(1) {
+        let is_left_child = get_left(parent) == Some(child);
+
+        child = balance_node(get_left, get_right, get_size, modify, child);
+
+        if is_left_child {
+            modify(parent, Some(child), get_right(parent), get_size(parent));
+        } else {
+            modify(parent, get_left(parent), Some(child), get_size(parent));
+        }
+
+        child = parent;
+    }
+
+    Some(balance_node(get_left, get_right, get_size, modify, child))
+}
+
+/// Number of elements less than `data`
+pub(crate) fn rank<NodePtr: Copy, Data>(
+    get_left: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_right: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_size: &impl Fn(NodePtr) -> u64,
+    compare: &impl Fn(&Data, NodePtr) -> std::cmp::Ordering,
+    mut root: Option<NodePtr>,
+    data: &Data,
+) -> u64 {
+    let mut rank = 0;
+
+    while let Some(current) = root {
+        if compare(data, current).is_le() {
+            root = get_left(current);
+        } else {
+            rank += weight(get_size, get_left(current));
+            root = get_right(current);
+        }
+    }
+
+    rank
+}
+
+/// The node holding the element with `index` elements less than it
+pub(crate) fn select<NodePtr: Copy>(
+    get_left: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_right: &impl Fn(NodePtr) -> Option<NodePtr>,
+    get_size: &impl Fn(NodePtr) -> u64,
+    mut root: Option<NodePtr>,
+    mut index: u64,
+) -> Option<NodePtr> {
+    while let Some(current) = root {
+        let left_size = get_left(current).map_or(0, get_size);
+
+        if index < left_size {
+            root = get_left(current);
+        } else if index == left_size {
+            return Some(current);
+        } else {
+            index -= left_size + 1;
+            root = get_right(current);
+        }
+    }
+
+    None
+}